@@ -1,12 +1,31 @@
 import sys
 import os
 import json
-from music_composer import MusicComposer
+import time
+import urllib.request
+import urllib.error
 
 logger_info_code = "LogCoQ=1001"
 logger_success_code = "LogCoQ=1002"
 logger_error_code = "LogCoQ=1003"
 
+output_abc = "output.abc"
+
+# Appended to the success line with the path of the MIDI written for the request
+midi_marker = "|midi="
+
+# Asked of providers that answer in text, the app turns the notation into MIDI and audio
+abc_instruction = "Answer only with one complete tune in ABC notation (X:, T:, M:, L:, Q:, K: headers, then the music), without any explanation."
+
+# Played by the mock provider when no responses are configured
+mock_tune = """X:1
+T:Mock Tune
+M:4/4
+L:1/8
+Q:1/4=120
+K:C
+CDEF GABc|c2G2 E2C2|DEFG ABcd|c4 z4|]"""
+
 try:
 	# Handle optional paths argument
 	input_data = json.loads(sys.argv[1])
@@ -14,17 +33,105 @@ try:
 	print(f'{logger_info_code}soundfont:{input_data.get("soundfont", "")}')
 	print(f'{logger_info_code}text:{input_data.get("text", "")}')
 
-	# Initialize SnapHound
-	musicComposer = MusicComposer(soundfont_path=input_data.get("soundfont", ""))
-	print(f"{logger_info_code}Music Composer Started.")
+	# The Gemini composer is only loaded when that provider is used
+	musicComposer = None
+	mock_index = 0
+
+	def composer():
+		global musicComposer
+		if musicComposer is None:
+			from music_composer import MusicComposer
+			musicComposer = MusicComposer(soundfont_path=input_data.get("soundfont", ""))
+			print(f"{logger_info_code}Music Composer Started.")
+		return musicComposer
+
+	def system_prompt():
+		try:
+			with open("config.json", encoding="utf-8") as config_file:
+				return json.load(config_file).get("system_prompt") or ""
+		except (OSError, ValueError):
+			return ""
+
+	# Any server with an OpenAI-style chat completions endpoint, the status code stays in the
+	# message so rate limits are recognised
+	def ask_openai_compatible(provider, text):
+		system = (system_prompt() + "\n\n" + abc_instruction).strip()
+		body = json.dumps({
+			"model": provider.get("model"),
+			"messages": [{"role": "system", "content": system}, {"role": "user", "content": text}],
+		}).encode("utf-8")
+		request = urllib.request.Request(provider.get("base_url", "") + "/chat/completions", data=body, method="POST")
+		request.add_header("Content-Type", "application/json")
+		api_key = os.environ.get("OPENAI_API_KEY")
+		if api_key:
+			request.add_header("Authorization", f"Bearer {api_key}")
+		try:
+			with urllib.request.urlopen(request, timeout=provider.get("timeout_secs", 120)) as response:
+				reply = json.loads(response.read().decode("utf-8"))
+		except urllib.error.HTTPError as e:
+			raise Exception(f"{e.code} {e.reason}: {e.read().decode('utf-8', 'replace')[:500]}")
+		return reply["choices"][0]["message"]["content"]
+
+	def ask_mock(provider):
+		global mock_index
+		responses = provider.get("responses") or [mock_tune]
+		reply = responses[mock_index % len(responses)]
+		mock_index += 1
+		return reply
+
+	# The MIDI the composer wrote for this request, next to its audio or where .env points it.
+	# Files older than the request are left out so an earlier tune is never reported
+	def written_midi(file_path, started):
+		candidates = [os.path.splitext(str(file_path))[0] + ".mid", os.environ.get("OUTPUT_MIDI") or "output.mid"]
+		for candidate in candidates:
+			if os.path.isfile(candidate) and os.path.getmtime(candidate) >= started - 1:
+				return os.path.abspath(candidate)
+		return ""
+
+	# Writes a text reply for the app to render, without the code fences models like to add
+	def write_abc(reply):
+		lines = [line for line in reply.strip().splitlines() if not line.strip().startswith("```")]
+		with open(output_abc, "w", encoding="utf-8") as abc_file:
+			abc_file.write("\n".join(lines) + "\n")
+		return os.path.abspath(output_abc)
+
+	# Adds the locally derived direction and section position to the prompt
+	def describe(data):
+		text = data.get("text", "")
+		suggestion = data.get("suggestion")
+		if suggestion:
+			instruments = ", ".join(instrument["name"] for instrument in suggestion.get("instruments", []))
+			text += f"\n\nMusical direction: {suggestion['key']}, about {suggestion['tempo_bpm']} BPM, {suggestion['dynamics']} dynamics, instruments: {instruments}."
+		section = data.get("section")
+		if section:
+			text += f"\nThis is section {section['index'] + 1} of {section['count']} of one continuous piece, keep the key and instruments."
+		variant = data.get("variant")
+		if variant:
+			text += f"\nThis is variation {variant['index'] + 1} of {variant['count']} (seed {data.get('seed', 0)}), make it clearly different from the other variations."
+		return text
 
 	def main(data):
 		input_data["text"] = data.get("text", "")
 		input_data["index"] = data.get("index", False)
 
 		if input_data["text"]:
-			file_path = musicComposer.generate_music(input_data["text"])
-			print(f"{logger_success_code}{file_path}")
+			provider = data.get("provider") or {}
+			kind = provider.get("kind", "gemini")
+			midi_path = ""
+			if kind == "gemini":
+				started = time.time()
+				file_path = composer().generate_music(describe(data))
+				midi_path = written_midi(file_path, started)
+			elif kind == "openai_compatible":
+				file_path = write_abc(ask_openai_compatible(provider, describe(data)))
+			elif kind == "mock":
+				file_path = write_abc(ask_mock(provider))
+			else:
+				raise Exception(f"Unknown provider: {kind}")
+			if midi_path:
+				print(f"{logger_success_code}{file_path}{midi_marker}{midi_path}")
+			else:
+				print(f"{logger_success_code}{file_path}")
 
 	def server_mode():
 		main(input_data)
@@ -0,0 +1,1165 @@
+use std::collections::HashMap;
+use std::fmt::{self, Write};
+use serde::Serialize;
+use crate::midi::{Composition, KeySignature, Note, TempoChange, TimeSignature, Track, DRUM_CHANNEL};
+use crate::notation::{Beam, Clef, Event, Pitch, Score};
+use crate::theory::{signature_alterations, Chord, Key};
+
+const TICKS_PER_BEAT: u16 = 480;
+const WHOLE: u64 = TICKS_PER_BEAT as u64 * 4;
+const DEFAULT_VELOCITY: u8 = 80;
+// Chord symbols are played by a nylon guitar starting at the C below middle C
+const CHORD_PROGRAM: u8 = 24;
+const CHORD_BASE: u8 = 48;
+const LETTERS: &str = "CDEFGAB";
+const LETTER_STEPS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+const DYNAMICS: [(&str, u8); 8] = [("ppp", 30), ("pp", 45), ("p", 60), ("mp", 72), ("mf", 85), ("f", 100), ("ff", 112), ("fff", 124)];
+// Exported tunes put this many bars on a line
+const BARS_PER_LINE: usize = 4;
+// Limits that keep lengths and repeat counts from overflowing or running away
+const MAX_NUMBER: u64 = 9999;
+const MAX_SLASHES: u32 = 6;
+const MAX_BROKEN_RHYTHM: u32 = 3;
+const MAX_REST_BARS: u64 = 999;
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub message: String,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}, column {}: {}", self.line, self.column, self.message)
+    }
+}
+
+pub struct Tune {
+    pub title: Option<String>,
+    pub composition: Composition,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Marker {
+    Tempo(u32),
+    Meter(u8, u8),
+    Key(i8, bool),
+}
+
+// A written bar; repeats and endings are only resolved once the whole voice is read
+#[derive(Debug, Clone, Default)]
+struct Bar {
+    start: u64,
+    end: u64,
+    repeat_start: bool,
+    repeat_end: bool,
+    ending: Option<u8>,
+}
+
+struct Voice {
+    id: String,
+    name: Option<String>,
+    program: u8,
+    // Set by %%MIDI channel, otherwise the voice gets the next free channel
+    channel: Option<u8>,
+    unit: (u64, u64),
+    key: [i32; 7],
+    velocity: u8,
+    // Positions are on the written timeline, before repeats are played out
+    notes: Vec<Note>,
+    chords: Vec<(u64, Chord)>,
+    markers: Vec<(u64, Marker)>,
+    bars: Vec<Bar>,
+    bar: Bar,
+    cursor: u64,
+    ending: Option<u8>,
+    bar_accidentals: HashMap<(usize, i32), i32>,
+    // Notes waiting for the continuation of a tie
+    tied: Vec<usize>,
+    // The previous note or chord and its length, for ties and broken rhythm
+    last: Vec<usize>,
+    last_duration: u64,
+    broken: Option<(u64, u64)>,
+    // Notes per group, the time of that many in ticks, and notes left
+    tuplet: Option<(u64, u64, u64)>,
+}
+
+impl Voice {
+    fn new(id: String, tune: &Parser) -> Self {
+        Voice {
+            id,
+            name: None,
+            program: tune.program,
+            channel: tune.channel,
+            unit: tune.unit(),
+            key: tune.key,
+            velocity: DEFAULT_VELOCITY,
+            notes: Vec::new(),
+            chords: Vec::new(),
+            markers: Vec::new(),
+            bars: Vec::new(),
+            bar: Bar::default(),
+            cursor: 0,
+            ending: None,
+            bar_accidentals: HashMap::new(),
+            tied: Vec::new(),
+            last: Vec::new(),
+            last_duration: 0,
+            broken: None,
+            tuplet: None,
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.cursor == 0 && self.bars.is_empty()
+    }
+
+    fn duration(&mut self, (num, den): (u64, u64)) -> u64 {
+        let mut duration = WHOLE * self.unit.0 * num / (self.unit.1 * den);
+        if let Some((num, den)) = self.broken.take() {
+            duration = duration * num / den;
+        }
+        if let Some((notes, time, left)) = self.tuplet {
+            duration = duration * time / notes;
+            self.tuplet = if left > 1 { Some((notes, time, left - 1)) } else { None };
+        }
+        duration
+    }
+
+    fn add_notes(&mut self, pitches: &[u8], length: (u64, u64)) {
+        let duration = self.duration(length);
+        let mut indices = Vec::new();
+        for &pitch in pitches {
+            let cursor = self.cursor;
+            let tied = self.tied.iter().position(|&index| self.notes[index].pitch == pitch && self.notes[index].end() == cursor);
+            match tied {
+                Some(position) => {
+                    let index = self.tied.remove(position);
+                    self.notes[index].duration += duration;
+                    indices.push(index);
+                }
+                None => {
+                    self.notes.push(Note { start: cursor, duration, pitch, velocity: self.velocity });
+                    indices.push(self.notes.len() - 1);
+                }
+            }
+        }
+        self.tied.clear();
+        self.last = indices;
+        self.last_duration = duration;
+        self.cursor += duration;
+    }
+
+    fn rest(&mut self, length: (u64, u64)) {
+        let duration = self.duration(length);
+        self.tied.clear();
+        self.last.clear();
+        self.last_duration = duration;
+        self.cursor += duration;
+    }
+
+    // `a>b` dots the first note and halves the second, `a<b` the other way round
+    fn broken_rhythm(&mut self, count: u32, longer_first: bool) {
+        let den = 1u64 << count;
+        let long = (2 * den - 1, den);
+        let (first, second) = if longer_first { (long, (1, den)) } else { ((1, den), long) };
+        let changed = self.last_duration * first.0 / first.1;
+        for &index in &self.last {
+            self.notes[index].duration = self.notes[index].duration + changed - self.last_duration;
+        }
+        self.cursor = self.cursor + changed - self.last_duration;
+        self.last_duration = changed;
+        self.broken = Some(second);
+    }
+
+    fn pitch(&mut self, letter: usize, octave: i32, accidental: Option<i32>) -> Result<u8, String> {
+        let accidental = match accidental {
+            Some(accidental) => {
+                self.bar_accidentals.insert((letter, octave), accidental);
+                accidental
+            }
+            None => self.bar_accidentals.get(&(letter, octave)).copied().unwrap_or(self.key[letter]),
+        };
+        let pitch = 12 * (octave + 1) + LETTER_STEPS[letter] + accidental;
+        u8::try_from(pitch).ok().filter(|pitch| *pitch <= 127).ok_or_else(|| "Note is outside the MIDI range".to_string())
+    }
+
+    fn close_bar(&mut self) {
+        self.bar_accidentals.clear();
+        if self.cursor == self.bar.start {
+            return;
+        }
+        self.bar.end = self.cursor;
+        let next = Bar { start: self.cursor, ending: self.ending, ..Bar::default() };
+        self.bars.push(std::mem::replace(&mut self.bar, next));
+    }
+
+    // Handles a bar line such as "|", "||", "|]", "|:", ":|", "::" or ":|2"
+    fn bar_line(&mut self, token: &str, ending: Option<u8>) {
+        if token.starts_with(':') {
+            match self.bars.last_mut() {
+                Some(last) if self.cursor == self.bar.start => last.repeat_end = true,
+                _ => self.bar.repeat_end = true,
+            }
+        }
+        self.close_bar();
+        if token != "|" {
+            self.ending = None;
+        }
+        if token.ends_with(':') {
+            self.bar.repeat_start = true;
+        }
+        if ending.is_some() {
+            self.ending = ending;
+        }
+        self.bar.ending = self.ending;
+    }
+
+    // Plays out repeats and endings, returning the order in which written bars sound
+    fn unroll(&self) -> Vec<Bar> {
+        let mut bars = self.bars.clone();
+        if self.cursor > self.bar.start {
+            bars.push(Bar { end: self.cursor, ..self.bar.clone() });
+        }
+        let mut order = Vec::new();
+        let (mut start, mut index) = (0, 0);
+        let mut jumped_from: Option<usize> = None;
+        while index < bars.len() {
+            let bar = &bars[index];
+            if jumped_from.is_some_and(|from| index > from) && bar.ending.is_none() {
+                jumped_from = None;
+            }
+            let pass = if jumped_from.is_some() { 2 } else { 1 };
+            if bar.repeat_start && pass == 1 {
+                start = index;
+            }
+            if bar.ending.is_some_and(|ending| ending != pass) {
+                index += 1;
+                continue;
+            }
+            order.push(bar.clone());
+            if bar.repeat_end {
+                if pass == 1 {
+                    jumped_from = Some(index);
+                    index = start;
+                    continue;
+                }
+                jumped_from = None;
+                start = index + 1;
+            }
+            index += 1;
+        }
+        order
+    }
+}
+
+struct Parser {
+    title: Option<String>,
+    meter: (u8, u8),
+    unit: Option<(u64, u64)>,
+    key: [i32; 7],
+    program: u8,
+    channel: Option<u8>,
+    header_markers: Vec<Marker>,
+    voices: Vec<Voice>,
+    current: usize,
+    in_body: bool,
+}
+
+fn fraction(text: &str) -> Option<(u64, u64)> {
+    let (num, den) = text.trim().split_once('/')?;
+    let (num, den) = (num.trim().parse::<u64>().ok()?, den.trim().parse::<u64>().ok()?);
+    (num > 0 && den > 0 && num <= MAX_NUMBER && den <= MAX_NUMBER).then_some((num, den))
+}
+
+fn parse_meter(text: &str) -> Result<Option<(u8, u8)>, String> {
+    let text = text.trim();
+    match text {
+        "" | "none" => return Ok(None),
+        "C" => return Ok(Some((4, 4))),
+        "C|" => return Ok(Some((2, 2))),
+        _ => {}
+    }
+    let invalid = || format!("Invalid meter \"{}\"", text);
+    let (num, den) = text.split_once('/').ok_or_else(invalid)?;
+    // Additive meters such as 2+3/8
+    let mut sum = 0u32;
+    for part in num.split('+') {
+        let part = part.trim().parse::<u32>().map_err(|_| invalid())?;
+        sum = sum.checked_add(part).ok_or_else(invalid)?;
+    }
+    let num = u8::try_from(sum).map_err(|_| invalid())?;
+    let den = den.trim().parse::<u8>().map_err(|_| invalid())?;
+    if num == 0 || !den.is_power_of_two() {
+        return Err(invalid());
+    }
+    Ok(Some((num, den)))
+}
+
+// "1/4=120", "3/8=60", "\"Allegro\" 1/4=120" or a bare number of unit notes per minute
+fn parse_tempo(text: &str, unit: (u64, u64)) -> Result<u32, String> {
+    let invalid = || format!("Invalid tempo \"{}\"", text.trim());
+    let plain: String = text.split('"').step_by(2).collect();
+    let (beat, bpm) = match plain.split_once('=') {
+        Some((beats, bpm)) => {
+            let beats = beats.split_whitespace().map(fraction).collect::<Option<Vec<_>>>().ok_or_else(invalid)?;
+            let beat = beats.iter().map(|(num, den)| *num as f64 / *den as f64).sum::<f64>();
+            (beat, bpm)
+        }
+        None => (unit.0 as f64 / unit.1 as f64, plain.as_str()),
+    };
+    let bpm = bpm.trim().parse::<f64>().map_err(|_| invalid())?;
+    if bpm <= 0.0 || beat <= 0.0 {
+        return Err(invalid());
+    }
+    Ok((60_000_000.0 / (bpm * beat * 4.0)).round() as u32)
+}
+
+// Signature of keys such as "G", "Am", "F#m", "Bb major" or "D dorian", in sharps (positive) or flats
+fn parse_key(text: &str) -> Result<(i8, bool), String> {
+    let name: String = text.split_whitespace().filter(|word| !word.contains('=')).collect();
+    match name.as_str() {
+        "" | "none" | "HP" => return Ok((0, false)),
+        "Hp" => return Ok((2, false)),
+        _ => {}
+    }
+    let invalid = || format!("Unknown key \"{}\"", text.trim());
+    let mut chars = name.chars();
+    let letter = chars.next().ok_or_else(invalid)?;
+    const LETTER_FIFTHS: [(char, i32); 7] = [('C', 0), ('G', 1), ('D', 2), ('A', 3), ('E', 4), ('B', 5), ('F', -1)];
+    let mut fifths = LETTER_FIFTHS.iter().find(|(l, _)| *l == letter.to_ascii_uppercase()).ok_or_else(invalid)?.1;
+    let mut rest = &name[letter.len_utf8()..];
+    if let Some(stripped) = rest.strip_prefix('#') {
+        fifths += 7;
+        rest = stripped;
+    } else if let Some(stripped) = rest.strip_prefix('b') {
+        fifths -= 7;
+        rest = stripped;
+    }
+    let mode = rest.to_lowercase();
+    let (offset, minor) = match mode.get(..3).unwrap_or(&mode) {
+        "" | "maj" | "ion" => (0, false),
+        "m" | "min" | "aeo" => (-3, true),
+        "mix" => (-1, false),
+        "dor" => (-2, false),
+        "phr" => (-4, false),
+        "lyd" => (1, false),
+        "loc" => (-5, false),
+        _ => return Err(invalid()),
+    };
+    let sharps = fifths + offset;
+    if sharps.abs() > 7 {
+        return Err(format!("Key \"{}\" needs more than seven sharps or flats", text.trim()));
+    }
+    Ok((sharps as i8, minor))
+}
+
+// Reads digits at `index`, leaving it after them; None when there are none
+fn number(chars: &[char], index: &mut usize) -> Result<Option<u64>, String> {
+    let start = *index;
+    while chars.get(*index).is_some_and(|c| c.is_ascii_digit()) {
+        *index += 1;
+    }
+    if start == *index {
+        return Ok(None);
+    }
+    let digits: String = chars[start..*index].iter().collect();
+    match digits.parse::<u64>() {
+        Ok(value) if value <= MAX_NUMBER => Ok(Some(value)),
+        _ => Err(format!("Number {} is larger than {}", digits, MAX_NUMBER)),
+    }
+}
+
+// Note length multiplier such as "2", "3/2", "/" or "//"
+fn length(chars: &[char], index: &mut usize) -> Result<(u64, u64), String> {
+    let num = number(chars, index)?.unwrap_or(1);
+    let mut den = 1;
+    if chars.get(*index) == Some(&'/') {
+        let mut slashes = 0;
+        while chars.get(*index) == Some(&'/') {
+            *index += 1;
+            slashes += 1;
+        }
+        if slashes > MAX_SLASHES {
+            return Err(format!("A note length has at most {} slashes", MAX_SLASHES));
+        }
+        den = match number(chars, index)? {
+            Some(den) if slashes == 1 => den,
+            Some(_) => return Err("A note length has either several slashes or a denominator".to_string()),
+            None => 1 << slashes,
+        };
+    }
+    if num == 0 || den == 0 {
+        return Err("Note length must not be zero".to_string());
+    }
+    Ok((num, den))
+}
+
+// Accidentals, letter and octave marks of a note, leaving `index` before its length
+fn note_name(chars: &[char], index: &mut usize) -> Result<(usize, i32, Option<i32>), String> {
+    let mut accidental = None;
+    while let Some(&c) = chars.get(*index) {
+        let step = match c {
+            '^' => 1,
+            '_' => -1,
+            '=' => 0,
+            _ => break,
+        };
+        accidental = Some(if step == 0 { 0 } else { accidental.unwrap_or(0) + step });
+        *index += 1;
+    }
+    let letter = *chars.get(*index).ok_or_else(|| "Accidental without a note".to_string())?;
+    let position = LETTERS.find(letter.to_ascii_uppercase()).filter(|_| letter.is_ascii_alphabetic());
+    let Some(position) = position else {
+        return Err(format!("Expected a note after the accidental, found '{}'", letter));
+    };
+    *index += 1;
+    let mut octave = if letter.is_ascii_lowercase() { 5 } else { 4 };
+    while let Some(&c) = chars.get(*index) {
+        match c {
+            '\'' => octave += 1,
+            ',' => octave -= 1,
+            _ => break,
+        }
+        *index += 1;
+    }
+    Ok((position, octave, accidental))
+}
+
+// Finds the closing delimiter of a chord symbol, decoration or grace group
+fn closing(chars: &[char], from: usize, delimiter: char) -> Option<usize> {
+    chars[from..].iter().position(|&c| c == delimiter).map(|offset| from + offset)
+}
+
+impl Parser {
+    fn unit(&self) -> (u64, u64) {
+        // Without an L: field short meters default to sixteenths, everything else to eighths
+        self.unit.unwrap_or(if (self.meter.0 as f64) / (self.meter.1 as f64) < 0.75 { (1, 16) } else { (1, 8) })
+    }
+
+    fn voice(&mut self) -> &mut Voice {
+        if self.voices.is_empty() {
+            let voice = Voice::new(String::new(), self);
+            self.voices.push(voice);
+        }
+        &mut self.voices[self.current]
+    }
+
+    fn select_voice(&mut self, definition: &str) {
+        let id = definition.split_whitespace().next().unwrap_or("").to_string();
+        // name="Violin" or nm="Violin"
+        let name = ["name=\"", "nm=\""]
+            .iter()
+            .find_map(|prefix| definition.split_once(prefix))
+            .and_then(|(_, rest)| rest.split_once('"'))
+            .map(|(name, _)| name.to_string());
+        let position = self.voices.iter().position(|voice| voice.id == id);
+        self.current = match position {
+            Some(position) => position,
+            // Music before the first V: field belongs to the first voice
+            None if self.voices.len() == 1 && self.voices[0].id.is_empty() && self.voices[0].is_empty() => {
+                self.voices[0].id = id;
+                0
+            }
+            None => {
+                let voice = Voice::new(id, self);
+                self.voices.push(voice);
+                self.voices.len() - 1
+            }
+        };
+        if let Some(name) = name {
+            self.voices[self.current].name = Some(name);
+        }
+    }
+
+    fn field(&mut self, field: char, value: &str) -> Result<(), String> {
+        match field {
+            'T' if !self.in_body && self.title.is_none() && !value.trim().is_empty() => {
+                self.title = Some(value.trim().to_string());
+            }
+            'M' => {
+                let meter = parse_meter(value)?;
+                if let Some((numerator, denominator)) = meter {
+                    self.meter = (numerator, denominator);
+                    self.marker(Marker::Meter(numerator, denominator));
+                }
+            }
+            'L' => {
+                let unit = fraction(value).ok_or_else(|| format!("Invalid unit note length \"{}\"", value.trim()))?;
+                self.unit = Some(unit);
+                if self.in_body {
+                    self.voice().unit = unit;
+                }
+            }
+            'Q' => {
+                let unit = if self.in_body { self.voice().unit } else { self.unit() };
+                let tempo = parse_tempo(value, unit)?;
+                self.marker(Marker::Tempo(tempo));
+            }
+            'K' => {
+                let (sharps, minor) = parse_key(value)?;
+                let key = signature_alterations(sharps);
+                self.marker(Marker::Key(sharps, minor));
+                if self.in_body {
+                    self.voice().key = key;
+                } else {
+                    self.key = key;
+                    // The key ends the header, every voice starts from the header's settings
+                    let unit = self.unit();
+                    for voice in self.voices.iter_mut() {
+                        voice.key = key;
+                        voice.unit = unit;
+                    }
+                    self.in_body = true;
+                }
+            }
+            'V' => self.select_voice(value),
+            _ => {}
+        }
+        Ok(())
+    }
+
+    fn marker(&mut self, marker: Marker) {
+        if self.in_body {
+            let voice = self.voice();
+            let cursor = voice.cursor;
+            voice.markers.push((cursor, marker));
+        } else {
+            self.header_markers.push(marker);
+        }
+    }
+
+    // %%MIDI program and %%MIDI channel directives set the instrument or channel (1-16) of the
+    // current voice, or of all voices in the header
+    fn directive(&mut self, line: &str) {
+        let words: Vec<&str> = line.trim_start_matches('%').split_whitespace().collect();
+        if words.len() < 3 || words[0] != "MIDI" {
+            return;
+        }
+        let value = words.last().and_then(|word| word.parse::<u8>().ok());
+        match words[1] {
+            "program" => {
+                if let Some(program) = value.filter(|program| *program < 128) {
+                    if self.in_body {
+                        self.voice().program = program;
+                    } else {
+                        self.program = program;
+                    }
+                }
+            }
+            "channel" => {
+                if let Some(channel) = value.filter(|channel| (1..=16).contains(channel)).map(|channel| channel - 1) {
+                    if self.in_body {
+                        self.voice().channel = Some(channel);
+                    } else {
+                        self.channel = Some(channel);
+                    }
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn body_line(&mut self, chars: &[char], line: usize) -> Result<(), ParseError> {
+        let mut index = 0;
+        let error = |column: usize, message: String| ParseError { line, column: column + 1, message };
+        while index < chars.len() {
+            let c = chars[index];
+            let start = index;
+            match c {
+                ' ' | '\t' | '`' | '\\' | ')' | 'y' | '.' | '~' | 'H' | 'L' | 'M' | 'O' | 'P' | 'S' | 'T' | 'u' | 'v' => index += 1,
+                '%' => break,
+                '"' => {
+                    let end = closing(chars, index + 1, '"').ok_or_else(|| error(start, "Unterminated chord symbol".to_string()))?;
+                    let symbol: String = chars[index + 1..end].iter().collect();
+                    // Annotations start with a placement character
+                    if !symbol.starts_with(['^', '_', '<', '>', '@']) {
+                        let chord = Chord::parse(&symbol).ok_or_else(|| error(start + 1, format!("Unknown chord symbol \"{}\"", symbol)))?;
+                        let voice = self.voice();
+                        let cursor = voice.cursor;
+                        voice.chords.push((cursor, chord));
+                    }
+                    index = end + 1;
+                }
+                '!' | '+' => {
+                    let end = closing(chars, index + 1, c).ok_or_else(|| error(start, "Unterminated decoration".to_string()))?;
+                    let decoration: String = chars[index + 1..end].iter().collect();
+                    if let Some((_, velocity)) = DYNAMICS.iter().find(|(name, _)| *name == decoration) {
+                        self.voice().velocity = *velocity;
+                    }
+                    index = end + 1;
+                }
+                '{' => {
+                    // Grace notes take no time of their own and are left out
+                    let end = closing(chars, index + 1, '}').ok_or_else(|| error(start, "Unterminated grace notes".to_string()))?;
+                    index = end + 1;
+                }
+                '(' => {
+                    index += 1;
+                    if let Some(notes) = number(chars, &mut index).map_err(|message| error(start, message))? {
+                        let mut parts = vec![notes];
+                        for _ in 0..2 {
+                            if chars.get(index) != Some(&':') {
+                                break;
+                            }
+                            index += 1;
+                            parts.push(number(chars, &mut index).map_err(|message| error(start, message))?.unwrap_or(0));
+                        }
+                        if !(2..=9).contains(&notes) {
+                            return Err(error(start, format!("Tuplets have 2 to 9 notes, not {}", notes)));
+                        }
+                        let compound = self.meter.0.is_multiple_of(3) && self.meter.0 > 3;
+                        let default_time = match notes {
+                            3 | 6 => 2,
+                            2 | 4 | 8 => 3,
+                            _ if compound => 3,
+                            _ => 2,
+                        };
+                        let time = parts.get(1).copied().filter(|time| *time > 0).unwrap_or(default_time);
+                        let count = parts.get(2).copied().filter(|count| *count > 0).unwrap_or(notes);
+                        self.voice().tuplet = Some((notes, time, count));
+                    }
+                }
+                '-' => {
+                    let voice = self.voice();
+                    if voice.last.is_empty() {
+                        return Err(error(start, "Tie without a note before it".to_string()));
+                    }
+                    voice.tied = voice.last.clone();
+                    index += 1;
+                }
+                '>' | '<' => {
+                    while chars.get(index) == Some(&c) {
+                        index += 1;
+                    }
+                    let count = (index - start) as u32;
+                    if count > MAX_BROKEN_RHYTHM {
+                        return Err(error(start, format!("Broken rhythm takes at most {} '{}'", MAX_BROKEN_RHYTHM, c)));
+                    }
+                    let voice = self.voice();
+                    if voice.last_duration == 0 {
+                        return Err(error(start, "Broken rhythm without a note before it".to_string()));
+                    }
+                    voice.broken_rhythm(count, c == '>');
+                }
+                '[' => {
+                    let next = chars.get(index + 1).copied();
+                    if next == Some('|') {
+                        index += 2;
+                        self.voice().bar_line("||", None);
+                    } else if next.is_some_and(|n| n.is_ascii_digit()) {
+                        index += 1;
+                        let ending = number(chars, &mut index).map_err(|message| error(start, message))?.map(|n| n.min(u8::MAX as u64) as u8);
+                        // Lists such as [1,3 play with the first ending
+                        while chars.get(index).is_some_and(|c| c.is_ascii_digit() || matches!(c, ',' | '-')) {
+                            index += 1;
+                        }
+                        let voice = self.voice();
+                        voice.ending = ending;
+                        voice.bar.ending = ending;
+                    } else if chars.get(index + 2) == Some(&':') && next.is_some_and(|n| n.is_ascii_alphabetic()) {
+                        let end = closing(chars, index, ']').ok_or_else(|| error(start, "Unterminated inline field".to_string()))?;
+                        let value: String = chars[index + 3..end].iter().collect();
+                        self.field(chars[index + 1], &value).map_err(|message| error(start + 3, message))?;
+                        index = end + 1;
+                    } else {
+                        let end = closing(chars, index, ']').ok_or_else(|| error(start, "Unterminated chord".to_string()))?;
+                        let mut pitches = Vec::new();
+                        let mut first_length = None;
+                        index += 1;
+                        while index < end {
+                            let at = index;
+                            match chars[index] {
+                                ' ' | '.' | '~' | '-' => index += 1,
+                                '!' | '+' => {
+                                    let delimiter = chars[index];
+                                    index = closing(chars, index + 1, delimiter).filter(|close| *close < end).ok_or_else(|| error(at, "Unterminated decoration".to_string()))? + 1;
+                                }
+                                _ => {
+                                    let (letter, octave, accidental) = note_name(chars, &mut index).map_err(|message| error(at, message))?;
+                                    let note_length = length(chars, &mut index).map_err(|message| error(at, message))?;
+                                    let pitch = self.voice().pitch(letter, octave, accidental).map_err(|message| error(at, message))?;
+                                    pitches.push(pitch);
+                                    first_length.get_or_insert(note_length);
+                                }
+                            }
+                        }
+                        if pitches.is_empty() {
+                            return Err(error(start, "Empty chord".to_string()));
+                        }
+                        index = end + 1;
+                        let (num, den) = first_length.unwrap_or((1, 1));
+                        let (outer_num, outer_den) = length(chars, &mut index).map_err(|message| error(end + 1, message))?;
+                        let tie = chars[start..end].contains(&'-');
+                        let voice = self.voice();
+                        voice.add_notes(&pitches, (num * outer_num, den * outer_den));
+                        if tie {
+                            voice.tied = voice.last.clone();
+                        }
+                    }
+                }
+                '|' | ':' => {
+                    while index < chars.len() && (matches!(chars[index], '|' | ':') || (chars[index] == ']' && chars[index - 1] == '|')) {
+                        index += 1;
+                    }
+                    let token: String = chars[start..index].iter().collect();
+                    if !token.contains('|') && token != "::" {
+                        return Err(error(start, format!("Unexpected '{}'", token)));
+                    }
+                    let ending = number(chars, &mut index).map_err(|message| error(start, message))?.map(|n| n.min(u8::MAX as u64) as u8);
+                    let token = match token.as_str() {
+                        "::" | ":||:" | ":|:" => ":|:",
+                        _ if token.starts_with(':') => ":|",
+                        _ if token.ends_with(':') => "|:",
+                        "|" => "|",
+                        _ => "||",
+                    };
+                    self.voice().bar_line(token, ending);
+                }
+                ']' => return Err(error(start, "Unmatched ']'".to_string())),
+                'z' | 'x' => {
+                    index += 1;
+                    let rest = length(chars, &mut index).map_err(|message| error(start, message))?;
+                    self.voice().rest(rest);
+                }
+                'Z' | 'X' => {
+                    index += 1;
+                    let bars = number(chars, &mut index).map_err(|message| error(start, message))?.unwrap_or(1);
+                    if bars > MAX_REST_BARS {
+                        return Err(error(start, format!("A multi-measure rest lasts at most {} bars", MAX_REST_BARS)));
+                    }
+                    let bar = WHOLE * self.meter.0 as u64 / self.meter.1 as u64;
+                    let voice = self.voice();
+                    for _ in 0..bars {
+                        voice.cursor += bar;
+                        voice.close_bar();
+                    }
+                }
+                '^' | '_' | '=' | 'A'..='G' | 'a'..='g' => {
+                    let (letter, octave, accidental) = note_name(chars, &mut index).map_err(|message| error(start, message))?;
+                    let note_length = length(chars, &mut index).map_err(|message| error(start, message))?;
+                    let voice = self.voice();
+                    let pitch = voice.pitch(letter, octave, accidental).map_err(|message| error(start, message))?;
+                    voice.add_notes(&[pitch], note_length);
+                }
+                _ => return Err(error(start, format!("Unexpected character '{}'", c))),
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<Tune, String> {
+        let mut composition = Composition::new(TICKS_PER_BEAT);
+        for marker in &self.header_markers {
+            apply_marker(&mut composition, 0, *marker);
+        }
+        let mut chords: Vec<(u64, Chord)> = Vec::new();
+        let mut end = 0;
+        let chosen: Vec<u8> = self.voices.iter().filter_map(|voice| voice.channel).collect();
+        let mut channels = (0..16u8).filter(|channel| *channel != DRUM_CHANNEL && !chosen.contains(channel));
+        for (index, voice) in self.voices.iter().enumerate() {
+            let mut notes = Vec::new();
+            let mut cursor = 0;
+            for bar in voice.unroll() {
+                let within = |tick: u64| tick >= bar.start && tick < bar.end;
+                let shift = |tick: u64| tick - bar.start + cursor;
+                notes.extend(voice.notes.iter().filter(|note| within(note.start)).map(|note| Note { start: shift(note.start), ..*note }));
+                chords.extend(voice.chords.iter().filter(|(tick, _)| within(*tick)).map(|(tick, chord)| (shift(*tick), *chord)));
+                // Tempo, meter and key changes follow the first voice
+                if index == 0 {
+                    for (tick, marker) in voice.markers.iter().filter(|(tick, _)| within(*tick)) {
+                        apply_marker(&mut composition, shift(*tick), *marker);
+                    }
+                }
+                cursor += bar.end - bar.start;
+            }
+            end = end.max(cursor);
+            if notes.is_empty() {
+                continue;
+            }
+            let channel = match voice.channel {
+                Some(channel) => channel,
+                None => channels.next().ok_or_else(|| "A tune can have at most 14 voices with notes".to_string())?,
+            };
+            let name = voice.name.clone().or_else(|| (!voice.id.is_empty()).then(|| format!("Voice {}", voice.id)));
+            composition.tracks.push(Track { name, channel, program: voice.program, notes });
+        }
+        if composition.tracks.is_empty() {
+            return Err("The tune has no notes".to_string());
+        }
+
+        chords.sort_by_key(|(tick, _)| *tick);
+        chords.dedup_by_key(|(tick, _)| *tick);
+        if !chords.is_empty() {
+            let channel = channels.next().ok_or_else(|| "No MIDI channel is left for the chord symbols".to_string())?;
+            let mut notes = Vec::new();
+            for (index, (tick, chord)) in chords.iter().enumerate() {
+                let until = chords.get(index + 1).map_or(end, |(next, _)| *next);
+                if until <= *tick {
+                    continue;
+                }
+                for step in chord.quality.intervals() {
+                    let pitch = CHORD_BASE + chord.root.0 + step;
+                    notes.push(Note { start: *tick, duration: until - tick, pitch, velocity: DEFAULT_VELOCITY * 3 / 4 });
+                }
+            }
+            composition.tracks.push(Track { name: Some("Chords".to_string()), channel, program: CHORD_PROGRAM, notes });
+        }
+
+        composition.tempos.sort_by_key(|change| change.tick);
+        composition.time_signatures.sort_by_key(|change| change.tick);
+        composition.key_signatures.sort_by_key(|change| change.tick);
+        Ok(Tune { title: self.title, composition })
+    }
+}
+
+fn apply_marker(composition: &mut Composition, tick: u64, marker: Marker) {
+    match marker {
+        Marker::Tempo(micros_per_beat) => {
+            composition.tempos.retain(|change| change.tick != tick);
+            composition.tempos.push(TempoChange { tick, micros_per_beat });
+        }
+        Marker::Meter(numerator, denominator) => {
+            composition.time_signatures.retain(|change| change.tick != tick);
+            composition.time_signatures.push(TimeSignature { tick, numerator, denominator });
+        }
+        Marker::Key(sharps, minor) => {
+            composition.key_signatures.retain(|change| change.tick != tick);
+            composition.key_signatures.push(KeySignature { tick, sharps, minor });
+        }
+    }
+}
+
+// Parses the first tune of an ABC text. Header fields are optional, so a bare line of notes
+// is a valid tune in C major and common time.
+pub fn parse(text: &str) -> Result<Tune, ParseError> {
+    let mut parser = Parser {
+        title: None,
+        meter: (4, 4),
+        unit: None,
+        key: [0; 7],
+        program: 0,
+        channel: None,
+        header_markers: Vec::new(),
+        voices: Vec::new(),
+        current: 0,
+        in_body: false,
+    };
+    let mut tunes = 0;
+    let mut last_line = 1;
+    for (index, line) in text.lines().enumerate() {
+        let line_number = index + 1;
+        last_line = line_number;
+        let chars: Vec<char> = line.chars().collect();
+        let trimmed = line.trim_start();
+        let indent = chars.len() - trimmed.chars().count();
+        if trimmed.starts_with("%%") {
+            parser.directive(trimmed);
+            continue;
+        }
+        if trimmed.is_empty() || trimmed.starts_with('%') {
+            continue;
+        }
+        let field = trimmed.chars().next().filter(|c| c.is_ascii_alphabetic() && trimmed[1..].starts_with(':'));
+        if let Some(field) = field {
+            if field == 'X' {
+                // Only the first tune of a tune book is played
+                tunes += 1;
+                if tunes > 1 {
+                    break;
+                }
+            }
+            // In the body only these fields may take a whole line, "A:" there is music
+            if !parser.in_body || "KLMQVTPWwNRIsm+".contains(field) {
+                parser
+                    .field(field, &trimmed[2..])
+                    .map_err(|message| ParseError { line: line_number, column: indent + 3, message })?;
+                continue;
+            }
+        }
+        parser.in_body = true;
+        parser.body_line(&chars, line_number)?;
+    }
+    parser.finish().map_err(|message| ParseError { line: last_line, column: 1, message })
+}
+
+fn gcd(a: u64, b: u64) -> u64 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn key_field(key: &Key) -> String {
+    format!("{}{}", key.tonic.name(key.uses_flats()), if key.minor { "m" } else { "" })
+}
+
+// Length as a multiple of the unit note: nothing for the unit itself, "3/2" for a dotted one
+fn length_text(duration: u64, unit: u64) -> String {
+    let divisor = gcd(duration, unit).max(1);
+    match (duration / divisor, unit / divisor) {
+        (1, 1) => String::new(),
+        (num, 1) => num.to_string(),
+        (1, 2) => "/".to_string(),
+        (num, den) => format!("{}/{}", num, den),
+    }
+}
+
+fn pitch_text(pitch: &Pitch, accidental: Option<i32>) -> String {
+    let mut text = match accidental {
+        Some(-2) => "__",
+        Some(-1) => "_",
+        Some(0) => "=",
+        Some(1) => "^",
+        Some(2) => "^^",
+        _ => "",
+    }
+    .to_string();
+    if pitch.octave >= 5 {
+        text.push(pitch.step.to_ascii_lowercase());
+        text.push_str(&"'".repeat((pitch.octave - 5) as usize));
+    } else {
+        text.push(pitch.step);
+        text.push_str(&",".repeat((4 - pitch.octave) as usize));
+    }
+    text
+}
+
+// One bar of a voice. Accidentals are worked out again per voice, since in ABC they only
+// carry through the bar of the voice they are written in, tied notes included.
+fn bar_text(score: &Score, index: usize, events: &[Event], unit: u64, first_voice: bool) -> String {
+    let measure = &score.measures[index];
+    let mut text = String::new();
+    if index > 0 {
+        if measure.time_change {
+            let _ = write!(text, "[M:{}/{}] ", measure.time.0, measure.time.1);
+        }
+        if measure.key_change {
+            let _ = write!(text, "[K:{}] ", key_field(&measure.key));
+        }
+        if let Some(bpm) = measure.tempo.filter(|_| first_voice) {
+            let _ = write!(text, "[Q:1/4={:.0}] ", bpm);
+        }
+    }
+    if events.is_empty() {
+        // Invisible rest where an extra voice has nothing to play
+        let _ = write!(text, "x{}", length_text(measure.length, unit));
+        return text;
+    }
+    let alterations = signature_alterations(measure.key.signature());
+    let mut in_effect: HashMap<(char, i32), i32> = HashMap::new();
+    for event in events {
+        if event.pitches.is_empty() {
+            let duration = if event.measure_rest { measure.length } else { event.duration };
+            let _ = write!(text, "z{}", length_text(duration, unit));
+        } else {
+            let pitches: Vec<String> = event
+                .pitches
+                .iter()
+                .map(|pitch| {
+                    let letter = LETTERS.find(pitch.step).unwrap_or(0);
+                    let current = in_effect.get(&(pitch.step, pitch.octave)).copied().unwrap_or(alterations[letter]);
+                    in_effect.insert((pitch.step, pitch.octave), pitch.alter);
+                    pitch_text(pitch, (current != pitch.alter).then_some(pitch.alter))
+                })
+                .collect();
+            if pitches.len() == 1 {
+                text.push_str(&pitches[0]);
+            } else {
+                let _ = write!(text, "[{}]", pitches.concat());
+            }
+            text.push_str(&length_text(event.duration, unit));
+            if event.tie_start {
+                text.push('-');
+            }
+        }
+        // Beamed notes are written without space between them
+        if !matches!(event.beam, Some(Beam::Begin | Beam::Continue)) {
+            text.push(' ');
+        }
+    }
+    text.trim_end().to_string()
+}
+
+// Writes a score as an ABC tune with one voice per notation voice, named after its part
+pub fn write(score: &Score) -> String {
+    let mut out = String::new();
+    let unit = (score.ticks_per_beat / 2).max(1);
+    let first = &score.measures[0];
+    let _ = writeln!(out, "X:1");
+    let _ = writeln!(out, "T:{}", score.title.lines().next().unwrap_or(""));
+    let _ = writeln!(out, "M:{}/{}", first.time.0, first.time.1);
+    let _ = writeln!(out, "L:1/8");
+    if let Some(bpm) = first.tempo {
+        let _ = writeln!(out, "Q:1/4={:.0}", bpm);
+    }
+    let _ = writeln!(out, "K:{}", key_field(&first.key));
+
+    // Voices that play at least once, numbered through all parts
+    let voices: Vec<(usize, usize)> = score
+        .parts
+        .iter()
+        .enumerate()
+        .flat_map(|(part, notation)| {
+            let count = notation.measures.iter().map(|voices| voices.len()).max().unwrap_or(0);
+            (0..count)
+                .filter(move |voice| notation.measures.iter().any(|voices| voices.get(*voice).is_some_and(|events| !events.is_empty())))
+                .map(move |voice| (part, voice))
+        })
+        .collect();
+    let last = score.measures.len() - 1;
+    for line_start in (0..score.measures.len()).step_by(BARS_PER_LINE) {
+        for (number, &(part, voice)) in voices.iter().enumerate() {
+            let notation = &score.parts[part];
+            if line_start == 0 {
+                let _ = writeln!(out, "V:{} name=\"{}\"", number + 1, notation.name.replace('"', "'"));
+                let _ = writeln!(out, "%%MIDI program {}", notation.program);
+                if notation.clef == Clef::Percussion {
+                    let _ = writeln!(out, "%%MIDI channel {}", DRUM_CHANNEL + 1);
+                }
+            } else if voices.len() > 1 {
+                let _ = writeln!(out, "V:{}", number + 1);
+            }
+            let line_end = (line_start + BARS_PER_LINE).min(score.measures.len());
+            let bars: Vec<String> = (line_start..line_end)
+                .map(|index| {
+                    let events = notation.measures[index].get(voice).map_or(&[][..], |events| events.as_slice());
+                    let bar = bar_text(score, index, events, unit, number == 0);
+                    format!("{} {}", bar, if index == last { "|]" } else { "|" })
+                })
+                .collect();
+            let _ = writeln!(out, "{}", bars.join(" "));
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::notation::build;
+
+    fn notes(composition: &Composition) -> Vec<(u64, u64, u8)> {
+        let mut notes: Vec<(u64, u64, u8)> = composition.tracks.iter().flat_map(|track| track.notes.iter().map(|note| (note.start, note.duration, note.pitch))).collect();
+        notes.sort();
+        notes
+    }
+
+    // Parses a tune, writes it back out through the notation model and parses the result
+    fn round_trip(text: &str) -> (Tune, String, Tune) {
+        let tune = parse(text).unwrap();
+        let written = write(&build(&tune.composition, tune.title.as_deref().unwrap_or("")));
+        let again = parse(&written).unwrap_or_else(|error| panic!("{}\n{}", error, written));
+        (tune, written, again)
+    }
+
+    #[test]
+    fn melody_keeps_ties_accidentals_and_header() {
+        let (tune, written, again) = round_trip("X:1\nT:Reel\nM:4/4\nL:1/8\nQ:1/4=100\nK:D\ndefg a2 f2- | f4 ^g2 =g2 | e8 |]\n");
+        assert_eq!(notes(&again.composition), notes(&tune.composition));
+        assert_eq!(again.title.as_deref(), Some("Reel"));
+        assert!(written.contains("K:D\n"));
+        assert!(written.contains("f2-"));
+        assert!(written.contains("^g2 =g2"));
+        assert_eq!(again.composition.key_signatures[0].sharps, 2);
+        assert_eq!((again.composition.time_signatures[0].numerator, again.composition.time_signatures[0].denominator), (4, 4));
+        assert_eq!(again.composition.tempos[0].micros_per_beat, 600_000);
+    }
+
+    #[test]
+    fn voices_keep_names_and_programs() {
+        let text = "X:1\nT:Duet\nM:3/4\nL:1/4\nK:Bb\nV:1 name=\"Violin\"\n%%MIDI program 40\nB c d | e2 f | B3 |]\nV:2 name=\"Cello\"\n%%MIDI program 42\nB,,3 | E,, F,, G,, | B,,3 |]\n";
+        let (tune, _, again) = round_trip(text);
+        assert_eq!(notes(&again.composition), notes(&tune.composition));
+        let tracks: Vec<(Option<&str>, u8)> = again.composition.tracks.iter().map(|track| (track.name.as_deref(), track.program)).collect();
+        assert_eq!(tracks, vec![(Some("Violin"), 40), (Some("Cello"), 42)]);
+    }
+
+    #[test]
+    fn chords_rests_and_changes_survive() {
+        let text = "X:1\nT:Changes\nM:2/4\nL:1/16\nK:C\n[CEG]4 z2 E2 | [M:3/4] [K:Eb] G4 A8 | c12 |]\n";
+        let (tune, written, again) = round_trip(text);
+        assert_eq!(notes(&again.composition), notes(&tune.composition));
+        assert!(written.contains("[M:3/4]"));
+        assert!(written.contains("[K:Eb]"));
+        let keys: Vec<i8> = again.composition.key_signatures.iter().map(|key| key.sharps).collect();
+        assert_eq!(keys, vec![0, -3]);
+    }
+
+    #[test]
+    fn repeats_and_broken_rhythm_are_played_out() {
+        let (tune, written, again) = round_trip("X:1\nT:Jig\nM:2/4\nL:1/8\nK:G\n|: G>A B2 :| d2 c>B |]\n");
+        assert_eq!(notes(&again.composition), notes(&tune.composition));
+        assert!(!written.contains("|:"));
+    }
+
+    #[test]
+    fn overlapping_notes_become_separate_voices() {
+        let (tune, written, again) = round_trip("X:1\nT:Canon\nM:4/4\nL:1/4\nK:C\nV:1\nc4 | d2 e2 |]\nV:2\nz2 G2 | A4 |]\n");
+        assert_eq!(notes(&again.composition), notes(&tune.composition));
+        assert!(written.contains("V:2"));
+    }
+
+    fn error(text: &str) -> (usize, usize, String) {
+        let error = parse(text).err().expect("the tune should not parse");
+        (error.line, error.column, error.message)
+    }
+
+    // Lengths and counts in the text are bounded instead of overflowing or looping for long
+    #[test]
+    fn oversized_numbers_are_rejected() {
+        let message = error("X:1\nK:C\nA99999999999999999999 B\n").2;
+        assert_eq!(message, "Number 99999999999999999999 is larger than 9999");
+        assert_eq!(error("X:1\nK:C\nA2 Z5000 |\n"), (3, 4, "A multi-measure rest lasts at most 999 bars".to_string()));
+        let markers = ">".repeat(64);
+        assert_eq!(error(&format!("X:1\nK:C\nA{}B\n", markers)), (3, 2, "Broken rhythm takes at most 3 '>'".to_string()));
+        assert_eq!(error("X:1\nK:C\nA////////// B\n").2, "A note length has at most 6 slashes");
+        assert_eq!(error("X:1\nM:200+100/8\nK:C\nA B\n").2, "Invalid meter \"200+100/8\"");
+        assert!(parse("X:1\nM:2+3/8\nK:C\nA>>>B Z2 |\n").is_ok());
+    }
+
+    #[test]
+    fn errors_point_at_the_offending_text() {
+        assert_eq!(error("X:1\nK:C\nC D [CE F\n"), (3, 5, "Unterminated chord".to_string()));
+        assert_eq!(error("X:1\nK:C\nC D/0 E\n"), (3, 3, "Note length must not be zero".to_string()));
+        assert_eq!(error("X:1\nK:H\nC D E\n"), (2, 3, "Unknown key \"H\"".to_string()));
+    }
+
+    #[test]
+    fn endings_are_taken_in_turn() {
+        let tune = parse("X:1\nM:4/4\nL:1/4\nK:C\n|: C4 |1 D4 :|2 E4 |]\n").unwrap();
+        assert_eq!(notes(&tune.composition), vec![(0, 1920, 60), (1920, 1920, 62), (3840, 1920, 60), (5760, 1920, 64)]);
+    }
+
+    #[test]
+    fn tuplets_share_the_time_of_fewer_notes() {
+        let tune = parse("X:1\nM:4/4\nL:1/8\nK:C\n(3CDE F2 (3:2:2G2A |]\n").unwrap();
+        assert_eq!(notes(&tune.composition), vec![(0, 160, 60), (160, 160, 62), (320, 160, 64), (480, 480, 65), (960, 320, 67), (1280, 160, 69)]);
+    }
+
+    #[test]
+    fn broken_rhythm_moves_time_between_notes() {
+        let tune = parse("X:1\nM:4/4\nL:1/8\nK:C\nC>D E<F G>>A |]\n").unwrap();
+        assert_eq!(notes(&tune.composition), vec![(0, 360, 60), (360, 120, 62), (480, 120, 64), (600, 360, 65), (960, 420, 67), (1380, 60, 69)]);
+    }
+
+    #[test]
+    fn voices_get_their_own_tracks() {
+        let tune = parse("X:1\nM:4/4\nL:1/4\nK:C\nV:T name=\"Tenor\"\nc2 d2 |]\nV:B\nC,4 |]\nV:T\ne4 |]\n").unwrap();
+        let tracks: Vec<(Option<&str>, u8, usize)> = tune.composition.tracks.iter().map(|track| (track.name.as_deref(), track.channel, track.notes.len())).collect();
+        assert_eq!(tracks, vec![(Some("Tenor"), 0, 3), (Some("Voice B"), 1, 1)]);
+        assert_eq!(tune.composition.tracks[0].notes[2].start, 1920);
+    }
+
+    #[test]
+    fn midi_channel_directives_are_kept() {
+        let tune = parse("X:1\nK:C\nV:1\nc2 |]\nV:2\n%%MIDI channel 10\nC,2 |]\nV:3\nE2 |]\n").unwrap();
+        let channels: Vec<u8> = tune.composition.tracks.iter().map(|track| track.channel).collect();
+        assert_eq!(channels, vec![0, DRUM_CHANNEL, 1]);
+
+        // Drums written to ABC come back as drums
+        let mut composition = Composition::new(480);
+        composition.tracks.push(Track { name: Some("Drums".to_string()), channel: DRUM_CHANNEL, program: 0, notes: vec![Note { start: 0, duration: 240, pitch: 36, velocity: 90 }] });
+        let again = parse(&write(&build(&composition, "Beat"))).unwrap();
+        assert_eq!(again.composition.tracks[0].channel, DRUM_CHANNEL);
+        assert_eq!(notes(&again.composition), vec![(0, 240, 36)]);
+    }
+}
@@ -0,0 +1,451 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+use crate::config::active_soundfont;
+use crate::library::{now_secs, Library, LibraryEntry};
+use crate::render::{render_midi, RenderSettings};
+use crate::setup::EnvPaths;
+use crate::utils::send_to_frontend;
+
+const DOCUMENT_FILE: &str = "arrangement.json";
+const MIXDOWN_FILE: &str = "mixdown.wav";
+const CLIPS_DIR: &str = "clips";
+const DEFAULT_SAMPLE_RATE: u32 = 44100;
+// Mixes louder than full scale are turned down to this peak
+const MAX_PEAK: f32 = 0.99;
+
+lazy_static::lazy_static! {
+    static ref ARRANGEMENT_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ClipSource {
+    // The latest render of the library entry
+    Audio,
+    // The entry's current MIDI, rendered for the mixdown
+    Midi,
+}
+
+// A library entry placed on the timeline; times are in seconds
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Clip {
+    #[serde(default)]
+    pub id: String,
+    pub entry: String,
+    pub source: ClipSource,
+    pub start: f64,
+    // Cut from the beginning and the end of the source
+    #[serde(default)]
+    pub trim_start: f64,
+    #[serde(default)]
+    pub trim_end: f64,
+    #[serde(default)]
+    pub gain_db: f32,
+    // -1 is hard left, 1 hard right
+    #[serde(default)]
+    pub pan: f32,
+    #[serde(default)]
+    pub fade_in: f64,
+    #[serde(default)]
+    pub fade_out: f64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ArrangementTrack {
+    #[serde(default)]
+    pub id: String,
+    pub name: String,
+    #[serde(default)]
+    pub clips: Vec<Clip>,
+    #[serde(default)]
+    pub gain_db: f32,
+    #[serde(default)]
+    pub pan: f32,
+    #[serde(default)]
+    pub muted: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Arrangement {
+    pub id: String,
+    pub title: String,
+    pub created: u64,
+    pub modified: u64,
+    // Counts saves; `modified` only has whole seconds, too coarse to tell saves apart
+    #[serde(default)]
+    pub revision: u64,
+    pub sample_rate: u32,
+    #[serde(default)]
+    pub tracks: Vec<ArrangementTrack>,
+    // The latest mixdown, cleared whenever the document changes
+    #[serde(default)]
+    pub mixdown: Option<PathBuf>,
+}
+
+pub fn new_id() -> String {
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    format!("{:x}", nanos)
+}
+
+pub fn arrangement_dir(id: &str) -> Result<PathBuf, String> {
+    // Ids become directory names, so only the hex ids handed out here are accepted
+    if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Invalid arrangement id {}", id));
+    }
+    Ok(EnvPaths::new().arrangements.join(id))
+}
+
+fn load(id: &str) -> Result<Arrangement, String> {
+    let path = arrangement_dir(id)?.join(DOCUMENT_FILE);
+    let content = fs::read_to_string(&path).map_err(|e| format!("Failed to read arrangement {}: {}", id, e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Failed to parse arrangement {}: {}", id, e))
+}
+
+fn store(arrangement: &Arrangement) -> Result<(), String> {
+    let dir = arrangement_dir(&arrangement.id)?;
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create arrangement directory: {}", e))?;
+    let content = serde_json::to_string_pretty(arrangement).map_err(|e| e.to_string())?;
+    fs::write(dir.join(DOCUMENT_FILE), content).map_err(|e| format!("Failed to write arrangement: {}", e))
+}
+
+fn check_clip(clip: &Clip, library: &Library) -> Result<(), String> {
+    let entry = library.get(&clip.entry)?;
+    let times = [clip.start, clip.trim_start, clip.trim_end, clip.fade_in, clip.fade_out];
+    if times.iter().any(|time| !time.is_finite() || *time < 0.0) {
+        return Err(format!("Clip of {} has a negative or invalid time", entry.title));
+    }
+    if !clip.gain_db.is_finite() || !(-1.0..=1.0).contains(&clip.pan) {
+        return Err(format!("Clip of {} has an invalid gain or pan", entry.title));
+    }
+    match clip.source {
+        ClipSource::Audio if entry.versions.is_empty() => Err(format!("{} has no rendered audio", entry.title)),
+        ClipSource::Midi if entry.midi.is_none() => Err(format!("{} has no MIDI", entry.title)),
+        _ => Ok(()),
+    }
+}
+
+// Audio file a clip plays: the entry's latest render, or its MIDI rendered once per MIDI version
+fn clip_audio(arrangement: &Arrangement, clip: &Clip, entry: &LibraryEntry) -> Result<PathBuf, String> {
+    match clip.source {
+        ClipSource::Audio => entry.versions.last().map(|version| version.audio.clone()).ok_or_else(|| format!("{} has no rendered audio", entry.title)),
+        ClipSource::Midi => {
+            let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI", entry.title))?;
+            let stem = midi.file_stem().and_then(|stem| stem.to_str()).unwrap_or("source");
+            let audio = arrangement_dir(&arrangement.id)?.join(CLIPS_DIR).join(format!("{}-{}-{}.wav", entry.id, stem, arrangement.sample_rate));
+            if !audio.exists() {
+                let settings = RenderSettings { sample_rate: arrangement.sample_rate, ..RenderSettings::default() };
+                render_midi(&midi, &active_soundfont()?, &audio, &settings)?;
+            }
+            Ok(audio)
+        }
+    }
+}
+
+// Reads a WAV file as stereo at the given rate; mono is spread to both sides, other layouts
+// keep their first two channels
+fn read_audio(path: &Path, sample_rate: u32) -> Result<Vec<[f32; 2]>, String> {
+    let mut reader = hound::WavReader::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let spec = reader.spec();
+    let samples: Vec<f32> = match spec.sample_format {
+        hound::SampleFormat::Float => reader.samples::<f32>().collect::<Result<_, _>>(),
+        hound::SampleFormat::Int => {
+            let scale = (1i64 << (spec.bits_per_sample.max(1) - 1)) as f32;
+            reader.samples::<i32>().map(|sample| sample.map(|sample| sample as f32 / scale)).collect()
+        }
+    }
+    .map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let channels = spec.channels.max(1) as usize;
+    let frames: Vec<[f32; 2]> = samples.chunks_exact(channels).map(|frame| [frame[0], frame[channels.min(2) - 1]]).collect();
+    if spec.sample_rate == sample_rate || frames.is_empty() {
+        return Ok(frames);
+    }
+
+    // Linear interpolation is plenty for lining clips up, the renders themselves share one rate
+    let ratio = spec.sample_rate as f64 / sample_rate as f64;
+    let length = (frames.len() as f64 / ratio) as usize;
+    Ok((0..length)
+        .map(|index| {
+            let position = index as f64 * ratio;
+            let before = position as usize;
+            let after = (before + 1).min(frames.len() - 1);
+            let weight = (position - before as f64) as f32;
+            [0, 1].map(|side| frames[before][side] * (1.0 - weight) + frames[after][side] * weight)
+        })
+        .collect())
+}
+
+fn db_to_gain(db: f32) -> f32 {
+    10f32.powf(db / 20.0)
+}
+
+// Adds a clip to the mix with its trims, fades, gain and balance applied
+fn mix_clip(mix: &mut Vec<[f32; 2]>, audio: &[[f32; 2]], clip: &Clip, track: &ArrangementTrack, sample_rate: u32) {
+    let rate = sample_rate as f64;
+    let first = ((clip.trim_start * rate) as usize).min(audio.len());
+    let last = audio.len().saturating_sub((clip.trim_end * rate) as usize).max(first);
+    let length = last - first;
+    if length == 0 {
+        return;
+    }
+    let offset = (clip.start * rate) as usize;
+    if mix.len() < offset + length {
+        mix.resize(offset + length, [0.0; 2]);
+    }
+    let gain = db_to_gain(clip.gain_db + track.gain_db);
+    let pan = (clip.pan + track.pan).clamp(-1.0, 1.0);
+    let sides = [gain * (1.0 - pan).min(1.0), gain * (1.0 + pan).min(1.0)];
+    let fade_in = (clip.fade_in * rate) as usize;
+    let fade_out = (clip.fade_out * rate) as usize;
+    for (index, frame) in audio[first..last].iter().enumerate() {
+        let mut envelope = 1.0;
+        if index < fade_in {
+            envelope *= index as f32 / fade_in as f32;
+        }
+        if length - index <= fade_out {
+            envelope *= (length - index) as f32 / fade_out as f32;
+        }
+        let target = &mut mix[offset + index];
+        for side in 0..2 {
+            target[side] += frame[side] * sides[side] * envelope;
+        }
+    }
+}
+
+fn write_wav(path: &Path, frames: &[[f32; 2]], sample_rate: u32) -> Result<(), String> {
+    if let Some(parent) = path.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+    }
+    let spec = hound::WavSpec { channels: 2, sample_rate, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+    let mut writer = hound::WavWriter::create(path, spec).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    for frame in frames {
+        for sample in frame {
+            writer
+                .write_sample((sample.clamp(-1.0, 1.0) * i16::MAX as f32) as i16)
+                .map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+        }
+    }
+    writer.finalize().map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+// Mixes every unmuted clip into one stereo file, returning its peak before any gain reduction
+fn mixdown(arrangement: &Arrangement, output: &Path) -> Result<f32, String> {
+    let library = Library::read()?;
+    let mut mix: Vec<[f32; 2]> = Vec::new();
+    for track in arrangement.tracks.iter().filter(|track| !track.muted) {
+        for clip in &track.clips {
+            let entry = library.get(&clip.entry)?;
+            let audio = read_audio(&clip_audio(arrangement, clip, entry)?, arrangement.sample_rate)?;
+            mix_clip(&mut mix, &audio, clip, track, arrangement.sample_rate);
+        }
+    }
+    if mix.is_empty() {
+        return Err(format!("{} has no clips to mix", arrangement.title));
+    }
+    let peak = mix.iter().flatten().fold(0.0f32, |peak, sample| peak.max(sample.abs()));
+    if peak > 1.0 {
+        let scale = MAX_PEAK / peak;
+        mix.iter_mut().flatten().for_each(|sample| *sample *= scale);
+    }
+    write_wav(output, &mix, arrangement.sample_rate)?;
+    Ok(peak)
+}
+
+#[tauri::command]
+pub async fn create_arrangement(title: String) -> Result<Arrangement, String> {
+    let _guard = ARRANGEMENT_LOCK.lock().map_err(|e| e.to_string())?;
+    let created = now_secs();
+    let title = if title.trim().is_empty() { "Arrangement".to_string() } else { title.trim().to_string() };
+    let arrangement = Arrangement { id: new_id(), title, created, modified: created, revision: 0, sample_rate: DEFAULT_SAMPLE_RATE, tracks: Vec::new(), mixdown: None };
+    store(&arrangement)?;
+    Ok(arrangement)
+}
+
+// Every stored arrangement, oldest first
+pub fn read_arrangements() -> Result<Vec<Arrangement>, String> {
+    let _guard = ARRANGEMENT_LOCK.lock().map_err(|e| e.to_string())?;
+    let dir = EnvPaths::new().arrangements;
+    if !dir.exists() {
+        return Ok(Vec::new());
+    }
+    let mut arrangements: Vec<Arrangement> = fs::read_dir(&dir)
+        .map_err(|e| format!("Failed to read arrangements: {}", e))?
+        .filter_map(|item| item.ok())
+        .filter_map(|item| item.file_name().to_str().and_then(|id| load(id).ok()))
+        .collect();
+    arrangements.sort_by_key(|arrangement| arrangement.created);
+    Ok(arrangements)
+}
+
+// Stores an arrangement brought in from elsewhere, e.g. an opened project
+pub fn add_arrangement(arrangement: &Arrangement) -> Result<(), String> {
+    let _guard = ARRANGEMENT_LOCK.lock().map_err(|e| e.to_string())?;
+    if arrangement_dir(&arrangement.id)?.join(DOCUMENT_FILE).exists() {
+        return Err(format!("Arrangement {} already exists", arrangement.id));
+    }
+    store(arrangement)
+}
+
+#[tauri::command]
+pub async fn list_arrangements() -> Result<Vec<Arrangement>, String> {
+    read_arrangements()
+}
+
+// Replaces the stored document after checking that every clip points at a usable library entry
+#[tauri::command]
+pub async fn save_arrangement(arrangement: Arrangement) -> Result<Arrangement, String> {
+    let _guard = ARRANGEMENT_LOCK.lock().map_err(|e| e.to_string())?;
+    let stored = load(&arrangement.id)?;
+    let library = Library::read()?;
+    let mut arrangement = arrangement;
+    if !(8000..=192_000).contains(&arrangement.sample_rate) {
+        return Err(format!("Unsupported sample rate {}", arrangement.sample_rate));
+    }
+    for track in arrangement.tracks.iter_mut() {
+        if !track.gain_db.is_finite() || !(-1.0..=1.0).contains(&track.pan) {
+            return Err(format!("Track {} has an invalid gain or pan", track.name));
+        }
+        if track.id.is_empty() {
+            track.id = new_id();
+        }
+        for clip in track.clips.iter_mut() {
+            check_clip(clip, &library)?;
+            if clip.id.is_empty() {
+                clip.id = new_id();
+            }
+        }
+    }
+    arrangement.created = stored.created;
+    arrangement.modified = now_secs();
+    arrangement.revision = stored.revision + 1;
+    arrangement.mixdown = None;
+    store(&arrangement)?;
+    Ok(arrangement)
+}
+
+#[tauri::command]
+pub async fn delete_arrangement(id: String) -> Result<(), String> {
+    let _guard = ARRANGEMENT_LOCK.lock().map_err(|e| e.to_string())?;
+    let dir = arrangement_dir(&id)?;
+    fs::remove_dir_all(&dir).map_err(|e| format!("Failed to delete arrangement {}: {}", id, e))
+}
+
+// Renders the arrangement to a single WAV next to its document, copied to `path` when exporting
+#[tauri::command]
+pub async fn mixdown_arrangement(app: AppHandle, id: String, path: Option<String>) -> Result<String, String> {
+    let mut arrangement = {
+        let _guard = ARRANGEMENT_LOCK.lock().map_err(|e| e.to_string())?;
+        load(&id)?
+    };
+    send_to_frontend(&app, format!("Mixing down {}...", arrangement.title), "info");
+    let output = arrangement_dir(&id)?.join(MIXDOWN_FILE);
+    let peak = mixdown(&arrangement, &output)?;
+    if peak > 1.0 {
+        send_to_frontend(&app, format!("The mix peaked at {:.1} dB and was turned down to avoid clipping", 20.0 * peak.log10()), "info");
+    }
+
+    let _guard = ARRANGEMENT_LOCK.lock().map_err(|e| e.to_string())?;
+    // Only remember the mixdown if the document did not change in the meantime
+    if load(&id)?.revision == arrangement.revision {
+        arrangement.mixdown = Some(output.clone());
+        store(&arrangement)?;
+    }
+    let exported = match path {
+        Some(path) => {
+            let path = PathBuf::from(path);
+            fs::copy(&output, &path).map_err(|e| format!("Failed to export mixdown: {}", e))?;
+            path
+        }
+        None => output,
+    };
+    send_to_frontend(&app, exported.display().to_string(), "success");
+    Ok(exported.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn clip(start: f64) -> Clip {
+        Clip { id: String::new(), entry: String::new(), source: ClipSource::Audio, start, trim_start: 0.0, trim_end: 0.0, gain_db: 0.0, pan: 0.0, fade_in: 0.0, fade_out: 0.0 }
+    }
+
+    fn track() -> ArrangementTrack {
+        ArrangementTrack { id: String::new(), name: String::new(), clips: Vec::new(), gain_db: 0.0, pan: 0.0, muted: false }
+    }
+
+    fn left(mix: &[[f32; 2]]) -> Vec<f32> {
+        mix.iter().map(|frame| (frame[0] * 1000.0).round() / 1000.0).collect()
+    }
+
+    fn temp_wav(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("arrangement-{}-{}.wav", name, std::process::id()))
+    }
+
+    // Ten frames per second keeps clip times easy to follow
+    #[test]
+    fn clips_are_trimmed_and_placed() {
+        let audio: Vec<[f32; 2]> = (1..=10).map(|frame| [frame as f32 / 10.0; 2]).collect();
+        let mut mix = vec![[0.5; 2]; 3];
+        let trimmed = Clip { trim_start: 0.2, trim_end: 0.3, ..clip(0.5) };
+        mix_clip(&mut mix, &audio, &trimmed, &track(), 10);
+        assert_eq!(left(&mix), vec![0.5, 0.5, 0.5, 0.0, 0.0, 0.3, 0.4, 0.5, 0.6, 0.7]);
+
+        // Trims longer than the audio leave nothing to mix
+        let mut untouched = Vec::new();
+        mix_clip(&mut untouched, &audio, &Clip { trim_start: 0.6, trim_end: 0.6, ..clip(0.0) }, &track(), 10);
+        assert!(untouched.is_empty());
+    }
+
+    #[test]
+    fn fades_shape_the_clip_edges() {
+        let audio = vec![[1.0; 2]; 5];
+        let mut mix = Vec::new();
+        mix_clip(&mut mix, &audio, &Clip { fade_in: 0.2, fade_out: 0.2, ..clip(0.0) }, &track(), 10);
+        assert_eq!(left(&mix), vec![0.0, 0.5, 1.0, 1.0, 0.5]);
+    }
+
+    #[test]
+    fn gain_and_pan_add_up_from_clip_and_track() {
+        let audio = vec![[1.0; 2]; 2];
+        let mut mix = Vec::new();
+        let panned = ArrangementTrack { gain_db: -6.0206, pan: 0.5, ..track() };
+        mix_clip(&mut mix, &audio, &Clip { pan: 0.5, ..clip(0.0) }, &panned, 10);
+        assert_eq!(left(&mix), vec![0.0, 0.0]);
+        assert!(mix.iter().all(|frame| (frame[1] - 0.5).abs() < 1e-4));
+
+        // Centred clips keep both sides at full level
+        let mut centred = Vec::new();
+        mix_clip(&mut centred, &audio, &clip(0.0), &ArrangementTrack { gain_db: 6.0206, ..track() }, 10);
+        assert!(centred.iter().flatten().all(|sample| (sample - 2.0).abs() < 1e-3));
+    }
+
+    #[test]
+    fn audio_is_read_as_stereo_at_the_mix_rate() {
+        let path = temp_wav("mono");
+        let spec = hound::WavSpec { channels: 1, sample_rate: 20, bits_per_sample: 16, sample_format: hound::SampleFormat::Int };
+        let mut writer = hound::WavWriter::create(&path, spec).unwrap();
+        for sample in [0i16, 8192, 16384, -16384] {
+            writer.write_sample(sample).unwrap();
+        }
+        writer.finalize().unwrap();
+        let same_rate = read_audio(&path, 20).unwrap();
+        let halved = read_audio(&path, 10).unwrap();
+        let doubled = read_audio(&path, 40).unwrap();
+        let _ = fs::remove_file(&path);
+        assert_eq!(same_rate, vec![[0.0; 2], [0.25; 2], [0.5; 2], [-0.5; 2]]);
+        assert_eq!(halved, vec![[0.0; 2], [0.5; 2]]);
+        // Upsampling interpolates, the last frame is held
+        assert_eq!(doubled.iter().map(|frame| frame[0]).collect::<Vec<_>>(), vec![0.0, 0.125, 0.25, 0.375, 0.5, 0.0, -0.5, -0.5]);
+
+        let path = temp_wav("stereo");
+        write_wav(&path, &[[0.5, -0.5], [-0.25, 0.25]], 8000).unwrap();
+        let stereo = read_audio(&path, 8000).unwrap();
+        let _ = fs::remove_file(&path);
+        let expected = [[0.5, -0.5], [-0.25, 0.25]];
+        assert!(stereo.iter().flatten().zip(expected.iter().flatten()).all(|(read, written)| (read - written).abs() < 1e-4));
+    }
+}
@@ -1,14 +1,33 @@
+use rodio::source::UniformSourceIterator;
 use rodio::{Decoder, OutputStream, OutputStreamHandle, Sink, Source};
+use std::collections::VecDeque;
 use std::fs::File;
-use std::io::BufReader;
+use std::io::{BufReader, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use sha2::{Digest, Sha256};
+use crate::config::active_soundfont;
+use crate::library::Library;
+use crate::render::{render_midi, RenderSettings};
 use crate::setup::EnvPaths;
 use crate::utils::send_to_frontend;
 use tauri::AppHandle;
 
+const PLAYBACK_CHANNELS: u16 = 2;
+const PLAYBACK_SAMPLE_RATE: u32 = 44100;
+
+type BoxedSource = Box<dyn Source<Item = f32> + Send>;
+
 pub struct AudioState {
     pub sink: Arc<Mutex<Option<Arc<Sink>>>>,
     pub stream_handle: OutputStreamHandle,
+    pub crossfade: Arc<Mutex<Duration>>,
+    // Samples played since the start of the current playback, across all channels
+    pub position: Arc<AtomicU64>,
+    // Audio of the variants being compared, switched between at the same position
+    pub compare: Arc<Mutex<Vec<PathBuf>>>,
 }
 
 pub fn initialize_audio() -> AudioState {
@@ -22,34 +41,237 @@ pub fn initialize_audio() -> AudioState {
     AudioState {
         sink: Arc::new(Mutex::new(None)),
         stream_handle,
+        crossfade: Arc::new(Mutex::new(Duration::ZERO)),
+        position: Arc::new(AtomicU64::new(0)),
+        compare: Arc::new(Mutex::new(Vec::new())),
     }
 }
 
-#[tauri::command]
-pub fn play_audio(app: AppHandle, state: tauri::State<AudioState>) -> Result<(), String> {
-    let paths = EnvPaths::new();
-    let file_path = paths.output_file;
-    println!("Playing audio: {}", file_path.display());
+// A decoded track converted to the playback format, with its length in samples when known
+struct Track {
+    source: BoxedSource,
+    samples: Option<u64>,
+}
     
-    // Open and decode file with detailed logging
-    let file = match File::open(&file_path) {
+// Plays tracks back to back inside a single source. When a crossfade is set, the tail of the
+// outgoing track is mixed with the head of the incoming one instead of swapping sinks.
+struct TrackChain {
+    current: Option<Track>,
+    incoming: Option<Track>,
+    queue: VecDeque<Track>,
+    fade_samples: u64,
+    fade_len: u64,
+    fade_pos: u64,
+    played: Arc<AtomicU64>,
+}
+
+impl TrackChain {
+    fn new(tracks: Vec<Track>, crossfade: Duration, played: Arc<AtomicU64>) -> Self {
+        let fade_frames = (crossfade.as_secs_f64() * PLAYBACK_SAMPLE_RATE as f64) as u64;
+        let mut queue: VecDeque<Track> = tracks.into();
+        TrackChain {
+            current: queue.pop_front(),
+            incoming: None,
+            queue,
+            fade_samples: fade_frames * PLAYBACK_CHANNELS as u64,
+            fade_len: 0,
+            fade_pos: 0,
+            played,
+        }
+    }
+
+    // Starts pulling the next track once the current one enters its fade window
+    fn start_fade(&mut self) {
+        if self.fade_samples == 0 || self.incoming.is_some() || self.queue.is_empty() {
+            return;
+        }
+        let remaining = match self.current.as_ref().and_then(|track| track.samples) {
+            Some(remaining) => remaining,
+            None => return, // Unknown length, fall back to a gapless transition
+        };
+        if remaining > 0 && remaining <= self.fade_samples {
+            self.incoming = self.queue.pop_front();
+            self.fade_len = remaining;
+            self.fade_pos = 0;
+        }
+    }
+
+    // Replaces the exhausted track with the one fading in, or the next queued track
+    fn advance(&mut self) -> bool {
+        self.current = match self.incoming.take() {
+            Some(mut track) => {
+                track.samples = track.samples.map(|n| n.saturating_sub(self.fade_pos));
+                Some(track)
+            }
+            None => self.queue.pop_front(),
+        };
+        self.fade_len = 0;
+        self.fade_pos = 0;
+        self.current.is_some()
+    }
+}
+
+impl Iterator for TrackChain {
+    type Item = f32;
+
+    fn next(&mut self) -> Option<f32> {
+        loop {
+            self.start_fade();
+            let current = self.current.as_mut()?;
+            match current.source.next() {
+                Some(sample) => {
+                    self.played.fetch_add(1, Ordering::Relaxed);
+                    if let Some(remaining) = current.samples.as_mut() {
+                        *remaining = remaining.saturating_sub(1);
+                    }
+                    if let Some(incoming) = self.incoming.as_mut() {
+                        let gain = self.fade_pos as f32 / self.fade_len as f32;
+                        self.fade_pos += 1;
+                        let mixed = incoming.source.next().unwrap_or(0.0);
+                        return Some(sample * (1.0 - gain) + mixed * gain);
+                    }
+                    return Some(sample);
+                }
+                None => {
+                    if !self.advance() {
+                        return None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+impl Source for TrackChain {
+    fn current_frame_len(&self) -> Option<usize> {
+        None
+    }
+
+    fn channels(&self) -> u16 {
+        PLAYBACK_CHANNELS
+    }
+
+    fn sample_rate(&self) -> u32 {
+        PLAYBACK_SAMPLE_RATE
+    }
+
+    fn total_duration(&self) -> Option<Duration> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AudioFormat {
+    Wav,
+    Flac,
+    Vorbis,
+    Mp3,
+    Midi,
+}
+
+// An MPEG audio frame header: 11 sync bits and a layer other than the reserved 00, which
+// ADTS AAC streams use with the same sync bits
+fn is_mpeg_frame(header: &[u8]) -> bool {
+    header.len() >= 2 && header[0] == 0xFF && header[1] & 0xE0 == 0xE0 && header[1] & 0x06 != 0
+}
+
+// Detects the file format from its leading bytes, file extensions are not trusted
+pub fn detect_format(header: &[u8]) -> Option<AudioFormat> {
+    if header.len() >= 12 && &header[0..4] == b"RIFF" && &header[8..12] == b"WAVE" {
+        Some(AudioFormat::Wav)
+    } else if header.starts_with(b"fLaC") {
+        Some(AudioFormat::Flac)
+    } else if header.starts_with(b"OggS") {
+        Some(AudioFormat::Vorbis)
+    } else if header.starts_with(b"MThd") {
+        Some(AudioFormat::Midi)
+    } else if header.starts_with(b"ID3") || is_mpeg_frame(header) {
+        Some(AudioFormat::Mp3)
+    } else {
+        None
+    }
+}
+
+// MIDI files are rendered through the active SoundFont before playback
+fn render_for_playback(file_path: &Path) -> Result<PathBuf, String> {
+    let paths = EnvPaths::new();
+    let soundfont = active_soundfont()?;
+    let stem = file_path.file_stem().and_then(|s| s.to_str()).unwrap_or("midi");
+    // Files sharing a name in different folders get their own render
+    let path_hash = format!("{:x}", Sha256::digest(file_path.to_string_lossy().as_bytes()));
+    let output = paths.renders.join(format!("{}-{}.playback.wav", stem, &path_hash[..16]));
+    render_midi(file_path, &soundfont, &output, &RenderSettings::default())?;
+    Ok(output)
+}
+
+fn open_track(file_path: &Path) -> Result<Track, String> {
+    let mut file = match File::open(file_path) {
         Ok(f) => {
             println!("File opened successfully: {}", file_path.display());
             f
         },
         Err(e) => return Err(format!("Error opening file: {}", e)),
     };
-    
-    let source = match Decoder::new_wav(BufReader::new(file)) {
+
+    let mut header = [0u8; 12];
+    let header_len = file.read(&mut header).map_err(|e| format!("Error reading file: {}", e))?;
+    file.seek(SeekFrom::Start(0)).map_err(|e| format!("Error reading file: {}", e))?;
+
+    let reader = BufReader::new(file);
+    let decoded = match detect_format(&header[..header_len]) {
+        Some(AudioFormat::Wav) => Decoder::new_wav(reader),
+        Some(AudioFormat::Flac) => Decoder::new_flac(reader),
+        Some(AudioFormat::Vorbis) => Decoder::new_vorbis(reader),
+        Some(AudioFormat::Mp3) => Decoder::new_mp3(reader),
+        Some(AudioFormat::Midi) => return open_track(&render_for_playback(file_path)?),
+        None => return Err(format!("Unsupported audio format: {}", file_path.display())),
+    };
+
+    let source = match decoded {
         Ok(s) => {
             println!("Audio decoded successfully");
             s
         },
         Err(e) => return Err(format!("Error decoding audio: {}", e)),
     };
-    // let source = source.convert_samples::<f32>().low_pass(44100); // Convert samples to f32 and cut frequencies above 10kHz
-    let source_sample_rate = source.sample_rate();
-    println!("Source sample rate: {}", source_sample_rate);
+    println!("Source sample rate: {}", source.sample_rate());
+
+    // Every track is converted to the same format so consecutive tracks can be mixed
+    let samples = source.total_duration().map(|duration| {
+        let frames = (duration.as_secs_f64() * PLAYBACK_SAMPLE_RATE as f64) as u64;
+        frames * PLAYBACK_CHANNELS as u64
+    });
+    let source: UniformSourceIterator<_, f32> =
+        UniformSourceIterator::new(source, PLAYBACK_CHANNELS, PLAYBACK_SAMPLE_RATE);
+
+    Ok(Track { source: Box::new(source), samples })
+}
+
+fn start_playback(app: AppHandle, state: &AudioState, files: &[PathBuf], start_at: Duration) -> Result<(), String> {
+    let mut tracks = Vec::new();
+    for file_path in files {
+        println!("Queueing audio: {}", file_path.display());
+        tracks.push(open_track(file_path)?);
+    }
+
+    // Playback may resume part way into the first track
+    let skipped = (start_at.as_secs_f64() * PLAYBACK_SAMPLE_RATE as f64) as u64 * PLAYBACK_CHANNELS as u64;
+    if !start_at.is_zero() && !tracks.is_empty() {
+        let first = tracks.remove(0);
+        let samples = first.samples.map(|samples| samples.saturating_sub(skipped));
+        tracks.insert(0, Track { source: Box::new(first.source.skip_duration(start_at)), samples });
+    }
+    state.position.store(skipped, Ordering::Relaxed);
+
+    let crossfade = *state.crossfade.lock().map_err(|e| format!("Failed to lock crossfade: {}", e))?;
+    let chain = TrackChain::new(tracks, crossfade, Arc::clone(&state.position));
+
+    // Replace whatever is currently playing
+    if let Ok(mut sink_lock) = state.sink.lock() {
+        if let Some(sink) = sink_lock.take() {
+            sink.stop();
+        }
+    }
     
     // Create sink with explicit error handling
     println!("Creating sink...");
@@ -62,7 +284,7 @@ pub fn play_audio(app: AppHandle, state: tauri::State<AudioState>) -> Result<(),
     };
     
     println!("Appending source to sink...");
-    sink.append(source);
+    sink.append(chain);
     println!("Source appended");
     
     println!("Starting playback...");
@@ -71,38 +293,33 @@ pub fn play_audio(app: AppHandle, state: tauri::State<AudioState>) -> Result<(),
     
     // Clone the shared state for the thread
     let sink_state = Arc::clone(&state.sink);
+    let sink = Arc::new(sink);
     
     // Store the sink in state before creating the monitoring thread
     println!("Storing sink in state...");
     match sink_state.lock() {
         Ok(mut sink_lock) => {
-            *sink_lock = Some(Arc::new(sink));
+            *sink_lock = Some(Arc::clone(&sink));
             println!("Sink stored in state");
         },
         Err(e) => return Err(format!("Failed to lock sink state: {}", e)),
     }
     
-    // Clone the app handle and state reference for the monitoring thread
-    let app_handle = app.clone();
-    let thread_sink_state = Arc::clone(&sink_state);
-    
     // Create a thread to monitor when playback is finished
     std::thread::spawn(move || {
-        // Get access to the sink for monitoring
-        let maybe_sink: Option<Arc<Sink>> = {
-            match thread_sink_state.lock() {
-                Ok(sink_lock) => sink_lock.as_ref().map(|s| Arc::clone(s)),
-                Err(_) => None,
-            }
+        // Wait for the sink to finish
+        sink.sleep_until_end();
+
+        // A newer playback may have replaced this sink, only report the one still in state
+        let is_current = match sink_state.lock() {
+            Ok(sink_lock) => sink_lock.as_ref().is_some_and(|s| Arc::ptr_eq(s, &sink)),
+            Err(_) => false,
         };
-        
-        if let Some(monitor_sink) = maybe_sink {
-            // Wait for the sink to finish
-            monitor_sink.sleep_until_end();
+        if is_current {
             println!("Audio playback completed");
             
             // Send a message to the frontend
-            send_to_frontend(&app_handle, "audio-playback-finished".to_string(), "play_finished");
+            send_to_frontend(&app, "audio-playback-finished".to_string(), "play_finished");
         }
     });
     
@@ -110,6 +327,66 @@ pub fn play_audio(app: AppHandle, state: tauri::State<AudioState>) -> Result<(),
     Ok(())
 }
 
+#[tauri::command]
+pub fn play_audio(app: AppHandle, state: tauri::State<AudioState>) -> Result<(), String> {
+    let paths = EnvPaths::new();
+    println!("Playing audio: {}", paths.output_file.display());
+    start_playback(app, &state, &[paths.output_file], Duration::ZERO)
+}
+
+#[tauri::command]
+pub fn play_playlist(app: AppHandle, state: tauri::State<AudioState>, files: Vec<String>) -> Result<(), String> {
+    if files.is_empty() {
+        return Err("Playlist is empty".to_string());
+    }
+    let files: Vec<PathBuf> = files.into_iter().map(PathBuf::from).collect();
+    start_playback(app, &state, &files, Duration::ZERO)
+}
+
+// Seconds into the current playback
+#[tauri::command]
+pub fn playback_position(state: tauri::State<AudioState>) -> f64 {
+    let samples = state.position.load(Ordering::Relaxed);
+    samples as f64 / PLAYBACK_CHANNELS as f64 / PLAYBACK_SAMPLE_RATE as f64
+}
+
+// Loads the variants of a library entry for A/B comparison and starts with the first one
+#[tauri::command]
+pub fn compare_variants(app: AppHandle, state: tauri::State<AudioState>, track: String) -> Result<usize, String> {
+    let library = Library::read()?;
+    let entry = library.get(&track)?;
+    if entry.variants.len() < 2 {
+        return Err(format!("{} has no variants to compare", entry.title));
+    }
+    let files: Vec<PathBuf> = entry.variants.iter().map(|variant| variant.audio.clone()).collect();
+    start_playback(app, &state, &files[..1], Duration::ZERO)?;
+    let count = files.len();
+    *state.compare.lock().map_err(|e| format!("Failed to lock comparison: {}", e))? = files;
+    Ok(count)
+}
+
+// Switches the comparison to another variant, continuing from the current position
+#[tauri::command]
+pub fn switch_variant(app: AppHandle, state: tauri::State<AudioState>, index: usize) -> Result<(), String> {
+    let file = state
+        .compare
+        .lock()
+        .map_err(|e| format!("Failed to lock comparison: {}", e))?
+        .get(index)
+        .cloned()
+        .ok_or_else(|| format!("No variant {} to switch to", index + 1))?;
+    let position = Duration::from_secs_f64(playback_position(state.clone()));
+    start_playback(app, &state, &[file], position)
+}
+
+#[tauri::command]
+pub fn set_crossfade(state: tauri::State<AudioState>, millis: u64) -> Result<(), String> {
+    let mut crossfade = state.crossfade.lock().map_err(|e| format!("Failed to lock crossfade: {}", e))?;
+    *crossfade = Duration::from_millis(millis);
+    println!("Crossfade set to {} ms", millis);
+    Ok(())
+}
+
 #[tauri::command]
 pub fn pause_audio(state: tauri::State<AudioState>) -> Result<(), String> {
     let sink_lock = state.sink.lock().map_err(|e| format!("Failed to lock sink: {}", e))?;
@@ -137,4 +414,78 @@ pub fn stop_audio(state: tauri::State<AudioState>) -> Result<(), String> {
         println!("No audio is playing.");
     }
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rodio::buffer::SamplesBuffer;
+
+    fn track(samples: &[f32]) -> Track {
+        let source = SamplesBuffer::new(PLAYBACK_CHANNELS, PLAYBACK_SAMPLE_RATE, samples.to_vec());
+        Track { source: Box::new(source), samples: Some(samples.len() as u64) }
+    }
+
+    fn chain(tracks: Vec<Track>, fade_samples: u64) -> (TrackChain, Arc<AtomicU64>) {
+        let played = Arc::new(AtomicU64::new(0));
+        let mut chain = TrackChain::new(tracks, Duration::ZERO, Arc::clone(&played));
+        chain.fade_samples = fade_samples;
+        (chain, played)
+    }
+
+    #[test]
+    fn tracks_play_back_to_back_without_a_crossfade() {
+        let (chain, played) = chain(vec![track(&[1.0, 2.0]), track(&[3.0, 4.0])], 0);
+        assert_eq!(chain.collect::<Vec<f32>>(), vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(played.load(Ordering::Relaxed), 4);
+    }
+
+    #[test]
+    fn crossfade_mixes_the_tail_with_the_next_head() {
+        let (chain, _) = chain(vec![track(&[1.0; 6]), track(&[2.0; 6])], 4);
+        let samples: Vec<f32> = chain.collect();
+        assert_eq!(samples[..2], [1.0, 1.0]);
+        // Gains of 0, 1/4, 2/4 and 3/4 over the four fade samples
+        assert_eq!(samples[2..6], [1.0, 1.25, 1.5, 1.75]);
+        // The incoming track continues after the samples used up by the fade
+        assert_eq!(samples[6..], [2.0, 2.0]);
+    }
+
+    #[test]
+    fn incoming_track_shorter_than_the_fade_ends_cleanly() {
+        let (chain, played) = chain(vec![track(&[1.0; 8]), track(&[10.0; 2]), track(&[5.0; 3])], 4);
+        let samples: Vec<f32> = chain.collect();
+        assert_eq!(samples[..4], [1.0; 4]);
+        // The short track runs out halfway through the fade and is mixed in as silence from there
+        assert_eq!(samples[4..8], [1.0, 3.25, 0.5, 0.25]);
+        assert_eq!(samples[8..], [5.0; 3]);
+        assert_eq!(played.load(Ordering::Relaxed), 11);
+    }
+
+    #[test]
+    fn formats_are_detected_by_content() {
+        assert_eq!(detect_format(b"RIFF\x24\0\0\0WAVEfmt "), Some(AudioFormat::Wav));
+        assert_eq!(detect_format(b"fLaC\0\0\0\x22"), Some(AudioFormat::Flac));
+        assert_eq!(detect_format(b"OggS\0\x02"), Some(AudioFormat::Vorbis));
+        assert_eq!(detect_format(b"MThd\0\0\0\x06"), Some(AudioFormat::Midi));
+        assert_eq!(detect_format(b"ID3\x04\0"), Some(AudioFormat::Mp3));
+        assert_eq!(detect_format(&[0xFF, 0xFB, 0x90, 0x64]), Some(AudioFormat::Mp3));
+        assert_eq!(detect_format(&[0xFF, 0xF3, 0x40, 0xC0]), Some(AudioFormat::Mp3));
+        assert_eq!(detect_format(b"RIFF\x24\0\0\0AVI "), None);
+        assert_eq!(detect_format(b""), None);
+    }
+
+    #[test]
+    fn adts_aac_is_not_taken_for_mp3() {
+        assert_eq!(detect_format(&[0xFF, 0xF1, 0x50, 0x80]), None);
+        assert_eq!(detect_format(&[0xFF, 0xF9, 0x50, 0x80]), None);
+    }
+
+    #[test]
+    fn unknown_length_falls_back_to_gapless() {
+        let mut first = track(&[1.0; 4]);
+        first.samples = None;
+        let (chain, _) = chain(vec![first, track(&[2.0; 2])], 4);
+        assert_eq!(chain.collect::<Vec<f32>>(), vec![1.0, 1.0, 1.0, 1.0, 2.0, 2.0]);
+    }
+}
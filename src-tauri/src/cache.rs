@@ -0,0 +1,264 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use sha2::{Digest, Sha256};
+use walkdir::WalkDir;
+use crate::config::{active_soundfont, cache_limit_mb, system_prompt};
+use crate::library::now_secs;
+use crate::setup::EnvPaths;
+
+const INDEX_FILE: &str = "index.json";
+const CACHED_MIDI: &str = "output.mid";
+const CACHED_AUDIO: &str = "output.wav";
+const DEFAULT_LIMIT_MB: u64 = 512;
+
+lazy_static::lazy_static! {
+    static ref CACHE_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CacheEntry {
+    size: u64,
+    last_used: u64,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct CacheIndex {
+    entries: HashMap<String, CacheEntry>,
+}
+
+impl CacheIndex {
+    fn load(paths: &EnvPaths) -> Self {
+        fs::read_to_string(paths.cache.join(INDEX_FILE))
+            .ok()
+            .and_then(|content| serde_json::from_str(&content).ok())
+            .unwrap_or_default()
+    }
+
+    fn save(&self, paths: &EnvPaths) -> Result<(), String> {
+        fs::create_dir_all(&paths.cache).map_err(|e| format!("Failed to create cache directory: {}", e))?;
+        let content = serde_json::to_string(self).map_err(|e| e.to_string())?;
+        fs::write(paths.cache.join(INDEX_FILE), content).map_err(|e| format!("Failed to write cache index: {}", e))
+    }
+}
+
+// Version of the music_composer package installed in the venv. It is installed from git, so the
+// commit pip recorded counts too: the version number alone stays the same between commits.
+fn composer_version(paths: &EnvPaths) -> Option<String> {
+    let venv = paths.python.parent()?.parent()?;
+    let dist_info = WalkDir::new(venv).max_depth(4).into_iter().filter_map(Result::ok).find(|entry| {
+        let name = entry.file_name().to_string_lossy().to_lowercase();
+        entry.file_type().is_dir() && name.starts_with("music_composer-") && name.ends_with(".dist-info")
+    })?;
+    let metadata = fs::read_to_string(dist_info.path().join("METADATA")).ok()?;
+    let version = metadata.lines().find_map(|line| line.strip_prefix("Version:"))?.trim();
+    let origin = fs::read_to_string(dist_info.path().join("direct_url.json")).unwrap_or_default();
+    Some(format!("{}:{:x}", version, Sha256::digest(origin)))
+}
+
+// Hash of everything that decides a generation's outcome. The command holds the prompt,
+// seed and musical parameters.
+pub fn cache_key(command: &str) -> String {
+    let paths = EnvPaths::new();
+    let soundfont = active_soundfont().ok().map(|soundfont| {
+        let size = fs::metadata(&soundfont).map(|metadata| metadata.len()).unwrap_or(0);
+        format!("{}:{}", soundfont.display(), size)
+    });
+    hash_key(command, system_prompt(), soundfont, composer_version(&paths))
+}
+
+fn hash_key(command: &str, system_prompt: Option<String>, soundfont: Option<String>, composer: Option<String>) -> String {
+    let material = json!({
+        "command": command,
+        "system_prompt": system_prompt,
+        "soundfont": soundfont,
+        "composer": composer,
+        "app_version": env!("CARGO_PKG_VERSION"),
+    });
+    format!("{:x}", Sha256::digest(material.to_string()))
+}
+
+// Copies a cached result to the given output files, returns false on a miss
+pub fn restore(key: &str, midi: &Path, audio: &Path) -> Result<bool, String> {
+    let _guard = CACHE_LOCK.lock().map_err(|e| e.to_string())?;
+    restore_in(&EnvPaths::new(), key, midi, audio)
+}
+
+fn restore_in(paths: &EnvPaths, key: &str, midi: &Path, audio: &Path) -> Result<bool, String> {
+    let mut index = CacheIndex::load(paths);
+    let Some(entry) = index.entries.get_mut(key) else {
+        return Ok(false);
+    };
+    let dir = paths.cache.join(key);
+    if fs::copy(dir.join(CACHED_AUDIO), audio).is_err() {
+        // Files removed behind our back make the entry useless
+        index.entries.remove(key);
+        index.save(paths)?;
+        return Ok(false);
+    }
+    let _ = fs::remove_file(midi);
+    if dir.join(CACHED_MIDI).exists() {
+        fs::copy(dir.join(CACHED_MIDI), midi).map_err(|e| format!("Failed to restore cached MIDI: {}", e))?;
+    }
+    entry.last_used = now_secs();
+    index.save(paths)?;
+    Ok(true)
+}
+
+// Stores a result, evicting the least recently used entries beyond the size cap
+pub fn store(key: &str, midi: &Path, audio: &Path) -> Result<(), String> {
+    let _guard = CACHE_LOCK.lock().map_err(|e| e.to_string())?;
+    let limit = cache_limit_mb().unwrap_or(DEFAULT_LIMIT_MB) * 1024 * 1024;
+    store_in(&EnvPaths::new(), key, midi, audio, limit)
+}
+
+fn store_in(paths: &EnvPaths, key: &str, midi: &Path, audio: &Path, limit: u64) -> Result<(), String> {
+    let mut index = CacheIndex::load(paths);
+    let dir = paths.cache.join(key);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create cache directory: {}", e))?;
+
+    let mut size = fs::copy(audio, dir.join(CACHED_AUDIO)).map_err(|e| format!("Failed to cache audio: {}", e))?;
+    if midi.exists() {
+        size += fs::copy(midi, dir.join(CACHED_MIDI)).map_err(|e| format!("Failed to cache MIDI: {}", e))?;
+    }
+    index.entries.insert(key.to_string(), CacheEntry { size, last_used: now_secs() });
+
+    let mut total: u64 = index.entries.values().map(|entry| entry.size).sum();
+    let mut by_age: Vec<(String, u64)> = index.entries.iter().map(|(key, entry)| (key.clone(), entry.last_used)).collect();
+    by_age.sort_by_key(|(_, last_used)| *last_used);
+    for (old, _) in by_age {
+        if total <= limit || old == key {
+            continue;
+        }
+        if let Some(entry) = index.entries.remove(&old) {
+            total -= entry.size;
+            let _ = fs::remove_dir_all(paths.cache.join(&old));
+        }
+    }
+    index.save(paths)
+}
+
+// Removes every cached result and returns the number of bytes freed
+#[tauri::command]
+pub async fn clear_cache() -> Result<u64, String> {
+    let _guard = CACHE_LOCK.lock().map_err(|e| e.to_string())?;
+    let paths = EnvPaths::new();
+    let freed = CacheIndex::load(&paths).entries.values().map(|entry| entry.size).sum();
+    if paths.cache.exists() {
+        fs::remove_dir_all(&paths.cache).map_err(|e| format!("Failed to clear cache: {}", e))?;
+    }
+    Ok(freed)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Application directory of a test, removed again when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("cache-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        fn paths(&self) -> EnvPaths {
+            EnvPaths::in_dir(self.0.clone())
+        }
+
+        // Output files of a generation with the given audio content
+        fn output(&self, audio: &str) -> (PathBuf, PathBuf) {
+            let (midi, wav) = (self.0.join("output.mid"), self.0.join("output.wav"));
+            fs::write(&midi, "midi").unwrap();
+            fs::write(&wav, audio).unwrap();
+            (midi, wav)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn stored_results_are_restored() {
+        let scratch = Scratch::new("restore");
+        let paths = scratch.paths();
+        let (midi, wav) = scratch.output("first");
+        assert_eq!(restore_in(&paths, "a", &midi, &wav), Ok(false));
+        store_in(&paths, "a", &midi, &wav, u64::MAX).unwrap();
+
+        fs::write(&wav, "later").unwrap();
+        fs::remove_file(&midi).unwrap();
+        assert_eq!(restore_in(&paths, "a", &midi, &wav), Ok(true));
+        assert_eq!(fs::read_to_string(&wav).unwrap(), "first");
+        assert_eq!(fs::read_to_string(&midi).unwrap(), "midi");
+        assert_eq!(CacheIndex::load(&paths).entries["a"].size, 9);
+    }
+
+    #[test]
+    fn missing_files_drop_the_entry() {
+        let scratch = Scratch::new("missing");
+        let paths = scratch.paths();
+        let (midi, wav) = scratch.output("audio");
+        store_in(&paths, "a", &midi, &wav, u64::MAX).unwrap();
+        fs::remove_dir_all(paths.cache.join("a")).unwrap();
+        assert_eq!(restore_in(&paths, "a", &midi, &wav), Ok(false));
+        assert!(CacheIndex::load(&paths).entries.is_empty());
+    }
+
+    #[test]
+    fn least_recently_used_entries_are_evicted() {
+        let scratch = Scratch::new("evict");
+        let paths = scratch.paths();
+        let (midi, wav) = scratch.output("12345");
+        // Every entry takes 5 + 4 bytes, the cap holds two of them
+        let limit = 18;
+        store_in(&paths, "a", &midi, &wav, limit).unwrap();
+        store_in(&paths, "b", &midi, &wav, limit).unwrap();
+        let mut index = CacheIndex::load(&paths);
+        index.entries.get_mut("a").unwrap().last_used = 1;
+        index.entries.get_mut("b").unwrap().last_used = 2;
+        index.save(&paths).unwrap();
+
+        // Using "a" again leaves "b" as the oldest
+        assert_eq!(restore_in(&paths, "a", &midi, &wav), Ok(true));
+        store_in(&paths, "c", &midi, &wav, limit).unwrap();
+        let mut kept: Vec<String> = CacheIndex::load(&paths).entries.into_keys().collect();
+        kept.sort();
+        assert_eq!(kept, ["a", "c"]);
+        assert!(!paths.cache.join("b").exists());
+    }
+
+    #[test]
+    fn key_follows_settings_and_composer() {
+        let key = |prompt: &str, soundfont: &str, composer: &str| hash_key("{}", Some(prompt.to_string()), Some(soundfont.to_string()), Some(composer.to_string()));
+        let base = key("prompt", "GM.sf2:100", "1.0:abc");
+        assert_eq!(key("prompt", "GM.sf2:100", "1.0:abc"), base);
+        assert_ne!(key("other prompt", "GM.sf2:100", "1.0:abc"), base);
+        assert_ne!(key("prompt", "GM.sf2:200", "1.0:abc"), base);
+        assert_ne!(key("prompt", "GM.sf2:100", "1.0:def"), base);
+        assert_ne!(hash_key("{\"seed\":1}", None, None, None), hash_key("{\"seed\":2}", None, None, None));
+
+        // The installed package's version and the commit it came from
+        let scratch = Scratch::new("version");
+        let paths = scratch.paths();
+        let venv = paths.python.parent().unwrap().parent().unwrap().to_path_buf();
+        let dist_info = venv.join("lib").join("python3.11").join("site-packages").join("music_composer-1.2.dist-info");
+        fs::create_dir_all(&dist_info).unwrap();
+        fs::write(dist_info.join("METADATA"), "Metadata-Version: 2.1\nName: music_composer\nVersion: 1.2\n").unwrap();
+        fs::write(dist_info.join("direct_url.json"), "{\"commit_id\": \"aaa\"}").unwrap();
+        let first = composer_version(&paths).unwrap();
+        assert!(first.starts_with("1.2:"));
+        fs::write(dist_info.join("direct_url.json"), "{\"commit_id\": \"bbb\"}").unwrap();
+        assert_ne!(composer_version(&paths).unwrap(), first);
+    }
+}
@@ -0,0 +1,238 @@
+use crate::humanize::SeededRng;
+use crate::midi::{Composition, KeySignature, Note, TempoChange, TimeSignature, Track};
+use crate::mood::analyze;
+use crate::theory::{Key, PitchClass};
+
+const TICKS_PER_BEAT: u16 = 480;
+const MIN_BARS: u64 = 4;
+const MELODY_BASE: u8 = 60;
+const CHORD_BASE: u8 = 48;
+const BASS_BASE: u8 = 36;
+
+// Melody, chord and bass programs, one set is picked per text
+const PALETTES: [(u8, u8, u8); 6] = [(0, 48, 32), (73, 0, 33), (24, 48, 32), (11, 89, 38), (40, 46, 43), (71, 49, 42)];
+
+// Scale degrees of the chord for each bar, cycled through the piece
+const MAJOR_PROGRESSIONS: [[i32; 4]; 3] = [[0, 4, 5, 3], [0, 3, 4, 0], [0, 5, 3, 4]];
+const MINOR_PROGRESSIONS: [[i32; 4]; 3] = [[0, 5, 2, 6], [0, 3, 4, 0], [0, 6, 5, 4]];
+
+struct Sentence {
+    words: Vec<String>,
+    // Words followed by a comma, where the melody breathes
+    pauses: Vec<usize>,
+    ending: char,
+}
+
+// FNV-1a, stable across platforms and releases unlike the std hasher
+fn text_seed(text: &str) -> u64 {
+    text.bytes().fold(0xcbf2_9ce4_8422_2325, |hash, byte| (hash ^ byte as u64).wrapping_mul(0x100_0000_01b3))
+}
+
+fn syllables(word: &str) -> usize {
+    let lower = word.to_lowercase();
+    let mut count = 0;
+    let mut previous_vowel = false;
+    for c in lower.chars() {
+        let vowel = "aeiouy".contains(c);
+        if vowel && !previous_vowel {
+            count += 1;
+        }
+        previous_vowel = vowel;
+    }
+    // A trailing silent e as in "love" or "time"
+    if lower.ends_with('e') && !lower.ends_with("le") && count > 1 {
+        count -= 1;
+    }
+    count.max(1)
+}
+
+fn split_sentences(text: &str) -> Vec<Sentence> {
+    let mut sentences = Vec::new();
+    let mut current = Sentence { words: Vec::new(), pauses: Vec::new(), ending: '.' };
+    let mut word = String::new();
+    for c in text.chars().chain(std::iter::once('\n')) {
+        if c.is_alphanumeric() || c == '\'' {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            current.words.push(std::mem::take(&mut word));
+        }
+        match c {
+            ',' | ':' | '-' if !current.words.is_empty() => current.pauses.push(current.words.len() - 1),
+            '.' | '!' | '?' | ';' | '\n' if !current.words.is_empty() => {
+                current.ending = if c == '\n' || c == ';' { '.' } else { c };
+                sentences.push(std::mem::replace(&mut current, Sentence { words: Vec::new(), pauses: Vec::new(), ending: '.' }));
+            }
+            _ => {}
+        }
+    }
+    sentences
+}
+
+// Key, base tempo and instruments, shared by all sections of a longer piece
+#[derive(Debug, Clone, Copy)]
+pub struct Style {
+    pub key: Key,
+    pub bpm: f64,
+    pub palette: usize,
+}
+
+// The key and tempo the mood analysis suggests; texts without any mood words fall back to a
+// tonic from their letters
+pub fn style_for(text: &str) -> Style {
+    let seed = text_seed(text);
+    let mood = analyze(text);
+    let palette = (seed >> 16) as usize % PALETTES.len();
+    if let Some(key) = Key::parse(&mood.suggestion.key).filter(|_| mood.coverage > 0.0) {
+        return Style { key, bpm: mood.suggestion.tempo_bpm as f64, palette };
+    }
+    let minor = seed.is_multiple_of(3);
+    let tonic = PitchClass::new(text.to_lowercase().bytes().filter(|b| b.is_ascii_alphabetic()).map(|b| b as i32).sum::<i32>());
+    Style { key: Key { tonic, minor }, bpm: if minor { 124.0 } else { 136.0 }, palette }
+}
+
+// Maps text deterministically to a short piece: sentences become phrases, syllables notes.
+// A non-zero seed gives a variation of the same text with another contour and progression.
+pub fn compose_with_style(text: &str, style: Style, seed: u64) -> Result<Composition, String> {
+    let sentences = split_sentences(text);
+    let words: Vec<&String> = sentences.iter().flat_map(|sentence| sentence.words.iter()).collect();
+    if words.is_empty() {
+        return Err("Enter some words to compose from".to_string());
+    }
+    let variation = seed;
+    let seed = text_seed(text).wrapping_add(variation);
+    let mut rng = SeededRng::new(seed);
+    let key = style.key;
+    let minor = key.minor;
+    let scale = key.scale();
+
+    // Long words slow the piece down, exclamations speed it up
+    let average_syllables = words.iter().map(|word| syllables(word)).sum::<usize>() as f64 / words.len() as f64;
+    let exclamations = sentences.iter().filter(|sentence| sentence.ending == '!').count() as f64 / sentences.len() as f64;
+    let bpm = (style.bpm - 20.0 * (average_syllables - 1.0) + 24.0 * exclamations).clamp(60.0, 168.0);
+
+    // Comma-heavy texts get a waltz
+    let pauses = sentences.iter().map(|sentence| sentence.pauses.len()).sum::<usize>() as f64 / sentences.len() as f64;
+    let numerator: u8 = if pauses >= 2.0 { 3 } else { 4 };
+    let beat = TICKS_PER_BEAT as u64;
+    let bar = beat * numerator as u64;
+
+    let mut melody = Vec::new();
+    let mut cursor = 0u64;
+    let mut degree = 0i32;
+    for sentence in &sentences {
+        let accent = if sentence.ending == '!' { 15 } else { 0 };
+        for (index, word) in sentence.words.iter().enumerate() {
+            let count = syllables(word);
+            let letters: Vec<u32> = word.to_lowercase().chars().map(|c| c as u32).collect();
+            for syllable in 0..count {
+                // The letter at each syllable's position steers the contour, pulled back towards the tonic
+                let letter = letters[syllable * letters.len() / count];
+                degree += ((letter as u64 + variation) % 5) as i32 - 2;
+                if degree.abs() > 6 {
+                    degree -= degree.signum() * 3;
+                }
+                let last = syllable + 1 == count;
+                let duration = if last && word.len() > 6 { beat } else { beat / 2 };
+                let stressed = syllable == 0;
+                let capital = stressed && word.chars().next().is_some_and(|c| c.is_uppercase()) && index > 0;
+                let velocity = 68.0 + accent as f64 + if stressed { 10.0 } else { 0.0 } + if capital { 8.0 } else { 0.0 } + rng.next_signed() * 4.0;
+                melody.push(Note {
+                    start: cursor,
+                    duration: duration * 9 / 10,
+                    pitch: scale.degree_pitch(MELODY_BASE, degree),
+                    velocity: velocity.round().clamp(1.0, 127.0) as u8,
+                });
+                cursor += duration;
+            }
+            if sentence.pauses.contains(&index) {
+                cursor += beat / 2;
+            }
+        }
+
+        // Statements come home to the tonic, questions hang on the dominant, exclamations leap to the octave
+        let cadence = match sentence.ending {
+            '?' => 4,
+            '!' => 7,
+            _ if degree > 3 => 7,
+            _ => 0,
+        };
+        let length = beat * 2;
+        melody.push(Note { start: cursor, duration: length * 9 / 10, pitch: scale.degree_pitch(MELODY_BASE, cadence), velocity: 72 + accent });
+        degree = cadence;
+        cursor = (cursor + length).div_ceil(bar) * bar;
+    }
+
+    let bars = (cursor / bar).max(MIN_BARS);
+    let progressions = if minor { &MINOR_PROGRESSIONS } else { &MAJOR_PROGRESSIONS };
+    let progression = progressions[(seed >> 8) as usize % progressions.len()];
+    let mut chords = Vec::new();
+    let mut bass = Vec::new();
+    for index in 0..bars {
+        let root = if index + 1 == bars { 0 } else { progression[index as usize % progression.len()] };
+        let start = index * bar;
+        // Chords are struck once per bar in triple time and twice in common time
+        let strokes = if numerator == 3 { 1 } else { 2 };
+        let stroke = bar / strokes;
+        for hit in 0..strokes {
+            for third in [0, 2, 4] {
+                chords.push(Note { start: start + hit * stroke, duration: stroke - beat / 8, pitch: scale.degree_pitch(CHORD_BASE, root + third), velocity: 58 });
+            }
+            let fifth = if hit == 1 { 4 } else { 0 };
+            bass.push(Note { start: start + hit * stroke, duration: stroke - beat / 4, pitch: scale.degree_pitch(BASS_BASE, root + fifth), velocity: 74 });
+        }
+    }
+
+    let (melody_program, chord_program, bass_program) = PALETTES[style.palette % PALETTES.len()];
+    let mut composition = Composition::new(TICKS_PER_BEAT);
+    composition.tempos.push(TempoChange { tick: 0, micros_per_beat: (60_000_000.0 / bpm).round() as u32 });
+    composition.time_signatures.push(TimeSignature { tick: 0, numerator, denominator: 4 });
+    composition.key_signatures.push(KeySignature { tick: 0, sharps: key.signature(), minor });
+    composition.tracks = vec![
+        Track { name: Some("Melody".to_string()), channel: 0, program: melody_program, notes: melody },
+        Track { name: Some("Chords".to_string()), channel: 1, program: chord_program, notes: chords },
+        Track { name: Some("Bass".to_string()), channel: 2, program: bass_program, notes: bass },
+    ];
+    Ok(composition)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEXT: &str = "The morning was bright and warm. We walked along the river, talking, laughing, dreaming!";
+
+    fn bytes(composition: &Composition) -> Vec<u8> {
+        composition.to_bytes().unwrap()
+    }
+
+    #[test]
+    fn same_text_and_seed_give_the_same_file() {
+        let style = style_for(TEXT);
+        let first = compose_with_style(TEXT, style, 3).unwrap();
+        assert_eq!(bytes(&compose_with_style(TEXT, style_for(TEXT), 3).unwrap()), bytes(&first));
+        assert_ne!(bytes(&compose_with_style(TEXT, style, 4).unwrap()), bytes(&first));
+        assert!(compose_with_style(" ... ", style, 0).is_err());
+    }
+
+    #[test]
+    fn style_follows_the_suggested_key_and_tempo() {
+        let happy = style_for("So happy, we dance and laugh and celebrate!");
+        let analysis = analyze("So happy, we dance and laugh and celebrate!");
+        assert_eq!(happy.key, Key::parse("D major").unwrap());
+        assert_eq!(happy.key, Key::parse(&analysis.suggestion.key).unwrap());
+        assert_eq!(happy.bpm, 160.0);
+        assert_eq!(happy.bpm, analysis.suggestion.tempo_bpm as f64);
+
+        let sad = style_for("Lonely tears in the cold grey rain.");
+        assert_eq!(sad.key, Key::parse("F minor").unwrap());
+        assert_eq!(sad.bpm, 91.0);
+
+        // Without mood words the letters pick the tonic
+        let plain = style_for("Table chair window");
+        let letters = "tablechairwindow".bytes().map(|b| b as i32).sum::<i32>();
+        assert_eq!(plain.key.tonic, PitchClass::new(letters));
+        assert_eq!(plain.bpm, if plain.key.minor { 124.0 } else { 136.0 });
+    }
+}
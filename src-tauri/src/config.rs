@@ -1,42 +1,495 @@
 use std::fs;
-use tauri::AppHandle;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use crate::library::now_secs;
+use crate::provider::{ProviderKind, ProviderSettings};
+use crate::python::release_api_key;
+use crate::secrets::{get_secret, has_secret, set_secret};
 use crate::setup::EnvPaths;
-use serde_json::json;
+use crate::utils::redact;
+use serde_json::{json, Value};
 use serde::{Deserialize, Serialize};
 
+// Where a single key was kept before keys were managed as a list
+const LEGACY_API_KEY_SECRET: &str = "gemini_api_key";
+// Characters of a saved API key shown to tell keys apart
+const API_KEY_HINT: usize = 4;
+const LAST_ERROR_LENGTH: usize = 300;
+// How long a key is skipped after the sidecar reports it rate limited or out of its daily quota
+const RATE_LIMIT_REST_SECS: u64 = 60;
+const DAILY_QUOTA_REST_SECS: u64 = 3600;
+
+lazy_static::lazy_static! {
+    // Serializes read-modify-write cycles on the key list
+    static ref KEYS_LOCK: Mutex<()> = Mutex::new(());
+    // Serializes writes to the config file, taken after KEYS_LOCK when both are held
+    static ref CONFIG_LOCK: Mutex<()> = Mutex::new(());
+}
+
+// Common install locations of the General MIDI SoundFont shipped with FluidSynth packages
+const SYSTEM_SOUNDFONTS: [&str; 3] = [
+    "/usr/share/sounds/sf2/FluidR3_GM.sf2",
+    "/usr/share/soundfonts/FluidR3_GM.sf2",
+    "/usr/share/soundfonts/default.sf2",
+];
+
+// Updates the given fields of the config file, keeping every other field as it was; null removes a field
+fn update_config(paths: &EnvPaths, fields: Value) -> Result<(), String> {
+    let _guard = CONFIG_LOCK.lock().map_err(|e| e.to_string())?;
+    let mut config = match fs::read_to_string(&paths.config) {
+        Ok(content) => serde_json::from_str::<Value>(&content).unwrap_or_else(|_| json!({})),
+        Err(_) => json!({}),
+    };
+    if !config.is_object() {
+        config = json!({});
+    }
+    if let (Some(config), Some(fields)) = (config.as_object_mut(), fields.as_object()) {
+        for (key, value) in fields {
+            if value.is_null() {
+                config.remove(key);
+            } else {
+                config.insert(key.clone(), value.clone());
+            }
+        }
+    }
+    fs::write(&paths.config, config.to_string()).map_err(|e| e.to_string())
+}
+
+// The sidecar's settings file; the API key is handed over through the environment instead
+fn write_env(paths: &EnvPaths) -> Result<(), String> {
+    let env_string = "CUSTOM_LOGGER_PLAY_ERROR_SOUND=\"False\"\nOUTPUT_WAV=\"output.wav\"\nOUTPUT_MIDI=\"output.mid\"";
+    fs::write(&paths.env, env_string).map_err(|e| e.to_string())
+}
+
+// A key given here is added to the Gemini keys and used from the next generation on
 #[tauri::command]
-pub async fn save_config(app: AppHandle, api_key: String, system_prompt: String) -> Result<(), String> {
+pub async fn save_config(api_key: Option<String>, system_prompt: String) -> Result<(), String> {
     let paths = EnvPaths::new();
+    if let Some(api_key) = api_key.filter(|api_key| !api_key.trim().is_empty()) {
+        update_keys(|keys, active| {
+            let added = add_key(keys, ProviderKind::Gemini, "", &api_key)?;
+            *active = Some(added.id);
+            Ok(())
+        })?;
+    }
+    write_env(&paths)?;
+    update_config(&paths, json!({ "system_prompt": system_prompt }))
+}
 
-    // Overwrite env file
-    let env_string = format!("CUSTOM_LOGGER_PLAY_ERROR_SOUND=\"False\"\nGEMINI_API_KEYS=\"{api_key}\"\nOUTPUT_WAV=\"output.wav\"");
-    fs::write(&paths.env, env_string).map_err(|e| e.to_string())?;
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKeyInfo {
+    pub id: String,
+    pub label: String,
+    #[serde(default)]
+    pub provider: ProviderKind,
+    pub hint: String,
+    pub enabled: bool,
+    pub added: u64,
+    // Commands sent to the sidecar while it ran with this key
+    #[serde(default)]
+    pub requests: u64,
+    #[serde(default)]
+    pub errors: u64,
+    #[serde(default)]
+    pub last_used: Option<u64>,
+    #[serde(default)]
+    pub last_success: Option<u64>,
+    #[serde(default)]
+    pub last_error: Option<String>,
+    #[serde(default)]
+    pub last_error_at: Option<u64>,
+    // Set after a rate-limit or quota error, the key is skipped until then
+    #[serde(default)]
+    pub resting_until: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ApiKeyHealth {
+    Healthy,
+    Resting,
+    // The latest request with the key failed
+    Failing,
+    Disabled,
+}
 
-    // Overwrite config file with JSON
-    let json_data = json!({ "api_key":api_key, "system_prompt": system_prompt });
-    fs::write(&paths.config, json_data.to_string()).map_err(|e| e.to_string())?;
+#[derive(Debug, Clone, Serialize)]
+pub struct ApiKeyStatus {
+    #[serde(flatten)]
+    pub key: ApiKeyInfo,
+    pub health: ApiKeyHealth,
+    pub active: bool,
+}
 
-    Ok(())
+impl ApiKeyInfo {
+    fn usable(&self, now: u64) -> bool {
+        self.enabled && self.resting_until.is_none_or(|until| until <= now)
+    }
+
+    fn health(&self, now: u64) -> ApiKeyHealth {
+        if !self.enabled {
+            ApiKeyHealth::Disabled
+        } else if !self.usable(now) {
+            ApiKeyHealth::Resting
+        } else if self.last_error_at.is_some_and(|failed| self.last_success.is_none_or(|succeeded| failed >= succeeded)) {
+            ApiKeyHealth::Failing
+        } else {
+            ApiKeyHealth::Healthy
+        }
+    }
 }
 
-#[derive(Debug, Serialize, Deserialize, Default)]
+#[derive(Serialize, Deserialize, Default)]
 pub struct ConfigData {
+    // Only read to move keys saved by older versions into the secret store
     api_key: Option<String>,
+    soundfont: Option<String>,
+    system_prompt: Option<String>,
+    cache_limit_mb: Option<u64>,
+    #[serde(default)]
+    api_keys: Vec<ApiKeyInfo>,
+    active_api_key: Option<String>,
+    provider: Option<ProviderSettings>,
+}
+
+fn read_config(paths: &EnvPaths) -> Result<Option<ConfigData>, String> {
+    if !paths.config.exists() {
+        return Ok(None);
+    }
+    let file_content = fs::read_to_string(&paths.config).map_err(|e| e.to_string())?;
+    serde_json::from_str(&file_content).map(Some).map_err(|e| e.to_string())
+}
+
+fn secret_name(id: &str) -> String {
+    format!("api_key:{}", id)
+}
+
+fn hint(api_key: &str) -> String {
+    let tail: Vec<char> = api_key.chars().rev().take(API_KEY_HINT).collect();
+    format!("\u{2022}\u{2022}\u{2022}\u{2022}{}", tail.into_iter().rev().collect::<String>())
+}
+
+// Stores a new key in the secret store; a key that is already in the list is returned as it is
+fn add_key(keys: &mut Vec<ApiKeyInfo>, provider: ProviderKind, label: &str, api_key: &str) -> Result<ApiKeyInfo, String> {
+    let api_key = api_key.trim();
+    if api_key.is_empty() {
+        return Err("The API key is empty".to_string());
+    }
+    for key in keys.iter().filter(|key| key.provider == provider) {
+        if get_secret(&secret_name(&key.id))?.as_deref() == Some(api_key) {
+            return Ok(key.clone());
+        }
+    }
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+    let label = if label.trim().is_empty() { format!("Key {}", keys.len() + 1) } else { label.trim().to_string() };
+    let key = ApiKeyInfo {
+        id: format!("{:x}", nanos),
+        label,
+        provider,
+        hint: hint(api_key),
+        enabled: true,
+        added: now_secs(),
+        requests: 0,
+        errors: 0,
+        last_used: None,
+        last_success: None,
+        last_error: None,
+        last_error_at: None,
+        resting_until: None,
+    };
+    set_secret(&secret_name(&key.id), Some(api_key))?;
+    keys.push(key.clone());
+    Ok(key)
+}
+
+// Runs a change on the key list and the active key, after moving in keys saved by older versions
+fn update_keys<T>(change: impl FnOnce(&mut Vec<ApiKeyInfo>, &mut Option<String>) -> Result<T, String>) -> Result<T, String> {
+    let _guard = KEYS_LOCK.lock().map_err(|e| e.to_string())?;
+    let paths = EnvPaths::new();
+    let config = read_config(&paths)?.unwrap_or_default();
+    let mut keys = config.api_keys;
+    let mut active = config.active_api_key;
+
+    // A plain-text key in the config file or the single encrypted key
+    let stored = if has_secret(LEGACY_API_KEY_SECRET) { get_secret(LEGACY_API_KEY_SECRET)? } else { None };
+    let legacy: Vec<String> = config.api_key.into_iter().chain(stored).collect();
+    for api_key in &legacy {
+        let added = add_key(&mut keys, ProviderKind::Gemini, "Default", api_key)?;
+        active.get_or_insert(added.id);
+    }
+
+    let result = change(&mut keys, &mut active)?;
+    update_config(&paths, json!({ "api_key": null, "api_keys": keys, "active_api_key": active }))?;
+    if !legacy.is_empty() {
+        set_secret(LEGACY_API_KEY_SECRET, None)?;
+        write_env(&paths)?;
+    }
+    Ok(result)
+}
+
+fn statuses(keys: &[ApiKeyInfo], active: &Option<String>) -> Vec<ApiKeyStatus> {
+    let now = now_secs();
+    keys.iter()
+        .map(|key| ApiKeyStatus { key: key.clone(), health: key.health(now), active: active.as_ref() == Some(&key.id) })
+        .collect()
+}
+
+// Picks the provider's key for a new sidecar process: the active key unless it is resting or
+// disabled, then the first usable one. When every enabled key is resting, the one that recovers
+// first is tried
+fn pick_key<'a>(keys: &'a [ApiKeyInfo], active: &Option<String>, provider: ProviderKind, now: u64) -> Option<&'a ApiKeyInfo> {
+    let candidates = || keys.iter().filter(|key| key.provider == provider);
+    candidates()
+        .find(|key| active.as_ref() == Some(&key.id) && key.usable(now))
+        .or_else(|| candidates().find(|key| key.usable(now)))
+        .or_else(|| candidates().filter(|key| key.enabled).min_by_key(|key| key.resting_until))
+}
+
+pub fn next_api_key(provider: ProviderKind) -> Result<Option<(String, String)>, String> {
+    update_keys(|keys, active| {
+        let Some(chosen) = pick_key(keys, active, provider, now_secs()) else {
+            return Ok(None);
+        };
+        let api_key = get_secret(&secret_name(&chosen.id))?.ok_or_else(|| format!("{} is missing from the secret store", chosen.label))?;
+        *active = Some(chosen.id.clone());
+        Ok(Some((chosen.id.clone(), api_key)))
+    })
+}
+
+fn with_key(id: &str, change: impl FnOnce(&mut ApiKeyInfo)) -> Result<(), String> {
+    update_keys(|keys, _| {
+        if let Some(key) = keys.iter_mut().find(|key| key.id == id) {
+            change(key);
+        }
+        Ok(())
+    })
+}
+
+pub fn record_api_key_request(id: &str) -> Result<(), String> {
+    with_key(id, |key| {
+        key.requests += 1;
+        key.last_used = Some(now_secs());
+    })
+}
+
+pub fn record_api_key_success(id: &str) -> Result<(), String> {
+    with_key(id, |key| {
+        key.last_success = Some(now_secs());
+        key.resting_until = None;
+    })
+}
+
+// How long to rest a key after an error, for rate-limit and quota errors
+fn rest_period(message: &str) -> Option<u64> {
+    let message = message.to_ascii_lowercase();
+    let limited = ["429", "rate limit", "too many requests", "quota", "resource_exhausted", "resource exhausted"];
+    if !limited.iter().any(|sign| message.contains(sign)) {
+        return None;
+    }
+    let daily = ["per day", "perday", "per_day", "daily"];
+    Some(if daily.iter().any(|sign| message.contains(sign)) { DAILY_QUOTA_REST_SECS } else { RATE_LIMIT_REST_SECS })
 }
 
+// Records an error the sidecar reported while using a key. A rate-limit or quota error rests the
+// key and makes the next usable one active; its label is returned when there is one
+pub fn report_api_key_error(id: &str, message: &str) -> Result<Option<String>, String> {
+    update_keys(|keys, active| {
+        let now = now_secs();
+        let Some(key) = keys.iter_mut().find(|key| key.id == id) else {
+            return Ok(None);
+        };
+        key.errors += 1;
+        key.last_error = Some(redact(message.trim()).chars().take(LAST_ERROR_LENGTH).collect());
+        key.last_error_at = Some(now);
+        let Some(rest) = rest_period(message) else {
+            return Ok(None);
+        };
+        key.resting_until = Some(now + rest);
+        let provider = key.provider;
+        let next = keys.iter().find(|key| key.id != id && key.provider == provider && key.usable(now));
+        *active = next.map(|key| key.id.clone());
+        Ok(next.map(|key| key.label.clone()))
+    })
+}
+
+// A hint of the active key, the key itself is never sent back
 #[tauri::command]
 pub async fn load_config(key: String) -> Result<Option<String>, String> {
     let paths = EnvPaths::new();
 
-    let config_data: ConfigData = if paths.config.exists() {
-        let file_content = fs::read_to_string(&paths.config).map_err(|e| e.to_string())?;
-        serde_json::from_str(&file_content).map_err(|e| e.to_string())?
-    } else {
-        return Ok(None);
-    };
-    println!("{:?}", config_data);
     match key.as_str() {
-        "api_key" => Ok(config_data.api_key.clone()),
+        "api_key" => update_keys(|keys, active| {
+            let mut gemini = keys.iter().filter(|key| key.provider == ProviderKind::Gemini);
+            let shown = gemini.clone().find(|key| active.as_ref() == Some(&key.id)).or_else(|| gemini.find(|key| key.enabled));
+            Ok(shown.map(|key| key.hint.clone()))
+        }),
+        "soundfont" => Ok(read_config(&paths)?.and_then(|config_data| config_data.soundfont)),
         _ => Err(format!("Unknown key: {}", key)),
     }
 }
+
+#[tauri::command]
+pub async fn list_api_keys() -> Result<Vec<ApiKeyStatus>, String> {
+    update_keys(|keys, active| Ok(statuses(keys, active)))
+}
+
+#[tauri::command]
+pub async fn add_api_key(label: String, key: String, provider: Option<ProviderKind>) -> Result<Vec<ApiKeyStatus>, String> {
+    update_keys(|keys, active| {
+        let added = add_key(keys, provider.unwrap_or_default(), &label, &key)?;
+        active.get_or_insert(added.id);
+        Ok(statuses(keys, active))
+    })
+}
+
+// A disabled key keeps its secret and statistics but is never picked
+#[tauri::command]
+pub async fn set_api_key_enabled(id: String, enabled: bool) -> Result<Vec<ApiKeyStatus>, String> {
+    let updated = update_keys(|keys, active| {
+        let key = keys.iter_mut().find(|key| key.id == id).ok_or_else(|| format!("Unknown API key {}", id))?;
+        key.enabled = enabled;
+        if enabled {
+            key.resting_until = None;
+        } else if active.as_ref() == Some(&id) {
+            *active = None;
+        }
+        Ok(statuses(keys, active))
+    })?;
+    if !enabled {
+        release_api_key(&id).await;
+    }
+    Ok(updated)
+}
+
+#[tauri::command]
+pub async fn remove_api_key(id: String) -> Result<Vec<ApiKeyStatus>, String> {
+    let updated = update_keys(|keys, active| {
+        let index = keys.iter().position(|key| key.id == id).ok_or_else(|| format!("Unknown API key {}", id))?;
+        keys.remove(index);
+        set_secret(&secret_name(&id), None)?;
+        if active.as_ref() == Some(&id) {
+            *active = None;
+        }
+        Ok(statuses(keys, active))
+    })?;
+    release_api_key(&id).await;
+    Ok(updated)
+}
+
+#[tauri::command]
+pub async fn set_soundfont(path: String) -> Result<(), String> {
+    let paths = EnvPaths::new();
+    if !PathBuf::from(&path).is_file() {
+        return Err(format!("SoundFont not found at {}", path));
+    }
+    update_config(&paths, json!({ "soundfont": path }))
+}
+
+// The SoundFont used for rendering: the configured one, else the bundled or a system-wide one
+pub fn active_soundfont() -> Result<PathBuf, String> {
+    let paths = EnvPaths::new();
+    if let Some(soundfont) = read_config(&paths).ok().flatten().and_then(|c| c.soundfont) {
+        let soundfont = PathBuf::from(soundfont);
+        if soundfont.is_file() {
+            return Ok(soundfont);
+        }
+    }
+    if paths.soundfont.is_file() {
+        return Ok(paths.soundfont);
+    }
+    SYSTEM_SOUNDFONTS
+        .iter()
+        .map(PathBuf::from)
+        .find(|path| path.is_file())
+        .ok_or_else(|| "No SoundFont configured. Please select a SoundFont in Config.".to_string())
+}
+
+pub fn provider_settings() -> ProviderSettings {
+    read_config(&EnvPaths::new()).ok().flatten().and_then(|config| config.provider).unwrap_or_default()
+}
+
+pub fn save_provider_settings(settings: &ProviderSettings) -> Result<(), String> {
+    let settings = serde_json::to_value(settings).map_err(|e| e.to_string())?;
+    update_config(&EnvPaths::new(), json!({ "provider": settings }))
+}
+
+pub fn system_prompt() -> Option<String> {
+    read_config(&EnvPaths::new()).ok().flatten().and_then(|config| config.system_prompt)
+}
+
+// Size cap of the generation cache in megabytes, when configured
+pub fn cache_limit_mb() -> Option<u64> {
+    read_config(&EnvPaths::new()).ok().flatten().and_then(|config| config.cache_limit_mb)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn key(id: &str, provider: ProviderKind) -> ApiKeyInfo {
+        ApiKeyInfo {
+            id: id.to_string(),
+            label: id.to_string(),
+            provider,
+            hint: hint(id),
+            enabled: true,
+            added: 0,
+            requests: 0,
+            errors: 0,
+            last_used: None,
+            last_success: None,
+            last_error: None,
+            last_error_at: None,
+            resting_until: None,
+        }
+    }
+
+    #[test]
+    fn rest_period_tells_rate_limits_from_daily_quotas() {
+        assert_eq!(rest_period("Network unreachable"), None);
+        assert_eq!(rest_period("Error 429: Too Many Requests"), Some(RATE_LIMIT_REST_SECS));
+        assert_eq!(rest_period("RESOURCE_EXHAUSTED: rate limit exceeded"), Some(RATE_LIMIT_REST_SECS));
+        assert_eq!(rest_period("Quota exceeded for GenerateRequestsPerDayPerProjectPerModel"), Some(DAILY_QUOTA_REST_SECS));
+        assert_eq!(rest_period("daily quota reached"), Some(DAILY_QUOTA_REST_SECS));
+    }
+
+    #[test]
+    fn health_follows_rest_and_latest_outcome() {
+        let mut info = key("a", ProviderKind::Gemini);
+        assert_eq!(info.health(100), ApiKeyHealth::Healthy);
+        info.last_error_at = Some(50);
+        assert_eq!(info.health(100), ApiKeyHealth::Failing);
+        info.last_success = Some(40);
+        assert_eq!(info.health(100), ApiKeyHealth::Failing);
+        info.last_success = Some(60);
+        assert_eq!(info.health(100), ApiKeyHealth::Healthy);
+        info.resting_until = Some(120);
+        assert_eq!(info.health(100), ApiKeyHealth::Resting);
+        assert_eq!(info.health(120), ApiKeyHealth::Healthy);
+        info.enabled = false;
+        assert_eq!(info.health(120), ApiKeyHealth::Disabled);
+    }
+
+    #[test]
+    fn picks_active_then_usable_then_earliest_recovery() {
+        let mut keys = vec![key("a", ProviderKind::Gemini), key("b", ProviderKind::Gemini), key("c", ProviderKind::OpenaiCompatible)];
+        let active = Some("b".to_string());
+        let picked = |keys: &[ApiKeyInfo], provider| pick_key(keys, &active, provider, 100).map(|key| key.id.clone());
+        assert_eq!(picked(&keys, ProviderKind::Gemini).as_deref(), Some("b"));
+        assert_eq!(picked(&keys, ProviderKind::OpenaiCompatible).as_deref(), Some("c"));
+        assert_eq!(picked(&keys, ProviderKind::Mock), None);
+
+        // A resting active key gives way to the first usable one
+        keys[1].resting_until = Some(200);
+        assert_eq!(picked(&keys, ProviderKind::Gemini).as_deref(), Some("a"));
+
+        // With every key resting, the one that recovers first; disabled keys never
+        keys[0].resting_until = Some(300);
+        assert_eq!(picked(&keys, ProviderKind::Gemini).as_deref(), Some("b"));
+        keys[1].enabled = false;
+        assert_eq!(picked(&keys, ProviderKind::Gemini).as_deref(), Some("a"));
+        keys[0].enabled = false;
+        assert_eq!(picked(&keys, ProviderKind::Gemini), None);
+    }
+}
@@ -0,0 +1,466 @@
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::library::{now_secs, Library, LibraryEntry};
+use crate::midi::{Composition, ControlEvent, KeySignature, Note, TempoChange, TimeSignature, DEFAULT_TEMPO, DRUM_CHANNEL};
+use crate::theory::{composition_key, Key};
+
+// Velocity of notes added without one
+const DEFAULT_VELOCITY: u8 = 90;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum EditOperation {
+    Transpose { semitones: i8 },
+    TransposeToKey { key: String },
+    // A factor above 1 speeds the piece up
+    ScaleTempo { factor: f64 },
+    // One-based, inclusive bar range to keep
+    Trim { from_bar: u32, to_bar: u32 },
+    // Grid as a note value, e.g. 16 for sixteenth notes
+    Quantize { grid: u32, strength: Option<f64> },
+    ScaleVelocity { factor: f64 },
+    // Note edits address tracks and notes by their index in the piano roll, times are in ticks
+    AddNote { track: usize, pitch: u8, start: u64, duration: u64, velocity: Option<u8> },
+    MoveNote { track: usize, note: usize, start: u64, pitch: u8 },
+    ResizeNote { track: usize, note: usize, duration: u64 },
+    DeleteNote { track: usize, note: usize },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct EditVersion {
+    pub operation: EditOperation,
+    pub previous: PathBuf,
+    pub midi: PathBuf,
+    pub created: u64,
+}
+
+pub fn transpose(composition: &mut Composition, semitones: i8) {
+    for track in composition.tracks.iter_mut().filter(|track| track.channel != DRUM_CHANNEL) {
+        for note in track.notes.iter_mut() {
+            note.pitch = (note.pitch as i32 + semitones as i32).clamp(0, 127) as u8;
+        }
+    }
+    for signature in composition.key_signatures.iter_mut() {
+        let key = Key::from_signature(signature.sharps, signature.minor);
+        signature.sharps = Key { tonic: key.tonic.transpose(semitones as i32), ..key }.signature();
+    }
+}
+
+pub fn transpose_to_key(composition: &mut Composition, key: &str) -> Result<(), String> {
+    let target = Key::parse(key).ok_or_else(|| format!("Unknown key: {}", key))?;
+    // Without a key signature the detected key is used
+    let current = composition_key(composition).ok_or_else(|| "The composition has no pitched notes to transpose".to_string())?;
+
+    // A change of mode goes through the relative key, so C major to A minor keeps the notes
+    let tonic = match (current.minor, target.minor) {
+        (false, true) => current.tonic.transpose(9),
+        (true, false) => current.tonic.transpose(3),
+        _ => current.tonic,
+    };
+    // Take the shortest way to the new tonic
+    let mut semitones = (target.tonic.0 as i32 - tonic.0 as i32).rem_euclid(12);
+    if semitones > 6 {
+        semitones -= 12;
+    }
+    transpose(composition, semitones as i8);
+    match composition.key_signatures.first_mut() {
+        Some(first) => {
+            first.minor = target.minor;
+            first.sharps = target.signature();
+        }
+        None => composition.key_signatures.push(KeySignature { tick: 0, sharps: target.signature(), minor: target.minor }),
+    }
+    Ok(())
+}
+
+pub fn scale_tempo(composition: &mut Composition, factor: f64) -> Result<(), String> {
+    if !(factor.is_finite() && factor > 0.0) {
+        return Err(format!("Invalid tempo factor: {}", factor));
+    }
+    if composition.tempos.is_empty() {
+        composition.tempos.push(TempoChange { tick: 0, micros_per_beat: DEFAULT_TEMPO });
+    }
+    for tempo in composition.tempos.iter_mut() {
+        tempo.micros_per_beat = (tempo.micros_per_beat as f64 / factor).round().clamp(1.0, 0xFF_FFFF as f64) as u32;
+    }
+    Ok(())
+}
+
+pub fn trim(composition: &mut Composition, from_bar: u32, to_bar: u32) -> Result<(), String> {
+    if from_bar == 0 || to_bar < from_bar {
+        return Err(format!("Invalid bar range {}-{}", from_bar, to_bar));
+    }
+    let start = composition.bar_to_tick(from_bar - 1);
+    let end = composition.bar_to_tick(to_bar);
+
+    // Tempo, meter and key in effect at the cut point move to the new start
+    let tempo = composition.tempo_at(start);
+    let (numerator, denominator) = composition.time_signature_at(start);
+    let key = composition.key_signatures.iter().take_while(|signature| signature.tick <= start).last().copied();
+
+    for track in composition.tracks.iter_mut() {
+        track.notes.retain(|note| note.end() > start && note.start < end);
+        for note in track.notes.iter_mut() {
+            let note_start = note.start.max(start);
+            let note_end = note.end().min(end);
+            note.start = note_start - start;
+            note.duration = note_end - note_start;
+        }
+    }
+    composition.tracks.retain(|track| !track.notes.is_empty());
+
+    // Controller values in effect at the cut point are set again at the new start
+    let mut carried: Vec<ControlEvent> = Vec::new();
+    for control in composition.controls.iter().filter(|control| control.tick <= start) {
+        carried.retain(|kept| kept.channel != control.channel || !kept.control.same_target(&control.control));
+        carried.push(ControlEvent { tick: 0, ..*control });
+    }
+    composition.controls.retain(|control| control.tick > start && control.tick < end);
+    composition.controls.iter_mut().for_each(|control| control.tick -= start);
+    composition.controls.splice(0..0, carried);
+
+    composition.tempos.retain(|change| change.tick > start && change.tick < end);
+    composition.tempos.iter_mut().for_each(|change| change.tick -= start);
+    composition.tempos.insert(0, TempoChange { tick: 0, micros_per_beat: tempo });
+
+    composition.time_signatures.retain(|change| change.tick > start && change.tick < end);
+    composition.time_signatures.iter_mut().for_each(|change| change.tick -= start);
+    composition.time_signatures.insert(0, TimeSignature { tick: 0, numerator, denominator });
+
+    composition.key_signatures.retain(|change| change.tick > start && change.tick < end);
+    composition.key_signatures.iter_mut().for_each(|change| change.tick -= start);
+    if let Some(key) = key {
+        composition.key_signatures.insert(0, KeySignature { tick: 0, ..key });
+    }
+    Ok(())
+}
+
+pub fn quantize(composition: &mut Composition, grid: u32, strength: f64) -> Result<(), String> {
+    if grid == 0 {
+        return Err("Quantize grid must be positive".to_string());
+    }
+    let step = (composition.ticks_per_beat as u64 * 4 / grid as u64).max(1);
+    let strength = strength.clamp(0.0, 1.0);
+    let snap = |tick: u64| -> u64 {
+        let target = ((tick as f64 / step as f64).round() as u64) * step;
+        (tick as f64 + (target as f64 - tick as f64) * strength).round() as u64
+    };
+    for track in composition.tracks.iter_mut() {
+        for note in track.notes.iter_mut() {
+            let start = snap(note.start);
+            let end = snap(note.end()).max(start + 1);
+            note.start = start;
+            note.duration = end - start;
+        }
+        track.notes.sort_by_key(|note| (note.start, note.pitch));
+    }
+    Ok(())
+}
+
+pub fn scale_velocity(composition: &mut Composition, factor: f64) -> Result<(), String> {
+    if !(factor.is_finite() && factor >= 0.0) {
+        return Err(format!("Invalid velocity factor: {}", factor));
+    }
+    for track in composition.tracks.iter_mut() {
+        for note in track.notes.iter_mut() {
+            note.velocity = (note.velocity as f64 * factor).round().clamp(1.0, 127.0) as u8;
+        }
+    }
+    Ok(())
+}
+
+fn notes_mut(composition: &mut Composition, track: usize) -> Result<&mut Vec<Note>, String> {
+    composition.tracks.get_mut(track).map(|track| &mut track.notes).ok_or_else(|| format!("No track {}", track + 1))
+}
+
+fn check_note(pitch: u8, duration: u64) -> Result<(), String> {
+    if pitch > 127 {
+        return Err(format!("Pitch {} is outside the MIDI range", pitch));
+    }
+    if duration == 0 {
+        return Err("Notes must last at least one tick".to_string());
+    }
+    Ok(())
+}
+
+// Notes stay in the order the MIDI reader produces, so indices match after a reload
+fn sort_notes(notes: &mut [Note]) {
+    notes.sort_by_key(|note| (note.start, note.pitch));
+}
+
+pub fn add_note(composition: &mut Composition, track: usize, note: Note) -> Result<(), String> {
+    check_note(note.pitch, note.duration)?;
+    let notes = notes_mut(composition, track)?;
+    notes.push(Note { velocity: note.velocity.clamp(1, 127), ..note });
+    sort_notes(notes);
+    Ok(())
+}
+
+pub fn move_note(composition: &mut Composition, track: usize, note: usize, start: u64, pitch: u8) -> Result<(), String> {
+    let notes = notes_mut(composition, track)?;
+    let moved = notes.get_mut(note).ok_or_else(|| format!("No note {} in track {}", note, track + 1))?;
+    check_note(pitch, moved.duration)?;
+    moved.start = start;
+    moved.pitch = pitch;
+    sort_notes(notes);
+    Ok(())
+}
+
+pub fn resize_note(composition: &mut Composition, track: usize, note: usize, duration: u64) -> Result<(), String> {
+    let notes = notes_mut(composition, track)?;
+    let resized = notes.get_mut(note).ok_or_else(|| format!("No note {} in track {}", note, track + 1))?;
+    check_note(resized.pitch, duration)?;
+    resized.duration = duration;
+    Ok(())
+}
+
+pub fn delete_note(composition: &mut Composition, track: usize, note: usize) -> Result<(), String> {
+    let notes = notes_mut(composition, track)?;
+    if note >= notes.len() {
+        return Err(format!("No note {} in track {}", note, track + 1));
+    }
+    // Tracks without notes are not written, which would shift the index of every later track
+    if notes.len() == 1 {
+        return Err(format!("Note {} is the last one of track {} and cannot be deleted", note, track + 1));
+    }
+    notes.remove(note);
+    Ok(())
+}
+
+pub fn apply(composition: &mut Composition, operation: &EditOperation) -> Result<(), String> {
+    match operation {
+        EditOperation::Transpose { semitones } => {
+            transpose(composition, *semitones);
+            Ok(())
+        }
+        EditOperation::TransposeToKey { key } => transpose_to_key(composition, key),
+        EditOperation::ScaleTempo { factor } => scale_tempo(composition, *factor),
+        EditOperation::Trim { from_bar, to_bar } => trim(composition, *from_bar, *to_bar),
+        EditOperation::Quantize { grid, strength } => quantize(composition, *grid, strength.unwrap_or(1.0)),
+        EditOperation::ScaleVelocity { factor } => scale_velocity(composition, *factor),
+        EditOperation::AddNote { track, pitch, start, duration, velocity } => {
+            let note = Note { start: *start, duration: *duration, pitch: *pitch, velocity: velocity.unwrap_or(DEFAULT_VELOCITY) };
+            add_note(composition, *track, note)
+        }
+        EditOperation::MoveNote { track, note, start, pitch } => move_note(composition, *track, *note, *start, *pitch),
+        EditOperation::ResizeNote { track, note, duration } => resize_note(composition, *track, *note, *duration),
+        EditOperation::DeleteNote { track, note } => delete_note(composition, *track, *note),
+    }
+}
+
+fn set_midi(entry: &mut LibraryEntry, midi: PathBuf) -> Result<(), String> {
+    entry.summary = Some(Composition::from_file(&midi)?.summary());
+    entry.midi = Some(midi);
+    entry.analysis = None;
+    // The audio still plays the MIDI from before the edit
+    entry.render_stale = true;
+    Ok(())
+}
+
+#[tauri::command]
+pub async fn edit_composition(track: String, operation: EditOperation) -> Result<LibraryEntry, String> {
+    Library::update(move |library| {
+        let entry = library.get_mut(&track)?;
+        let previous = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to edit", entry.title))?;
+        let mut composition = Composition::from_file(&previous)?;
+        apply(&mut composition, &operation)?;
+
+        // Every edit is written to its own file so it can be undone
+        let midi = (1..)
+            .map(|number| entry.dir().join(format!("edit{}.mid", number)))
+            .find(|path| !path.exists())
+            .unwrap_or_default();
+        composition.write_file(&midi)?;
+        set_midi(entry, midi.clone())?;
+        entry.undone.clear();
+        entry.edits.push(EditVersion { operation, previous, midi, created: now_secs() });
+        Ok(entry.clone())
+    })
+}
+
+#[tauri::command]
+pub async fn undo_edit(track: String) -> Result<LibraryEntry, String> {
+    Library::update(move |library| {
+        let entry = library.get_mut(&track)?;
+        let edit = entry.edits.pop().ok_or_else(|| "Nothing to undo".to_string())?;
+        set_midi(entry, edit.previous.clone())?;
+        entry.undone.push(edit);
+        Ok(entry.clone())
+    })
+}
+
+#[tauri::command]
+pub async fn redo_edit(track: String) -> Result<LibraryEntry, String> {
+    Library::update(move |library| {
+        let entry = library.get_mut(&track)?;
+        let edit = entry.undone.pop().ok_or_else(|| "Nothing to redo".to_string())?;
+        set_midi(entry, edit.midi.clone())?;
+        entry.edits.push(edit);
+        Ok(entry.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{Control, Track};
+
+    fn note(start: u64, duration: u64, pitch: u8) -> Note {
+        Note { start, duration, pitch, velocity: 80 }
+    }
+
+    // A C major melody on channel 0 and a drum track, 480 ticks per beat in 4/4
+    fn piece(notes: Vec<Note>) -> Composition {
+        let mut composition = Composition::new(480);
+        composition.key_signatures.push(KeySignature { tick: 0, sharps: 0, minor: false });
+        composition.tracks.push(Track { name: None, channel: 0, program: 0, notes });
+        composition.tracks.push(Track { name: None, channel: DRUM_CHANNEL, program: 0, notes: vec![note(0, 120, 36)] });
+        composition
+    }
+
+    fn pitches(composition: &Composition) -> Vec<u8> {
+        composition.tracks[0].notes.iter().map(|note| note.pitch).collect()
+    }
+
+    #[test]
+    fn transpose_moves_pitched_notes_and_the_key_signature() {
+        let mut composition = piece(vec![note(0, 480, 60), note(480, 480, 127)]);
+        transpose(&mut composition, 2);
+        assert_eq!(pitches(&composition), vec![62, 127]);
+        assert_eq!(composition.tracks[1].notes[0].pitch, 36);
+        assert_eq!(composition.key_signatures[0].sharps, 2);
+    }
+
+    #[test]
+    fn transpose_to_the_relative_minor_keeps_the_notes() {
+        let mut composition = piece(vec![note(0, 480, 60), note(480, 480, 64)]);
+        transpose_to_key(&mut composition, "Am").unwrap();
+        assert_eq!(pitches(&composition), vec![60, 64]);
+        assert_eq!((composition.key_signatures[0].sharps, composition.key_signatures[0].minor), (0, true));
+    }
+
+    #[test]
+    fn transpose_to_key_takes_the_shortest_way() {
+        let mut composition = piece(vec![note(0, 480, 60)]);
+        transpose_to_key(&mut composition, "G").unwrap();
+        assert_eq!(pitches(&composition), vec![55]);
+        assert_eq!(composition.key_signatures[0].sharps, 1);
+
+        // C major to E minor goes through A minor, five semitones down
+        let mut composition = piece(vec![note(0, 480, 60)]);
+        transpose_to_key(&mut composition, "Em").unwrap();
+        assert_eq!(pitches(&composition), vec![55]);
+        assert_eq!((composition.key_signatures[0].sharps, composition.key_signatures[0].minor), (1, true));
+
+        assert!(transpose_to_key(&mut composition, "H").is_err());
+    }
+
+    #[test]
+    fn scale_tempo_divides_every_tempo() {
+        let mut composition = piece(vec![note(0, 480, 60)]);
+        scale_tempo(&mut composition, 2.0).unwrap();
+        assert_eq!(composition.tempos, vec![TempoChange { tick: 0, micros_per_beat: DEFAULT_TEMPO / 2 }]);
+        assert!(scale_tempo(&mut composition, 0.0).is_err());
+        assert!(scale_tempo(&mut composition, f64::NAN).is_err());
+    }
+
+    #[test]
+    fn trim_cuts_notes_and_carries_tempo_and_controls() {
+        let mut composition = piece(vec![note(0, 480, 60), note(1800, 480, 62), note(3840, 480, 64)]);
+        composition.tempos.push(TempoChange { tick: 960, micros_per_beat: 400_000 });
+        let sustain = |tick, value| ControlEvent { tick, channel: 0, control: Control::Controller { controller: 64, value } };
+        composition.controls = vec![
+            sustain(100, 127),
+            ControlEvent { tick: 500, channel: 0, control: Control::Controller { controller: 7, value: 90 } },
+            sustain(1000, 0),
+            sustain(2000, 127),
+        ];
+
+        trim(&mut composition, 2, 2).unwrap();
+        assert_eq!(composition.tracks.len(), 1);
+        assert_eq!(composition.tracks[0].notes, vec![note(0, 360, 62)]);
+        assert_eq!(composition.tempos, vec![TempoChange { tick: 0, micros_per_beat: 400_000 }]);
+        assert_eq!(
+            composition.controls,
+            vec![
+                ControlEvent { tick: 0, channel: 0, control: Control::Controller { controller: 7, value: 90 } },
+                sustain(0, 0),
+                sustain(80, 127),
+            ]
+        );
+        assert!(trim(&mut composition, 0, 1).is_err());
+        assert!(trim(&mut composition, 3, 2).is_err());
+    }
+
+    #[test]
+    fn quantize_snaps_to_the_grid_with_strength() {
+        let mut composition = piece(vec![note(130, 100, 60)]);
+        quantize(&mut composition, 16, 1.0).unwrap();
+        assert_eq!(composition.tracks[0].notes, vec![note(120, 120, 60)]);
+
+        let mut composition = piece(vec![note(130, 100, 60)]);
+        quantize(&mut composition, 16, 0.5).unwrap();
+        assert_eq!(composition.tracks[0].notes[0].start, 125);
+        assert!(quantize(&mut composition, 0, 1.0).is_err());
+    }
+
+    #[test]
+    fn scale_velocity_stays_in_range() {
+        let mut composition = piece(vec![note(0, 480, 60)]);
+        scale_velocity(&mut composition, 10.0).unwrap();
+        assert_eq!(composition.tracks[0].notes[0].velocity, 127);
+        scale_velocity(&mut composition, 0.0).unwrap();
+        assert_eq!(composition.tracks[0].notes[0].velocity, 1);
+        assert!(scale_velocity(&mut composition, -1.0).is_err());
+    }
+
+    #[test]
+    fn controls_survive_an_edit_round_trip() {
+        let mut composition = piece(vec![note(0, 480, 60), note(480, 480, 62)]);
+        composition.controls = vec![
+            ControlEvent { tick: 0, channel: 0, control: Control::Controller { controller: 64, value: 127 } },
+            ControlEvent { tick: 240, channel: 0, control: Control::PitchBend { value: 10000 } },
+            ControlEvent { tick: 960, channel: 0, control: Control::ChannelPressure { value: 40 } },
+        ];
+        transpose(&mut composition, 1);
+        let reloaded = Composition::from_bytes(&composition.to_bytes().unwrap()).unwrap();
+        assert_eq!(reloaded.controls, composition.controls);
+    }
+
+    // Reloads the written MIDI the way the next edit of an entry would
+    fn reloaded(composition: &Composition) -> Composition {
+        Composition::from_bytes(&composition.to_bytes().unwrap()).unwrap()
+    }
+
+    #[test]
+    fn note_edits_keep_the_reader_order() {
+        let mut composition = piece(vec![note(0, 480, 60), note(480, 480, 64), note(960, 480, 67)]);
+        add_note(&mut composition, 0, Note { velocity: 200, ..note(480, 240, 62) }).unwrap();
+        assert_eq!(pitches(&composition), vec![60, 62, 64, 67]);
+        assert_eq!(composition.tracks[0].notes[1].velocity, 127);
+
+        move_note(&mut composition, 0, 3, 0, 55).unwrap();
+        assert_eq!(pitches(&composition), vec![55, 60, 62, 64]);
+        resize_note(&mut composition, 0, 2, 960).unwrap();
+        assert_eq!(composition.tracks[0].notes[2], Note { velocity: 127, ..note(480, 960, 62) });
+        delete_note(&mut composition, 0, 0).unwrap();
+        assert_eq!(pitches(&composition), vec![60, 62, 64]);
+
+        // Indices shown after an edit still address the same notes once the file is read back
+        assert_eq!(reloaded(&composition).tracks, composition.tracks);
+    }
+
+    #[test]
+    fn note_edits_are_checked() {
+        let mut composition = piece(vec![note(0, 480, 60)]);
+        assert!(add_note(&mut composition, 0, note(0, 0, 60)).is_err());
+        assert!(add_note(&mut composition, 0, note(0, 480, 128)).is_err());
+        assert!(add_note(&mut composition, 2, note(0, 480, 60)).is_err());
+        assert!(move_note(&mut composition, 0, 1, 0, 60).is_err());
+        assert!(resize_note(&mut composition, 0, 0, 0).is_err());
+        assert!(delete_note(&mut composition, 0, 1).is_err());
+        // The track would disappear on reload and the drums would take its index
+        assert!(delete_note(&mut composition, 0, 0).is_err());
+        assert_eq!(reloaded(&composition).tracks.len(), 2);
+    }
+}
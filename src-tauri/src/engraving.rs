@@ -0,0 +1,850 @@
+use std::fmt::Write;
+use std::fs;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::SystemTime;
+use tauri::State;
+use crate::audio_player::{playback_position, AudioState};
+use crate::library::Library;
+use crate::midi::Composition;
+use crate::notation::{build, Beam, Clef, Event, Part, Score};
+use crate::theory::Key;
+
+// A4 in points, shared by the SVG view and the PDF export
+const PAGE_WIDTH: f64 = 595.0;
+const PAGE_HEIGHT: f64 = 842.0;
+const MARGIN: f64 = 48.0;
+const TITLE_HEIGHT: f64 = 48.0;
+// Distance between two staff lines, every other length derives from it
+const SPACE: f64 = 6.0;
+const STAFF_DISTANCE: f64 = 11.0 * SPACE;
+const SYSTEM_GAP: f64 = 8.0 * SPACE;
+const NAME_INDENT: f64 = 12.0 * SPACE;
+const STEM_LENGTH: f64 = 3.5 * SPACE;
+const HEAD_WIDTH: f64 = 0.65 * SPACE;
+const LINE_WIDTH: f64 = 0.12 * SPACE;
+// Diatonic step of the bottom staff line, E4 for the treble clef and G2 otherwise
+const TREBLE_BOTTOM: i32 = 30;
+const BASS_BOTTOM: i32 = 18;
+// Positions of key signature accidentals in the treble clef, the bass clef sits two octaves lower
+const SHARP_STEPS: [i32; 7] = [38, 35, 39, 36, 33, 37, 34];
+const FLAT_STEPS: [i32; 7] = [34, 37, 33, 36, 32, 35, 31];
+const HIGHLIGHT_COLOR: &str = "#fff3b0";
+
+#[derive(Debug, Clone, Copy)]
+enum Op {
+    Move(f64, f64),
+    Line(f64, f64),
+    Cubic(f64, f64, f64, f64, f64, f64),
+    Close,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Anchor {
+    Start,
+    Middle,
+}
+
+#[derive(Debug, Clone)]
+enum Shape {
+    // Filled when the width is zero, stroked otherwise
+    Path { ops: Vec<Op>, width: f64 },
+    Text { x: f64, y: f64, size: f64, text: String, bold: bool, anchor: Anchor },
+    Highlight { x: f64, y: f64, width: f64, height: f64, measure: usize, active: bool },
+}
+
+#[derive(Default)]
+struct Canvas {
+    shapes: Vec<Shape>,
+}
+
+impl Canvas {
+    fn line(&mut self, x1: f64, y1: f64, x2: f64, y2: f64, width: f64) {
+        self.shapes.push(Shape::Path { ops: vec![Op::Move(x1, y1), Op::Line(x2, y2)], width });
+    }
+
+    fn polygon(&mut self, points: &[(f64, f64)]) {
+        let mut ops: Vec<Op> = points.iter().enumerate().map(|(index, &(x, y))| if index == 0 { Op::Move(x, y) } else { Op::Line(x, y) }).collect();
+        ops.push(Op::Close);
+        self.shapes.push(Shape::Path { ops, width: 0.0 });
+    }
+
+    // Ellipse out of four cubic curves, rotated by `angle` degrees
+    fn ellipse(&mut self, cx: f64, cy: f64, rx: f64, ry: f64, angle: f64, width: f64) {
+        const K: f64 = 0.5523;
+        let (sin, cos) = angle.to_radians().sin_cos();
+        let at = |x: f64, y: f64| (cx + x * cos - y * sin, cy + x * sin + y * cos);
+        let quarters = [
+            [(rx, K * ry), (K * rx, ry), (0.0, ry)],
+            [(-K * rx, ry), (-rx, K * ry), (-rx, 0.0)],
+            [(-rx, -K * ry), (-K * rx, -ry), (0.0, -ry)],
+            [(K * rx, -ry), (rx, -K * ry), (rx, 0.0)],
+        ];
+        let (x, y) = at(rx, 0.0);
+        let mut ops = vec![Op::Move(x, y)];
+        for [c1, c2, end] in quarters {
+            let (c1, c2, end) = (at(c1.0, c1.1), at(c2.0, c2.1), at(end.0, end.1));
+            ops.push(Op::Cubic(c1.0, c1.1, c2.0, c2.1, end.0, end.1));
+        }
+        ops.push(Op::Close);
+        self.shapes.push(Shape::Path { ops, width });
+    }
+
+    // Path in staff spaces relative to an origin
+    fn glyph(&mut self, x: f64, y: f64, ops: &[Op], width: f64) {
+        let at = |dx: f64, dy: f64| (x + dx * SPACE, y + dy * SPACE);
+        let ops = ops
+            .iter()
+            .map(|op| match *op {
+                Op::Move(dx, dy) => {
+                    let (x, y) = at(dx, dy);
+                    Op::Move(x, y)
+                }
+                Op::Line(dx, dy) => {
+                    let (x, y) = at(dx, dy);
+                    Op::Line(x, y)
+                }
+                Op::Cubic(x1, y1, x2, y2, dx, dy) => {
+                    let ((x1, y1), (x2, y2), (x, y)) = (at(x1, y1), at(x2, y2), at(dx, dy));
+                    Op::Cubic(x1, y1, x2, y2, x, y)
+                }
+                Op::Close => Op::Close,
+            })
+            .collect();
+        self.shapes.push(Shape::Path { ops, width: width * SPACE });
+    }
+
+    fn text(&mut self, x: f64, y: f64, size: f64, text: &str, bold: bool, anchor: Anchor) {
+        self.shapes.push(Shape::Text { x, y, size, text: text.to_string(), bold, anchor });
+    }
+}
+
+// Stylized treble clef around the G line
+fn treble_clef(canvas: &mut Canvas, x: f64, g_line: f64) {
+    canvas.glyph(
+        x + 1.2 * SPACE,
+        g_line,
+        &[
+            Op::Move(-0.3, 3.3),
+            Op::Cubic(-1.0, 3.4, -1.0, 2.4, -0.3, 2.4),
+            Op::Cubic(0.4, 2.4, 0.3, 3.4, 0.2, 3.8),
+            Op::Line(0.2, 3.8),
+            Op::Cubic(0.1, 1.0, 0.0, -2.0, 0.2, -4.2),
+            Op::Cubic(0.4, -5.6, 1.4, -5.0, 0.8, -3.6),
+            Op::Cubic(0.2, -2.4, -1.4, -1.4, -1.2, 0.2),
+            Op::Cubic(-1.0, 1.6, 1.3, 1.6, 1.3, 0.2),
+            Op::Cubic(1.3, -0.9, -0.2, -1.0, -0.2, 0.0),
+        ],
+        0.2,
+    );
+}
+
+// Bass clef with its dot on the F line
+fn bass_clef(canvas: &mut Canvas, x: f64, f_line: f64) {
+    let x = x + 0.6 * SPACE;
+    canvas.ellipse(x, f_line, 0.4 * SPACE, 0.4 * SPACE, 0.0, 0.0);
+    canvas.glyph(x, f_line, &[Op::Move(-0.3, 0.0), Op::Cubic(-0.3, -1.4, 2.2, -1.4, 2.0, 0.3), Op::Cubic(1.9, 1.6, 0.8, 2.4, -0.4, 2.9)], 0.25);
+    canvas.ellipse(x + 2.6 * SPACE, f_line - 0.5 * SPACE, 0.2 * SPACE, 0.2 * SPACE, 0.0, 0.0);
+    canvas.ellipse(x + 2.6 * SPACE, f_line + 0.5 * SPACE, 0.2 * SPACE, 0.2 * SPACE, 0.0, 0.0);
+}
+
+fn accidental(canvas: &mut Canvas, x: f64, y: f64, alter: i32) {
+    match alter {
+        1 => {
+            canvas.line(x - 0.3 * SPACE, y - 1.3 * SPACE, x - 0.3 * SPACE, y + 1.4 * SPACE, LINE_WIDTH);
+            canvas.line(x + 0.3 * SPACE, y - 1.4 * SPACE, x + 0.3 * SPACE, y + 1.3 * SPACE, LINE_WIDTH);
+            canvas.line(x - 0.6 * SPACE, y - 0.3 * SPACE, x + 0.6 * SPACE, y - 0.6 * SPACE, 0.3 * SPACE);
+            canvas.line(x - 0.6 * SPACE, y + 0.6 * SPACE, x + 0.6 * SPACE, y + 0.3 * SPACE, 0.3 * SPACE);
+        }
+        2 => {
+            canvas.line(x - 0.4 * SPACE, y - 0.4 * SPACE, x + 0.4 * SPACE, y + 0.4 * SPACE, 0.2 * SPACE);
+            canvas.line(x - 0.4 * SPACE, y + 0.4 * SPACE, x + 0.4 * SPACE, y - 0.4 * SPACE, 0.2 * SPACE);
+        }
+        -1 | -2 => {
+            for offset in 0..alter.unsigned_abs() {
+                let x = x - offset as f64 * 0.9 * SPACE;
+                canvas.line(x - 0.3 * SPACE, y - 2.0 * SPACE, x - 0.3 * SPACE, y + 0.5 * SPACE, LINE_WIDTH);
+                canvas.glyph(x, y, &[Op::Move(-0.3, 0.0), Op::Cubic(0.2, -0.7, 0.9, -0.3, -0.3, 0.5)], 0.18);
+            }
+        }
+        _ => {
+            canvas.line(x - 0.3 * SPACE, y - 1.5 * SPACE, x - 0.3 * SPACE, y + 0.5 * SPACE, LINE_WIDTH);
+            canvas.line(x + 0.3 * SPACE, y - 0.5 * SPACE, x + 0.3 * SPACE, y + 1.5 * SPACE, LINE_WIDTH);
+            canvas.line(x - 0.3 * SPACE, y - 0.3 * SPACE, x + 0.3 * SPACE, y - 0.5 * SPACE, 0.3 * SPACE);
+            canvas.line(x - 0.3 * SPACE, y + 0.5 * SPACE, x + 0.3 * SPACE, y + 0.3 * SPACE, 0.3 * SPACE);
+        }
+    }
+}
+
+fn rest(canvas: &mut Canvas, x: f64, middle: f64, name: &str) {
+    match name {
+        "whole" => canvas.polygon(&[(x - 0.6 * SPACE, middle - SPACE), (x + 0.6 * SPACE, middle - SPACE), (x + 0.6 * SPACE, middle - 0.5 * SPACE), (x - 0.6 * SPACE, middle - 0.5 * SPACE)]),
+        "half" => canvas.polygon(&[(x - 0.6 * SPACE, middle - 0.5 * SPACE), (x + 0.6 * SPACE, middle - 0.5 * SPACE), (x + 0.6 * SPACE, middle), (x - 0.6 * SPACE, middle)]),
+        "quarter" => canvas.glyph(
+            x,
+            middle,
+            &[Op::Move(-0.3, -1.5), Op::Line(0.3, -0.7), Op::Line(-0.3, 0.1), Op::Line(0.3, 0.9), Op::Cubic(-0.4, 0.7, -0.5, 1.3, 0.0, 1.6)],
+            0.28,
+        ),
+        _ => {
+            let flags = if name == "eighth" { 1 } else { 2 };
+            canvas.line(x + 0.5 * SPACE, middle - 1.0 * SPACE, x - 0.2 * SPACE, middle + 1.3 * SPACE, LINE_WIDTH);
+            for flag in 0..flags {
+                let y = middle - 0.8 * SPACE + flag as f64 * SPACE;
+                let dx = -0.3 * flag as f64 * SPACE;
+                canvas.ellipse(x - 0.3 * SPACE + dx, y, 0.3 * SPACE, 0.3 * SPACE, 0.0, 0.0);
+                canvas.glyph(x + dx, y, &[Op::Move(-0.3, 0.1), Op::Cubic(0.0, 0.4, 0.4, 0.1, 0.6, -0.2)], 0.15);
+            }
+        }
+    }
+}
+
+fn staff_step(clef: Clef) -> i32 {
+    if clef == Clef::Treble { TREBLE_BOTTOM } else { BASS_BOTTOM }
+}
+
+fn key_width(key: &Key) -> f64 {
+    key.signature().unsigned_abs() as f64 * 1.1 * SPACE
+}
+
+// Horizontal layout of one measure: its onsets and the natural width each one needs
+struct Columns {
+    onsets: Vec<u64>,
+    widths: Vec<f64>,
+}
+
+impl Columns {
+    fn new(score: &Score, index: usize) -> Self {
+        let measure = &score.measures[index];
+        let events = || score.parts.iter().flat_map(move |part| part.measures[index].iter().flatten());
+        let mut onsets: Vec<u64> = events().map(|event| event.start).collect();
+        onsets.push(0);
+        onsets.sort();
+        onsets.dedup();
+        let sixteenth = (score.ticks_per_beat / 4).max(1) as f64;
+        let widths = onsets
+            .iter()
+            .enumerate()
+            .map(|(position, &onset)| {
+                let next = onsets.get(position + 1).copied().unwrap_or(measure.length);
+                let gap = next.saturating_sub(onset).max(1) as f64;
+                let at_onset = || events().filter(move |event| event.start == onset && !event.measure_rest);
+                let accidentals = at_onset().any(|event| event.pitches.iter().any(|pitch| pitch.accidental.is_some()));
+                let dots = at_onset().any(|event| event.value.dots > 0);
+                SPACE * (2.2 + 1.4 * (gap / sixteenth).log2().max(0.0)) + if accidentals { 1.4 * SPACE } else { 0.0 } + if dots { 0.6 * SPACE } else { 0.0 }
+            })
+            .collect();
+        Columns { onsets, widths }
+    }
+
+    fn width(&self) -> f64 {
+        SPACE + self.widths.iter().sum::<f64>()
+    }
+
+    // Offset of an onset from the start of the measure's notes, after stretching
+    fn x(&self, onset: u64, stretch: f64) -> f64 {
+        let position = self.onsets.iter().position(|&o| o >= onset).unwrap_or(self.onsets.len());
+        SPACE + self.widths[..position].iter().sum::<f64>() * stretch
+    }
+}
+
+struct Layout<'a> {
+    score: &'a Score,
+    canvas: Canvas,
+    pages: Vec<Canvas>,
+}
+
+impl Layout<'_> {
+    fn header_width(&self, index: usize, system_start: bool) -> f64 {
+        let measure = &self.score.measures[index];
+        let mut width = 0.0;
+        if system_start {
+            width += 4.0 * SPACE + key_width(&measure.key);
+        } else if measure.key_change {
+            width += key_width(&measure.key) + SPACE;
+        }
+        if measure.time_change {
+            width += 3.0 * SPACE;
+        }
+        width
+    }
+
+    fn key_signature(&mut self, x: f64, top: f64, clef: Clef, key: &Key) {
+        if clef == Clef::Percussion {
+            return;
+        }
+        let signature = key.signature();
+        let steps = if signature > 0 { SHARP_STEPS } else { FLAT_STEPS };
+        let offset = if clef == Clef::Treble { 0 } else { 14 };
+        let bottom = top + 4.0 * SPACE;
+        for (index, step) in steps.iter().take(signature.unsigned_abs() as usize).enumerate() {
+            let y = bottom - (step - offset - staff_step(clef)) as f64 * SPACE / 2.0;
+            accidental(&mut self.canvas, x + 0.6 * SPACE + index as f64 * 1.1 * SPACE, y, if signature > 0 { 1 } else { -1 });
+        }
+    }
+
+    fn time_signature(&mut self, x: f64, top: f64, time: (u8, u8)) {
+        let size = 2.9 * SPACE;
+        self.canvas.text(x + 1.2 * SPACE, top + 2.0 * SPACE, size, &time.0.to_string(), true, Anchor::Middle);
+        self.canvas.text(x + 1.2 * SPACE, top + 4.0 * SPACE, size, &time.1.to_string(), true, Anchor::Middle);
+    }
+
+    // Draws the clef, key and time at the start of a system or where they change
+    fn measure_header(&mut self, index: usize, x: f64, tops: &[f64], system_start: bool) {
+        let measure = self.score.measures[index].clone();
+        for (part, &top) in self.score.parts.iter().zip(tops) {
+            let mut x = x;
+            if system_start {
+                match part.clef {
+                    Clef::Treble => treble_clef(&mut self.canvas, x, top + 3.0 * SPACE),
+                    Clef::Bass => bass_clef(&mut self.canvas, x, top + SPACE),
+                    Clef::Percussion => {
+                        for dx in [0.8, 1.6] {
+                            self.canvas.line(x + dx * SPACE, top + SPACE, x + dx * SPACE, top + 3.0 * SPACE, 0.35 * SPACE);
+                        }
+                    }
+                }
+                x += 4.0 * SPACE;
+            }
+            if system_start || measure.key_change {
+                self.key_signature(x, top, part.clef, &measure.key);
+                x += key_width(&measure.key) + if system_start { 0.0 } else { SPACE };
+            }
+            if measure.time_change {
+                self.time_signature(x, top, measure.time);
+            }
+        }
+    }
+
+    // Notes, rests, stems, beams and ties of one voice in one measure
+    fn voice(&mut self, part: &Part, events: &[Event], top: f64, x_of: &dyn Fn(u64) -> f64, direction: Option<bool>, measure_end: f64) {
+        let bottom = top + 4.0 * SPACE;
+        let middle = top + 2.0 * SPACE;
+        let reference = staff_step(part.clef);
+        let y_of = |step: i32| bottom - (step - reference) as f64 * SPACE / 2.0;
+        let steps = |event: &Event| -> Vec<i32> {
+            event
+                .pitches
+                .iter()
+                .map(|pitch| pitch.octave * 7 + "CDEFGAB".find(pitch.step).unwrap_or(0) as i32)
+                .collect()
+        };
+        // Stems point up when the notes sit low, or as the voice dictates when voices share the staff
+        let up_for = |event_steps: &[i32]| {
+            direction.unwrap_or_else(|| {
+                let average = event_steps.iter().sum::<i32>() as f64 / event_steps.len().max(1) as f64;
+                average < (reference + 4) as f64
+            })
+        };
+        let shift = match direction {
+            Some(true) => -2.0 * SPACE,
+            Some(false) => 2.0 * SPACE,
+            None => 0.0,
+        };
+
+        // Beam groups decide their stem direction and beam height together
+        let mut beam_ends: Vec<Option<(bool, f64)>> = vec![None; events.len()];
+        let mut index = 0;
+        while index < events.len() {
+            if events[index].beam != Some(Beam::Begin) {
+                index += 1;
+                continue;
+            }
+            let end = (index..events.len()).find(|&i| events[i].beam == Some(Beam::End)).unwrap_or(events.len() - 1);
+            let group: Vec<i32> = events[index..=end].iter().flat_map(steps).collect();
+            let up = up_for(&group);
+            let ys: Vec<f64> = group.iter().map(|&step| y_of(step)).collect();
+            let beam_y = if up {
+                ys.iter().cloned().fold(f64::MAX, f64::min).min(middle) - STEM_LENGTH
+            } else {
+                ys.iter().cloned().fold(f64::MIN, f64::max).max(middle) + STEM_LENGTH
+            };
+            for slot in beam_ends.iter_mut().take(end + 1).skip(index) {
+                *slot = Some((up, beam_y));
+            }
+            let stem_x = |event: &Event| x_of(event.start) + if up { HEAD_WIDTH } else { -HEAD_WIDTH };
+            let thickness = if up { 0.5 * SPACE } else { -0.5 * SPACE };
+            let (first, last) = (stem_x(&events[index]), stem_x(&events[end]));
+            self.canvas.polygon(&[(first, beam_y), (last, beam_y), (last, beam_y + thickness), (first, beam_y + thickness)]);
+            // Sixteenths get a second beam between neighbours, or a stub when alone
+            let secondary = beam_y + thickness * 1.6;
+            for position in index..=end {
+                if events[position].value.name != "16th" {
+                    continue;
+                }
+                let x = stem_x(&events[position]);
+                let next_is_16th = position < end && events[position + 1].value.name == "16th";
+                let previous_is_16th = position > index && events[position - 1].value.name == "16th";
+                let (from, to) = if next_is_16th {
+                    (x, stem_x(&events[position + 1]))
+                } else if previous_is_16th {
+                    continue;
+                } else if position == end {
+                    (x - 1.2 * SPACE, x)
+                } else {
+                    (x, x + 1.2 * SPACE)
+                };
+                self.canvas.polygon(&[(from, secondary), (to, secondary), (to, secondary + thickness), (from, secondary + thickness)]);
+            }
+            index = end + 1;
+        }
+
+        for (position, event) in events.iter().enumerate() {
+            let x = x_of(event.start);
+            if event.pitches.is_empty() {
+                if event.measure_rest {
+                    rest(&mut self.canvas, (x_of(0) + measure_end) / 2.0, middle + shift, "whole");
+                } else {
+                    rest(&mut self.canvas, x, middle + shift, event.value.name);
+                }
+                continue;
+            }
+            let event_steps = steps(event);
+            let ys: Vec<f64> = event_steps.iter().map(|&step| y_of(step)).collect();
+            let whole = event.value.name == "whole";
+            let filled = !matches!(event.value.name, "whole" | "half");
+            for (pitch, &y) in event.pitches.iter().zip(&ys) {
+                // Ledger lines above and below the staff
+                let mut ledger = top - SPACE;
+                while ledger >= y - 0.1 {
+                    self.canvas.line(x - 1.1 * HEAD_WIDTH * 1.5, ledger, x + 1.1 * HEAD_WIDTH * 1.5, ledger, LINE_WIDTH);
+                    ledger -= SPACE;
+                }
+                let mut ledger = bottom + SPACE;
+                while ledger <= y + 0.1 {
+                    self.canvas.line(x - 1.1 * HEAD_WIDTH * 1.5, ledger, x + 1.1 * HEAD_WIDTH * 1.5, ledger, LINE_WIDTH);
+                    ledger += SPACE;
+                }
+                if whole {
+                    self.canvas.ellipse(x, y, 0.8 * SPACE, 0.5 * SPACE, 0.0, 0.2 * SPACE);
+                } else if filled {
+                    self.canvas.ellipse(x, y, HEAD_WIDTH, 0.45 * SPACE, -20.0, 0.0);
+                } else {
+                    self.canvas.ellipse(x, y, HEAD_WIDTH, 0.45 * SPACE, -20.0, 0.15 * SPACE);
+                }
+                if let Some(alter) = pitch.accidental {
+                    accidental(&mut self.canvas, x - 1.9 * SPACE, y, alter);
+                }
+                for dot in 0..event.value.dots {
+                    let on_line = ((bottom - y) / SPACE).fract().abs() < 0.25;
+                    let dot_y = if on_line { y - SPACE / 2.0 } else { y };
+                    self.canvas.ellipse(x + (1.3 + 0.6 * dot as f64) * SPACE, dot_y, 0.2 * SPACE, 0.2 * SPACE, 0.0, 0.0);
+                }
+            }
+
+            let up = beam_ends[position].map_or_else(|| up_for(&event_steps), |(up, _)| up);
+            if !whole {
+                let (highest, lowest) = (ys.iter().cloned().fold(f64::MAX, f64::min), ys.iter().cloned().fold(f64::MIN, f64::max));
+                let stem_x = x + if up { HEAD_WIDTH } else { -HEAD_WIDTH };
+                let (from, to) = match beam_ends[position] {
+                    Some((_, beam_y)) => (if up { lowest } else { highest }, beam_y),
+                    None if up => (lowest, highest - STEM_LENGTH),
+                    None => (highest, lowest + STEM_LENGTH),
+                };
+                self.canvas.line(stem_x, from, stem_x, to, LINE_WIDTH);
+                if beam_ends[position].is_none() {
+                    let flags = match event.value.name {
+                        "eighth" => 1,
+                        "16th" => 2,
+                        _ => 0,
+                    };
+                    let sign = if up { 1.0 } else { -1.0 };
+                    for flag in 0..flags {
+                        let y = to + sign * flag as f64 * 0.8 * SPACE;
+                        self.canvas.glyph(stem_x, y, &[Op::Move(0.0, 0.0), Op::Cubic(0.2, sign * 1.0, 1.4, sign * 1.2, 0.9, sign * 2.6)], 0.2);
+                    }
+                }
+            }
+
+            // Ties curve away from the stems towards the next note or the end of the measure
+            if event.tie_start {
+                let next_x = events.get(position + 1).map_or(measure_end + SPACE, |next| x_of(next.start));
+                let sign = if up { 1.0 } else { -1.0 };
+                for &y in &ys {
+                    let (from, to) = (x + HEAD_WIDTH + 0.3 * SPACE, next_x - HEAD_WIDTH - 0.3 * SPACE);
+                    let y = y + sign * 0.6 * SPACE;
+                    self.canvas.shapes.push(Shape::Path {
+                        ops: vec![Op::Move(from, y), Op::Cubic(from + (to - from) / 3.0, y + sign * SPACE, to - (to - from) / 3.0, y + sign * SPACE, to, y)],
+                        width: 0.15 * SPACE,
+                    });
+                }
+            }
+        }
+    }
+
+    fn new_page(&mut self) {
+        let page = std::mem::take(&mut self.canvas);
+        self.pages.push(page);
+    }
+
+    // Breaks the measures into systems that fill the line width and the systems into pages
+    fn run(mut self, highlight: Option<usize>) -> Vec<Canvas> {
+        let score = self.score;
+        let staves = score.parts.len().max(1);
+        let system_height = (staves - 1) as f64 * STAFF_DISTANCE + 4.0 * SPACE;
+        let indent = if score.parts.len() > 1 { NAME_INDENT } else { 0.0 };
+        self.canvas.text(PAGE_WIDTH / 2.0, MARGIN + 16.0, 18.0, &score.title, true, Anchor::Middle);
+        let columns: Vec<Columns> = (0..score.measures.len()).map(|index| Columns::new(score, index)).collect();
+
+        let mut top = MARGIN + TITLE_HEIGHT;
+        let mut index = 0;
+        let mut first_system = true;
+        while index < score.measures.len() {
+            let left = MARGIN + if first_system { indent } else { 0.0 };
+            let available = PAGE_WIDTH - MARGIN - left;
+            // Fill the system greedily, always taking at least one measure
+            let mut end = index;
+            let mut natural = 0.0;
+            let mut headers = 0.0;
+            while end < score.measures.len() {
+                let header = self.header_width(end, end == index);
+                let width = columns[end].width() + header;
+                if end > index && natural + headers + width > available {
+                    break;
+                }
+                natural += columns[end].width();
+                headers += header;
+                end += 1;
+            }
+            let last_system = end == score.measures.len();
+            let stretch = if last_system { 1.0f64.max((available - headers) / natural * 0.75).min((available - headers) / natural) } else { (available - headers) / natural };
+            let stretch = stretch.max(0.5);
+
+            if top + system_height > PAGE_HEIGHT - MARGIN {
+                self.new_page();
+                top = MARGIN;
+            }
+            let tops: Vec<f64> = (0..staves).map(|staff| top + staff as f64 * STAFF_DISTANCE).collect();
+            let right = left + headers + natural * stretch;
+
+            // Measure backgrounds come first so everything else is drawn on top
+            let mut x = left;
+            for (measure, measure_columns) in columns.iter().enumerate().take(end).skip(index) {
+                let width = self.header_width(measure, measure == index) + measure_columns.width() * stretch;
+                self.canvas.shapes.push(Shape::Highlight {
+                    x,
+                    y: top - 2.0 * SPACE,
+                    width,
+                    height: system_height + 4.0 * SPACE,
+                    measure,
+                    active: highlight == Some(measure),
+                });
+                x += width;
+            }
+
+            for &staff_top in &tops {
+                for line in 0..5 {
+                    let y = staff_top + line as f64 * SPACE;
+                    self.canvas.line(left, y, right, y, LINE_WIDTH);
+                }
+            }
+            if first_system && score.parts.len() > 1 {
+                for (part, &staff_top) in score.parts.iter().zip(&tops) {
+                    let name: String = part.name.chars().take(14).collect();
+                    self.canvas.text(MARGIN, staff_top + 2.5 * SPACE, 8.0, &name, false, Anchor::Start);
+                }
+            }
+            self.canvas.line(left, top, left, top + system_height, LINE_WIDTH);
+            self.canvas.text(left, top - 1.5 * SPACE, 7.0, &(index + 1).to_string(), false, Anchor::Start);
+
+            let mut x = left;
+            for (measure, measure_columns) in columns.iter().enumerate().take(end).skip(index) {
+                let header = self.header_width(measure, measure == index);
+                self.measure_header(measure, x, &tops, measure == index);
+                let notes_x = x + header;
+                let measure_end = notes_x + measure_columns.width() * stretch;
+                let x_of = |onset: u64| notes_x + measure_columns.x(onset, stretch);
+                for (part, &staff_top) in score.parts.iter().zip(&tops) {
+                    let voices = &part.measures[measure];
+                    let shared = voices.iter().filter(|events| !events.is_empty()).count() > 1;
+                    for (number, events) in voices.iter().enumerate() {
+                        let direction = shared.then_some(number == 0);
+                        self.voice(part, events, staff_top, &x_of, direction, measure_end);
+                    }
+                    let width = if last_system && measure + 1 == end { 0.5 * SPACE } else { LINE_WIDTH };
+                    self.canvas.line(measure_end, staff_top, measure_end, staff_top + 4.0 * SPACE, width);
+                }
+                x = measure_end;
+            }
+
+            top += system_height + SYSTEM_GAP;
+            index = end;
+            first_system = false;
+        }
+        self.new_page();
+        self.pages
+    }
+}
+
+fn engrave(score: &Score, highlight: Option<usize>) -> Vec<Canvas> {
+    Layout { score, canvas: Canvas::default(), pages: Vec::new() }.run(highlight)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn svg_path(ops: &[Op]) -> String {
+    let mut d = String::new();
+    for op in ops {
+        let _ = match *op {
+            Op::Move(x, y) => write!(d, "M{:.2} {:.2}", x, y),
+            Op::Line(x, y) => write!(d, "L{:.2} {:.2}", x, y),
+            Op::Cubic(x1, y1, x2, y2, x, y) => write!(d, "C{:.2} {:.2} {:.2} {:.2} {:.2} {:.2}", x1, y1, x2, y2, x, y),
+            Op::Close => write!(d, "Z"),
+        };
+    }
+    d
+}
+
+// All pages stacked into one SVG; every measure has a background rectangle that the
+// `active` class lights up
+pub fn render_svg(score: &Score, highlight: Option<usize>) -> String {
+    let pages = engrave(score, highlight);
+    let height = PAGE_HEIGHT * pages.len() as f64;
+    let mut out = String::new();
+    let _ = writeln!(out, "<svg xmlns=\"http://www.w3.org/2000/svg\" class=\"score\" viewBox=\"0 0 {} {}\" width=\"{}\" height=\"{}\">", PAGE_WIDTH, height, PAGE_WIDTH, height);
+    let _ = writeln!(out, "<style>.measure-highlight.active {{ fill: {}; }}</style>", HIGHLIGHT_COLOR);
+    for (number, page) in pages.iter().enumerate() {
+        let _ = writeln!(out, "<g transform=\"translate(0 {})\">", number as f64 * PAGE_HEIGHT);
+        let _ = writeln!(out, "<rect x=\"0\" y=\"0\" width=\"{}\" height=\"{}\" fill=\"white\" stroke=\"#ddd\"/>", PAGE_WIDTH, PAGE_HEIGHT);
+        for shape in &page.shapes {
+            let _ = match shape {
+                Shape::Path { ops, width } if *width == 0.0 => writeln!(out, "<path d=\"{}\" fill=\"black\"/>", svg_path(ops)),
+                Shape::Path { ops, width } => {
+                    writeln!(out, "<path d=\"{}\" fill=\"none\" stroke=\"black\" stroke-width=\"{:.2}\" stroke-linecap=\"round\"/>", svg_path(ops), width)
+                }
+                Shape::Text { x, y, size, text, bold, anchor } => writeln!(
+                    out,
+                    "<text x=\"{:.2}\" y=\"{:.2}\" font-family=\"Helvetica, Arial, sans-serif\" font-size=\"{:.2}\" font-weight=\"{}\" text-anchor=\"{}\">{}</text>",
+                    x,
+                    y,
+                    size,
+                    if *bold { "bold" } else { "normal" },
+                    if *anchor == Anchor::Middle { "middle" } else { "start" },
+                    escape(text)
+                ),
+                Shape::Highlight { x, y, width, height, measure, active } => writeln!(
+                    out,
+                    "<rect class=\"measure-highlight{}\" data-measure=\"{}\" x=\"{:.2}\" y=\"{:.2}\" width=\"{:.2}\" height=\"{:.2}\" fill=\"none\"/>",
+                    if *active { " active" } else { "" },
+                    measure,
+                    x,
+                    y,
+                    width,
+                    height
+                ),
+            };
+        }
+        out.push_str("</g>\n");
+    }
+    out.push_str("</svg>\n");
+    out
+}
+
+// PDF strings are Latin-1 here, anything else becomes a question mark
+fn pdf_text(text: &str) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    for c in text.chars() {
+        match c {
+            '(' | ')' | '\\' => bytes.extend([b'\\', c as u8]),
+            c if (c as u32) < 256 => bytes.push(c as u32 as u8),
+            _ => bytes.push(b'?'),
+        }
+    }
+    bytes
+}
+
+fn pdf_content(page: &Canvas) -> Vec<u8> {
+    let mut out = String::new();
+    // Flip the y axis so the layout's top-down coordinates can be used as they are
+    let _ = writeln!(out, "1 0 0 -1 0 {} cm 1 J 1 j 0 g 0 G", PAGE_HEIGHT);
+    let mut bytes = Vec::new();
+    for shape in &page.shapes {
+        match shape {
+            Shape::Path { ops, width } => {
+                for op in ops {
+                    let _ = match *op {
+                        Op::Move(x, y) => writeln!(out, "{:.2} {:.2} m", x, y),
+                        Op::Line(x, y) => writeln!(out, "{:.2} {:.2} l", x, y),
+                        Op::Cubic(x1, y1, x2, y2, x, y) => writeln!(out, "{:.2} {:.2} {:.2} {:.2} {:.2} {:.2} c", x1, y1, x2, y2, x, y),
+                        Op::Close => writeln!(out, "h"),
+                    };
+                }
+                let _ = if *width == 0.0 { writeln!(out, "f") } else { writeln!(out, "{:.2} w S", width) };
+            }
+            Shape::Text { x, y, size, text, bold, anchor } => {
+                // Helvetica averages about half an em per character
+                let width = if *anchor == Anchor::Middle { text.chars().count() as f64 * size * 0.55 } else { 0.0 };
+                let _ = write!(out, "BT /{} {:.2} Tf 1 0 0 -1 {:.2} {:.2} Tm (", if *bold { "F2" } else { "F1" }, size, x - width / 2.0, y);
+                bytes.extend(out.as_bytes());
+                bytes.extend(pdf_text(text));
+                out.clear();
+                let _ = writeln!(out, ") Tj ET");
+            }
+            Shape::Highlight { x, y, width, height, active: true, .. } => {
+                let _ = writeln!(out, "1 0.95 0.69 rg {:.2} {:.2} {:.2} {:.2} re f 0 g", x, y, width, height);
+            }
+            Shape::Highlight { .. } => {}
+        }
+    }
+    bytes.extend(out.as_bytes());
+    bytes
+}
+
+// A minimal PDF 1.4 document with the two standard Helvetica fonts and one content stream per page
+pub fn render_pdf(score: &Score) -> Vec<u8> {
+    let pages = engrave(score, None);
+    let mut objects: Vec<Vec<u8>> = Vec::new();
+    let first_page = 5;
+    let kids: Vec<String> = (0..pages.len()).map(|page| format!("{} 0 R", first_page + page * 2)).collect();
+    objects.push(b"<< /Type /Catalog /Pages 2 0 R >>".to_vec());
+    objects.push(format!("<< /Type /Pages /Kids [{}] /Count {} >>", kids.join(" "), pages.len()).into_bytes());
+    objects.push(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica /Encoding /WinAnsiEncoding >>".to_vec());
+    objects.push(b"<< /Type /Font /Subtype /Type1 /BaseFont /Helvetica-Bold /Encoding /WinAnsiEncoding >>".to_vec());
+    for (page, canvas) in pages.iter().enumerate() {
+        let content = first_page + page * 2 + 1;
+        objects.push(
+            format!(
+                "<< /Type /Page /Parent 2 0 R /MediaBox [0 0 {} {}] /Resources << /Font << /F1 3 0 R /F2 4 0 R >> >> /Contents {} 0 R >>",
+                PAGE_WIDTH, PAGE_HEIGHT, content
+            )
+            .into_bytes(),
+        );
+        let stream = pdf_content(canvas);
+        let mut object = format!("<< /Length {} >>\nstream\n", stream.len()).into_bytes();
+        object.extend(stream);
+        object.extend(b"\nendstream");
+        objects.push(object);
+    }
+
+    let mut out = b"%PDF-1.4\n".to_vec();
+    let mut offsets = Vec::new();
+    for (index, object) in objects.iter().enumerate() {
+        offsets.push(out.len());
+        out.extend(format!("{} 0 obj\n", index + 1).as_bytes());
+        out.extend(object);
+        out.extend(b"\nendobj\n");
+    }
+    let xref = out.len();
+    out.extend(format!("xref\n0 {}\n0000000000 65535 f \n", objects.len() + 1).as_bytes());
+    for offset in offsets {
+        out.extend(format!("{:010} 00000 n \n", offset).as_bytes());
+    }
+    out.extend(format!("trailer\n<< /Size {} /Root 1 0 R >>\nstartxref\n{}\n%%EOF\n", objects.len() + 1, xref).as_bytes());
+    out
+}
+
+fn entry_score(track: &str) -> Result<(Composition, Score), String> {
+    let entry = Library::read()?.get(track)?.clone();
+    let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to engrave", entry.title))?;
+    let composition = Composition::from_file(&midi)?;
+    let score = build(&composition, &entry.title);
+    Ok((composition, score))
+}
+
+// Score of a library entry as SVG, with the given measure lit up
+#[tauri::command]
+pub async fn render_score(track: String, highlight: Option<usize>) -> Result<String, String> {
+    let (_, score) = entry_score(&track)?;
+    Ok(render_svg(&score, highlight))
+}
+
+// The MIDI a score follows playback of, kept between polls while the file stays the same
+struct FollowedScore {
+    midi: PathBuf,
+    modified: Option<SystemTime>,
+    composition: Composition,
+    measures: usize,
+}
+
+lazy_static::lazy_static! {
+    static ref FOLLOWED: Mutex<Option<FollowedScore>> = Mutex::new(None);
+}
+
+// Zero-based measure of the entry at the player's position, to follow playback in the score
+#[tauri::command]
+pub fn current_measure(state: State<AudioState>, track: String) -> Result<usize, String> {
+    let entry = Library::read()?.get(&track)?.clone();
+    let midi = entry.midi.ok_or_else(|| format!("{} has no MIDI to engrave", entry.title))?;
+    let modified = fs::metadata(&midi).and_then(|metadata| metadata.modified()).ok();
+    let mut followed = FOLLOWED.lock().map_err(|e| e.to_string())?;
+    if followed.as_ref().is_none_or(|followed| followed.midi != midi || followed.modified != modified) {
+        let composition = Composition::from_file(&midi)?;
+        let measures = build(&composition, &entry.title).measures.len();
+        *followed = Some(FollowedScore { midi, modified, composition, measures });
+    }
+    let followed = followed.as_ref().ok_or_else(|| "No score is followed".to_string())?;
+    let tick = followed.composition.seconds_to_tick(playback_position(state));
+    let bar = followed.composition.tick_to_bar(tick) as usize;
+    Ok(bar.min(followed.measures.saturating_sub(1)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{Note, Track, DRUM_CHANNEL};
+
+    #[test]
+    fn scores_engrave_to_svg() {
+        let mut composition = Composition::new(480);
+        let melody = (0..40).map(|index| Note { start: index * 240, duration: 240, pitch: 60 + (index % 13) as u8, velocity: 80 }).collect();
+        let bass = (0..10).map(|index| Note { start: index * 960, duration: 960, pitch: 36 + index as u8, velocity: 80 }).collect();
+        let drums = (0..20).map(|index| Note { start: index * 480, duration: 120, pitch: 36, velocity: 90 }).collect();
+        composition.tracks.push(Track { name: Some("Lead <1>".to_string()), channel: 0, program: 0, notes: melody });
+        composition.tracks.push(Track { name: None, channel: 1, program: 32, notes: bass });
+        composition.tracks.push(Track { name: None, channel: DRUM_CHANNEL, program: 0, notes: drums });
+        let score = build(&composition, "Smoke & Mirrors");
+        let svg = render_svg(&score, Some(2));
+
+        assert!(svg.starts_with("<svg xmlns=\"http://www.w3.org/2000/svg\""));
+        assert!(svg.trim_end().ends_with("</svg>"));
+        assert_eq!(svg.matches("<g ").count(), svg.matches("</g>").count());
+        assert!(svg.contains("Smoke &amp; Mirrors"));
+        assert!(!svg.contains("NaN") && !svg.contains("inf"));
+        // Every measure has one highlight per system it is drawn in, only the asked one is lit
+        let highlighted: Vec<&str> = svg.lines().filter(|line| line.contains("measure-highlight")).collect();
+        assert!(highlighted.len() >= score.measures.len());
+        assert!(highlighted.iter().filter(|line| line.contains(" active")).all(|line| line.contains("data-measure=\"2\"")));
+        assert!(highlighted.iter().any(|line| line.contains(" active")));
+    }
+
+    // Measure numbers start every system, one text per system
+    fn systems(page: &Canvas) -> usize {
+        page.shapes.iter().filter(|shape| matches!(shape, Shape::Text { size, .. } if *size == 7.0)).count()
+    }
+
+    #[test]
+    fn scores_engrave_to_pdf() {
+        let mut composition = Composition::new(480);
+        for channel in 0..3u8 {
+            let notes = (0..400).map(|index| Note { start: index * 240, duration: 240, pitch: 48 + channel * 12 + (index % 7) as u8, velocity: 80 }).collect();
+            composition.tracks.push(Track { name: None, channel, program: 0, notes });
+        }
+        let score = build(&composition, "Long Piece");
+        let pages = engrave(&score, None);
+        // Three staves per system leave room for three systems on every page
+        let per_page: Vec<usize> = pages.iter().map(systems).collect();
+        assert!(pages.len() > 2, "{:?}", per_page);
+        assert!(per_page[..per_page.len() - 1].iter().all(|&count| count == 3), "{:?}", per_page);
+        assert!((1..=3).contains(per_page.last().unwrap()));
+
+        let pdf = render_pdf(&score);
+        let text = String::from_utf8_lossy(&pdf);
+        assert!(pdf.starts_with(b"%PDF-"));
+        assert!(pdf.ends_with(b"%%EOF\n"));
+        assert!(text.contains(&format!("/Count {} >>", pages.len())));
+        assert_eq!(text.matches("/Type /Page ").count(), pages.len());
+
+        // The cross-reference table and startxref point at the objects and the table
+        let startxref: usize = text.rsplit("startxref\n").next().unwrap().lines().next().unwrap().parse().unwrap();
+        assert!(pdf[startxref..].starts_with(b"xref\n"));
+        let mut lines = text[startxref..].lines().skip(1);
+        let count: usize = lines.next().unwrap().split(' ').nth(1).unwrap().parse().unwrap();
+        assert_eq!(count, 4 + 2 * pages.len() + 1);
+        for (number, line) in lines.skip(1).take(count - 1).enumerate() {
+            let offset: usize = line[..10].parse().unwrap();
+            assert!(pdf[offset..].starts_with(format!("{} 0 obj\n", number + 1).as_bytes()), "object {}", number + 1);
+        }
+    }
+}
@@ -0,0 +1,253 @@
+use std::path::PathBuf;
+use serde::{Deserialize, Serialize};
+use crate::library::{Library, LibraryEntry};
+use crate::midi::Composition;
+
+const DEFAULT_GROOVE_GRID: u32 = 16;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct HumanizeSettings {
+    pub enabled: bool,
+    // Maximum random shift of note starts
+    pub timing_jitter_ms: f64,
+    // Maximum random change of note velocities
+    pub velocity_variation: u8,
+    // Position of the off-beat within a pair of grid steps, 50 is straight and 66 a triplet feel
+    pub swing: f64,
+    // Grid the swing applies to as a note value, e.g. 8 for eighth notes
+    pub swing_grid: u32,
+    pub groove: Option<GrooveTemplate>,
+    pub groove_strength: f64,
+    pub seed: u64,
+    // Indices of composition tracks left as written
+    pub disabled_tracks: Vec<usize>,
+}
+
+impl Default for HumanizeSettings {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timing_jitter_ms: 10.0,
+            velocity_variation: 8,
+            swing: 50.0,
+            swing_grid: 8,
+            groove: None,
+            groove_strength: 1.0,
+            seed: 1,
+            disabled_tracks: Vec::new(),
+        }
+    }
+}
+
+// Average timing offset (in grid steps) and velocity factor for each step of a bar-long grid
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GrooveTemplate {
+    // Grid as a note value, e.g. 16 for sixteenth notes
+    pub grid: u32,
+    pub timing: Vec<f64>,
+    pub velocity: Vec<f64>,
+}
+
+// Small deterministic generator (SplitMix64) so a seed always yields the same performance
+pub struct SeededRng(u64);
+
+impl SeededRng {
+    pub fn new(seed: u64) -> Self {
+        SeededRng(seed)
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Uniform value in [-1, 1]
+    pub fn next_signed(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 52) as f64 - 1.0
+    }
+}
+
+fn grid_step(composition: &Composition, grid: u32) -> u64 {
+    (composition.ticks_per_beat as u64 * 4 / grid.max(1) as u64).max(1)
+}
+
+pub fn extract_groove(composition: &Composition, grid: u32) -> Result<GrooveTemplate, String> {
+    let step = grid_step(composition, grid);
+    let steps = (composition.ticks_per_bar(0) / step).max(1) as usize;
+    let mut offsets = vec![0.0; steps];
+    let mut velocities = vec![0.0; steps];
+    let mut counts = vec![0u32; steps];
+
+    let notes: Vec<_> = composition.tracks.iter().flat_map(|track| track.notes.iter()).collect();
+    if notes.is_empty() {
+        return Err("The MIDI file contains no notes to extract a groove from".to_string());
+    }
+    for note in &notes {
+        let nearest = (note.start as f64 / step as f64).round();
+        let position = nearest as usize % steps;
+        offsets[position] += (note.start as f64 - nearest * step as f64) / step as f64;
+        velocities[position] += note.velocity as f64;
+        counts[position] += 1;
+    }
+
+    let average_velocity = notes.iter().map(|note| note.velocity as f64).sum::<f64>() / notes.len() as f64;
+    let timing = offsets.iter().zip(&counts).map(|(offset, &count)| if count > 0 { offset / count as f64 } else { 0.0 }).collect();
+    let velocity = velocities
+        .iter()
+        .zip(&counts)
+        .map(|(velocity, &count)| if count > 0 { velocity / count as f64 / average_velocity } else { 1.0 })
+        .collect();
+    Ok(GrooveTemplate { grid, timing, velocity })
+}
+
+// Returns a humanized copy of the composition, the original is left untouched
+pub fn humanize(composition: &Composition, settings: &HumanizeSettings) -> Composition {
+    let mut result = composition.clone();
+    let swing_step = grid_step(composition, settings.swing_grid);
+    let swing_shift = 2.0 * swing_step as f64 * (settings.swing.clamp(50.0, 75.0) / 100.0) - swing_step as f64;
+
+    for (index, track) in result.tracks.iter_mut().enumerate() {
+        if settings.disabled_tracks.contains(&index) {
+            continue;
+        }
+        let mut rng = SeededRng::new(settings.seed ^ (index as u64).wrapping_mul(0x1000_0000_01B3));
+        for note in track.notes.iter_mut() {
+            let mut offset = 0.0;
+            let mut velocity = note.velocity as f64;
+
+            // Swing only moves notes sitting on the off-beat of the swing grid
+            let position = note.start as f64 / swing_step as f64;
+            if (position - position.round()).abs() < 0.25 && position.round() as u64 % 2 == 1 {
+                offset += swing_shift;
+            }
+
+            if let Some(groove) = settings.groove.as_ref().filter(|groove| !groove.timing.is_empty()) {
+                let step = grid_step(composition, groove.grid);
+                let slot = (note.start as f64 / step as f64).round() as usize % groove.timing.len();
+                offset += groove.timing[slot] * step as f64 * settings.groove_strength;
+                let factor = groove.velocity.get(slot).copied().unwrap_or(1.0);
+                velocity *= 1.0 + (factor - 1.0) * settings.groove_strength;
+            }
+
+            let ticks_per_ms = composition.ticks_per_beat as f64 * 1000.0 / composition.tempo_at(note.start).max(1) as f64;
+            offset += rng.next_signed() * settings.timing_jitter_ms * ticks_per_ms;
+            velocity += rng.next_signed() * settings.velocity_variation as f64;
+
+            let start = (note.start as f64 + offset).round().max(0.0) as u64;
+            // The note end stays put so moved notes do not overlap their successors
+            note.duration = note.end().saturating_sub(start).max(1);
+            note.start = start;
+            note.velocity = velocity.round().clamp(1.0, 127.0) as u8;
+        }
+        track.notes.sort_by_key(|note| (note.start, note.pitch));
+    }
+    result
+}
+
+#[tauri::command]
+pub async fn set_humanize(track: String, settings: Option<HumanizeSettings>) -> Result<LibraryEntry, String> {
+    Library::update(move |library| {
+        let entry = library.get_mut(&track)?;
+        entry.humanize = settings;
+        entry.render_stale = true;
+        Ok(entry.clone())
+    })
+}
+
+#[tauri::command]
+pub async fn extract_groove_template(path: String, grid: Option<u32>) -> Result<GrooveTemplate, String> {
+    let composition = Composition::from_file(&PathBuf::from(path))?;
+    extract_groove(&composition, grid.unwrap_or(DEFAULT_GROOVE_GRID))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::midi::{Note, Track};
+
+    fn note(start: u64, duration: u64, pitch: u8, velocity: u8) -> Note {
+        Note { start, duration, pitch, velocity }
+    }
+
+    fn composition(notes: Vec<Note>) -> Composition {
+        let mut composition = Composition::new(480);
+        composition.tracks.push(Track { name: None, channel: 0, program: 0, notes });
+        composition
+    }
+
+    fn plain() -> HumanizeSettings {
+        HumanizeSettings { timing_jitter_ms: 0.0, velocity_variation: 0, ..HumanizeSettings::default() }
+    }
+
+    #[test]
+    fn seed_decides_the_sequence() {
+        let draw = |seed| {
+            let mut rng = SeededRng::new(seed);
+            (0..8).map(|_| rng.next_u64()).collect::<Vec<_>>()
+        };
+        assert_eq!(draw(7), draw(7));
+        assert_ne!(draw(7), draw(8));
+        let mut rng = SeededRng::new(3);
+        assert!((0..1000).map(|_| rng.next_signed()).all(|value| (-1.0..=1.0).contains(&value)));
+    }
+
+    #[test]
+    fn swing_moves_only_off_beats() {
+        let eighths = composition((0..4).map(|index| note(index * 240, 240, 60, 80)).collect());
+        let swung = humanize(&eighths, &HumanizeSettings { swing: 66.0, ..plain() });
+        let starts: Vec<u64> = swung.tracks[0].notes.iter().map(|note| note.start).collect();
+        // 2 * 240 * 0.66 - 240 = 76.8 ticks later
+        assert_eq!(starts, vec![0, 317, 480, 797]);
+        // Moved notes keep their end
+        assert_eq!(swung.tracks[0].notes[1].end(), 480);
+        assert_eq!(humanize(&eighths, &plain()), eighths);
+    }
+
+    #[test]
+    fn groove_is_averaged_per_grid_step() {
+        let dir = std::env::temp_dir().join(format!("humanize-test-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("groove.mid");
+        // Sixteenths are 120 ticks: the second step is played late in both bars, the third early
+        let notes = vec![note(0, 60, 60, 100), note(130, 60, 60, 80), note(228, 60, 60, 80), note(480, 60, 60, 60), note(2050, 60, 60, 80)];
+        composition(notes).write_file(&path).unwrap();
+        let groove = extract_groove(&Composition::from_file(&path).unwrap(), 16).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(groove.timing.len(), 16);
+        assert!((groove.timing[1] - 10.0 / 120.0).abs() < 1e-9);
+        assert!((groove.timing[2] + 0.1).abs() < 1e-9);
+        assert!(groove.timing.iter().enumerate().filter(|(step, _)| !matches!(step, 1 | 2)).all(|(_, offset)| *offset == 0.0));
+        assert_eq!(groove.velocity[0], 1.25);
+        assert_eq!(groove.velocity[4], 0.75);
+        assert_eq!(groove.velocity[1], 1.0);
+        assert!(extract_groove(&composition(Vec::new()), 16).is_err());
+    }
+
+    #[test]
+    fn humanized_notes_stay_in_range_and_order() {
+        let original = composition((0..64).map(|index| note(index * 480, 240, 48 + (index % 24) as u8, 30 + index as u8)).collect());
+        let settings = HumanizeSettings { timing_jitter_ms: 20.0, velocity_variation: 10, ..HumanizeSettings::default() };
+        let humanized = humanize(&original, &settings);
+        assert_eq!(humanize(&original, &settings), humanized);
+        let (before, after) = (&original.tracks[0].notes, &humanized.tracks[0].notes);
+        assert_eq!(after.len(), before.len());
+        // 20 ms at 120 BPM and 480 ticks per beat
+        let max_shift: f64 = 20.0 * 0.96;
+        for (was, now) in before.iter().zip(after) {
+            assert_eq!(now.pitch, was.pitch);
+            assert!((now.start as f64 - was.start as f64).abs() <= max_shift.ceil());
+            assert!((now.velocity as i32 - was.velocity as i32).abs() <= 10);
+            assert_eq!(now.end(), was.end());
+        }
+        assert_ne!(after, before);
+
+        let skipped = humanize(&original, &HumanizeSettings { disabled_tracks: vec![0], ..settings });
+        assert_eq!(skipped, original);
+    }
+}
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+use crate::config::active_soundfont;
+use crate::editing::EditVersion;
+use crate::humanize::{humanize, HumanizeSettings};
+use crate::mixer::{apply_mixer, ChannelMix};
+use crate::midi::{override_programs, Composition, CompositionSummary};
+use crate::render::{render_midi, RenderSettings};
+use crate::segment::TimelineEntry;
+use crate::setup::EnvPaths;
+use crate::theory::Analysis;
+use crate::validation::ValidationIssue;
+use crate::utils::send_to_frontend;
+
+const INDEX_FILE: &str = "index.json";
+const SOURCE_MIDI: &str = "source.mid";
+// Intermediate files of a render are named after its output, so renders of one entry running
+// side by side (a re-render and a stem export) keep apart
+const HUMANIZED_MIDI: &str = "humanized.mid";
+const MIXED_MIDI: &str = "mixed.mid";
+const TITLE_LENGTH: usize = 60;
+
+lazy_static::lazy_static! {
+    // Serializes read-modify-write cycles on the library index
+    static ref LIBRARY_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EntrySource {
+    Generated,
+    Imported,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RenderVersion {
+    pub version: u32,
+    pub audio: PathBuf,
+    // Unset for audio rendered by the Python sidecar
+    pub soundfont: Option<String>,
+    pub settings: Option<RenderSettings>,
+    #[serde(default)]
+    pub humanized: bool,
+    pub created: u64,
+}
+
+// One of several candidates generated for the same prompt
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Variant {
+    pub seed: u64,
+    pub midi: Option<PathBuf>,
+    pub audio: PathBuf,
+    pub summary: Option<CompositionSummary>,
+}
+
+// What a generation produced besides the output files
+#[derive(Debug, Default)]
+pub struct Generated {
+    pub validation: Vec<ValidationIssue>,
+    pub timeline: Vec<TimelineEntry>,
+    pub variants: Vec<Variant>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct LibraryEntry {
+    pub id: String,
+    pub title: String,
+    // Full text of the prompt a generated entry came from
+    #[serde(default)]
+    pub prompt: Option<String>,
+    pub source: EntrySource,
+    pub created: u64,
+    pub midi: Option<PathBuf>,
+    pub summary: Option<CompositionSummary>,
+    #[serde(default)]
+    pub versions: Vec<RenderVersion>,
+    #[serde(default)]
+    pub edits: Vec<EditVersion>,
+    // Undone edits, available for redo until the next edit
+    #[serde(default)]
+    pub undone: Vec<EditVersion>,
+    // Applied to a copy of the MIDI at render time, the stored MIDI is never changed
+    #[serde(default)]
+    pub humanize: Option<HumanizeSettings>,
+    // Result of the last analysis of the current MIDI
+    #[serde(default)]
+    pub analysis: Option<Analysis>,
+    // Problems found in generated output, repaired or not
+    #[serde(default)]
+    pub validation: Vec<ValidationIssue>,
+    // Time range of each text section in pieces generated section by section
+    #[serde(default)]
+    pub timeline: Vec<TimelineEntry>,
+    #[serde(default)]
+    pub variants: Vec<Variant>,
+    #[serde(default)]
+    pub selected_variant: usize,
+    // Set when the MIDI changed after the latest audio was rendered
+    #[serde(default)]
+    pub render_stale: bool,
+    // Channel mix applied to a copy of the MIDI at render time
+    #[serde(default)]
+    pub mixer: Vec<ChannelMix>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Library {
+    pub entries: Vec<LibraryEntry>,
+}
+
+impl Library {
+    fn load(paths: &EnvPaths) -> Result<Self, String> {
+        let index = paths.library.join(INDEX_FILE);
+        if !index.exists() {
+            return Ok(Library::default());
+        }
+        let content = fs::read_to_string(&index).map_err(|e| format!("Failed to read library: {}", e))?;
+        serde_json::from_str(&content).map_err(|e| format!("Failed to parse library: {}", e))
+    }
+
+    fn save(&self, paths: &EnvPaths) -> Result<(), String> {
+        fs::create_dir_all(&paths.library).map_err(|e| format!("Failed to create library directory: {}", e))?;
+        let content = serde_json::to_string_pretty(self).map_err(|e| e.to_string())?;
+        fs::write(paths.library.join(INDEX_FILE), content).map_err(|e| format!("Failed to write library: {}", e))
+    }
+
+    pub fn read() -> Result<Self, String> {
+        let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+        Library::load(&EnvPaths::new())
+    }
+
+    // Loads the index, applies the change and writes it back while holding the library lock
+    pub fn update<T>(change: impl FnOnce(&mut Library) -> Result<T, String>) -> Result<T, String> {
+        let _guard = LIBRARY_LOCK.lock().map_err(|e| e.to_string())?;
+        let paths = EnvPaths::new();
+        let mut library = Library::load(&paths)?;
+        let result = change(&mut library)?;
+        library.save(&paths)?;
+        Ok(result)
+    }
+
+    pub fn get(&self, id: &str) -> Result<&LibraryEntry, String> {
+        self.entries
+            .iter()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| format!("No library entry with id {}", id))
+    }
+
+    pub fn get_mut(&mut self, id: &str) -> Result<&mut LibraryEntry, String> {
+        self.entries
+            .iter_mut()
+            .find(|entry| entry.id == id)
+            .ok_or_else(|| format!("No library entry with id {}", id))
+    }
+}
+
+impl LibraryEntry {
+    pub fn new(title: String, source: EntrySource) -> Self {
+        let created = now_secs();
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        Self {
+            id: format!("{:x}", nanos),
+            title,
+            prompt: None,
+            source,
+            created,
+            midi: None,
+            summary: None,
+            versions: Vec::new(),
+            edits: Vec::new(),
+            undone: Vec::new(),
+            humanize: None,
+            analysis: None,
+            validation: Vec::new(),
+            timeline: Vec::new(),
+            variants: Vec::new(),
+            selected_variant: 0,
+            render_stale: false,
+            mixer: Vec::new(),
+        }
+    }
+
+    pub fn dir(&self) -> PathBuf {
+        EnvPaths::new().library.join(&self.id)
+    }
+
+    fn next_version(&self) -> u32 {
+        self.versions.last().map_or(1, |last| last.version + 1)
+    }
+
+    // Renders the MIDI at `midi` into a new version of this entry
+    pub fn render(&mut self, midi: &Path, soundfont: Option<PathBuf>, settings: RenderSettings) -> Result<RenderVersion, String> {
+        let version = self.next_version();
+        let audio = self.dir().join(format!("v{}.wav", version));
+        let (soundfont, humanized) = self.render_to(midi, soundfont, &settings, &self.mixer, &audio)?;
+
+        let rendered = RenderVersion {
+            version,
+            audio,
+            soundfont: Some(soundfont.display().to_string()),
+            settings: Some(settings),
+            humanized,
+            created: now_secs(),
+        };
+        self.versions.push(rendered.clone());
+        self.render_stale = false;
+        Ok(rendered)
+    }
+
+    // Renders the MIDI at `midi` to `output` through the entry's humanization and the given mixer,
+    // returning the SoundFont used and whether the notes were humanized
+    pub fn render_to(&self, midi: &Path, soundfont: Option<PathBuf>, settings: &RenderSettings, mixer: &[ChannelMix], output: &Path) -> Result<(PathBuf, bool), String> {
+        let soundfont = match soundfont {
+            Some(soundfont) => soundfont,
+            None => active_soundfont()?,
+        };
+
+        let humanize_settings = self.humanize.as_ref().filter(|settings| settings.enabled);
+        let humanized = output.with_extension(HUMANIZED_MIDI);
+        let mixed = output.with_extension(MIXED_MIDI);
+        let midi = match humanize_settings {
+            Some(humanize_settings) => {
+                humanize(&Composition::from_file(midi)?, humanize_settings).write_file(&humanized)?;
+                humanized.clone()
+            }
+            None => midi.to_path_buf(),
+        };
+        let midi = if mixer.is_empty() {
+            midi
+        } else {
+            let bytes = fs::read(&midi).map_err(|e| format!("Failed to read MIDI file: {}", e))?;
+            fs::write(&mixed, apply_mixer(&bytes, mixer)?).map_err(|e| format!("Failed to write MIDI file: {}", e))?;
+            mixed.clone()
+        };
+        let rendered = render_midi(&midi, &soundfont, output, settings);
+        let _ = fs::remove_file(&humanized);
+        let _ = fs::remove_file(&mixed);
+        rendered?;
+        Ok((soundfont, humanize_settings.is_some()))
+    }
+}
+
+// Adds the result of a sidecar generation, keeping its MIDI when the sidecar wrote one
+pub fn add_generated(prompt: &str, generated: Generated) -> Result<LibraryEntry, String> {
+    let paths = EnvPaths::new();
+    let title: String = prompt.trim().chars().take(TITLE_LENGTH).collect();
+    let title = if title.is_empty() { "Generated tune".to_string() } else { title };
+    let mut entry = LibraryEntry::new(title, EntrySource::Generated);
+    entry.prompt = Some(prompt.trim().to_string()).filter(|prompt| !prompt.is_empty());
+    entry.validation = generated.validation;
+    entry.timeline = generated.timeline;
+    let dir = entry.dir();
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create library directory: {}", e))?;
+    or_discard(&dir, store_generated(&paths, entry, generated.variants))
+}
+
+// Removes a new entry's directory when adding the entry fails, so no orphaned files stay behind
+fn or_discard<T>(dir: &Path, result: Result<T, String>) -> Result<T, String> {
+    if result.is_err() {
+        let _ = fs::remove_dir_all(dir);
+    }
+    result
+}
+
+fn store_generated(paths: &EnvPaths, mut entry: LibraryEntry, variants: Vec<Variant>) -> Result<LibraryEntry, String> {
+    // Candidates are kept side by side, the first one becomes the entry's MIDI and audio
+    for (index, variant) in variants.into_iter().enumerate() {
+        let audio = entry.dir().join(format!("variant{}.wav", index + 1));
+        fs::copy(&variant.audio, &audio).map_err(|e| format!("Failed to copy audio file: {}", e))?;
+        let midi = match variant.midi {
+            Some(source) => {
+                let midi = entry.dir().join(format!("variant{}.mid", index + 1));
+                fs::copy(&source, &midi).map_err(|e| format!("Failed to copy MIDI file: {}", e))?;
+                Some(midi)
+            }
+            None => None,
+        };
+        let summary = midi.as_deref().and_then(|midi| Composition::from_file(midi).ok()).map(|composition| composition.summary());
+        entry.variants.push(Variant { seed: variant.seed, midi, audio, summary });
+    }
+    let (source_midi, source_audio) = match entry.variants.first() {
+        Some(first) => (first.midi.clone(), first.audio.clone()),
+        None => (Some(paths.output_midi.clone()).filter(|midi| midi.exists()), paths.output_file.clone()),
+    };
+
+    if let Some(source_midi) = source_midi {
+        let midi = entry.dir().join(SOURCE_MIDI);
+        fs::copy(&source_midi, &midi).map_err(|e| format!("Failed to copy MIDI file: {}", e))?;
+        entry.summary = Composition::from_file(&midi).ok().map(|composition| composition.summary());
+        entry.midi = Some(midi);
+    }
+
+    let version = entry.next_version();
+    let audio = entry.dir().join(format!("v{}.wav", version));
+    fs::copy(&source_audio, &audio).map_err(|e| format!("Failed to copy audio file: {}", e))?;
+    entry.versions.push(RenderVersion { version, audio, soundfont: None, settings: None, humanized: false, created: now_secs() });
+
+    let stored = entry.clone();
+    Library::update(move |library| {
+        library.entries.push(entry);
+        Ok(())
+    })?;
+    Ok(stored)
+}
+
+pub fn now_secs() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0)
+}
+
+#[tauri::command]
+pub async fn list_library() -> Result<Vec<LibraryEntry>, String> {
+    Ok(Library::read()?.entries)
+}
+
+#[tauri::command]
+pub async fn import_midi(app: AppHandle, path: String, soundfont: Option<String>, settings: Option<RenderSettings>) -> Result<LibraryEntry, String> {
+    let source = PathBuf::from(&path);
+    let composition = Composition::from_file(&source)?;
+    if composition.note_count() == 0 {
+        return Err(format!("{} contains no notes", path));
+    }
+
+    let title = source.file_stem().and_then(|s| s.to_str()).unwrap_or("Imported MIDI").to_string();
+    let mut entry = LibraryEntry::new(title, EntrySource::Imported);
+
+    // The original file is kept as is so controller data survives rendering
+    let dir = entry.dir();
+    let midi = dir.join(SOURCE_MIDI);
+    fs::create_dir_all(&dir).map_err(|e| format!("Failed to create library directory: {}", e))?;
+    or_discard(&dir, fs::copy(&source, &midi).map_err(|e| format!("Failed to copy MIDI file: {}", e)))?;
+    entry.midi = Some(midi.clone());
+    entry.summary = Some(composition.summary());
+
+    if let Some(summary) = &entry.summary {
+        let instruments: Vec<String> = summary.tracks.iter().map(|track| format!("{} ({})", track.name, track.instrument)).collect();
+        send_to_frontend(
+            &app,
+            format!("Imported {}: {} tracks, {:.1}s - {}", entry.title, summary.tracks.len(), summary.duration_seconds, instruments.join(", ")),
+            "info",
+        );
+    }
+
+    let rendered = or_discard(&dir, entry.render(&midi, soundfont.map(PathBuf::from), settings.unwrap_or_default()))?;
+
+    let stored = entry.clone();
+    or_discard(&dir, Library::update(move |library| {
+        library.entries.push(entry);
+        Ok(())
+    }))?;
+    send_to_frontend(&app, rendered.audio.display().to_string(), "tune_file_created");
+    Ok(stored)
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct RerenderOptions {
+    pub soundfont: Option<String>,
+    // Channel to General MIDI program
+    pub programs: HashMap<u8, u8>,
+    pub settings: Option<RenderSettings>,
+}
+
+#[tauri::command]
+pub async fn rerender_track(app: AppHandle, track: String, options: RerenderOptions) -> Result<RenderVersion, String> {
+    let mut entry = Library::read()?.get(&track)?.clone();
+    let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to re-render", entry.title))?;
+
+    // Instrument overrides are written to a copy so the stored MIDI stays untouched
+    let render_source = if options.programs.is_empty() {
+        midi
+    } else {
+        let bytes = fs::read(&midi).map_err(|e| format!("Failed to read MIDI file: {}", e))?;
+        let patched = entry.dir().join(format!("v{}.mid", entry.next_version()));
+        fs::write(&patched, override_programs(&bytes, &options.programs)?).map_err(|e| format!("Failed to write MIDI file: {}", e))?;
+        patched
+    };
+
+    // Unspecified settings carry over from the latest render
+    let settings = options
+        .settings
+        .or_else(|| entry.versions.last().and_then(|version| version.settings.clone()))
+        .unwrap_or_default();
+    let soundfont = options
+        .soundfont
+        .or_else(|| entry.versions.last().and_then(|version| version.soundfont.clone()))
+        .map(PathBuf::from);
+
+    send_to_frontend(&app, format!("Re-rendering {}...", entry.title), "info");
+    let rendered = entry.render(&render_source, soundfont, settings)?;
+    send_to_frontend(&app, rendered.audio.display().to_string(), "tune_file_created");
+
+    let version = rendered.clone();
+    let rendered_midi = entry.midi.clone();
+    Library::update(move |library| {
+        let stored = library.get_mut(&track)?;
+        stored.versions.push(version);
+        // An edit made while rendering keeps the entry stale
+        stored.render_stale = stored.midi != rendered_midi;
+        Ok(())
+    })?;
+    Ok(rendered)
+}
+
+// Makes another variant the entry's current MIDI and audio
+#[tauri::command]
+pub async fn select_variant(track: String, variant: usize) -> Result<LibraryEntry, String> {
+    Library::update(move |library| {
+        let entry = library.get_mut(&track)?;
+        let chosen = entry.variants.get(variant).cloned().ok_or_else(|| format!("{} has no variant {}", entry.title, variant + 1))?;
+        entry.midi = chosen.midi;
+        entry.summary = chosen.summary;
+        entry.analysis = None;
+        // The edit history belongs to the MIDI that was current before
+        entry.edits.clear();
+        entry.undone.clear();
+        let version = entry.next_version();
+        entry.versions.push(RenderVersion { version, audio: chosen.audio, soundfont: None, settings: None, humanized: false, created: now_secs() });
+        entry.selected_variant = variant;
+        entry.render_stale = false;
+        Ok(entry.clone())
+    })
+}
@@ -0,0 +1,219 @@
+use std::fmt::Write;
+use crate::notation::{Clef, Event, NoteValue, Pitch, Score};
+use crate::theory::Key;
+
+const VERSION: &str = "2.24.0";
+const VOICE_COMMANDS: [&str; 4] = ["\\voiceOne", "\\voiceTwo", "\\voiceThree", "\\voiceFour"];
+
+fn escape(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// Dutch note names, where E flat and A flat shorten to "es" and "as"
+fn note_name(step: char, alter: i32) -> String {
+    let letter = step.to_ascii_lowercase();
+    let suffix = match alter {
+        -2 => "eses",
+        -1 => "es",
+        1 => "is",
+        2 => "isis",
+        _ => "",
+    };
+    match (letter, suffix.starts_with('e')) {
+        ('e' | 'a', true) => format!("{}{}", letter, &suffix[1..]),
+        _ => format!("{}{}", letter, suffix),
+    }
+}
+
+// Absolute pitch, where "c'" is middle C
+fn pitch_text(pitch: &Pitch) -> String {
+    let marks = if pitch.octave >= 3 { "'".repeat((pitch.octave - 3) as usize) } else { ",".repeat((3 - pitch.octave) as usize) };
+    format!("{}{}", note_name(pitch.step, pitch.alter), marks)
+}
+
+fn key_text(key: &Key) -> String {
+    let name = key.tonic.name(key.uses_flats());
+    let mut chars = name.chars();
+    let step = chars.next().unwrap_or('C');
+    let alter = match chars.next() {
+        Some('#') => 1,
+        Some('b') => -1,
+        _ => 0,
+    };
+    format!("\\key {} {}", note_name(step, alter), if key.minor { "\\minor" } else { "\\major" })
+}
+
+fn duration_text(value: NoteValue) -> String {
+    let base = match value.name {
+        "whole" => "1",
+        "half" => "2",
+        "quarter" => "4",
+        "eighth" => "8",
+        _ => "16",
+    };
+    format!("{}{}", base, ".".repeat(value.dots as usize))
+}
+
+// Variable names may only hold letters, so parts and voices count A, B, ... Z, AA, AB, ...
+fn letters(mut index: usize) -> String {
+    let mut name = Vec::new();
+    loop {
+        name.push(b'A' + (index % 26) as u8);
+        if index < 26 {
+            break;
+        }
+        index = index / 26 - 1;
+    }
+    name.reverse();
+    String::from_utf8(name).unwrap_or_default()
+}
+
+fn variable(part: usize, voice: usize) -> String {
+    format!("part{}voice{}", letters(part), letters(voice))
+}
+
+// Music of one voice, a bar per line with bar checks; durations are only written when they change
+fn voice_music(score: &Score, part: usize, voice: usize) -> String {
+    let notation = &score.parts[part];
+    let mut out = String::new();
+    let _ = writeln!(out, "{} = {{", variable(part, voice));
+    if voice == 0 {
+        let clef = match notation.clef {
+            Clef::Treble => "treble",
+            Clef::Bass => "bass",
+            Clef::Percussion => "percussion",
+        };
+        let _ = writeln!(out, "  \\clef {}", clef);
+    }
+    let mut previous: Option<String> = None;
+    for (index, (measure, voices)) in score.measures.iter().zip(&notation.measures).enumerate() {
+        let mut attributes = Vec::new();
+        if measure.key_change {
+            attributes.push(key_text(&measure.key));
+        }
+        if measure.time_change {
+            attributes.push(format!("\\time {}/{}", measure.time.0, measure.time.1));
+        }
+        if let Some(bpm) = measure.tempo.filter(|_| part == 0 && voice == 0) {
+            attributes.push(format!("\\tempo 4 = {:.0}", bpm));
+        }
+        if !attributes.is_empty() {
+            let _ = writeln!(out, "  {}", attributes.join(" "));
+        }
+        let mut line = Vec::new();
+        let whole_bar = format!("1*{}/{}", measure.time.0, measure.time.1);
+        let events: &[Event] = voices.get(voice).map_or(&[], |events| events.as_slice());
+        if events.is_empty() {
+            // Spacer rest where an extra voice has nothing to play
+            line.push(format!("s{}", whole_bar));
+            previous = None;
+        }
+        for event in events {
+            if event.measure_rest {
+                line.push(format!("R{}", whole_bar));
+                previous = None;
+                continue;
+            }
+            let duration = duration_text(event.value);
+            let written = if previous.as_ref() == Some(&duration) { String::new() } else { duration.clone() };
+            previous = Some(duration);
+            let tie = if event.tie_start { "~" } else { "" };
+            match event.pitches.as_slice() {
+                [] => line.push(format!("r{}", written)),
+                [pitch] => line.push(format!("{}{}{}", pitch_text(pitch), written, tie)),
+                pitches => {
+                    let chord: Vec<String> = pitches.iter().map(pitch_text).collect();
+                    line.push(format!("<{}>{}{}", chord.join(" "), written, tie));
+                }
+            }
+        }
+        let bar = if index + 1 == score.measures.len() { "\\bar \"|.\"" } else { "|" };
+        let _ = writeln!(out, "  {} {}", line.join(" "), bar);
+    }
+    out.push_str("}\n");
+    out
+}
+
+// Writes a score as a LilyPond file: a variable per voice and a staff per part
+pub fn write(score: &Score) -> String {
+    let mut out = String::new();
+    let _ = writeln!(out, "\\version \"{}\"\n", VERSION);
+    let _ = writeln!(out, "\\header {{\n  title = \"{}\"\n  tagline = ##f\n}}\n", escape(&score.title));
+
+    let voices: Vec<Vec<usize>> = score
+        .parts
+        .iter()
+        .map(|part| {
+            let count = part.measures.iter().map(|voices| voices.len()).max().unwrap_or(0);
+            (0..count).filter(|voice| part.measures.iter().any(|voices| voices.get(*voice).is_some_and(|events| !events.is_empty()))).collect()
+        })
+        .collect();
+    for (part, used) in voices.iter().enumerate() {
+        for &voice in used {
+            let _ = writeln!(out, "{}", voice_music(score, part, voice));
+        }
+    }
+
+    out.push_str("\\score {\n  <<\n");
+    for (part, used) in voices.iter().enumerate() {
+        let notation = &score.parts[part];
+        let _ = write!(out, "    \\new Staff \\with {{ instrumentName = \"{}\" }} ", escape(&notation.name));
+        match used.as_slice() {
+            [voice] => {
+                let _ = writeln!(out, "\\{}", variable(part, *voice));
+            }
+            _ => {
+                out.push_str("<<\n");
+                for (number, &voice) in used.iter().enumerate() {
+                    let _ = writeln!(out, "      \\new Voice {{ {} \\{} }}", VOICE_COMMANDS[number.min(3)], variable(part, voice));
+                }
+                out.push_str("    >>\n");
+            }
+        }
+    }
+    out.push_str("  >>\n  \\layout { }\n  \\midi { }\n}\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::abc;
+    use crate::midi::{Composition, Note, Track};
+    use crate::notation::build;
+
+    fn lilypond(text: &str) -> String {
+        let tune = abc::parse(text).unwrap();
+        write(&build(&tune.composition, "Test"))
+    }
+
+    #[test]
+    fn writes_keys_meters_durations_and_ties() {
+        let written = lilypond("X:1\nM:6/8\nL:1/8\nQ:1/4=90\nK:Bb\nB,cd e2 f | f6- | f3 _a3 |]\n");
+        assert!(written.contains("\\key bes \\major \\time 6/8 \\tempo 4 = 90"));
+        assert!(written.contains("bes8 c'' d'' es''4 f''8 |"));
+        assert!(written.contains("f''2.~ |"));
+        assert!(written.contains("f''4. as'' \\bar \"|.\""));
+    }
+
+    #[test]
+    fn parts_get_named_staves_and_clefs() {
+        let written = lilypond("X:1\nM:4/4\nL:1/4\nK:Am\nV:1 name=\"Flute\"\nc4 | [ce]2 z2 |]\nV:2 name=\"Bassoon\"\nz2 A,,2 | A,,4 |]\n");
+        assert!(written.contains("\\key a \\minor"));
+        assert!(written.contains("<c'' e''>2 r \\bar"));
+        assert!(written.contains("\\new Staff \\with { instrumentName = \"Flute\" } \\partAvoiceA"));
+        assert!(written.contains("\\new Staff \\with { instrumentName = \"Bassoon\" } \\partBvoiceA"));
+        assert!(written.contains("\\clef bass\n"));
+    }
+
+    #[test]
+    fn overlapping_notes_share_a_staff_as_voices() {
+        let mut composition = Composition::new(480);
+        let notes = vec![Note { start: 0, duration: 1920, pitch: 72, velocity: 80 }, Note { start: 960, duration: 960, pitch: 64, velocity: 80 }];
+        composition.tracks.push(Track { name: Some("Organ".to_string()), channel: 0, program: 19, notes });
+        let written = write(&build(&composition, "Test"));
+        assert!(written.contains("\\new Voice { \\voiceOne \\partAvoiceA }"));
+        assert!(written.contains("\\new Voice { \\voiceTwo \\partAvoiceB }"));
+        assert!(written.contains("partAvoiceB = {\n  \\key c \\major \\time 4/4\n  r2 e' \\bar"));
+    }
+}
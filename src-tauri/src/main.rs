@@ -8,6 +8,28 @@ mod fluidsynth_config;
 mod tune_processor;
 mod config;
 mod audio_player;
+mod render;
+mod midi;
+mod library;
+mod editing;
+mod humanize;
+mod theory;
+mod validation;
+mod composer;
+mod mood;
+mod segment;
+mod cache;
+mod abc;
+mod notation;
+mod musicxml;
+mod engraving;
+mod lilypond;
+mod piano_roll;
+mod arrangement;
+mod mixer;
+mod project;
+mod secrets;
+mod provider;
 use audio_player::initialize_audio;
 
 #[tokio::main]
@@ -19,9 +41,47 @@ async fn main() {
             tune_processor::generate_tunes,
             config::save_config,
             config::load_config,
+            config::set_soundfont,
+            config::list_api_keys,
+            config::add_api_key,
+            config::set_api_key_enabled,
+            config::remove_api_key,
+            provider::get_provider,
+            provider::set_provider,
             audio_player::play_audio,
             audio_player::pause_audio,
             audio_player::stop_audio,
+            audio_player::play_playlist,
+            audio_player::set_crossfade,
+            audio_player::playback_position,
+            audio_player::compare_variants,
+            audio_player::switch_variant,
+            library::list_library,
+            library::import_midi,
+            library::rerender_track,
+            library::select_variant,
+            editing::edit_composition,
+            editing::undo_edit,
+            editing::redo_edit,
+            humanize::set_humanize,
+            humanize::extract_groove_template,
+            theory::analyze_composition,
+            mood::analyze_text,
+            cache::clear_cache,
+            notation::export_notation,
+            engraving::render_score,
+            engraving::current_measure,
+            piano_roll::get_piano_roll,
+            arrangement::create_arrangement,
+            arrangement::list_arrangements,
+            arrangement::save_arrangement,
+            arrangement::delete_arrangement,
+            arrangement::mixdown_arrangement,
+            mixer::get_mixer,
+            mixer::set_mixer,
+            mixer::export_stems,
+            project::save_project,
+            project::open_project,
         ])
         .run(tauri::generate_context!())
         .expect("error while running Tauri application");
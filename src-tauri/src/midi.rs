@@ -0,0 +1,658 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::Path;
+use midly::num::{u14, u15, u24, u28, u4, u7};
+use midly::{Format, Header, MetaMessage, MidiMessage, PitchBend, Smf, Timing, TrackEvent, TrackEventKind};
+use serde::{Deserialize, Serialize};
+
+pub const DEFAULT_TEMPO: u32 = 500_000;
+pub const DRUM_CHANNEL: u8 = 9;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Note {
+    pub start: u64,
+    pub duration: u64,
+    pub pitch: u8,
+    pub velocity: u8,
+}
+
+impl Note {
+    pub fn end(&self) -> u64 {
+        self.start + self.duration
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Track {
+    pub name: Option<String>,
+    pub channel: u8,
+    pub program: u8,
+    pub notes: Vec<Note>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TempoChange {
+    pub tick: u64,
+    pub micros_per_beat: u32,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct TimeSignature {
+    pub tick: u64,
+    pub numerator: u8,
+    pub denominator: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct KeySignature {
+    pub tick: u64,
+    pub sharps: i8,
+    pub minor: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Control {
+    // Includes sustain (64), volume (7), pan (10) and the effect sends
+    Controller { controller: u8, value: u8 },
+    // 14-bit value, 8192 is the centre
+    PitchBend { value: u16 },
+    ChannelPressure { value: u8 },
+}
+
+impl Control {
+    // Events with the same target replace each other's value
+    pub fn same_target(&self, other: &Control) -> bool {
+        match (self, other) {
+            (Control::Controller { controller: a, .. }, Control::Controller { controller: b, .. }) => a == b,
+            (Control::PitchBend { .. }, Control::PitchBend { .. }) => true,
+            (Control::ChannelPressure { .. }, Control::ChannelPressure { .. }) => true,
+            _ => false,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ControlEvent {
+    pub tick: u64,
+    pub channel: u8,
+    pub control: Control,
+}
+
+// Note-level view of a MIDI file, with absolute tick positions and one track per channel
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Composition {
+    pub ticks_per_beat: u16,
+    pub tempos: Vec<TempoChange>,
+    pub time_signatures: Vec<TimeSignature>,
+    pub key_signatures: Vec<KeySignature>,
+    pub tracks: Vec<Track>,
+    // Controller, pitch bend and pressure events, kept so edits do not lose them
+    #[serde(default)]
+    pub controls: Vec<ControlEvent>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TrackSummary {
+    pub index: usize,
+    pub name: String,
+    pub channel: u8,
+    pub program: u8,
+    pub instrument: String,
+    pub notes: usize,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CompositionSummary {
+    pub tracks: Vec<TrackSummary>,
+    pub duration_seconds: f64,
+    pub tempo_bpm: f64,
+    pub time_signature: String,
+}
+
+// Notes still sounding while a track is read, keyed by pitch
+#[derive(Default)]
+struct ChannelState {
+    notes: Vec<Note>,
+    open: HashMap<u8, Vec<(u64, u8)>>,
+}
+
+impl ChannelState {
+    fn close(&mut self, pitch: u8, tick: u64) {
+        if let Some(started) = self.open.get_mut(&pitch) {
+            if !started.is_empty() {
+                let (start, velocity) = started.remove(0);
+                self.notes.push(Note { start, duration: tick.saturating_sub(start), pitch, velocity });
+            }
+        }
+    }
+
+    fn close_all(&mut self, tick: u64) {
+        let pitches: Vec<u8> = self.open.keys().copied().collect();
+        for pitch in pitches {
+            while self.open.get(&pitch).is_some_and(|started| !started.is_empty()) {
+                self.close(pitch, tick);
+            }
+        }
+    }
+}
+
+impl Composition {
+    pub fn new(ticks_per_beat: u16) -> Self {
+        Self {
+            ticks_per_beat,
+            tempos: Vec::new(),
+            time_signatures: Vec::new(),
+            key_signatures: Vec::new(),
+            tracks: Vec::new(),
+            controls: Vec::new(),
+        }
+    }
+
+    pub fn from_file(path: &Path) -> Result<Self, String> {
+        let bytes = fs::read(path).map_err(|e| format!("Failed to read MIDI file: {}", e))?;
+        Self::from_bytes(&bytes)
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, String> {
+        let smf = Smf::parse(bytes).map_err(|e| format!("Invalid MIDI file: {}", e))?;
+
+        // SMPTE timing is expressed as a fixed tempo of one beat per second
+        let mut composition = match smf.header.timing {
+            Timing::Metrical(ticks) => Composition::new(ticks.as_int().max(1)),
+            Timing::Timecode(fps, subframes) => {
+                let ticks_per_second = (fps.as_f32() * subframes as f32).round().max(1.0) as u16;
+                let mut composition = Composition::new(ticks_per_second);
+                composition.tempos.push(TempoChange { tick: 0, micros_per_beat: 1_000_000 });
+                composition
+            }
+        };
+
+        // Program changes are collected per channel over all tracks, as files often set them in
+        // a conductor track or another track than the one holding the channel's notes
+        let mut programs: BTreeMap<u8, Vec<(u64, u8)>> = BTreeMap::new();
+        for events in &smf.tracks {
+            let mut tick = 0u64;
+            for event in events {
+                tick += event.delta.as_int() as u64;
+                if let TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { program } } = event.kind {
+                    programs.entry(channel.as_int()).or_default().push((tick, program.as_int()));
+                }
+            }
+        }
+        for changes in programs.values_mut() {
+            changes.sort_by_key(|(tick, _)| *tick);
+        }
+
+        for events in &smf.tracks {
+            let mut tick = 0u64;
+            let mut name = None;
+            let mut channels: BTreeMap<u8, ChannelState> = BTreeMap::new();
+
+            for event in events {
+                tick += event.delta.as_int() as u64;
+                match event.kind {
+                    TrackEventKind::Midi { channel, message } => {
+                        let state = channels.entry(channel.as_int()).or_default();
+                        let control = match message {
+                            MidiMessage::Controller { controller, value } => Some(Control::Controller { controller: controller.as_int(), value: value.as_int() }),
+                            MidiMessage::PitchBend { bend } => Some(Control::PitchBend { value: bend.0.as_int() }),
+                            MidiMessage::ChannelAftertouch { vel } => Some(Control::ChannelPressure { value: vel.as_int() }),
+                            _ => None,
+                        };
+                        if let Some(control) = control {
+                            composition.controls.push(ControlEvent { tick, channel: channel.as_int(), control });
+                        }
+                        match message {
+                            MidiMessage::NoteOn { key, vel } if vel.as_int() > 0 => {
+                                state.open.entry(key.as_int()).or_default().push((tick, vel.as_int()));
+                            }
+                            MidiMessage::NoteOn { key, .. } | MidiMessage::NoteOff { key, .. } => {
+                                state.close(key.as_int(), tick);
+                            }
+                            _ => {}
+                        }
+                    }
+                    TrackEventKind::Meta(MetaMessage::TrackName(raw)) => {
+                        let text = String::from_utf8_lossy(raw).trim().to_string();
+                        if !text.is_empty() {
+                            name = Some(text);
+                        }
+                    }
+                    TrackEventKind::Meta(MetaMessage::Tempo(micros)) => {
+                        composition.tempos.push(TempoChange { tick, micros_per_beat: micros.as_int() });
+                    }
+                    TrackEventKind::Meta(MetaMessage::TimeSignature(numerator, denominator, _, _)) => {
+                        composition.time_signatures.push(TimeSignature {
+                            tick,
+                            numerator,
+                            denominator: 1u8.checked_shl(denominator as u32).unwrap_or(4),
+                        });
+                    }
+                    TrackEventKind::Meta(MetaMessage::KeySignature(sharps, minor)) => {
+                        composition.key_signatures.push(KeySignature { tick, sharps, minor });
+                    }
+                    _ => {}
+                }
+            }
+
+            for (channel, mut state) in channels {
+                state.close_all(tick);
+                if state.notes.is_empty() {
+                    continue;
+                }
+                state.notes.sort_by_key(|note| (note.start, note.pitch));
+                // Only the instrument the channel starts with is kept
+                let first = state.notes[0].start;
+                let program = programs
+                    .get(&channel)
+                    .and_then(|changes| changes.iter().take_while(|(tick, _)| *tick <= first).last())
+                    .map_or(0, |(_, program)| *program);
+                composition.tracks.push(Track {
+                    name: name.clone(),
+                    channel,
+                    program,
+                    notes: state.notes,
+                });
+            }
+        }
+
+        composition.tempos.sort_by_key(|tempo| tempo.tick);
+        composition.time_signatures.sort_by_key(|signature| signature.tick);
+        composition.key_signatures.sort_by_key(|signature| signature.tick);
+        composition.controls.sort_by_key(|control| control.tick);
+        Ok(composition)
+    }
+
+    pub fn write_file(&self, path: &Path) -> Result<(), String> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent).map_err(|e| format!("Failed to create directory: {}", e))?;
+        }
+        fs::write(path, self.to_bytes()?).map_err(|e| format!("Failed to write MIDI file: {}", e))
+    }
+
+    pub fn to_bytes(&self) -> Result<Vec<u8>, String> {
+        let mut tracks: Vec<Vec<TrackEvent>> = Vec::new();
+
+        // Conductor track holding tempo, meter and key changes
+        let mut conductor: Vec<(u64, TrackEventKind)> = Vec::new();
+        for tempo in &self.tempos {
+            conductor.push((tempo.tick, TrackEventKind::Meta(MetaMessage::Tempo(u24::new(tempo.micros_per_beat.min(0xFF_FFFF))))));
+        }
+        for signature in &self.time_signatures {
+            let denominator = signature.denominator.max(1).trailing_zeros() as u8;
+            conductor.push((signature.tick, TrackEventKind::Meta(MetaMessage::TimeSignature(signature.numerator, denominator, 24, 8))));
+        }
+        for signature in &self.key_signatures {
+            conductor.push((signature.tick, TrackEventKind::Meta(MetaMessage::KeySignature(signature.sharps, signature.minor))));
+        }
+        conductor.sort_by_key(|(tick, _)| *tick);
+        tracks.push(to_track_events(conductor));
+
+        let mut written_channels = HashSet::new();
+        for track in &self.tracks {
+            let channel = u4::new(track.channel & 0x0F);
+            let mut events: Vec<(u64, u8, TrackEventKind)> = Vec::new();
+            if let Some(name) = &track.name {
+                events.push((0, 0, TrackEventKind::Meta(MetaMessage::TrackName(name.as_bytes()))));
+            }
+            events.push((0, 0, TrackEventKind::Midi {
+                channel,
+                message: MidiMessage::ProgramChange { program: u7::new(track.program & 0x7F) },
+            }));
+            // Controls go with the first track of their channel, before notes on the same tick
+            if written_channels.insert(track.channel) {
+                for control in self.controls.iter().filter(|control| control.channel == track.channel) {
+                    let message = match control.control {
+                        Control::Controller { controller, value } => MidiMessage::Controller { controller: u7::new(controller & 0x7F), value: u7::new(value & 0x7F) },
+                        Control::PitchBend { value } => MidiMessage::PitchBend { bend: PitchBend(u14::new(value.min(0x3FFF))) },
+                        Control::ChannelPressure { value } => MidiMessage::ChannelAftertouch { vel: u7::new(value & 0x7F) },
+                    };
+                    events.push((control.tick, 0, TrackEventKind::Midi { channel, message }));
+                }
+            }
+            // A note without length would have its note off sorted before its note on and hang
+            for note in track.notes.iter().filter(|note| note.duration > 0) {
+                let key = u7::new(note.pitch.min(127));
+                events.push((note.start, 2, TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOn { key, vel: u7::new(note.velocity.clamp(1, 127)) },
+                }));
+                // Note offs sort before note ons on the same tick so repeated notes retrigger
+                events.push((note.end(), 1, TrackEventKind::Midi {
+                    channel,
+                    message: MidiMessage::NoteOff { key, vel: u7::new(0) },
+                }));
+            }
+            events.sort_by_key(|(tick, order, _)| (*tick, *order));
+            tracks.push(to_track_events(events.into_iter().map(|(tick, _, kind)| (tick, kind)).collect()));
+        }
+
+        let header = Header::new(Format::Parallel, Timing::Metrical(u15::new(self.ticks_per_beat.min(0x7FFF))));
+        let smf = Smf { header, tracks };
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).map_err(|e| format!("Failed to encode MIDI: {}", e))?;
+        Ok(bytes)
+    }
+
+    pub fn end_tick(&self) -> u64 {
+        self.tracks
+            .iter()
+            .flat_map(|track| track.notes.iter())
+            .map(Note::end)
+            .max()
+            .unwrap_or(0)
+    }
+
+    pub fn note_count(&self) -> usize {
+        self.tracks.iter().map(|track| track.notes.len()).sum()
+    }
+
+    pub fn tempo_at(&self, tick: u64) -> u32 {
+        self.tempos
+            .iter()
+            .take_while(|tempo| tempo.tick <= tick)
+            .last()
+            .map_or(DEFAULT_TEMPO, |tempo| tempo.micros_per_beat)
+    }
+
+    pub fn bpm(&self) -> f64 {
+        60_000_000.0 / self.tempo_at(0).max(1) as f64
+    }
+
+    pub fn time_signature_at(&self, tick: u64) -> (u8, u8) {
+        self.time_signatures
+            .iter()
+            .take_while(|signature| signature.tick <= tick)
+            .last()
+            .map_or((4, 4), |signature| (signature.numerator.max(1), signature.denominator.max(1)))
+    }
+
+    pub fn ticks_per_bar(&self, tick: u64) -> u64 {
+        let (numerator, denominator) = self.time_signature_at(tick);
+        self.ticks_per_beat as u64 * 4 * numerator as u64 / denominator as u64
+    }
+
+    // Tick at which the given zero-based bar starts, following time signature changes
+    pub fn bar_to_tick(&self, bar: u32) -> u64 {
+        let mut tick = 0u64;
+        for _ in 0..bar {
+            tick += self.ticks_per_bar(tick);
+        }
+        tick
+    }
+
+    // Zero-based bar containing the given tick
+    pub fn tick_to_bar(&self, tick: u64) -> u32 {
+        let mut bar = 0u32;
+        let mut bar_start = 0u64;
+        loop {
+            let bar_end = bar_start + self.ticks_per_bar(bar_start).max(1);
+            if tick < bar_end {
+                return bar;
+            }
+            bar += 1;
+            bar_start = bar_end;
+        }
+    }
+
+    // Converts a tick position to seconds by walking the tempo map
+    pub fn tick_to_seconds(&self, tick: u64) -> f64 {
+        let mut seconds = 0.0;
+        let mut last_tick = 0u64;
+        let mut tempo = DEFAULT_TEMPO;
+        for change in self.tempos.iter().take_while(|change| change.tick <= tick) {
+            seconds += self.ticks_duration(change.tick - last_tick, tempo);
+            last_tick = change.tick;
+            tempo = change.micros_per_beat;
+        }
+        seconds + self.ticks_duration(tick - last_tick, tempo)
+    }
+
+    // Tick sounding at the given time, the inverse of tick_to_seconds
+    pub fn seconds_to_tick(&self, seconds: f64) -> u64 {
+        let mut elapsed = 0.0;
+        let mut last_tick = 0u64;
+        let mut tempo = DEFAULT_TEMPO;
+        for change in &self.tempos {
+            let next = elapsed + self.ticks_duration(change.tick - last_tick, tempo);
+            if next > seconds {
+                break;
+            }
+            elapsed = next;
+            last_tick = change.tick;
+            tempo = change.micros_per_beat;
+        }
+        let remaining = (seconds - elapsed).max(0.0);
+        last_tick + (remaining * 1_000_000.0 / tempo.max(1) as f64 * self.ticks_per_beat as f64).round() as u64
+    }
+
+    fn ticks_duration(&self, ticks: u64, micros_per_beat: u32) -> f64 {
+        ticks as f64 * micros_per_beat as f64 / 1_000_000.0 / self.ticks_per_beat.max(1) as f64
+    }
+
+    pub fn duration_seconds(&self) -> f64 {
+        self.tick_to_seconds(self.end_tick())
+    }
+
+    pub fn summary(&self) -> CompositionSummary {
+        let tracks = self
+            .tracks
+            .iter()
+            .enumerate()
+            .map(|(index, track)| TrackSummary {
+                index,
+                name: track.name.clone().unwrap_or_else(|| format!("Track {}", index + 1)),
+                channel: track.channel,
+                program: track.program,
+                instrument: instrument_name(track.channel, track.program).to_string(),
+                notes: track.notes.len(),
+            })
+            .collect();
+        let (numerator, denominator) = self.time_signature_at(0);
+        CompositionSummary {
+            tracks,
+            duration_seconds: self.duration_seconds(),
+            tempo_bpm: self.bpm(),
+            time_signature: format!("{}/{}", numerator, denominator),
+        }
+    }
+}
+
+fn to_track_events(events: Vec<(u64, TrackEventKind)>) -> Vec<TrackEvent> {
+    let mut last_tick = 0u64;
+    let mut track: Vec<TrackEvent> = events
+        .into_iter()
+        .map(|(tick, kind)| {
+            let delta = (tick - last_tick).min(0x0FFF_FFFF) as u32;
+            last_tick = tick;
+            TrackEvent { delta: u28::new(delta), kind }
+        })
+        .collect();
+    track.push(TrackEvent { delta: u28::new(0), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+    track
+}
+
+// Replaces the instrument of the given channels, leaving every other event of the file untouched
+pub fn override_programs(bytes: &[u8], programs: &HashMap<u8, u8>) -> Result<Vec<u8>, String> {
+    for (&channel, &program) in programs {
+        if channel > 15 {
+            return Err(format!("Invalid MIDI channel {}, channels go from 0 to 15", channel));
+        }
+        if program > 127 {
+            return Err(format!("Invalid program {} for channel {}, programs go from 0 to 127", program, channel));
+        }
+    }
+    let mut smf = Smf::parse(bytes).map_err(|e| format!("Invalid MIDI file: {}", e))?;
+    let mut patched = HashSet::new();
+
+    for track in smf.tracks.iter_mut() {
+        for event in track.iter_mut() {
+            if let TrackEventKind::Midi { channel, message: MidiMessage::ProgramChange { program } } = &mut event.kind {
+                if let Some(&replacement) = programs.get(&channel.as_int()) {
+                    *program = u7::new(replacement);
+                    patched.insert(channel.as_int());
+                }
+            }
+        }
+    }
+
+    // Channels that never select an instrument get one at the start of the first track using them
+    for (&channel, &program) in programs {
+        if patched.contains(&channel) {
+            continue;
+        }
+        let track = smf.tracks.iter_mut().find(|track| {
+            track.iter().any(|event| matches!(event.kind, TrackEventKind::Midi { channel: c, .. } if c.as_int() == channel))
+        });
+        if let Some(track) = track {
+            track.insert(0, TrackEvent {
+                delta: 0.into(),
+                kind: TrackEventKind::Midi {
+                    channel: channel.into(),
+                    message: MidiMessage::ProgramChange { program: u7::new(program) },
+                },
+            });
+        }
+    }
+
+    let mut output = Vec::new();
+    smf.write_std(&mut output).map_err(|e| format!("Failed to encode MIDI: {}", e))?;
+    Ok(output)
+}
+
+pub fn instrument_name(channel: u8, program: u8) -> &'static str {
+    if channel == DRUM_CHANNEL {
+        return "Drum Kit";
+    }
+    GM_INSTRUMENTS[(program & 0x7F) as usize]
+}
+
+const GM_INSTRUMENTS: [&str; 128] = [
+    "Acoustic Grand Piano", "Bright Acoustic Piano", "Electric Grand Piano", "Honky-tonk Piano",
+    "Electric Piano 1", "Electric Piano 2", "Harpsichord", "Clavinet",
+    "Celesta", "Glockenspiel", "Music Box", "Vibraphone",
+    "Marimba", "Xylophone", "Tubular Bells", "Dulcimer",
+    "Drawbar Organ", "Percussive Organ", "Rock Organ", "Church Organ",
+    "Reed Organ", "Accordion", "Harmonica", "Tango Accordion",
+    "Acoustic Guitar (nylon)", "Acoustic Guitar (steel)", "Electric Guitar (jazz)", "Electric Guitar (clean)",
+    "Electric Guitar (muted)", "Overdriven Guitar", "Distortion Guitar", "Guitar Harmonics",
+    "Acoustic Bass", "Electric Bass (finger)", "Electric Bass (pick)", "Fretless Bass",
+    "Slap Bass 1", "Slap Bass 2", "Synth Bass 1", "Synth Bass 2",
+    "Violin", "Viola", "Cello", "Contrabass",
+    "Tremolo Strings", "Pizzicato Strings", "Orchestral Harp", "Timpani",
+    "String Ensemble 1", "String Ensemble 2", "Synth Strings 1", "Synth Strings 2",
+    "Choir Aahs", "Voice Oohs", "Synth Choir", "Orchestra Hit",
+    "Trumpet", "Trombone", "Tuba", "Muted Trumpet",
+    "French Horn", "Brass Section", "Synth Brass 1", "Synth Brass 2",
+    "Soprano Sax", "Alto Sax", "Tenor Sax", "Baritone Sax",
+    "Oboe", "English Horn", "Bassoon", "Clarinet",
+    "Piccolo", "Flute", "Recorder", "Pan Flute",
+    "Blown Bottle", "Shakuhachi", "Whistle", "Ocarina",
+    "Lead 1 (square)", "Lead 2 (sawtooth)", "Lead 3 (calliope)", "Lead 4 (chiff)",
+    "Lead 5 (charang)", "Lead 6 (voice)", "Lead 7 (fifths)", "Lead 8 (bass + lead)",
+    "Pad 1 (new age)", "Pad 2 (warm)", "Pad 3 (polysynth)", "Pad 4 (choir)",
+    "Pad 5 (bowed)", "Pad 6 (metallic)", "Pad 7 (halo)", "Pad 8 (sweep)",
+    "FX 1 (rain)", "FX 2 (soundtrack)", "FX 3 (crystal)", "FX 4 (atmosphere)",
+    "FX 5 (brightness)", "FX 6 (goblins)", "FX 7 (echoes)", "FX 8 (sci-fi)",
+    "Sitar", "Banjo", "Shamisen", "Koto",
+    "Kalimba", "Bagpipe", "Fiddle", "Shanai",
+    "Tinkle Bell", "Agogo", "Steel Drums", "Woodblock",
+    "Taiko Drum", "Melodic Tom", "Synth Drum", "Reverse Cymbal",
+    "Guitar Fret Noise", "Breath Noise", "Seashore", "Bird Tweet",
+    "Telephone Ring", "Helicopter", "Applause", "Gunshot",
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(delta: u32, kind: TrackEventKind<'static>) -> TrackEvent<'static> {
+        TrackEvent { delta: u28::new(delta), kind }
+    }
+
+    fn midi(channel: u8, message: MidiMessage) -> TrackEventKind<'static> {
+        TrackEventKind::Midi { channel: u4::new(channel), message }
+    }
+
+    fn note(delta: u32, key: u8, on: bool) -> TrackEvent<'static> {
+        let message = if on {
+            MidiMessage::NoteOn { key: u7::new(key), vel: u7::new(90) }
+        } else {
+            MidiMessage::NoteOff { key: u7::new(key), vel: u7::new(0) }
+        };
+        event(delta, midi(0, message))
+    }
+
+    fn program(delta: u32, channel: u8, program: u8) -> TrackEvent<'static> {
+        event(delta, midi(channel, MidiMessage::ProgramChange { program: u7::new(program) }))
+    }
+
+    fn parse(tracks: Vec<Vec<TrackEvent<'static>>>) -> Composition {
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(u15::new(480))));
+        smf.tracks = tracks
+            .into_iter()
+            .map(|mut events| {
+                events.push(event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack)));
+                events
+            })
+            .collect();
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        Composition::from_bytes(&bytes).unwrap()
+    }
+
+    #[test]
+    fn program_overrides_are_range_checked() {
+        let mut smf = Smf::new(Header::new(Format::SingleTrack, Timing::Metrical(u15::new(480))));
+        smf.tracks.push(vec![note(0, 60, true), note(480, 60, false), event(0, TrackEventKind::Meta(MetaMessage::EndOfTrack))]);
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+
+        assert!(override_programs(&bytes, &HashMap::from([(0, 200)])).is_err());
+        assert!(override_programs(&bytes, &HashMap::from([(16, 1)])).is_err());
+        let patched = override_programs(&bytes, &HashMap::from([(0, 73)])).unwrap();
+        assert_eq!(Composition::from_bytes(&patched).unwrap().tracks[0].program, 73);
+    }
+
+    #[test]
+    fn notes_without_length_are_not_written() {
+        let mut composition = Composition::new(480);
+        let notes = vec![Note { start: 0, duration: 0, pitch: 60, velocity: 80 }, Note { start: 0, duration: 480, pitch: 64, velocity: 80 }];
+        composition.tracks.push(Track { name: None, channel: 0, program: 0, notes });
+        let reloaded = Composition::from_bytes(&composition.to_bytes().unwrap()).unwrap();
+        assert_eq!(reloaded.tracks[0].notes, vec![Note { start: 0, duration: 480, pitch: 64, velocity: 80 }]);
+    }
+
+    #[test]
+    fn programs_set_in_another_track_apply_to_the_channel() {
+        let composition = parse(vec![vec![program(0, 0, 40)], vec![note(0, 60, true), note(480, 60, false)]]);
+        assert_eq!(composition.tracks.len(), 1);
+        assert_eq!(composition.tracks[0].program, 40);
+    }
+
+    #[test]
+    fn the_channel_keeps_the_instrument_it_starts_with() {
+        let composition = parse(vec![
+            vec![program(0, 0, 40), program(960, 0, 73)],
+            vec![note(0, 60, true), note(480, 60, false), note(960, 62, true), note(480, 62, false)],
+            vec![program(0, 1, 24)],
+        ]);
+        assert_eq!(composition.tracks[0].program, 40);
+        assert_eq!(composition.tracks[0].notes.len(), 2);
+    }
+
+    #[test]
+    fn seconds_to_tick_inverts_tick_to_seconds() {
+        let mut composition = Composition::new(480);
+        composition.tempos.push(TempoChange { tick: 0, micros_per_beat: 500_000 });
+        composition.tempos.push(TempoChange { tick: 1920, micros_per_beat: 250_000 });
+        composition.tempos.push(TempoChange { tick: 3840, micros_per_beat: 1_000_000 });
+        assert_eq!(composition.tick_to_seconds(1920), 2.0);
+        assert_eq!(composition.tick_to_seconds(3840), 3.0);
+        assert_eq!(composition.tick_to_seconds(4320), 4.0);
+        for tick in (0..6000).step_by(160) {
+            assert_eq!(composition.seconds_to_tick(composition.tick_to_seconds(tick)), tick);
+        }
+    }
+}
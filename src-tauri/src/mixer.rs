@@ -0,0 +1,295 @@
+use std::collections::{BTreeSet, HashMap};
+use std::fs;
+use std::path::PathBuf;
+use midly::num::{u4, u7};
+use midly::{MidiMessage, Smf, TrackEvent, TrackEventKind};
+use serde::{Deserialize, Serialize};
+use tauri::AppHandle;
+use crate::library::{Library, LibraryEntry};
+use crate::midi::{instrument_name, Composition};
+use crate::utils::send_to_frontend;
+
+const VOLUME_CONTROLLER: u8 = 7;
+const PAN_CONTROLLER: u8 = 10;
+const REVERB_CONTROLLER: u8 = 91;
+const STEMS_DIR: &str = "stems";
+
+// Mixer strip of one MIDI channel, in controller values
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ChannelMix {
+    pub channel: u8,
+    // 0 to 127
+    pub volume: u8,
+    // -64 hard left to 63 hard right
+    pub pan: i8,
+    pub mute: bool,
+    pub solo: bool,
+    // Reverb send, 0 to 127
+    pub reverb: u8,
+}
+
+impl Default for ChannelMix {
+    fn default() -> Self {
+        // General MIDI power-on values
+        Self { channel: 0, volume: 100, pan: 0, mute: false, solo: false, reverb: 40 }
+    }
+}
+
+impl ChannelMix {
+    fn controller_values(&self, soloed: bool) -> [(u8, u8); 3] {
+        let audible = !self.mute && (self.solo || !soloed);
+        [
+            (VOLUME_CONTROLLER, if audible { self.volume.min(127) } else { 0 }),
+            (PAN_CONTROLLER, (self.pan.clamp(-64, 63) as i16 + 64) as u8),
+            (REVERB_CONTROLLER, self.reverb.min(127)),
+        ]
+    }
+}
+
+// A mixer strip with a channel's name and instrument, for showing the mixer
+#[derive(Debug, Clone, Serialize)]
+pub struct MixerStrip {
+    pub name: String,
+    pub instrument: String,
+    #[serde(flatten)]
+    pub mix: ChannelMix,
+}
+
+// Sets volume, pan and reverb send of the mixed channels at their start and replaces any
+// controller changes of the file, so the mix holds for the whole piece. Muted channels and
+// channels left out of a solo are turned down to silence rather than removed
+pub fn apply_mixer(bytes: &[u8], channels: &[ChannelMix]) -> Result<Vec<u8>, String> {
+    let mut smf = Smf::parse(bytes).map_err(|e| format!("Invalid MIDI file: {}", e))?;
+    let soloed = channels.iter().any(|mix| mix.solo);
+
+    let used: BTreeSet<u8> = smf
+        .tracks
+        .iter()
+        .flat_map(|track| track.iter())
+        .filter_map(|event| match event.kind {
+            TrackEventKind::Midi { channel, .. } => Some(channel.as_int()),
+            _ => None,
+        })
+        .collect();
+    // Channels without a strip keep their own controllers unless a solo silences them
+    let values: HashMap<u8, [(u8, u8); 3]> = used
+        .iter()
+        .filter_map(|&channel| match channels.iter().find(|mix| mix.channel == channel) {
+            Some(mix) => Some((channel, mix.controller_values(soloed))),
+            None if soloed => Some((channel, ChannelMix { channel, ..ChannelMix::default() }.controller_values(true))),
+            None => None,
+        })
+        .collect();
+
+    for track in smf.tracks.iter_mut() {
+        for event in track.iter_mut() {
+            if let TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } } = &mut event.kind {
+                let mixed = values.get(&channel.as_int()).and_then(|values| values.iter().find(|(number, _)| *number == controller.as_int()));
+                if let Some(&(_, mixed)) = mixed {
+                    *value = u7::new(mixed);
+                }
+            }
+        }
+    }
+
+    for (&channel, controllers) in &values {
+        let track = smf.tracks.iter_mut().find(|track| {
+            track.iter().any(|event| matches!(event.kind, TrackEventKind::Midi { channel: c, .. } if c.as_int() == channel))
+        });
+        if let Some(track) = track {
+            for &(controller, value) in controllers.iter().rev() {
+                track.insert(0, TrackEvent {
+                    delta: 0.into(),
+                    kind: TrackEventKind::Midi {
+                        channel: u4::new(channel),
+                        message: MidiMessage::Controller { controller: u7::new(controller), value: u7::new(value) },
+                    },
+                });
+            }
+        }
+    }
+
+    let mut output = Vec::new();
+    smf.write_std(&mut output).map_err(|e| format!("Failed to encode MIDI: {}", e))?;
+    Ok(output)
+}
+
+fn file_name(text: &str) -> String {
+    let name: String = text.chars().map(|c| if c.is_alphanumeric() { c.to_ascii_lowercase() } else { '-' }).collect();
+    name.split('-').filter(|part| !part.is_empty()).collect::<Vec<_>>().join("-")
+}
+
+// A strip for every channel of the composition, stored settings first and defaults for the rest
+#[tauri::command]
+pub async fn get_mixer(track: String) -> Result<Vec<MixerStrip>, String> {
+    let entry = Library::read()?.get(&track)?.clone();
+    let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to mix", entry.title))?;
+    let composition = Composition::from_file(&midi)?;
+    Ok(composition
+        .summary()
+        .tracks
+        .into_iter()
+        .map(|summary| {
+            let mix = entry.mixer.iter().find(|mix| mix.channel == summary.channel).cloned();
+            MixerStrip {
+                name: summary.name,
+                instrument: summary.instrument,
+                mix: mix.unwrap_or(ChannelMix { channel: summary.channel, ..ChannelMix::default() }),
+            }
+        })
+        .collect())
+}
+
+// Stores the mixer of an entry, used from its next render on
+#[tauri::command]
+pub async fn set_mixer(track: String, channels: Vec<ChannelMix>) -> Result<LibraryEntry, String> {
+    let mut seen = BTreeSet::new();
+    for mix in &channels {
+        if mix.channel > 15 {
+            return Err(format!("MIDI channel {} does not exist", mix.channel + 1));
+        }
+        if !seen.insert(mix.channel) {
+            return Err(format!("Channel {} is mixed twice", mix.channel + 1));
+        }
+        if mix.volume > 127 || mix.reverb > 127 || !(-64..=63).contains(&mix.pan) {
+            return Err(format!("Mixer values of channel {} are out of range", mix.channel + 1));
+        }
+    }
+    Library::update(move |library| {
+        let entry = library.get_mut(&track)?;
+        entry.mixer = channels;
+        entry.render_stale = true;
+        Ok(entry.clone())
+    })
+}
+
+// Renders each channel of an entry to its own WAV file with the channel's volume, pan and send.
+// Mute and solo only shape the full mix, every channel that plays notes gets a stem
+#[tauri::command]
+pub async fn export_stems(app: AppHandle, track: String, folder: Option<String>) -> Result<Vec<String>, String> {
+    let entry = Library::read()?.get(&track)?.clone();
+    let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to export stems from", entry.title))?;
+    let composition = Composition::from_file(&midi)?;
+    let folder = folder.map(PathBuf::from).unwrap_or_else(|| entry.dir().join(STEMS_DIR));
+    fs::create_dir_all(&folder).map_err(|e| format!("Failed to create stems directory: {}", e))?;
+
+    let latest = entry.versions.last();
+    let settings = latest.and_then(|version| version.settings.clone()).unwrap_or_default();
+    let soundfont = latest.and_then(|version| version.soundfont.clone()).map(PathBuf::from);
+
+    let channels: BTreeSet<u8> = composition.tracks.iter().filter(|track| !track.notes.is_empty()).map(|track| track.channel).collect();
+    let mut stems = Vec::new();
+    for (number, &channel) in channels.iter().enumerate() {
+        let name = composition
+            .tracks
+            .iter()
+            .find(|track| track.channel == channel)
+            .map(|track| track.name.clone().unwrap_or_else(|| instrument_name(channel, track.program).to_string()))
+            .unwrap_or_default();
+        send_to_frontend(&app, format!("Rendering stem {} of {}: {}", number + 1, channels.len(), name), "info");
+
+        let mixer: Vec<ChannelMix> = channels
+            .iter()
+            .map(|&other| {
+                let stored = entry.mixer.iter().find(|mix| mix.channel == other).cloned();
+                let mix = stored.unwrap_or(ChannelMix { channel: other, ..ChannelMix::default() });
+                ChannelMix { mute: false, solo: other == channel, ..mix }
+            })
+            .collect();
+        let output = folder.join(format!("{:02}-{}.wav", number + 1, file_name(&name)));
+        entry.render_to(&midi, soundfont.clone(), &settings, &mixer, &output)?;
+        stems.push(output.display().to_string());
+    }
+
+    send_to_frontend(&app, format!("Exported {} stems to {}", stems.len(), folder.display()), "success");
+    Ok(stems)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use midly::num::u28;
+    use midly::{Format, Header, MetaMessage, Timing};
+
+    fn event(delta: u32, channel: u8, message: MidiMessage) -> TrackEvent<'static> {
+        TrackEvent { delta: u28::new(delta), kind: TrackEventKind::Midi { channel: u4::new(channel), message } }
+    }
+
+    fn controller(delta: u32, channel: u8, controller: u8, value: u8) -> TrackEvent<'static> {
+        event(delta, channel, MidiMessage::Controller { controller: u7::new(controller), value: u7::new(value) })
+    }
+
+    fn notes(channel: u8) -> Vec<TrackEvent<'static>> {
+        vec![
+            event(0, channel, MidiMessage::NoteOn { key: u7::new(60), vel: u7::new(90) }),
+            event(480, channel, MidiMessage::NoteOff { key: u7::new(60), vel: u7::new(0) }),
+        ]
+    }
+
+    // Channel 0 turns its volume down halfway through, channel 1 has no controllers and
+    // channel 2 sets its own volume and pan
+    fn file() -> Vec<u8> {
+        let mut first = notes(0);
+        first.insert(1, controller(240, 0, VOLUME_CONTROLLER, 50));
+        first[2].delta = u28::new(240);
+        let mut third = vec![controller(0, 2, VOLUME_CONTROLLER, 70), controller(0, 2, PAN_CONTROLLER, 20)];
+        third.extend(notes(2));
+        let mut smf = Smf::new(Header::new(Format::Parallel, Timing::Metrical(480.into())));
+        smf.tracks = vec![first, notes(1), third];
+        for track in smf.tracks.iter_mut() {
+            track.push(TrackEvent { delta: 0.into(), kind: TrackEventKind::Meta(MetaMessage::EndOfTrack) });
+        }
+        let mut bytes = Vec::new();
+        smf.write_std(&mut bytes).unwrap();
+        bytes
+    }
+
+    // Controller changes per channel in file order
+    fn controllers(bytes: &[u8]) -> Vec<Vec<(u8, u8)>> {
+        let smf = Smf::parse(bytes).unwrap();
+        let mut channels = vec![Vec::new(); 3];
+        for event in smf.tracks.iter().flatten() {
+            if let TrackEventKind::Midi { channel, message: MidiMessage::Controller { controller, value } } = event.kind {
+                channels[channel.as_int() as usize].push((controller.as_int(), value.as_int()));
+            }
+        }
+        channels
+    }
+
+    fn strip(channel: u8) -> ChannelMix {
+        ChannelMix { channel, ..ChannelMix::default() }
+    }
+
+    #[test]
+    fn strips_set_and_override_controllers() {
+        let mix = [ChannelMix { volume: 90, pan: -64, reverb: 10, ..strip(0) }, ChannelMix { pan: 63, ..strip(1) }];
+        let channels = controllers(&apply_mixer(&file(), &mix).unwrap());
+        assert_eq!(channels[0], vec![(7, 90), (10, 0), (91, 10), (7, 90)]);
+        assert_eq!(channels[1], vec![(7, 100), (10, 127), (91, 40)]);
+        // Channels without a strip keep what the file says
+        assert_eq!(channels[2], vec![(7, 70), (10, 20)]);
+    }
+
+    #[test]
+    fn mute_and_solo_silence_channels() {
+        let muted = controllers(&apply_mixer(&file(), &[ChannelMix { mute: true, ..strip(1) }]).unwrap());
+        assert_eq!(muted[1], vec![(7, 0), (10, 64), (91, 40)]);
+        assert_eq!(muted[2], vec![(7, 70), (10, 20)]);
+
+        let soloed = controllers(&apply_mixer(&file(), &[ChannelMix { solo: true, volume: 80, ..strip(1) }, strip(0)]).unwrap());
+        assert_eq!(soloed[0], vec![(7, 0), (10, 64), (91, 40), (7, 0)]);
+        assert_eq!(soloed[1], vec![(7, 80), (10, 64), (91, 40)]);
+        // A solo silences channels without a strip too, their own volume changes included
+        assert_eq!(soloed[2], vec![(7, 0), (10, 64), (91, 40), (7, 0), (10, 64)]);
+    }
+
+    #[test]
+    fn notes_and_timing_are_left_alone() {
+        let mixed = apply_mixer(&file(), &[strip(0), strip(1), strip(2)]).unwrap();
+        let before = Composition::from_bytes(&file()).unwrap();
+        let after = Composition::from_bytes(&mixed).unwrap();
+        assert_eq!(after.tracks, before.tracks);
+        assert!(apply_mixer(b"not midi", &[]).is_err());
+    }
+}
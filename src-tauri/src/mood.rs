@@ -0,0 +1,216 @@
+use serde::{Deserialize, Serialize};
+use crate::midi::instrument_name;
+
+// Valence (-1 negative to 1 positive) and arousal (0 calm to 1 excited) of common English words
+const LEXICON: [(&str, f64, f64); 96] = [
+    ("happy", 0.9, 0.6), ("happiness", 0.9, 0.6), ("joy", 0.95, 0.7), ("joyful", 0.95, 0.7), ("love", 0.9, 0.55),
+    ("lovely", 0.8, 0.4), ("beautiful", 0.85, 0.45), ("bright", 0.7, 0.55), ("sun", 0.6, 0.5), ("sunny", 0.75, 0.55),
+    ("smile", 0.8, 0.45), ("laugh", 0.85, 0.7), ("dance", 0.75, 0.8), ("celebrate", 0.9, 0.85), ("party", 0.75, 0.9),
+    ("hope", 0.7, 0.45), ("warm", 0.6, 0.3), ("gentle", 0.6, 0.15), ("calm", 0.55, 0.05), ("peace", 0.75, 0.05),
+    ("peaceful", 0.75, 0.05), ("quiet", 0.3, 0.05), ("soft", 0.4, 0.1), ("dream", 0.55, 0.25), ("spring", 0.6, 0.5),
+    ("morning", 0.45, 0.4), ("summer", 0.65, 0.55), ("free", 0.7, 0.6), ("friend", 0.75, 0.45), ("home", 0.6, 0.2),
+    ("win", 0.8, 0.8), ("victory", 0.85, 0.85), ("triumph", 0.85, 0.85), ("proud", 0.7, 0.6), ("wonder", 0.65, 0.5),
+    ("magic", 0.7, 0.6), ("adventure", 0.7, 0.8), ("excited", 0.8, 0.9), ("exciting", 0.8, 0.9), ("energy", 0.5, 0.85),
+    ("fast", 0.2, 0.8), ("run", 0.1, 0.8), ("fly", 0.5, 0.7), ("fire", -0.1, 0.85), ("wild", 0.2, 0.85),
+    ("sad", -0.8, 0.3), ("sadness", -0.8, 0.3), ("sorrow", -0.85, 0.3), ("grief", -0.9, 0.4), ("cry", -0.7, 0.5),
+    ("tears", -0.7, 0.4), ("lonely", -0.75, 0.2), ("alone", -0.5, 0.15), ("loss", -0.8, 0.35), ("lost", -0.6, 0.4),
+    ("dark", -0.5, 0.4), ("darkness", -0.6, 0.4), ("night", -0.1, 0.2), ("rain", -0.3, 0.3), ("cold", -0.45, 0.3),
+    ("grey", -0.4, 0.1), ("gray", -0.4, 0.1), ("winter", -0.2, 0.2), ("death", -0.9, 0.5), ("dead", -0.85, 0.45),
+    ("die", -0.9, 0.55), ("pain", -0.85, 0.6), ("hurt", -0.75, 0.55), ("broken", -0.7, 0.4), ("empty", -0.55, 0.1),
+    ("tired", -0.4, 0.05), ("slow", -0.1, 0.1), ("fear", -0.8, 0.8), ("afraid", -0.75, 0.75), ("scared", -0.75, 0.8),
+    ("terror", -0.9, 0.95), ("horror", -0.9, 0.9), ("angry", -0.8, 0.9), ("anger", -0.8, 0.9), ("rage", -0.85, 0.95),
+    ("hate", -0.9, 0.85), ("storm", -0.4, 0.85), ("war", -0.85, 0.9), ("battle", -0.4, 0.9), ("fight", -0.4, 0.85),
+    ("chase", -0.1, 0.85), ("danger", -0.6, 0.85), ("tense", -0.4, 0.75), ("mystery", 0.0, 0.5), ("strange", -0.15, 0.5),
+    ("ghost", -0.5, 0.6), ("old", -0.1, 0.15), ("memory", 0.2, 0.25), ("remember", 0.2, 0.25), ("goodbye", -0.5, 0.3),
+    ("miss", -0.45, 0.3),
+];
+
+const NEGATIONS: [&str; 6] = ["not", "no", "never", "without", "don't", "can't"];
+const INTENSIFIERS: [&str; 5] = ["very", "so", "really", "extremely", "totally"];
+
+// Frequent function words per language, matched against the text to guess its language
+const STOPWORDS: [(&str, &[&str]); 6] = [
+    ("en", &["the", "and", "of", "to", "is", "in", "it", "that", "with", "you", "was", "for"]),
+    ("es", &["el", "la", "de", "que", "y", "en", "los", "las", "por", "con", "una", "es"]),
+    ("fr", &["le", "la", "de", "et", "les", "des", "est", "une", "dans", "que", "pour", "pas"]),
+    ("de", &["der", "die", "und", "das", "ist", "nicht", "ein", "eine", "mit", "den", "ich", "zu"]),
+    ("it", &["il", "di", "che", "e", "la", "un", "per", "non", "una", "sono", "gli", "con"]),
+    ("pt", &["o", "de", "que", "e", "do", "da", "em", "um", "para", "não", "uma", "os"]),
+];
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SuggestedInstrument {
+    pub program: u8,
+    pub name: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MusicSuggestion {
+    pub key: String,
+    pub minor: bool,
+    pub tempo_bpm: u32,
+    // Overall loudness as a dynamic marking from pp to ff
+    pub dynamics: String,
+    pub instruments: Vec<SuggestedInstrument>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MoodAnalysis {
+    pub language: String,
+    pub valence: f64,
+    pub arousal: f64,
+    // Share of words found in the lexicon, low for short or non-English texts
+    pub coverage: f64,
+    pub sentences: usize,
+    pub average_sentence_length: f64,
+    pub suggestion: MusicSuggestion,
+}
+
+fn words(text: &str) -> Vec<String> {
+    text.split(|c: char| !(c.is_alphanumeric() || c == '\''))
+        .filter(|word| !word.is_empty())
+        .map(|word| word.to_lowercase())
+        .collect()
+}
+
+fn detect_language(words: &[String]) -> String {
+    STOPWORDS
+        .iter()
+        .map(|(language, stopwords)| (language, words.iter().filter(|word| stopwords.contains(&word.as_str())).count()))
+        .filter(|(_, hits)| *hits > 0)
+        .max_by_key(|(_, hits)| *hits)
+        .map_or("unknown", |(language, _)| *language)
+        .to_string()
+}
+
+fn suggest(valence: f64, arousal: f64) -> MusicSuggestion {
+    let minor = valence < -0.05;
+    // Bright sharp keys for excited moods, flat keys for calm or dark ones
+    let key = match (minor, arousal >= 0.5) {
+        (false, true) => if valence > 0.5 { "D major" } else { "G major" },
+        (false, false) => if valence > 0.5 { "F major" } else { "C major" },
+        (true, true) => if valence < -0.5 { "C minor" } else { "E minor" },
+        (true, false) => if valence < -0.5 { "F minor" } else { "D minor" },
+    };
+    let dynamics = match arousal {
+        a if a < 0.2 => "pp",
+        a if a < 0.35 => "p",
+        a if a < 0.5 => "mp",
+        a if a < 0.65 => "mf",
+        a if a < 0.8 => "f",
+        _ => "ff",
+    };
+    let programs: &[u8] = match (minor, arousal >= 0.5) {
+        (false, true) => &[0, 56, 25, 48],  // Piano, trumpet, steel guitar, strings
+        (false, false) => &[0, 73, 24, 89], // Piano, flute, nylon guitar, warm pad
+        (true, true) => &[48, 30, 33, 47],  // Strings, distortion guitar, bass, timpani
+        (true, false) => &[0, 42, 71, 52],  // Piano, cello, clarinet, choir
+    };
+    MusicSuggestion {
+        key: key.to_string(),
+        minor,
+        tempo_bpm: (60.0 + arousal * 100.0).round() as u32,
+        dynamics: dynamics.to_string(),
+        instruments: programs
+            .iter()
+            .map(|&program| SuggestedInstrument { program, name: instrument_name(0, program).to_string() })
+            .collect(),
+    }
+}
+
+pub fn analyze(text: &str) -> MoodAnalysis {
+    let words = words(text);
+    let language = detect_language(&words);
+
+    let mut valence = 0.0;
+    let mut arousal = 0.0;
+    let mut matched = 0usize;
+    for (index, word) in words.iter().enumerate() {
+        if let Some(&(_, word_valence, word_arousal)) = LEXICON.iter().find(|(entry, _, _)| entry == word) {
+            let previous = |distance: usize| index.checked_sub(distance).map(|i| words[i].as_str());
+            // "not happy" counts as mildly negative, "very happy" as stronger
+            let negated = (1..=2).any(|distance| previous(distance).is_some_and(|w| NEGATIONS.contains(&w)));
+            let weight = if previous(1).is_some_and(|w| INTENSIFIERS.contains(&w)) { 1.5 } else { 1.0 };
+            valence += (if negated { -0.5 * word_valence } else { word_valence }) * weight;
+            arousal += word_arousal * weight;
+            matched += 1;
+        }
+    }
+    let mut valence = if matched > 0 { valence / matched as f64 } else { 0.0 };
+    let mut arousal = if matched > 0 { arousal / matched as f64 } else { 0.4 };
+
+    let sentences = text.split(['.', '!', '?', '\n']).filter(|sentence| sentence.chars().any(|c| c.is_alphanumeric())).count().max(1);
+    let average_sentence_length = words.len() as f64 / sentences as f64;
+    let exclamations = text.matches('!').count() as f64 / sentences as f64;
+    let questions = text.matches('?').count() as f64 / sentences as f64;
+    let ellipses = text.matches("...").count() as f64 / sentences as f64;
+    let capitals = text.chars().filter(|c| c.is_uppercase()).count() as f64 / text.chars().filter(|c| c.is_alphabetic()).count().max(1) as f64;
+
+    // Exclamations and shouting excite, trailing ellipses and long flowing sentences calm
+    arousal += 0.25 * exclamations.min(1.0) + 0.3 * (capitals - 0.1).max(0.0) - 0.15 * ellipses.min(1.0);
+    arousal -= 0.01 * (average_sentence_length - 12.0).clamp(-10.0, 20.0);
+    valence -= 0.1 * questions.min(1.0) + 0.1 * ellipses.min(1.0);
+    valence = valence.clamp(-1.0, 1.0);
+    arousal = arousal.clamp(0.0, 1.0);
+
+    MoodAnalysis {
+        language,
+        valence,
+        arousal,
+        coverage: if words.is_empty() { 0.0 } else { matched as f64 / words.len() as f64 },
+        sentences,
+        average_sentence_length,
+        suggestion: suggest(valence, arousal),
+    }
+}
+
+#[tauri::command]
+pub async fn analyze_text(text: String) -> Result<MoodAnalysis, String> {
+    Ok(analyze(&text))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn negation_and_intensifiers_shape_valence() {
+        let plain = analyze("It is warm").valence;
+        assert!((plain - 0.6).abs() < 1e-9);
+        // "not warm" is mildly negative, "very warm" counts half again
+        assert!((analyze("It is not warm").valence + 0.3).abs() < 1e-9);
+        assert!((analyze("It was never so warm").valence + 0.45).abs() < 1e-9);
+        assert!((analyze("It is very warm").valence - 0.9).abs() < 1e-9);
+        assert!(analyze("I am not happy").valence < 0.0);
+    }
+
+    #[test]
+    fn language_follows_stopwords() {
+        assert_eq!(analyze("The dog and the cat are in the garden").language, "en");
+        assert_eq!(analyze("El perro y la casa de los niños").language, "es");
+        assert_eq!(analyze("Der Hund und die Katze sind nicht hier").language, "de");
+        assert_eq!(analyze("Lorem ipsum dolor").language, "unknown");
+        // Unknown words leave the mood neutral
+        let foreign = analyze("Der Hund und das Haus");
+        assert_eq!(foreign.coverage, 0.0);
+        assert_eq!(foreign.suggestion.key, "C major");
+    }
+
+    #[test]
+    fn quadrants_pick_mode_key_and_tempo() {
+        let cases = [
+            ((0.8, 0.8), "D major", false, 140, "ff"),
+            ((0.3, 0.2), "C major", false, 80, "p"),
+            ((-0.8, 0.9), "C minor", true, 150, "ff"),
+            ((-0.3, 0.1), "D minor", true, 70, "pp"),
+        ];
+        for ((valence, arousal), key, minor, tempo_bpm, dynamics) in cases {
+            let suggestion = suggest(valence, arousal);
+            assert_eq!(suggestion.key, key);
+            assert_eq!(suggestion.minor, minor);
+            assert_eq!(suggestion.tempo_bpm, tempo_bpm);
+            assert_eq!(suggestion.dynamics, dynamics);
+            assert_eq!(suggestion.instruments.len(), 4);
+        }
+    }
+}
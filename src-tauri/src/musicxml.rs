@@ -0,0 +1,248 @@
+use std::fmt::Write;
+use crate::notation::{Beam, Clef, Event, Measure, Part, Score};
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+fn accidental_name(alter: i32) -> &'static str {
+    match alter {
+        -2 => "flat-flat",
+        -1 => "flat",
+        1 => "sharp",
+        2 => "double-sharp",
+        _ => "natural",
+    }
+}
+
+fn attributes(out: &mut String, score: &Score, measure: &Measure, part: &Part, first: bool) {
+    if !first && !measure.key_change && !measure.time_change {
+        return;
+    }
+    out.push_str("      <attributes>\n");
+    if first {
+        let _ = writeln!(out, "        <divisions>{}</divisions>", score.ticks_per_beat);
+    }
+    if measure.key_change {
+        let mode = if measure.key.minor { "minor" } else { "major" };
+        let _ = writeln!(out, "        <key><fifths>{}</fifths><mode>{}</mode></key>", measure.key.signature(), mode);
+    }
+    if measure.time_change {
+        let _ = writeln!(out, "        <time><beats>{}</beats><beat-type>{}</beat-type></time>", measure.time.0, measure.time.1);
+    }
+    if first {
+        let clef = match part.clef {
+            Clef::Treble => "<sign>G</sign><line>2</line>",
+            Clef::Bass => "<sign>F</sign><line>4</line>",
+            Clef::Percussion => "<sign>percussion</sign>",
+        };
+        let _ = writeln!(out, "        <clef>{}</clef>", clef);
+    }
+    out.push_str("      </attributes>\n");
+}
+
+fn note(out: &mut String, event: &Event, voice: usize, percussion: bool) {
+    let pitches: Vec<Option<_>> = if event.pitches.is_empty() { vec![None] } else { event.pitches.iter().map(Some).collect() };
+    for (index, pitch) in pitches.into_iter().enumerate() {
+        out.push_str("      <note>\n");
+        if index > 0 {
+            out.push_str("        <chord/>\n");
+        }
+        match pitch {
+            None if event.measure_rest => out.push_str("        <rest measure=\"yes\"/>\n"),
+            None => out.push_str("        <rest/>\n"),
+            Some(pitch) if percussion => {
+                let _ = writeln!(out, "        <unpitched><display-step>{}</display-step><display-octave>{}</display-octave></unpitched>", pitch.step, pitch.octave);
+            }
+            Some(pitch) => {
+                let alter = if pitch.alter != 0 { format!("<alter>{}</alter>", pitch.alter) } else { String::new() };
+                let _ = writeln!(out, "        <pitch><step>{}</step>{}<octave>{}</octave></pitch>", pitch.step, alter, pitch.octave);
+            }
+        }
+        let _ = writeln!(out, "        <duration>{}</duration>", event.duration);
+        if event.tie_stop {
+            out.push_str("        <tie type=\"stop\"/>\n");
+        }
+        if event.tie_start {
+            out.push_str("        <tie type=\"start\"/>\n");
+        }
+        let _ = writeln!(out, "        <voice>{}</voice>", voice + 1);
+        if !event.measure_rest {
+            let _ = writeln!(out, "        <type>{}</type>", event.value.name);
+            for _ in 0..event.value.dots {
+                out.push_str("        <dot/>\n");
+            }
+        }
+        if let Some(accidental) = pitch.and_then(|pitch| pitch.accidental) {
+            let _ = writeln!(out, "        <accidental>{}</accidental>", accidental_name(accidental));
+        }
+        if let (Some(beam), 0) = (event.beam, index) {
+            let beam = match beam {
+                Beam::Begin => "begin",
+                Beam::Continue => "continue",
+                Beam::End => "end",
+            };
+            let _ = writeln!(out, "        <beam number=\"1\">{}</beam>", beam);
+        }
+        if event.tie_stop || event.tie_start {
+            out.push_str("        <notations>");
+            if event.tie_stop {
+                out.push_str("<tied type=\"stop\"/>");
+            }
+            if event.tie_start {
+                out.push_str("<tied type=\"start\"/>");
+            }
+            out.push_str("</notations>\n");
+        }
+        out.push_str("      </note>\n");
+    }
+}
+
+// Partwise MusicXML 4.0 with one part per instrument and voices separated by backups
+pub fn write(score: &Score) -> String {
+    let mut out = String::new();
+    out.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\" standalone=\"no\"?>\n");
+    out.push_str("<!DOCTYPE score-partwise PUBLIC \"-//Recordare//DTD MusicXML 4.0 Partwise//EN\" \"http://www.musicxml.org/dtds/partwise.dtd\">\n");
+    out.push_str("<score-partwise version=\"4.0\">\n");
+    let _ = writeln!(out, "  <work><work-title>{}</work-title></work>", escape(&score.title));
+    out.push_str("  <identification><encoding><software>Text Tunes</software></encoding></identification>\n");
+
+    out.push_str("  <part-list>\n");
+    for (index, part) in score.parts.iter().enumerate() {
+        let id = format!("P{}", index + 1);
+        let _ = writeln!(out, "    <score-part id=\"{}\">", id);
+        let _ = writeln!(out, "      <part-name>{}</part-name>", escape(&part.name));
+        let _ = writeln!(out, "      <score-instrument id=\"{}-I1\"><instrument-name>{}</instrument-name></score-instrument>", id, escape(&part.instrument));
+        let _ = writeln!(
+            out,
+            "      <midi-instrument id=\"{}-I1\"><midi-channel>{}</midi-channel><midi-program>{}</midi-program></midi-instrument>",
+            id,
+            part.channel + 1,
+            part.program as u16 + 1
+        );
+        out.push_str("    </score-part>\n");
+    }
+    out.push_str("  </part-list>\n");
+
+    for (index, part) in score.parts.iter().enumerate() {
+        let _ = writeln!(out, "  <part id=\"P{}\">", index + 1);
+        for (number, (measure, voices)) in score.measures.iter().zip(&part.measures).enumerate() {
+            let _ = writeln!(out, "    <measure number=\"{}\">", number + 1);
+            attributes(&mut out, score, measure, part, number == 0);
+            if let Some(bpm) = measure.tempo.filter(|_| index == 0) {
+                let _ = writeln!(
+                    out,
+                    "      <direction placement=\"above\"><direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>{:.0}</per-minute></metronome></direction-type><sound tempo=\"{:.2}\"/></direction>",
+                    bpm,
+                    bpm
+                );
+            }
+            for (voice, events) in voices.iter().enumerate().filter(|(_, events)| !events.is_empty()) {
+                if voice > 0 {
+                    let _ = writeln!(out, "      <backup><duration>{}</duration></backup>", measure.length);
+                }
+                for event in events {
+                    note(&mut out, event, voice, part.clef == Clef::Percussion);
+                }
+            }
+            out.push_str("    </measure>\n");
+        }
+        out.push_str("  </part>\n");
+    }
+    out.push_str("</score-partwise>\n");
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{Composition, KeySignature, Note, Track};
+    use crate::notation::build;
+
+    // Two voices in the first bar, a tie over the bar line and a key change in the second bar,
+    // at four ticks per beat to keep durations readable
+    #[test]
+    fn score_matches_snapshot() {
+        let mut composition = Composition::new(4);
+        composition.key_signatures.push(KeySignature { tick: 0, sharps: 1, minor: false });
+        composition.key_signatures.push(KeySignature { tick: 16, sharps: -1, minor: false });
+        let notes = vec![
+            Note { start: 0, duration: 20, pitch: 72, velocity: 80 },
+            Note { start: 0, duration: 8, pitch: 66, velocity: 80 },
+            Note { start: 20, duration: 4, pitch: 70, velocity: 80 },
+        ];
+        composition.tracks.push(Track { name: Some("Piano & Voice".to_string()), channel: 0, program: 0, notes });
+        let expected = r#"<?xml version="1.0" encoding="UTF-8" standalone="no"?>
+<!DOCTYPE score-partwise PUBLIC "-//Recordare//DTD MusicXML 4.0 Partwise//EN" "http://www.musicxml.org/dtds/partwise.dtd">
+<score-partwise version="4.0">
+  <work><work-title>Snap</work-title></work>
+  <identification><encoding><software>Text Tunes</software></encoding></identification>
+  <part-list>
+    <score-part id="P1">
+      <part-name>Piano &amp; Voice</part-name>
+      <score-instrument id="P1-I1"><instrument-name>Acoustic Grand Piano</instrument-name></score-instrument>
+      <midi-instrument id="P1-I1"><midi-channel>1</midi-channel><midi-program>1</midi-program></midi-instrument>
+    </score-part>
+  </part-list>
+  <part id="P1">
+    <measure number="1">
+      <attributes>
+        <divisions>4</divisions>
+        <key><fifths>1</fifths><mode>major</mode></key>
+        <time><beats>4</beats><beat-type>4</beat-type></time>
+        <clef><sign>G</sign><line>2</line></clef>
+      </attributes>
+      <direction placement="above"><direction-type><metronome><beat-unit>quarter</beat-unit><per-minute>120</per-minute></metronome></direction-type><sound tempo="120.00"/></direction>
+      <note>
+        <pitch><step>C</step><octave>5</octave></pitch>
+        <duration>16</duration>
+        <tie type="start"/>
+        <voice>1</voice>
+        <type>whole</type>
+        <notations><tied type="start"/></notations>
+      </note>
+      <backup><duration>16</duration></backup>
+      <note>
+        <pitch><step>F</step><alter>1</alter><octave>4</octave></pitch>
+        <duration>8</duration>
+        <voice>2</voice>
+        <type>half</type>
+      </note>
+      <note>
+        <rest/>
+        <duration>8</duration>
+        <voice>2</voice>
+        <type>half</type>
+      </note>
+    </measure>
+    <measure number="2">
+      <attributes>
+        <key><fifths>-1</fifths><mode>major</mode></key>
+      </attributes>
+      <note>
+        <pitch><step>C</step><octave>5</octave></pitch>
+        <duration>4</duration>
+        <tie type="stop"/>
+        <voice>1</voice>
+        <type>quarter</type>
+        <notations><tied type="stop"/></notations>
+      </note>
+      <note>
+        <pitch><step>B</step><alter>-1</alter><octave>4</octave></pitch>
+        <duration>4</duration>
+        <voice>1</voice>
+        <type>quarter</type>
+      </note>
+      <note>
+        <rest/>
+        <duration>8</duration>
+        <voice>1</voice>
+        <type>half</type>
+      </note>
+    </measure>
+  </part>
+</score-partwise>
+"#;
+        assert_eq!(write(&build(&composition, "Snap")), expected);
+    }
+}
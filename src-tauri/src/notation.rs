@@ -0,0 +1,480 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::PathBuf;
+use crate::library::Library;
+use crate::midi::{instrument_name, Composition, DRUM_CHANNEL};
+use crate::{abc, engraving, lilypond, musicxml};
+use crate::theory::{composition_key, signature_alterations, Key};
+
+const LETTERS: [char; 7] = ['C', 'D', 'E', 'F', 'G', 'A', 'B'];
+const LETTER_STEPS: [i32; 7] = [0, 2, 4, 5, 7, 9, 11];
+// Notes are quantized to sixteenths, so every length is a sum of the values below
+const GRID_PER_BEAT: u64 = 4;
+// Note values in quarter notes times eight, longest first
+const NOTE_VALUES: [(u64, &str, u8); 10] = [
+    (48, "whole", 1),
+    (32, "whole", 0),
+    (24, "half", 1),
+    (16, "half", 0),
+    (12, "quarter", 1),
+    (8, "quarter", 0),
+    (6, "eighth", 1),
+    (4, "eighth", 0),
+    (3, "16th", 1),
+    (2, "16th", 0),
+];
+// Overlapping notes of one instrument are spread over at most this many voices
+const MAX_VOICES: usize = 4;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Pitch {
+    pub midi: u8,
+    pub step: char,
+    pub alter: i32,
+    pub octave: i32,
+    // Accidental to print, when the alteration differs from the key or an earlier note in the bar
+    pub accidental: Option<i32>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Beam {
+    Begin,
+    Continue,
+    End,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NoteValue {
+    pub name: &'static str,
+    pub dots: u8,
+}
+
+// A note, chord or rest within one measure; an empty pitch list is a rest
+#[derive(Debug, Clone)]
+pub struct Event {
+    pub start: u64,
+    pub duration: u64,
+    pub value: NoteValue,
+    pub pitches: Vec<Pitch>,
+    pub tie_start: bool,
+    pub tie_stop: bool,
+    pub beam: Option<Beam>,
+    pub measure_rest: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Clef {
+    Treble,
+    Bass,
+    Percussion,
+}
+
+#[derive(Debug, Clone)]
+pub struct Part {
+    pub name: String,
+    pub instrument: String,
+    pub channel: u8,
+    pub program: u8,
+    pub clef: Clef,
+    // Events per measure and voice
+    pub measures: Vec<Vec<Vec<Event>>>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Measure {
+    pub start: u64,
+    pub length: u64,
+    pub time: (u8, u8),
+    pub key: Key,
+    // Set on the first measure and wherever the value changes
+    pub time_change: bool,
+    pub key_change: bool,
+    pub tempo: Option<f64>,
+}
+
+#[derive(Debug, Clone)]
+pub struct Score {
+    pub title: String,
+    pub ticks_per_beat: u64,
+    pub measures: Vec<Measure>,
+    pub parts: Vec<Part>,
+}
+
+// Notes of equal start and end sounding together
+struct Group {
+    start: u64,
+    end: u64,
+    pitches: Vec<u8>,
+}
+
+// Splits a length into note values, longest first
+fn note_values(duration: u64, ticks_per_beat: u64) -> Vec<(u64, NoteValue)> {
+    let mut values = Vec::new();
+    let mut left = duration;
+    while left > 0 {
+        let (length, name, dots) = NOTE_VALUES
+            .iter()
+            .map(|(eighths, name, dots)| (eighths * ticks_per_beat / 8, *name, *dots))
+            .find(|(length, _, _)| *length <= left && *length > 0)
+            .unwrap_or((left, "16th", 0));
+        values.push((length, NoteValue { name, dots }));
+        left -= length;
+    }
+    values
+}
+
+// Spells a MIDI pitch in the given key, preferring letters of the key, then naturals,
+// then sharps in sharp keys and flats in flat keys
+fn spell(midi: u8, key: &Key) -> Pitch {
+    let alterations = signature_alterations(key.signature());
+    let class = midi as i32 % 12;
+    let matches = |letter: usize, alter: i32| (LETTER_STEPS[letter] + alter).rem_euclid(12) == class;
+    let sharp = if key.uses_flats() { -1 } else { 1 };
+    let (letter, alter) = (0..7)
+        .find(|&letter| matches(letter, alterations[letter]))
+        .map(|letter| (letter, alterations[letter]))
+        .or_else(|| (0..7).find(|&letter| matches(letter, 0)).map(|letter| (letter, 0)))
+        .or_else(|| (0..7).find(|&letter| matches(letter, sharp)).map(|letter| (letter, sharp)))
+        .unwrap_or((0, 0));
+    let octave = (midi as i32 - LETTER_STEPS[letter] - alter).div_euclid(12) - 1;
+    Pitch { midi, step: LETTERS[letter], alter, octave, accidental: None }
+}
+
+// Groups notes into chords and spreads overlapping ones over separate voices
+fn split_voices(mut groups: Vec<Group>) -> Vec<Vec<Group>> {
+    groups.sort_by_key(|group| group.start);
+    let mut voices: Vec<Vec<Group>> = Vec::new();
+    for group in groups {
+        let free = voices.iter().position(|voice| voice.last().is_none_or(|last| last.end <= group.start));
+        match free {
+            Some(index) => voices[index].push(group),
+            None if voices.len() < MAX_VOICES => voices.push(vec![group]),
+            None => {
+                // Out of voices, the earliest ending note makes room
+                let Some(voice) = voices.iter_mut().min_by_key(|voice| voice.last().map_or(0, |last| last.end)) else { continue };
+                let Some(last) = voice.last_mut() else { continue };
+                if last.start == group.start {
+                    last.end = last.end.min(group.end);
+                    last.pitches.extend(group.pitches);
+                } else {
+                    last.end = group.start;
+                    voice.push(group);
+                }
+            }
+        }
+    }
+    voices
+}
+
+// Cuts a voice into measures, ties notes across bar lines and fills the gaps with rests
+fn measure_events(voice: &[Group], measure: &Measure, ticks_per_beat: u64, first_voice: bool) -> Vec<Event> {
+    let end = measure.start + measure.length;
+    let mut events = Vec::new();
+    let mut cursor = measure.start;
+    let push = |events: &mut Vec<Event>, start: u64, duration: u64, pitches: &[u8], tie_stop: bool, tie_start: bool| {
+        let values = note_values(duration, ticks_per_beat);
+        let count = values.len();
+        let mut offset = start - measure.start;
+        for (index, (length, value)) in values.into_iter().enumerate() {
+            let rest = pitches.is_empty();
+            events.push(Event {
+                start: offset,
+                duration: length,
+                value,
+                pitches: pitches.iter().map(|&midi| Pitch { midi, step: 'C', alter: 0, octave: 4, accidental: None }).collect(),
+                tie_start: !rest && (tie_start || index + 1 < count),
+                tie_stop: !rest && (tie_stop || index > 0),
+                beam: None,
+                measure_rest: false,
+            });
+            offset += length;
+        }
+    };
+    for group in voice.iter().filter(|group| group.start < end && group.end > measure.start) {
+        let start = group.start.max(measure.start);
+        if start > cursor {
+            push(&mut events, cursor, start - cursor, &[], false, false);
+        }
+        let stop = group.end.min(end);
+        push(&mut events, start, stop - start, &group.pitches, group.start < measure.start, group.end > end);
+        cursor = stop;
+    }
+    if events.is_empty() {
+        if !first_voice {
+            return events;
+        }
+        return vec![Event {
+            start: 0,
+            duration: measure.length,
+            value: NoteValue { name: "whole", dots: 0 },
+            pitches: Vec::new(),
+            tie_start: false,
+            tie_stop: false,
+            beam: None,
+            measure_rest: true,
+        }];
+    }
+    if cursor < end {
+        push(&mut events, cursor, end - cursor, &[], false, false);
+    }
+    events
+}
+
+// Beams runs of eighths and shorter notes within a beat, a dotted quarter in compound meters
+fn beam(events: &mut [Event], time: (u8, u8), ticks_per_beat: u64) {
+    let compound = time.0 > 3 && time.0.is_multiple_of(3) && time.1 == 8;
+    let beat = if compound { ticks_per_beat * 3 / 2 } else { ticks_per_beat * 4 / time.1.max(1) as u64 };
+    let beamable = |event: &Event| !event.pitches.is_empty() && matches!(event.value.name, "eighth" | "16th");
+    let mut index = 0;
+    while index < events.len() {
+        let beat_index = events[index].start / beat.max(1);
+        let mut end = index;
+        while end < events.len() && beamable(&events[end]) && (events[end].start + events[end].duration - 1) / beat.max(1) == beat_index {
+            end += 1;
+        }
+        if end - index >= 2 {
+            for (position, event) in events[index..end].iter_mut().enumerate() {
+                event.beam = Some(match position {
+                    0 => Beam::Begin,
+                    p if p + 1 == end - index => Beam::End,
+                    _ => Beam::Continue,
+                });
+            }
+            index = end;
+        } else {
+            index += 1;
+        }
+    }
+}
+
+// Spells the pitches of a measure, printing accidentals that differ from the key or an earlier
+// note on the same staff position
+fn spell_measure(voices: &mut [Vec<Event>], key: &Key) {
+    let alterations = signature_alterations(key.signature());
+    let mut in_effect: HashMap<(char, i32), i32> = HashMap::new();
+    let mut order: Vec<(u64, usize, usize)> = voices
+        .iter()
+        .enumerate()
+        .flat_map(|(voice, events)| events.iter().enumerate().map(move |(index, event)| (event.start, voice, index)))
+        .collect();
+    order.sort();
+    for (_, voice, index) in order {
+        let event = &mut voices[voice][index];
+        let tie_stop = event.tie_stop;
+        for pitch in event.pitches.iter_mut() {
+            let spelled = spell(pitch.midi, key);
+            let letter = LETTERS.iter().position(|&letter| letter == spelled.step).unwrap_or(0);
+            let current = in_effect.get(&(spelled.step, spelled.octave)).copied().unwrap_or(alterations[letter]);
+            let accidental = (current != spelled.alter && !tie_stop).then_some(spelled.alter);
+            in_effect.insert((spelled.step, spelled.octave), spelled.alter);
+            *pitch = Pitch { accidental, ..spelled };
+        }
+    }
+}
+
+// Lays out a composition as notation: measures follow its time signatures, notes are quantized
+// to sixteenths and every track becomes a part with as many voices as its overlaps need
+pub fn build(composition: &Composition, title: &str) -> Score {
+    let ticks_per_beat = composition.ticks_per_beat.max(1) as u64;
+    let grid = (ticks_per_beat / GRID_PER_BEAT).max(1);
+    let quantize = |tick: u64| (tick + grid / 2) / grid * grid;
+
+    let tracks: Vec<Vec<Group>> = composition
+        .tracks
+        .iter()
+        .map(|track| {
+            let mut groups: Vec<Group> = Vec::new();
+            for note in &track.notes {
+                let start = quantize(note.start);
+                let end = quantize(note.end()).max(start + grid);
+                match groups.iter_mut().find(|group| group.start == start && group.end == end) {
+                    Some(group) if !group.pitches.contains(&note.pitch) => group.pitches.push(note.pitch),
+                    Some(_) => {}
+                    None => groups.push(Group { start, end, pitches: vec![note.pitch] }),
+                }
+            }
+            for group in groups.iter_mut() {
+                group.pitches.sort();
+            }
+            groups
+        })
+        .collect();
+
+    let end = tracks.iter().flatten().map(|group| group.end).max().unwrap_or(0);
+    let bars = if end == 0 { 1 } else { composition.tick_to_bar(end - 1) + 1 };
+    // Without a key signature the detected key decides the spelling
+    let fallback = composition_key(composition).unwrap_or(Key::from_signature(0, false));
+    let mut measures: Vec<Measure> = Vec::new();
+    for bar in 0..bars {
+        let start = composition.bar_to_tick(bar);
+        let key = composition
+            .key_signatures
+            .iter()
+            .take_while(|signature| signature.tick <= start)
+            .last()
+            .map_or(fallback, |signature| Key::from_signature(signature.sharps, signature.minor));
+        let time = composition.time_signature_at(start);
+        let bpm = 60_000_000.0 / composition.tempo_at(start).max(1) as f64;
+        let previous = measures.last();
+        measures.push(Measure {
+            start,
+            length: composition.ticks_per_bar(start),
+            time,
+            key,
+            time_change: previous.is_none_or(|previous| previous.time != time),
+            key_change: previous.is_none_or(|previous| previous.key != key),
+            tempo: (bar == 0 || 60_000_000.0 / composition.tempo_at(start.saturating_sub(1)).max(1) as f64 != bpm).then_some(bpm),
+        });
+    }
+
+    let parts = composition
+        .tracks
+        .iter()
+        .zip(tracks)
+        .filter(|(_, groups)| !groups.is_empty())
+        .map(|(track, groups)| {
+            let average = groups.iter().flat_map(|group| group.pitches.iter()).map(|&pitch| pitch as f64).sum::<f64>()
+                / groups.iter().map(|group| group.pitches.len()).sum::<usize>().max(1) as f64;
+            let clef = if track.channel == DRUM_CHANNEL {
+                Clef::Percussion
+            } else if average < 57.0 {
+                Clef::Bass
+            } else {
+                Clef::Treble
+            };
+            let voices = split_voices(groups);
+            let measures = measures
+                .iter()
+                .map(|measure| {
+                    let mut events: Vec<Vec<Event>> = voices
+                        .iter()
+                        .enumerate()
+                        .map(|(index, voice)| measure_events(voice, measure, ticks_per_beat, index == 0))
+                        .collect();
+                    for voice in events.iter_mut() {
+                        beam(voice, measure.time, ticks_per_beat);
+                    }
+                    spell_measure(&mut events, &measure.key);
+                    events
+                })
+                .collect();
+            let instrument = instrument_name(track.channel, track.program).to_string();
+            Part {
+                name: track.name.clone().unwrap_or_else(|| instrument.clone()),
+                instrument,
+                channel: track.channel,
+                program: track.program,
+                clef,
+                measures,
+            }
+        })
+        .collect();
+
+    Score { title: title.to_string(), ticks_per_beat, measures, parts }
+}
+
+// Writes the notation of a library entry, next to its files unless a path is given
+#[tauri::command]
+pub async fn export_notation(track: String, format: Option<String>, path: Option<String>) -> Result<String, String> {
+    let entry = Library::read()?.get(&track)?.clone();
+    let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to export", entry.title))?;
+    let score = build(&Composition::from_file(&midi)?, &entry.title);
+    let (content, extension) = match format.as_deref().unwrap_or("musicxml") {
+        "musicxml" => (musicxml::write(&score).into_bytes(), "musicxml"),
+        "svg" => (engraving::render_svg(&score, None).into_bytes(), "svg"),
+        "pdf" => (engraving::render_pdf(&score), "pdf"),
+        "lilypond" => (lilypond::write(&score).into_bytes(), "ly"),
+        "abc" => (abc::write(&score).into_bytes(), "abc"),
+        other => return Err(format!("Unknown notation format: {}", other)),
+    };
+    let path = path.map(PathBuf::from).unwrap_or_else(|| entry.dir().join(format!("score.{}", extension)));
+    fs::write(&path, content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    Ok(path.display().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{KeySignature, Note, Track};
+
+    fn group(start: u64, end: u64, pitches: &[u8]) -> Group {
+        Group { start, end, pitches: pitches.to_vec() }
+    }
+
+    fn value(name: &'static str, dots: u8) -> NoteValue {
+        NoteValue { name, dots }
+    }
+
+    fn piece(sharps: i8, notes: Vec<Note>) -> Composition {
+        let mut composition = Composition::new(480);
+        composition.key_signatures.push(KeySignature { tick: 0, sharps, minor: false });
+        composition.tracks.push(Track { name: Some("Piano".to_string()), channel: 0, program: 0, notes });
+        composition
+    }
+
+    fn note(start: u64, duration: u64, pitch: u8) -> Note {
+        Note { start, duration, pitch, velocity: 80 }
+    }
+
+    #[test]
+    fn lengths_split_into_note_values() {
+        assert_eq!(note_values(1920, 480), vec![(1920, value("whole", 0))]);
+        assert_eq!(note_values(720, 480), vec![(720, value("quarter", 1))]);
+        // A quarter tied to a sixteenth has no single value
+        assert_eq!(note_values(600, 480), vec![(480, value("quarter", 0)), (120, value("16th", 0))]);
+        assert_eq!(note_values(3360, 480), vec![(2880, value("whole", 1)), (480, value("quarter", 0))]);
+        assert_eq!(note_values(0, 480), vec![]);
+    }
+
+    #[test]
+    fn pitches_are_spelled_in_the_key() {
+        let spelled = |midi: u8, key: &str| {
+            let pitch = spell(midi, &Key::parse(key).unwrap());
+            (pitch.step, pitch.alter, pitch.octave)
+        };
+        assert_eq!(spelled(60, "C"), ('C', 0, 4));
+        assert_eq!(spelled(66, "D"), ('F', 1, 4));
+        assert_eq!(spelled(70, "F"), ('B', -1, 4));
+        assert_eq!(spelled(61, "G"), ('C', 1, 4));
+        assert_eq!(spelled(61, "Eb"), ('D', -1, 4));
+        // C# major is written as Db, F# major keeps its E#
+        assert_eq!(spelled(60, "C#"), ('C', 0, 4));
+        assert_eq!(spelled(65, "F#"), ('E', 1, 4));
+        assert_eq!(spelled(66, "Db"), ('G', -1, 4));
+    }
+
+    #[test]
+    fn overlapping_groups_get_separate_voices() {
+        let voices = split_voices(vec![group(480, 960, &[64]), group(0, 960, &[60]), group(960, 1440, &[62])]);
+        let starts: Vec<Vec<u64>> = voices.iter().map(|voice| voice.iter().map(|group| group.start).collect()).collect();
+        assert_eq!(starts, vec![vec![0, 960], vec![480]]);
+
+        // A fifth overlapping group cuts the earliest ending voice short
+        let crowded: Vec<Group> = (0..5).map(|index| group(index * 10, 1000 + index, &[60 + index as u8])).collect();
+        let voices = split_voices(crowded);
+        assert_eq!(voices.len(), MAX_VOICES);
+        assert_eq!((voices[0][0].end, voices[0][1].start), (40, 40));
+    }
+
+    #[test]
+    fn notes_across_bar_lines_are_tied() {
+        let score = build(&piece(0, vec![note(1440, 960, 67)]), "Tie");
+        let bars = &score.parts[0].measures;
+        let first = bars[0][0].last().unwrap();
+        let second = bars[1][0].first().unwrap();
+        assert_eq!((first.start, first.duration, first.tie_start, first.tie_stop), (1440, 480, true, false));
+        assert_eq!((second.start, second.duration, second.tie_start, second.tie_stop), (0, 480, false, true));
+        assert!(bars[1][0][1].pitches.is_empty());
+    }
+
+    #[test]
+    fn accidentals_last_for_the_bar() {
+        let score = build(&piece(2, vec![note(0, 480, 65), note(480, 480, 65), note(960, 480, 66), note(1920, 480, 65)]), "Accidentals");
+        let accidentals: Vec<Vec<Option<i32>>> = score.parts[0]
+            .measures
+            .iter()
+            .map(|voices| voices[0].iter().filter(|event| !event.pitches.is_empty()).map(|event| event.pitches[0].accidental).collect())
+            .collect();
+        assert_eq!(accidentals, vec![vec![Some(0), None, Some(1)], vec![Some(0)]]);
+    }
+}
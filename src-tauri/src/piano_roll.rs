@@ -0,0 +1,139 @@
+use serde::Serialize;
+use crate::library::Library;
+use crate::midi::{instrument_name, Composition, TrackSummary};
+
+#[derive(Debug, Clone, Serialize)]
+pub struct RollNote {
+    pub track: usize,
+    // Position within the track, used to address the note in edits
+    pub index: usize,
+    pub pitch: u8,
+    pub start: u64,
+    pub duration: u64,
+    pub velocity: u8,
+    pub instrument: String,
+    pub start_seconds: f64,
+    pub end_seconds: f64,
+}
+
+// A tempo segment starting at `tick`, enough for the frontend to convert between ticks and seconds
+#[derive(Debug, Clone, Serialize)]
+pub struct TempoPoint {
+    pub tick: u64,
+    pub seconds: f64,
+    pub bpm: f64,
+    pub micros_per_beat: u32,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct BarLine {
+    pub bar: u32,
+    pub tick: u64,
+    pub seconds: f64,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct PianoRoll {
+    pub ticks_per_beat: u16,
+    pub duration_seconds: f64,
+    pub tracks: Vec<TrackSummary>,
+    pub tempo_map: Vec<TempoPoint>,
+    pub bars: Vec<BarLine>,
+    pub notes: Vec<RollNote>,
+    pub render_stale: bool,
+}
+
+pub fn piano_roll(composition: &Composition, render_stale: bool) -> PianoRoll {
+    let notes = composition
+        .tracks
+        .iter()
+        .enumerate()
+        .flat_map(|(track_index, track)| {
+            let instrument = instrument_name(track.channel, track.program);
+            track.notes.iter().enumerate().map(move |(index, note)| RollNote {
+                track: track_index,
+                index,
+                pitch: note.pitch,
+                start: note.start,
+                duration: note.duration,
+                velocity: note.velocity,
+                instrument: instrument.to_string(),
+                start_seconds: composition.tick_to_seconds(note.start),
+                end_seconds: composition.tick_to_seconds(note.end()),
+            })
+        })
+        .collect();
+
+    // Several changes at one tick collapse into the one that takes effect
+    let mut ticks: Vec<u64> = composition.tempos.iter().map(|change| change.tick).filter(|tick| *tick > 0).collect();
+    ticks.insert(0, 0);
+    ticks.dedup();
+    let tempo_map = ticks
+        .into_iter()
+        .map(|tick| {
+            let micros_per_beat = composition.tempo_at(tick);
+            TempoPoint { tick, seconds: composition.tick_to_seconds(tick), bpm: 60_000_000.0 / micros_per_beat.max(1) as f64, micros_per_beat }
+        })
+        .collect();
+
+    let end = composition.end_tick();
+    let bar_count = if end == 0 { 1 } else { composition.tick_to_bar(end - 1) + 1 };
+    let bars = (0..=bar_count)
+        .map(|bar| {
+            let tick = composition.bar_to_tick(bar);
+            BarLine { bar, tick, seconds: composition.tick_to_seconds(tick) }
+        })
+        .collect();
+
+    PianoRoll {
+        ticks_per_beat: composition.ticks_per_beat,
+        duration_seconds: composition.duration_seconds(),
+        tracks: composition.summary().tracks,
+        tempo_map,
+        bars,
+        notes,
+        render_stale,
+    }
+}
+
+// Notes of a library entry for a piano-roll view; edits go through edit_composition
+#[tauri::command]
+pub async fn get_piano_roll(track: String) -> Result<PianoRoll, String> {
+    let entry = Library::read()?.get(&track)?.clone();
+    let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to show", entry.title))?;
+    Ok(piano_roll(&Composition::from_file(&midi)?, entry.render_stale))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{Note, TempoChange, Track};
+
+    #[test]
+    fn notes_bars_and_tempo_follow_the_composition() {
+        let mut composition = Composition::new(480);
+        composition.tempos.push(TempoChange { tick: 0, micros_per_beat: 500_000 });
+        composition.tempos.push(TempoChange { tick: 1920, micros_per_beat: 600_000 });
+        composition.tempos.push(TempoChange { tick: 1920, micros_per_beat: 1_000_000 });
+        let note = |start: u64, pitch: u8| Note { start, duration: 480, pitch, velocity: 80 };
+        composition.tracks.push(Track { name: None, channel: 0, program: 0, notes: vec![note(0, 60), note(1920, 62)] });
+        composition.tracks.push(Track { name: None, channel: 1, program: 40, notes: vec![note(3360, 48)] });
+        let roll = piano_roll(&composition, true);
+
+        let addressed: Vec<(usize, usize, u8)> = roll.notes.iter().map(|note| (note.track, note.index, note.pitch)).collect();
+        assert_eq!(addressed, vec![(0, 0, 60), (0, 1, 62), (1, 0, 48)]);
+        assert_eq!(roll.notes[1].instrument, "Acoustic Grand Piano");
+        assert_eq!(roll.notes[2].instrument, "Violin");
+        assert_eq!((roll.notes[1].start_seconds, roll.notes[1].end_seconds), (2.0, 3.0));
+
+        // The later of two changes at one tick is the one that plays
+        let tempo: Vec<(u64, f64, f64)> = roll.tempo_map.iter().map(|point| (point.tick, point.seconds, point.bpm)).collect();
+        assert_eq!(tempo, vec![(0, 0.0, 120.0), (1920, 2.0, 60.0)]);
+
+        // Two bars and the closing bar line
+        let bars: Vec<(u32, u64)> = roll.bars.iter().map(|bar| (bar.bar, bar.tick)).collect();
+        assert_eq!(bars, vec![(0, 0), (1, 1920), (2, 3840)]);
+        assert_eq!(roll.duration_seconds, 6.0);
+        assert!(roll.render_stale);
+    }
+}
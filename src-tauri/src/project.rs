@@ -0,0 +1,500 @@
+use std::collections::{HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, Read};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+use tauri::AppHandle;
+use zip::write::SimpleFileOptions;
+use zip::{CompressionMethod, ZipArchive, ZipWriter};
+use crate::arrangement::{add_arrangement, new_id, read_arrangements, Arrangement};
+use crate::config::{active_soundfont, system_prompt};
+use crate::library::{now_secs, Library, LibraryEntry};
+use crate::setup::EnvPaths;
+use crate::utils::send_to_frontend;
+
+const EXTENSION: &str = "tunes";
+const MANIFEST_FILE: &str = "manifest.json";
+const ENTRIES_DIR: &str = "entries";
+const ARRANGEMENTS_DIR: &str = "arrangements";
+const FORMAT_VERSION: u32 = 1;
+
+// Steps that bring a manifest from one format version to the next, the first one upgrading
+// version 1. Fields added to library entries and arrangements are covered by serde defaults,
+// only changes of layout need a step here
+const MIGRATIONS: [fn(&mut Map<String, Value>); FORMAT_VERSION as usize - 1] = [];
+
+// A SoundFont renders were made with. Projects carry its hash instead of the file, so the
+// teammate opening the project can be matched to their own copy
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct SoundfontReference {
+    pub path: String,
+    pub name: String,
+    pub size: u64,
+    // Unset when the file was missing at save time
+    pub sha256: Option<String>,
+}
+
+// Library entries and arrangements with paths relative to the root of the bundle
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Manifest {
+    pub format_version: u32,
+    pub app_version: String,
+    pub name: String,
+    pub saved: u64,
+    #[serde(default)]
+    pub system_prompt: Option<String>,
+    #[serde(default)]
+    pub soundfonts: Vec<SoundfontReference>,
+    #[serde(default)]
+    pub entries: Vec<LibraryEntry>,
+    #[serde(default)]
+    pub arrangements: Vec<Arrangement>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct OpenedProject {
+    pub name: String,
+    pub system_prompt: Option<String>,
+    pub entries: Vec<LibraryEntry>,
+    pub arrangements: Vec<Arrangement>,
+    // Things that did not come over, e.g. SoundFonts missing on this machine
+    pub warnings: Vec<String>,
+}
+
+// Every file a library entry points at
+fn entry_paths(entry: &mut LibraryEntry) -> Vec<&mut PathBuf> {
+    let mut paths: Vec<&mut PathBuf> = Vec::new();
+    paths.extend(entry.midi.as_mut());
+    paths.extend(entry.versions.iter_mut().map(|version| &mut version.audio));
+    for edit in entry.edits.iter_mut().chain(entry.undone.iter_mut()) {
+        paths.push(&mut edit.previous);
+        paths.push(&mut edit.midi);
+    }
+    for variant in entry.variants.iter_mut() {
+        paths.extend(variant.midi.as_mut());
+        paths.push(&mut variant.audio);
+    }
+    paths
+}
+
+fn file_name(path: &Path) -> Result<String, String> {
+    path.file_name()
+        .and_then(|name| name.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| format!("Invalid file name in {}", path.display()))
+}
+
+fn zip_error(e: zip::result::ZipError) -> String {
+    format!("Invalid project file: {}", e)
+}
+
+fn hash_file(path: &Path) -> Result<String, String> {
+    let mut file = File::open(path).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    let mut hasher = Sha256::new();
+    io::copy(&mut file, &mut hasher).map_err(|e| format!("Failed to read {}: {}", path.display(), e))?;
+    Ok(format!("{:x}", hasher.finalize()))
+}
+
+fn soundfont_reference(path: &str) -> SoundfontReference {
+    let file = Path::new(path);
+    SoundfontReference {
+        path: path.to_string(),
+        name: file.file_name().and_then(|name| name.to_str()).unwrap_or(path).to_string(),
+        size: fs::metadata(file).map(|metadata| metadata.len()).unwrap_or(0),
+        sha256: hash_file(file).ok(),
+    }
+}
+
+// Brings an older manifest up to the current format; newer ones are refused
+fn migrate(mut manifest: Value) -> Result<Manifest, String> {
+    let fields = manifest.as_object_mut().ok_or_else(|| "The project manifest is not a JSON object".to_string())?;
+    let version = fields.get("format_version").and_then(Value::as_u64).ok_or_else(|| "The project manifest has no format version".to_string())? as u32;
+    if version == 0 || version > FORMAT_VERSION {
+        return Err(format!("The project was saved in format {}, this version of the app reads up to format {}", version, FORMAT_VERSION));
+    }
+    for step in &MIGRATIONS[version as usize - 1..] {
+        step(fields);
+    }
+    fields.insert("format_version".to_string(), Value::from(FORMAT_VERSION));
+    serde_json::from_value(manifest).map_err(|e| format!("Invalid project manifest: {}", e))
+}
+
+// Ids become directory names, so ones that are not plain hex-like text or already in use are replaced
+fn fresh_entry_id(id: &str, taken: &HashSet<String>) -> String {
+    if !id.is_empty() && id.chars().all(|c| c.is_ascii_alphanumeric()) && !taken.contains(id) {
+        return id.to_string();
+    }
+    loop {
+        let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_nanos()).unwrap_or(0);
+        let id = format!("{:x}", nanos);
+        if !taken.contains(&id) {
+            return id;
+        }
+    }
+}
+
+fn write_project(path: &Path, name: String) -> Result<Vec<String>, String> {
+    let library = Library::read()?;
+    let mut soundfonts: Vec<String> = library.entries.iter().flat_map(|entry| entry.versions.iter()).filter_map(|version| version.soundfont.clone()).collect();
+    soundfonts.extend(active_soundfont().ok().map(|soundfont| soundfont.display().to_string()));
+    write_bundle(path, name, &library.entries, read_arrangements()?, soundfonts)
+}
+
+// Writes the given entries and arrangements with their files, returning the files that were missing
+fn write_bundle(path: &Path, name: String, library: &[LibraryEntry], arrangements: Vec<Arrangement>, mut soundfonts: Vec<String>) -> Result<Vec<String>, String> {
+    let mut missing = Vec::new();
+
+    let file = File::create(path).map_err(|e| format!("Failed to create {}: {}", path.display(), e))?;
+    let mut writer = ZipWriter::new(file);
+    let options = SimpleFileOptions::default().compression_method(CompressionMethod::Deflated).large_file(true);
+    let mut written = HashSet::new();
+    let mut add_file = |writer: &mut ZipWriter<File>, source: &Path, name: String| -> Result<bool, String> {
+        if written.contains(&name) {
+            return Ok(true);
+        }
+        let Ok(mut file) = File::open(source) else {
+            return Ok(false);
+        };
+        writer.start_file(name.as_str(), options).map_err(zip_error)?;
+        io::copy(&mut file, writer).map_err(|e| format!("Failed to add {} to the project: {}", source.display(), e))?;
+        written.insert(name);
+        Ok(true)
+    };
+
+    let mut entries = Vec::new();
+    for entry in library {
+        let mut entry = entry.clone();
+        let title = entry.title.clone();
+        let folder = format!("{}/{}", ENTRIES_DIR, entry.id);
+        for path in entry_paths(&mut entry) {
+            let name = format!("{}/{}", folder, file_name(path)?);
+            if !add_file(&mut writer, path, name.clone())? {
+                missing.push(format!("{} of {}", file_name(path)?, title));
+            }
+            *path = PathBuf::from(name);
+        }
+        entries.push(entry);
+    }
+
+    let mut bundled = Vec::new();
+    for arrangement in arrangements {
+        let mut arrangement = arrangement;
+        if let Some(mixdown) = arrangement.mixdown.take() {
+            let name = format!("{}/{}/{}", ARRANGEMENTS_DIR, arrangement.id, file_name(&mixdown)?);
+            if add_file(&mut writer, &mixdown, name.clone())? {
+                arrangement.mixdown = Some(PathBuf::from(name));
+            }
+        }
+        bundled.push(arrangement);
+    }
+
+    soundfonts.sort();
+    soundfonts.dedup();
+
+    let manifest = Manifest {
+        format_version: FORMAT_VERSION,
+        app_version: env!("CARGO_PKG_VERSION").to_string(),
+        name,
+        saved: now_secs(),
+        system_prompt: system_prompt(),
+        soundfonts: soundfonts.iter().map(|soundfont| soundfont_reference(soundfont)).collect(),
+        entries,
+        arrangements: bundled,
+    };
+    let content = serde_json::to_vec_pretty(&manifest).map_err(|e| e.to_string())?;
+    writer.start_file(MANIFEST_FILE, options).map_err(zip_error)?;
+    io::Write::write_all(&mut writer, &content).map_err(|e| format!("Failed to write the project manifest: {}", e))?;
+    writer.finish().map_err(zip_error)?;
+    Ok(missing)
+}
+
+fn extract(archive: &mut ZipArchive<File>, name: &str, target: &Path) -> Result<bool, String> {
+    let mut file = match archive.by_name(name) {
+        Ok(file) => file,
+        Err(zip::result::ZipError::FileNotFound) => return Ok(false),
+        Err(e) => return Err(zip_error(e)),
+    };
+    if let Some(parent) = target.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create {}: {}", parent.display(), e))?;
+    }
+    let mut output = File::create(target).map_err(|e| format!("Failed to create {}: {}", target.display(), e))?;
+    io::copy(&mut file, &mut output).map_err(|e| format!("Failed to extract {}: {}", name, e))?;
+    Ok(true)
+}
+
+// Finds this machine's copy of a SoundFont: the same path or the active SoundFont, if the hash matches
+fn local_soundfont(reference: &SoundfontReference) -> Option<String> {
+    let expected = reference.sha256.as_ref()?;
+    let candidates = [Some(PathBuf::from(&reference.path)), active_soundfont().ok()];
+    candidates
+        .into_iter()
+        .flatten()
+        .find(|candidate| candidate.is_file() && hash_file(candidate).ok().as_ref() == Some(expected))
+        .map(|candidate| candidate.display().to_string())
+}
+
+fn read_project(path: &Path) -> Result<OpenedProject, String> {
+    let paths = EnvPaths::new();
+    let taken: HashSet<String> = Library::read()?.entries.into_iter().map(|entry| entry.id).collect();
+    let project = read_bundle(path, taken, &paths.library, &paths.arrangements)?;
+    let added = project.entries.clone();
+    Library::update(move |library| {
+        library.entries.extend(added);
+        Ok(())
+    })?;
+    for arrangement in &project.arrangements {
+        add_arrangement(arrangement)?;
+    }
+    Ok(project)
+}
+
+// Extracts a project's files below the given library and arrangement directories, giving
+// entries new ids where theirs are taken
+fn read_bundle(path: &Path, mut taken: HashSet<String>, library_dir: &Path, arrangements_dir: &Path) -> Result<OpenedProject, String> {
+    let file = File::open(path).map_err(|e| format!("Failed to open {}: {}", path.display(), e))?;
+    let mut archive = ZipArchive::new(file).map_err(zip_error)?;
+    let manifest: Value = {
+        let mut content = String::new();
+        archive.by_name(MANIFEST_FILE).map_err(zip_error)?.read_to_string(&mut content).map_err(|e| format!("Failed to read the project manifest: {}", e))?;
+        serde_json::from_str(&content).map_err(|e| format!("Invalid project manifest: {}", e))?
+    };
+    let manifest = migrate(manifest)?;
+    let mut warnings = Vec::new();
+
+    // Renders keep their SoundFont only where this machine has the same file
+    let mut soundfonts = HashMap::new();
+    for reference in &manifest.soundfonts {
+        let local = local_soundfont(reference);
+        if local.is_none() {
+            warnings.push(format!("SoundFont {} is not available here, re-renders use the active SoundFont", reference.name));
+        }
+        soundfonts.insert(reference.path.clone(), local);
+    }
+
+    let mut ids = HashMap::new();
+    let mut entries = Vec::new();
+    for mut entry in manifest.entries {
+        let id = fresh_entry_id(&entry.id, &taken);
+        taken.insert(id.clone());
+        ids.insert(entry.id.clone(), id.clone());
+        let dir = library_dir.join(&id);
+        entry.id = id;
+        let title = entry.title.clone();
+        let mut extracted = HashSet::new();
+        for path in entry_paths(&mut entry) {
+            let name = path.to_string_lossy().replace('\\', "/");
+            // Only the file name is used, names in the bundle cannot point outside the entry
+            let target = dir.join(file_name(path)?);
+            if extracted.insert(name.clone()) && !extract(&mut archive, &name, &target)? {
+                warnings.push(format!("{} of {} is missing from the project", file_name(path)?, title));
+            }
+            *path = target;
+        }
+        for version in entry.versions.iter_mut() {
+            version.soundfont = version.soundfont.take().and_then(|soundfont| soundfonts.get(&soundfont).cloned().flatten());
+        }
+        entries.push(entry);
+    }
+
+    let mut arrangements = Vec::new();
+    for mut arrangement in manifest.arrangements {
+        arrangement.id = new_id();
+        for clip in arrangement.tracks.iter_mut().flat_map(|track| track.clips.iter_mut()) {
+            if let Some(id) = ids.get(&clip.entry) {
+                clip.entry = id.clone();
+            }
+        }
+        if let Some(mixdown) = arrangement.mixdown.take() {
+            let name = mixdown.to_string_lossy().replace('\\', "/");
+            let target = arrangements_dir.join(&arrangement.id).join(file_name(&mixdown)?);
+            if extract(&mut archive, &name, &target)? {
+                arrangement.mixdown = Some(target);
+            }
+        }
+        arrangements.push(arrangement);
+    }
+
+    Ok(OpenedProject { name: manifest.name, system_prompt: manifest.system_prompt, entries, arrangements, warnings })
+}
+
+// Bundles the library, arrangements and prompt settings into a .tunes file
+#[tauri::command]
+pub async fn save_project(app: AppHandle, path: String, name: Option<String>) -> Result<String, String> {
+    let mut path = PathBuf::from(path);
+    if path.extension().and_then(|extension| extension.to_str()) != Some(EXTENSION) {
+        path.as_mut_os_string().push(format!(".{}", EXTENSION));
+    }
+    let name = name
+        .filter(|name| !name.trim().is_empty())
+        .or_else(|| path.file_stem().and_then(|stem| stem.to_str()).map(str::to_string))
+        .unwrap_or_else(|| "Project".to_string());
+
+    // Written next to the target first so a failed save leaves an existing project intact
+    let partial = path.with_extension(format!("{}.partial", EXTENSION));
+    send_to_frontend(&app, format!("Saving project {}...", name), "info");
+    let missing = match write_project(&partial, name) {
+        Ok(missing) => missing,
+        Err(e) => {
+            let _ = fs::remove_file(&partial);
+            return Err(e);
+        }
+    };
+    fs::rename(&partial, &path).map_err(|e| format!("Failed to save project: {}", e))?;
+    if !missing.is_empty() {
+        send_to_frontend(&app, format!("Files no longer on disk were left out: {}", missing.join(", ")), "info");
+    }
+    send_to_frontend(&app, path.display().to_string(), "success");
+    Ok(path.display().to_string())
+}
+
+// Adds the entries and arrangements of a .tunes file to the library, entries whose id is
+// already taken get a new one
+#[tauri::command]
+pub async fn open_project(app: AppHandle, path: String) -> Result<OpenedProject, String> {
+    let project = read_project(&PathBuf::from(&path))?;
+    for warning in &project.warnings {
+        send_to_frontend(&app, warning.clone(), "info");
+    }
+    send_to_frontend(
+        &app,
+        format!("Opened {}: {} tracks, {} arrangements", project.name, project.entries.len(), project.arrangements.len()),
+        "success",
+    );
+    Ok(project)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use crate::arrangement::{ArrangementTrack, Clip, ClipSource};
+    use crate::editing::{EditOperation, EditVersion};
+    use crate::library::{EntrySource, RenderVersion};
+
+    // A scratch directory per test, removed again when dropped
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("project-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn manifest(version: Value) -> Value {
+        json!({ "format_version": version, "app_version": "0.1.0", "name": "Demo", "saved": 1 })
+    }
+
+    #[test]
+    fn manifests_are_brought_to_the_current_format() {
+        let mut old = manifest(json!(1));
+        old["entries"] = json!([{ "id": "a1", "title": "Old", "source": "imported", "created": 1, "midi": "entries/a1/old.mid", "summary": null }]);
+        let migrated = migrate(old).unwrap();
+        assert_eq!(migrated.format_version, FORMAT_VERSION);
+        assert!(migrated.system_prompt.is_none() && migrated.soundfonts.is_empty() && migrated.arrangements.is_empty());
+        // Fields added since are filled with their defaults
+        let entry = &migrated.entries[0];
+        assert!(entry.versions.is_empty() && entry.mixer.is_empty() && !entry.render_stale);
+    }
+
+    #[test]
+    fn unknown_format_versions_are_refused() {
+        let newer = migrate(manifest(json!(FORMAT_VERSION + 1))).unwrap_err();
+        assert!(newer.contains(&format!("format {}", FORMAT_VERSION + 1)), "{}", newer);
+        assert!(migrate(manifest(json!(0))).is_err());
+        assert!(migrate(manifest(json!("1"))).is_err());
+        assert!(migrate(json!([])).is_err());
+    }
+
+    #[test]
+    fn taken_or_unsafe_ids_are_replaced() {
+        let taken: HashSet<String> = ["abc1".to_string()].into();
+        assert_eq!(fresh_entry_id("beef", &taken), "beef");
+        for id in ["abc1", "", "../../etc", "a/b", "a.b"] {
+            let fresh = fresh_entry_id(id, &taken);
+            assert_ne!(fresh, id);
+            assert!(!taken.contains(&fresh) && fresh.chars().all(|c| c.is_ascii_hexdigit()));
+        }
+    }
+
+    #[test]
+    fn saved_projects_open_with_their_files() {
+        let scratch = Scratch::new("round-trip");
+        let source = scratch.0.join("source");
+        fs::create_dir_all(&source).unwrap();
+        fs::write(source.join("tune.mid"), b"midi").unwrap();
+        fs::write(source.join("render1.wav"), b"audio").unwrap();
+        fs::write(source.join("mixdown.wav"), b"mix").unwrap();
+
+        let mut entry = LibraryEntry::new("Tune".to_string(), EntrySource::Generated);
+        entry.id = "e1".to_string();
+        entry.midi = Some(source.join("tune.mid"));
+        entry.versions.push(RenderVersion { version: 1, audio: source.join("render1.wav"), soundfont: None, settings: None, humanized: false, created: 1 });
+        let operation = EditOperation::Transpose { semitones: 2 };
+        entry.edits.push(EditVersion { operation, previous: source.join("gone.mid"), midi: source.join("tune.mid"), created: 1 });
+
+        let clip = Clip { id: "c1".to_string(), entry: "e1".to_string(), source: ClipSource::Audio, start: 0.0, trim_start: 0.0, trim_end: 0.0, gain_db: 0.0, pan: 0.0, fade_in: 0.0, fade_out: 0.0 };
+        let track = ArrangementTrack { id: "t1".to_string(), name: "Main".to_string(), clips: vec![clip], gain_db: 0.0, pan: 0.0, muted: false };
+        let arrangement = Arrangement { id: "a1".to_string(), title: "Set".to_string(), created: 1, modified: 1, revision: 3, sample_rate: 44100, tracks: vec![track], mixdown: Some(source.join("mixdown.wav")) };
+
+        let bundle = scratch.0.join("demo.tunes");
+        let missing = write_bundle(&bundle, "Demo".to_string(), &[entry], vec![arrangement], Vec::new()).unwrap();
+        assert_eq!(missing, vec!["gone.mid of Tune".to_string()]);
+
+        // The id is taken on the opening side, so the entry and the clip pointing at it move to a new one
+        let (library, arrangements) = (scratch.0.join("library"), scratch.0.join("arrangements"));
+        let opened = read_bundle(&bundle, ["e1".to_string()].into(), &library, &arrangements).unwrap();
+        assert_eq!(opened.name, "Demo");
+        assert_eq!(opened.warnings, vec!["gone.mid of Tune is missing from the project".to_string()]);
+        let entry = &opened.entries[0];
+        assert_ne!(entry.id, "e1");
+        let dir = library.join(&entry.id);
+        assert_eq!(entry.midi.as_deref(), Some(dir.join("tune.mid").as_path()));
+        assert_eq!(fs::read(dir.join("tune.mid")).unwrap(), b"midi");
+        assert_eq!(fs::read(&entry.versions[0].audio).unwrap(), b"audio");
+        assert_eq!(entry.edits[0].midi, dir.join("tune.mid"));
+
+        let arrangement = &opened.arrangements[0];
+        assert_ne!(arrangement.id, "a1");
+        assert_eq!(arrangement.revision, 3);
+        assert_eq!(arrangement.tracks[0].clips[0].entry, entry.id);
+        let mixdown = arrangement.mixdown.clone().unwrap();
+        assert!(mixdown.starts_with(arrangements.join(&arrangement.id)));
+        assert_eq!(fs::read(mixdown).unwrap(), b"mix");
+    }
+
+    #[test]
+    fn bundle_paths_cannot_leave_the_entry() {
+        let scratch = Scratch::new("zip-slip");
+        let bundle = scratch.0.join("evil.tunes");
+        let mut writer = ZipWriter::new(File::create(&bundle).unwrap());
+        let options = SimpleFileOptions::default();
+        let mut content = manifest(json!(1));
+        content["entries"] = json!([{ "id": "../../outside", "title": "Evil", "source": "imported", "created": 1, "midi": "../../escaped.mid", "summary": null }]);
+        writer.start_file(MANIFEST_FILE, options).unwrap();
+        io::Write::write_all(&mut writer, content.to_string().as_bytes()).unwrap();
+        writer.start_file("../../escaped.mid", options).unwrap();
+        io::Write::write_all(&mut writer, b"payload").unwrap();
+        writer.finish().unwrap();
+
+        let library = scratch.0.join("deep").join("library");
+        let opened = read_bundle(&bundle, HashSet::new(), &library, &scratch.0.join("arrangements")).unwrap();
+        let entry = &opened.entries[0];
+        assert!(entry.id.chars().all(|c| c.is_ascii_alphanumeric()));
+        let midi = entry.midi.clone().unwrap();
+        assert_eq!(midi, library.join(&entry.id).join("escaped.mid"));
+        assert_eq!(fs::read(midi).unwrap(), b"payload");
+        assert!(!scratch.0.join("escaped.mid").exists() && !scratch.0.join("outside").exists());
+    }
+}
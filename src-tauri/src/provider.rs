@@ -0,0 +1,134 @@
+use serde::{Deserialize, Serialize};
+use crate::config::{provider_settings, save_provider_settings};
+use crate::python;
+
+// Per-request limit of the sidecar when the settings give none
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProviderKind {
+    // Through the music_composer package
+    #[default]
+    Gemini,
+    // Any server with an OpenAI-style /chat/completions endpoint, e.g. llama.cpp or Ollama
+    OpenaiCompatible,
+    // Canned replies without any network access
+    Mock,
+}
+
+impl ProviderKind {
+    // Environment variable the sidecar reads the provider's API key from
+    pub fn key_variable(self) -> Option<&'static str> {
+        match self {
+            ProviderKind::Gemini => Some("GEMINI_API_KEYS"),
+            ProviderKind::OpenaiCompatible => Some("OPENAI_API_KEY"),
+            ProviderKind::Mock => None,
+        }
+    }
+}
+
+// Which model answers generation requests. Everything but the key travels in each sidecar
+// command; the key goes through the sidecar's environment
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ProviderSettings {
+    pub kind: ProviderKind,
+    pub base_url: Option<String>,
+    pub model: Option<String>,
+    // ABC tunes the mock provider hands out in turn; a built-in tune when empty
+    pub responses: Vec<String>,
+    pub timeout_secs: Option<u64>,
+}
+
+impl ProviderSettings {
+    fn validate(&self) -> Result<(), String> {
+        if self.kind == ProviderKind::OpenaiCompatible {
+            let base_url = self.base_url.as_deref().map(str::trim).unwrap_or_default();
+            if !(base_url.starts_with("http://") || base_url.starts_with("https://")) {
+                return Err("An OpenAI-compatible provider needs a base URL starting with http:// or https://".to_string());
+            }
+            if self.model.as_deref().is_none_or(|model| model.trim().is_empty()) {
+                return Err("An OpenAI-compatible provider needs a model name".to_string());
+            }
+        }
+        if self.timeout_secs == Some(0) {
+            return Err("The request timeout must be at least one second".to_string());
+        }
+        Ok(())
+    }
+
+    // The provider part of a sidecar command
+    pub fn command(&self) -> serde_json::Value {
+        serde_json::json!({
+            "kind": self.kind,
+            "base_url": self.base_url.as_deref().map(|base_url| base_url.trim().trim_end_matches('/')),
+            "model": self.model.as_deref().map(str::trim),
+            "responses": self.responses,
+            "timeout_secs": self.timeout_secs.unwrap_or(DEFAULT_TIMEOUT_SECS),
+        })
+    }
+}
+
+#[tauri::command]
+pub async fn get_provider() -> Result<ProviderSettings, String> {
+    Ok(provider_settings())
+}
+
+// The sidecar is restarted on the next request, as it reads the provider's key when it starts
+#[tauri::command]
+pub async fn set_provider(settings: ProviderSettings) -> Result<ProviderSettings, String> {
+    settings.validate()?;
+    save_provider_settings(&settings)?;
+    python::stop().await;
+    Ok(settings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn openai(base_url: &str, model: &str) -> ProviderSettings {
+        ProviderSettings {
+            kind: ProviderKind::OpenaiCompatible,
+            base_url: Some(base_url.to_string()),
+            model: Some(model.to_string()),
+            ..ProviderSettings::default()
+        }
+    }
+
+    #[test]
+    fn validate_checks_what_the_provider_needs() {
+        assert!(ProviderSettings::default().validate().is_ok());
+        assert!(ProviderSettings { kind: ProviderKind::Mock, ..ProviderSettings::default() }.validate().is_ok());
+        assert!(openai("http://localhost:8080/v1", "llama").validate().is_ok());
+        assert!(openai("localhost:8080", "llama").validate().is_err());
+        assert!(openai("https://api.example.com/v1", " ").validate().is_err());
+        assert!(ProviderSettings { kind: ProviderKind::OpenaiCompatible, ..ProviderSettings::default() }.validate().is_err());
+        assert!(ProviderSettings { timeout_secs: Some(0), ..ProviderSettings::default() }.validate().is_err());
+    }
+
+    #[test]
+    fn command_is_trimmed_and_has_a_timeout() {
+        let command = openai(" https://api.example.com/v1/ ", " gpt ").command();
+        assert_eq!(command["kind"], "openai_compatible");
+        assert_eq!(command["base_url"], "https://api.example.com/v1");
+        assert_eq!(command["model"], "gpt");
+        assert_eq!(command["timeout_secs"], DEFAULT_TIMEOUT_SECS);
+        let command = ProviderSettings { timeout_secs: Some(30), ..ProviderSettings::default() }.command();
+        assert_eq!(command["kind"], "gemini");
+        assert_eq!(command["timeout_secs"], 30);
+    }
+
+    // The key only reaches the sidecar through its environment, never inside a command
+    #[test]
+    fn command_never_carries_a_key() {
+        for kind in [ProviderKind::Gemini, ProviderKind::OpenaiCompatible, ProviderKind::Mock] {
+            let settings = ProviderSettings { kind, ..openai("https://api.example.com/v1", "gpt") };
+            let command = settings.command();
+            let fields: Vec<&String> = command.as_object().unwrap().keys().collect();
+            assert_eq!(fields, ["base_url", "kind", "model", "responses", "timeout_secs"]);
+            assert!(!command.to_string().to_ascii_lowercase().contains("key"));
+        }
+    }
+}
@@ -3,23 +3,110 @@ use std::sync::{Arc, Mutex};
 use std::collections::HashSet;
 use std::io::{BufRead, BufReader, Write};
 use std::thread;
+use std::fs;
+use std::path::PathBuf;
 use tauri::AppHandle;
+use crate::config::{next_api_key, provider_settings, record_api_key_request, record_api_key_success, report_api_key_error};
+use crate::library::add_generated;
 use crate::setup::EnvPaths;
+use crate::tune_processor::{cache_result, current_prompt, current_text, finish_generation, render_abc_reply, retry_generation, Progress};
 use crate::utils::send_to_frontend;
+use crate::validation::{validate_output, ValidationReport};
+
+// Separates the audio path of a success line from the MIDI written for the request
+const MIDI_MARKER: &str = "|midi=";
 
 lazy_static::lazy_static! {
 	static ref PYTHON_PROCESS: Arc<Mutex<Option<std::process::Child>>> = Arc::new(Mutex::new(None));
 	static ref SENT_RESULTS: Mutex<HashSet<String>> = Mutex::new(HashSet::new());
+	// Id of the API key the running sidecar was started with
+	static ref PROCESS_API_KEY: Mutex<Option<String>> = Mutex::new(None);
 }
 
-async fn handle_process_output(app: &AppHandle, reader: impl BufRead, event_type: &str) {
+// Validates a finished generation, then either retries it, moves on to the next part of a
+// batch or adds the result to the library
+pub async fn complete_generation(app: &AppHandle, line: &str, event_type: &str) {
 	let paths = EnvPaths::new();
+	// The MIDI the sidecar reports for this request is the one kept, under the output.mid name
+	let line = match line.split_once(MIDI_MARKER) {
+		Some((line, midi)) => {
+			let midi = PathBuf::from(midi.trim());
+			if midi != paths.output_midi {
+				if let Err(e) = fs::copy(&midi, &paths.output_midi) {
+					send_to_frontend(app, format!("Failed to keep the generated MIDI: {}", e), "error");
+				}
+			}
+			line
+		}
+		None => line,
+	};
+	if let Err(e) = render_abc_reply(&paths) {
+		if retry_generation(app).await {
+			send_to_frontend(app, format!("{}, generating it again", e), event_type);
+			return;
+		}
+		send_to_frontend(app, line.to_string(), "initialize_setup_completed");
+		send_to_frontend(app, e, "error");
+		return;
+	}
+	let report = if paths.output_midi.exists() {
+		validate_output(&paths.output_midi, &paths.output_file, &current_text()).unwrap_or_else(|e| {
+			send_to_frontend(app, format!("Failed to validate tune: {}", e), "error");
+			ValidationReport::default()
+		})
+	} else {
+		ValidationReport::default()
+	};
+	if !report.issues.is_empty() {
+		send_to_frontend(app, serde_json::to_string(&report).unwrap_or_default(), "validation_report");
+	}
+	if report.retry && retry_generation(app).await {
+		send_to_frontend(app, "The tune failed validation, generating it again".to_string(), event_type);
+		return;
+	}
+	if !report.retry {
+		cache_result();
+	}
+	let generated = match finish_generation(app, report.issues).await {
+		Ok(Progress::NextPart) => return,
+		Ok(Progress::Finished(generated)) => generated,
+		Err(e) => {
+			send_to_frontend(app, line.to_string(), "initialize_setup_completed");
+			send_to_frontend(app, format!("Failed to finish generation: {}", e), "error");
+			return;
+		}
+	};
+	if let Err(e) = add_generated(&current_prompt(), generated) {
+		send_to_frontend(app, format!("Failed to add tune to library: {}", e), "error");
+	}
+	send_to_frontend(app, line.to_string(), "initialize_setup_completed");
+	send_to_frontend(app, paths.output_file.display().to_string(), "tune_file_created");
+}
+
+async fn handle_process_output(app: &AppHandle, reader: impl BufRead, event_type: &str) {
 	for line in reader.lines().flatten() {
 		if line.contains("LogCoQ=1002") {
-			send_to_frontend(app, line.to_string(), "initialize_setup_completed");
-			send_to_frontend(app, paths.output_file.display().to_string(), "tune_file_created");
+			if let Some(id) = process_api_key() {
+				let _ = record_api_key_success(&id);
+			}
+			complete_generation(app, &line, event_type).await;
 		} else if line.contains("LogCoQ=1003") {
 			let msg = line.split("LogCoQ=1003").collect::<Vec<&str>>()[1];
+			if let Some(id) = process_api_key() {
+				match report_api_key_error(&id, msg) {
+					// The sidecar reads its key at start, so it is restarted for the retry
+					Ok(Some(next)) => {
+						send_to_frontend(app, format!("The API key hit its rate limit or quota, switching to {}", next), event_type);
+						stop().await;
+					}
+					Ok(None) => {}
+					Err(e) => send_to_frontend(app, format!("Failed to record the API key error: {}", e), "error"),
+				}
+			}
+			if retry_generation(app).await {
+				send_to_frontend(app, format!("Generation failed, trying again: {}", msg), event_type);
+				continue;
+			}
 			send_to_frontend(app, line.to_string(), "initialize_setup_completed");
 			send_to_frontend(app, msg.to_string(), "error");
 		} else if line.contains("LogCoQ=1001") {
@@ -32,7 +119,8 @@ async fn handle_process_output(app: &AppHandle, reader: impl BufRead, event_type
 pub async fn start(app: AppHandle, command: String) {
 	let paths = EnvPaths::new();
 	
-	match Command::new(&paths.python)
+	let mut process = Command::new(&paths.python);
+	process
 		.arg("-u")
 		.arg(&paths.main_py)
         .arg(command)
@@ -40,9 +128,24 @@ pub async fn start(app: AppHandle, command: String) {
 		.current_dir(&paths.temp_dir) // Set the working directory to paths.tempdir
 		.stdin(Stdio::piped())
 		.stdout(Stdio::piped())
-		.stderr(Stdio::piped())
-		.spawn()
-	{
+		.stderr(Stdio::piped());
+
+	// The key is decrypted only here and reaches the sidecar through its environment. Only one
+	// key is handed over, rotating between keys is done here when the sidecar reports a limit
+	let mut key_id = None;
+	let kind = provider_settings().kind;
+	if let Some(variable) = kind.key_variable() {
+		match next_api_key(kind) {
+			Ok(Some((id, key))) => {
+				process.env(variable, key);
+				key_id = Some(id);
+			}
+			Ok(None) => {}
+			Err(e) => send_to_frontend(&app, e, "error"),
+		}
+	}
+
+	match process.spawn() {
 		Ok(mut child) => {
 			let stdout = child.stdout.take().unwrap();
 			let stderr = child.stderr.take().unwrap();
@@ -51,6 +154,7 @@ pub async fn start(app: AppHandle, command: String) {
 			{
 				let mut process_guard = PYTHON_PROCESS.lock().unwrap();
 				*process_guard = Some(child); // Store the whole child process, stdin is now part of it.
+				*PROCESS_API_KEY.lock().unwrap() = key_id;
 			}
 
 			// Handle stdout asynchronously
@@ -104,6 +208,20 @@ pub async fn send_command(app: &AppHandle, command: &str) {
         println!("Python process is not running, starting...");
 		start(app.clone(), command.to_string()).await; // Safe to await here as guard is dropped
     }
+	if let Some(id) = process_api_key() {
+		let _ = record_api_key_request(&id);
+	}
+}
+
+fn process_api_key() -> Option<String> {
+	PROCESS_API_KEY.lock().ok().and_then(|id| id.clone())
+}
+
+// Stops the sidecar when it runs with the given key, e.g. after the key was disabled
+pub async fn release_api_key(id: &str) {
+	if process_api_key().as_deref() == Some(id) {
+		stop().await;
+	}
 }
 
 // Stop the Python process
@@ -112,4 +230,7 @@ pub async fn stop() {
 	if let Some(mut child) = process_guard.take() {
 		let _ = child.kill();
 	}
+	if let Ok(mut id) = PROCESS_API_KEY.lock() {
+		*id = None;
+	}
 }
\ No newline at end of file
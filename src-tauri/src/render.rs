@@ -0,0 +1,82 @@
+use std::fs;
+use std::path::Path;
+use std::process::Command;
+use serde::{Deserialize, Serialize};
+use crate::setup::EnvPaths;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RenderSettings {
+    pub sample_rate: u32,
+    pub gain: f32,
+    pub reverb: bool,
+    pub chorus: bool,
+    pub reverb_room_size: Option<f32>,
+    pub reverb_level: Option<f32>,
+    pub chorus_depth: Option<f32>,
+    pub chorus_level: Option<f32>,
+}
+
+impl Default for RenderSettings {
+    fn default() -> Self {
+        Self {
+            sample_rate: 44100,
+            gain: 0.5,
+            reverb: true,
+            chorus: true,
+            reverb_room_size: None,
+            reverb_level: None,
+            chorus_depth: None,
+            chorus_level: None,
+        }
+    }
+}
+
+// Renders a MIDI file to WAV through the FluidSynth command line
+pub fn render_midi(midi: &Path, soundfont: &Path, output: &Path, settings: &RenderSettings) -> Result<(), String> {
+    if !soundfont.exists() {
+        return Err(format!("SoundFont not found at {:?}", soundfont));
+    }
+    if let Some(parent) = output.parent() {
+        fs::create_dir_all(parent).map_err(|e| format!("Failed to create render directory: {}", e))?;
+    }
+
+    let mut command = Command::new(&EnvPaths::new().fluidsynth);
+    command
+        .arg("-ni")
+        .args(["-T", "wav"])
+        .args(["-r", &settings.sample_rate.to_string()])
+        .args(["-g", &settings.gain.to_string()])
+        .args(["-R", if settings.reverb { "1" } else { "0" }])
+        .args(["-C", if settings.chorus { "1" } else { "0" }]);
+
+    // Fine-grained effect parameters are passed as FluidSynth settings
+    let effects = [
+        ("synth.reverb.room-size", settings.reverb_room_size),
+        ("synth.reverb.level", settings.reverb_level),
+        ("synth.chorus.depth", settings.chorus_depth),
+        ("synth.chorus.level", settings.chorus_level),
+    ];
+    for (name, value) in effects {
+        if let Some(value) = value {
+            command.arg("-o").arg(format!("{}={}", name, value));
+        }
+    }
+
+    command
+        .arg("-F")
+        .arg(output)
+        .arg(soundfont)
+        .arg(midi);
+
+    println!("Rendering {} with {}", midi.display(), soundfont.display());
+    match command.output() {
+        Ok(result) if result.status.success() => Ok(()),
+        Ok(result) => Err(format!(
+            "FluidSynth failed with status {}: {}",
+            result.status,
+            String::from_utf8_lossy(&result.stderr)
+        )),
+        Err(e) => Err(format!("Failed to start FluidSynth: {}", e)),
+    }
+}
@@ -0,0 +1,252 @@
+use std::collections::BTreeMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::sync::Mutex;
+use aes_gcm::aead::{Aead, AeadCore, KeyInit, OsRng, Payload};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use serde::{Deserialize, Serialize};
+use crate::setup::EnvPaths;
+use crate::utils::register_secret;
+
+const KEYRING_SERVICE: &str = "musiccomposer";
+const KEYRING_USER: &str = "secrets-key";
+
+lazy_static::lazy_static! {
+    // Serializes read-modify-write cycles on the secrets file
+    static ref SECRETS_LOCK: Mutex<()> = Mutex::new(());
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct Sealed {
+    nonce: String,
+    ciphertext: String,
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn from_hex(text: &str) -> Result<Vec<u8>, String> {
+    if !text.is_ascii() || !text.len().is_multiple_of(2) {
+        return Err("Invalid hex text".to_string());
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|index| u8::from_str_radix(&text[index..index + 2], 16).map_err(|_| "Invalid hex text".to_string()))
+        .collect()
+}
+
+// Writes a file only the current user can read; the mode is set again for files that already existed
+fn write_private(path: &Path, content: &[u8]) -> Result<(), String> {
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        options.mode(0o600);
+    }
+    let mut file = options.open(path).map_err(|e| format!("Failed to write {}: {}", path.display(), e))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(0o600)).map_err(|e| format!("Failed to restrict {}: {}", path.display(), e))?;
+    }
+    file.write_all(content).map_err(|e| format!("Failed to write {}: {}", path.display(), e))
+}
+
+fn keyring_entry() -> Option<keyring::Entry> {
+    keyring::Entry::new(KEYRING_SERVICE, KEYRING_USER).ok()
+}
+
+// The key secrets are encrypted with. It lives in the OS secret service when there is one,
+// otherwise in a key file next to the secrets. A key file, once written, stays in use.
+// A new key is only made while no secrets are stored, since they would become unreadable
+fn master_key(paths: &EnvPaths, entry: Option<keyring::Entry>) -> Result<Vec<u8>, String> {
+    if paths.secrets_key.exists() {
+        let content = fs::read_to_string(&paths.secrets_key).map_err(|e| format!("Failed to read secrets key: {}", e))?;
+        return from_hex(content.trim());
+    }
+    let unavailable = match entry.as_ref().map(|entry| entry.get_password()) {
+        Some(Ok(stored)) => return from_hex(stored.trim()),
+        Some(Err(keyring::Error::NoEntry)) => None,
+        // A locked keyring holds the key until it is unlocked
+        Some(Err(keyring::Error::NoStorageAccess(e))) => return Err(format!("The system keyring is locked, unlock it and try again: {}", e)),
+        Some(Err(e)) => Some(e.to_string()),
+        None => Some("no secret service".to_string()),
+    };
+    if !load(paths)?.is_empty() {
+        return Err(match unavailable {
+            Some(reason) => format!("The key of the stored secrets cannot be read ({}), try again later", reason),
+            None => format!("The key of the stored secrets is gone, delete {} and enter them again", paths.secrets.display()),
+        });
+    }
+
+    let key = to_hex(&Aes256Gcm::generate_key(OsRng));
+    // Only trust the secret service when the key can be read back
+    let stored = entry.is_some_and(|entry| entry.set_password(&key).is_ok() && entry.get_password().is_ok_and(|read| read == key));
+    if !stored {
+        write_private(&paths.secrets_key, key.as_bytes())?;
+    }
+    from_hex(&key)
+}
+
+fn cipher(paths: &EnvPaths, entry: Option<keyring::Entry>) -> Result<Aes256Gcm, String> {
+    let key = master_key(paths, entry)?;
+    if key.len() != 32 {
+        return Err("The secrets key is damaged".to_string());
+    }
+    Ok(Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&key)))
+}
+
+fn load(paths: &EnvPaths) -> Result<BTreeMap<String, Sealed>, String> {
+    if !paths.secrets.exists() {
+        return Ok(BTreeMap::new());
+    }
+    let content = fs::read_to_string(&paths.secrets).map_err(|e| format!("Failed to read secrets: {}", e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Failed to parse secrets: {}", e))
+}
+
+fn save(paths: &EnvPaths, secrets: &BTreeMap<String, Sealed>) -> Result<(), String> {
+    let content = serde_json::to_string_pretty(secrets).map_err(|e| e.to_string())?;
+    write_private(&paths.secrets, content.as_bytes())
+}
+
+// Stores a secret encrypted, or removes it when `value` is None or empty.
+// The name is bound to the ciphertext, so values cannot be swapped between names
+pub fn set_secret(name: &str, value: Option<&str>) -> Result<(), String> {
+    let _guard = SECRETS_LOCK.lock().map_err(|e| e.to_string())?;
+    seal(&EnvPaths::new(), keyring_entry(), name, value)
+}
+
+fn seal(paths: &EnvPaths, entry: Option<keyring::Entry>, name: &str, value: Option<&str>) -> Result<(), String> {
+    let mut secrets = load(paths)?;
+    match value.filter(|value| !value.is_empty()) {
+        Some(value) => {
+            register_secret(value);
+            let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+            let ciphertext = cipher(paths, entry)?
+                .encrypt(&nonce, Payload { msg: value.as_bytes(), aad: name.as_bytes() })
+                .map_err(|_| format!("Failed to encrypt {}", name))?;
+            secrets.insert(name.to_string(), Sealed { nonce: to_hex(&nonce), ciphertext: to_hex(&ciphertext) });
+        }
+        None => {
+            secrets.remove(name);
+        }
+    }
+    save(paths, &secrets)
+}
+
+// Decrypts a secret; only call this right before handing it to the process that needs it
+pub fn get_secret(name: &str) -> Result<Option<String>, String> {
+    let _guard = SECRETS_LOCK.lock().map_err(|e| e.to_string())?;
+    unseal(&EnvPaths::new(), keyring_entry(), name)
+}
+
+fn unseal(paths: &EnvPaths, entry: Option<keyring::Entry>, name: &str) -> Result<Option<String>, String> {
+    let Some(sealed) = load(paths)?.remove(name) else {
+        return Ok(None);
+    };
+    let nonce = from_hex(&sealed.nonce)?;
+    if nonce.len() != 12 {
+        return Err(format!("The stored {} is damaged", name));
+    }
+    let plain = cipher(paths, entry)?
+        .decrypt(Nonce::from_slice(&nonce), Payload { msg: &from_hex(&sealed.ciphertext)?, aad: name.as_bytes() })
+        .map_err(|_| format!("The stored {} could not be decrypted, please enter it again", name))?;
+    let value = String::from_utf8(plain).map_err(|_| format!("The stored {} is damaged", name))?;
+    register_secret(&value);
+    Ok(Some(value))
+}
+
+pub fn has_secret(name: &str) -> bool {
+    let _guard = SECRETS_LOCK.lock();
+    load(&EnvPaths::new()).is_ok_and(|secrets| secrets.contains_key(name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    // Application directory of a test, removed again when dropped. Tests run without the
+    // system keyring, so the key always goes to the key file
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Self {
+            let dir = std::env::temp_dir().join(format!("secrets-test-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(&dir).unwrap();
+            Scratch(dir)
+        }
+
+        fn paths(&self) -> EnvPaths {
+            EnvPaths::in_dir(self.0.clone())
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    #[test]
+    fn secrets_round_trip_and_delete() {
+        let scratch = Scratch::new("round-trip");
+        let paths = scratch.paths();
+        assert_eq!(unseal(&paths, None, "token"), Ok(None));
+        seal(&paths, None, "token", Some("s3cret value")).unwrap();
+        seal(&paths, None, "other", Some("second")).unwrap();
+        assert_eq!(unseal(&paths, None, "token"), Ok(Some("s3cret value".to_string())));
+        assert!(!fs::read_to_string(&paths.secrets).unwrap().contains("s3cret"));
+
+        seal(&paths, None, "token", None).unwrap();
+        assert_eq!(unseal(&paths, None, "token"), Ok(None));
+        seal(&paths, None, "other", Some("")).unwrap();
+        assert!(load(&paths).unwrap().is_empty());
+    }
+
+    #[test]
+    fn ciphertext_is_bound_to_its_name() {
+        let scratch = Scratch::new("aad");
+        let paths = scratch.paths();
+        seal(&paths, None, "first", Some("value")).unwrap();
+        let mut secrets = load(&paths).unwrap();
+        let sealed = secrets["first"].clone();
+        secrets.insert("second".to_string(), sealed);
+        save(&paths, &secrets).unwrap();
+        assert_eq!(unseal(&paths, None, "first"), Ok(Some("value".to_string())));
+        assert!(unseal(&paths, None, "second").unwrap_err().contains("could not be decrypted"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn key_file_is_private() {
+        use std::os::unix::fs::PermissionsExt;
+        let scratch = Scratch::new("mode");
+        let paths = scratch.paths();
+        seal(&paths, None, "token", Some("value")).unwrap();
+        for file in [&paths.secrets_key, &paths.secrets] {
+            assert_eq!(fs::metadata(file).unwrap().permissions().mode() & 0o777, 0o600);
+        }
+    }
+
+    #[test]
+    fn lost_key_is_not_replaced_while_secrets_exist() {
+        let scratch = Scratch::new("lost-key");
+        let paths = scratch.paths();
+        seal(&paths, None, "token", Some("value")).unwrap();
+        fs::remove_file(&paths.secrets_key).unwrap();
+        assert!(unseal(&paths, None, "token").unwrap_err().contains("cannot be read"));
+        assert!(seal(&paths, None, "other", Some("value")).is_err());
+        assert!(!paths.secrets_key.exists());
+
+        // Once the secrets are gone a new key is made
+        fs::remove_file(&paths.secrets).unwrap();
+        seal(&paths, None, "token", Some("again")).unwrap();
+        assert!(paths.secrets_key.exists());
+        assert_eq!(unseal(&paths, None, "token"), Ok(Some("again".to_string())));
+    }
+}
@@ -0,0 +1,215 @@
+use serde::{Deserialize, Serialize};
+use crate::midi::{Composition, ControlEvent, KeySignature, Note, TempoChange, TimeSignature, Track, DEFAULT_TEMPO};
+
+// Short paragraphs are joined and long ones split at sentence ends to keep sections musical
+const MIN_SECTION_WORDS: usize = 40;
+const MAX_SECTION_WORDS: usize = 250;
+const EXCERPT_LENGTH: usize = 60;
+const SCENE_BREAKS: [&str; 4] = ["***", "* * *", "---", "###"];
+// Velocity of the last note of a section relative to the start of its final bar
+const FADE_OUT: f64 = 0.7;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct TimelineEntry {
+    pub section: usize,
+    pub excerpt: String,
+    pub start_seconds: f64,
+    pub end_seconds: f64,
+}
+
+fn word_count(text: &str) -> usize {
+    text.split_whitespace().count()
+}
+
+// Splits a long paragraph after the sentence that takes it past the word limit
+fn split_long(paragraph: &str) -> Vec<String> {
+    let mut parts = Vec::new();
+    let mut current = String::new();
+    for (index, c) in paragraph.char_indices() {
+        current.push(c);
+        let sentence_end = matches!(c, '.' | '!' | '?') && paragraph[index + c.len_utf8()..].starts_with(char::is_whitespace);
+        if sentence_end && word_count(&current) >= MAX_SECTION_WORDS {
+            parts.push(std::mem::take(&mut current).trim().to_string());
+        }
+    }
+    if !current.trim().is_empty() {
+        parts.push(current.trim().to_string());
+    }
+    parts
+}
+
+// Splits text into sections at scene breaks, headings and paragraphs
+pub fn split_sections(text: &str) -> Vec<String> {
+    let mut paragraphs: Vec<(String, bool)> = Vec::new();
+    let mut current = String::new();
+    let mut scene_break = false;
+    for line in text.lines().chain(std::iter::once("")) {
+        let trimmed = line.trim();
+        let heading = trimmed.starts_with('#') || ["chapter ", "scene ", "part "].iter().any(|word| trimmed.to_lowercase().starts_with(word));
+        let is_break = SCENE_BREAKS.contains(&trimmed);
+        if trimmed.is_empty() || is_break || heading {
+            if !current.trim().is_empty() {
+                paragraphs.push((std::mem::take(&mut current), scene_break));
+                scene_break = false;
+            }
+            scene_break |= is_break || heading;
+            if !heading {
+                continue;
+            }
+        }
+        current.push_str(trimmed);
+        current.push('\n');
+    }
+
+    // Paragraphs are merged until long enough, but never across a scene break
+    let mut sections: Vec<String> = Vec::new();
+    for (paragraph, after_break) in paragraphs {
+        match sections.last_mut() {
+            Some(last) if !after_break && word_count(last) < MIN_SECTION_WORDS => {
+                last.push('\n');
+                last.push_str(paragraph.trim());
+            }
+            _ => sections.push(paragraph.trim().to_string()),
+        }
+    }
+    sections.iter().flat_map(|section| split_long(section)).collect()
+}
+
+pub fn excerpt(text: &str) -> String {
+    let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+    if line.chars().count() <= EXCERPT_LENGTH {
+        line
+    } else {
+        format!("{}…", line.chars().take(EXCERPT_LENGTH).collect::<String>())
+    }
+}
+
+// Tick after the last bar the segment touches
+fn padded_end(segment: &Composition) -> u64 {
+    let end = segment.end_tick();
+    if end == 0 {
+        return 0;
+    }
+    segment.bar_to_tick(segment.tick_to_bar(end - 1) + 1)
+}
+
+// Joins segments bar-aligned into one piece. Each channel keeps the program of the segment
+// that used it first, the end of every segment fades and its tempo glides into the next one.
+pub fn stitch(segments: &[Composition]) -> Result<(Composition, Vec<(f64, f64)>), String> {
+    let first = segments.first().ok_or_else(|| "Nothing to stitch".to_string())?;
+    let ticks_per_beat = first.ticks_per_beat;
+    let mut result = Composition::new(ticks_per_beat);
+    let mut ranges = Vec::new();
+    let mut offset = 0u64;
+
+    for (index, segment) in segments.iter().enumerate() {
+        let scale = |tick: u64| tick * ticks_per_beat as u64 / segment.ticks_per_beat.max(1) as u64;
+        let length = scale(padded_end(segment));
+        let last_bar = scale(segment.bar_to_tick(segment.tick_to_bar(padded_end(segment).saturating_sub(1))));
+
+        let mut tempos: Vec<TempoChange> = segment.tempos.iter().map(|change| TempoChange { tick: offset + scale(change.tick), ..*change }).collect();
+        if tempos.first().is_none_or(|change| change.tick > offset) {
+            tempos.insert(0, TempoChange { tick: offset, micros_per_beat: DEFAULT_TEMPO });
+        }
+        // Glide beat by beat through the final bar towards the next segment's tempo
+        if let Some(next) = segments.get(index + 1) {
+            let from = segment.tempo_at(padded_end(segment).saturating_sub(1)) as f64;
+            let to = next.tempo_at(0) as f64;
+            let beat = ticks_per_beat as u64;
+            let beats = ((length - last_bar) / beat).max(1);
+            for step in 1..beats {
+                let micros_per_beat = (from + (to - from) * step as f64 / beats as f64).round() as u32;
+                tempos.push(TempoChange { tick: offset + last_bar + step * beat, micros_per_beat });
+            }
+        }
+        result.tempos.extend(tempos);
+        result.time_signatures.extend(segment.time_signatures.iter().map(|change| TimeSignature { tick: offset + scale(change.tick), ..*change }));
+        result.key_signatures.extend(segment.key_signatures.iter().map(|change| KeySignature { tick: offset + scale(change.tick), ..*change }));
+        result.controls.extend(segment.controls.iter().map(|event| ControlEvent { tick: offset + scale(event.tick), ..*event }));
+
+        for track in &segment.tracks {
+            let notes = track.notes.iter().map(|note| {
+                let start = scale(note.start);
+                let mut velocity = note.velocity as f64;
+                if index + 1 < segments.len() && start >= last_bar && length > last_bar {
+                    velocity *= 1.0 - (1.0 - FADE_OUT) * (start - last_bar) as f64 / (length - last_bar) as f64;
+                }
+                Note { start: offset + start, duration: scale(note.duration).max(1), pitch: note.pitch, velocity: velocity.round().clamp(1.0, 127.0) as u8 }
+            });
+            match result.tracks.iter_mut().find(|existing| existing.channel == track.channel) {
+                Some(existing) => existing.notes.extend(notes),
+                None => result.tracks.push(Track { name: track.name.clone(), channel: track.channel, program: track.program, notes: notes.collect() }),
+            }
+        }
+        ranges.push((offset, offset + length));
+        offset += length;
+    }
+
+    result.tempos.sort_by_key(|change| change.tick);
+    result.tempos.dedup_by_key(|change| change.tick);
+    result.controls.sort_by_key(|event| event.tick);
+    for track in result.tracks.iter_mut() {
+        track.notes.sort_by_key(|note| (note.start, note.pitch));
+    }
+    let ranges = ranges.into_iter().map(|(start, end)| (result.tick_to_seconds(start), result.tick_to_seconds(end))).collect();
+    Ok((result, ranges))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::Control;
+
+    fn sentences(count: usize, words: usize) -> String {
+        vec![format!("{}end.", "word ".repeat(words - 1)); count].join(" ")
+    }
+
+    fn segment(ticks_per_beat: u16, notes: &[(u64, u64)], micros_per_beat: u32, control: (u64, u8, u8)) -> Composition {
+        let mut segment = Composition::new(ticks_per_beat);
+        segment.tempos.push(TempoChange { tick: 0, micros_per_beat });
+        let notes = notes.iter().map(|&(start, duration)| Note { start, duration, pitch: 60, velocity: 100 }).collect();
+        segment.tracks.push(Track { name: None, channel: 0, program: 0, notes });
+        let (tick, controller, value) = control;
+        segment.controls.push(ControlEvent { tick, channel: 0, control: Control::Controller { controller, value } });
+        segment
+    }
+
+    #[test]
+    fn sections_follow_breaks_and_length() {
+        let text = format!("# One\n{}\n\n{}\n\n***\n\nShort tail.\nScene 2\nAnother scene.", sentences(2, 15), sentences(1, 10));
+        let sections = split_sections(&text);
+        assert_eq!(sections.len(), 3);
+        // The short second paragraph joins the first, the scene break and the heading start new sections
+        assert!(sections[0].starts_with("# One\n") && sections[0].ends_with(".\nword word word word word word word word word end."));
+        assert_eq!(sections[1], "Short tail.");
+        assert_eq!(sections[2], "Scene 2\nAnother scene.");
+
+        let parts = split_long(&sentences(6, 50));
+        assert_eq!(parts.iter().map(|part| word_count(part)).collect::<Vec<_>>(), vec![250, 50]);
+        assert_eq!(split_long("No sentence end here"), vec!["No sentence end here"]);
+    }
+
+    #[test]
+    fn segments_are_joined_on_bar_lines() {
+        // The first segment spills into its second bar, the second runs at half the resolution
+        let first = segment(480, &[(0, 1920), (2400, 80)], 500_000, (960, 64, 127));
+        let second = segment(240, &[(0, 240)], 1_000_000, (120, 7, 90));
+        let (joined, ranges) = stitch(&[first, second]).unwrap();
+
+        let notes: Vec<(u64, u64)> = joined.tracks[0].notes.iter().map(|note| (note.start, note.duration)).collect();
+        assert_eq!(notes, vec![(0, 1920), (2400, 80), (3840, 480)]);
+        let controls: Vec<(u64, Control)> = joined.controls.iter().map(|event| (event.tick, event.control)).collect();
+        assert_eq!(controls, vec![(960, Control::Controller { controller: 64, value: 127 }), (4080, Control::Controller { controller: 7, value: 90 })]);
+
+        // The last bar of the first segment glides from 120 to 60 BPM beat by beat
+        let tempos: Vec<(u64, u32)> = joined.tempos.iter().map(|change| (change.tick, change.micros_per_beat)).collect();
+        assert_eq!(tempos, vec![(0, 500_000), (2400, 625_000), (2880, 750_000), (3360, 875_000), (3840, 1_000_000)]);
+        let expected = [(0.0, 4.75), (4.75, 8.75)];
+        for ((start, end), (expected_start, expected_end)) in ranges.iter().zip(expected) {
+            assert!((start - expected_start).abs() < 1e-9 && (end - expected_end).abs() < 1e-9, "{:?}", ranges);
+        }
+        // A beat into the faded final bar
+        assert_eq!(joined.tracks[0].notes[1].velocity, 93);
+        assert!(stitch(&[]).is_err());
+    }
+}
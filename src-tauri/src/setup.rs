@@ -13,8 +13,17 @@ const VENV_DIR: &str = "venv";
 const CONFIG_FILE: &str = "config.json";
 const MAIN_PY: &str = "main.py";
 const ENV: &str = ".env";
-// const SOUNDFONT: &str = "FluidR3_GM.sf2";
+const SOUNDFONT: &str = "FluidR3_GM.sf2";
+const FLUIDSYNTH_EXE: &str = "fluidsynth.exe";
 const OUTPUT_FILE: &str = "output.wav";
+const OUTPUT_MIDI: &str = "output.mid";
+const OUTPUT_ABC: &str = "output.abc";
+const RENDERS_DIR: &str = "renders";
+const LIBRARY_DIR: &str = "library";
+const CACHE_DIR: &str = "cache";
+const ARRANGEMENTS_DIR: &str = "arrangements";
+const SECRETS_FILE: &str = "secrets.json";
+const SECRETS_KEY_FILE: &str = "secrets.key";
 
 pub struct EnvPaths {
     pub python: PathBuf,
@@ -22,15 +31,31 @@ pub struct EnvPaths {
     pub temp_dir: PathBuf,
     pub main_py: PathBuf,
     pub env: PathBuf,
-    // pub soundfont: PathBuf,
+    pub soundfont: PathBuf,
+    // The copy of the bundled executable on Windows once setup placed it, otherwise the one on PATH
+    pub fluidsynth: PathBuf,
     pub output_file: PathBuf,
+    pub output_midi: PathBuf,
+    // Written by providers that answer in ABC notation instead of MIDI
+    pub output_abc: PathBuf,
+    pub renders: PathBuf,
+    pub library: PathBuf,
+    pub cache: PathBuf,
+    pub arrangements: PathBuf,
+    pub secrets: PathBuf,
+    // Only used where no OS secret service is available
+    pub secrets_key: PathBuf,
 }
 
 impl EnvPaths {
     pub fn new() -> Self {
         let temp_dir = env::temp_dir().join(APP_TEMP_DIR);
         fs::create_dir_all(&temp_dir).expect("Failed to create application directory");
+        Self::in_dir(temp_dir)
+    }
 
+    // The same layout below another directory, which the caller creates
+    pub fn in_dir(temp_dir: PathBuf) -> Self {
         let python = if cfg!(target_os = "windows") {
             temp_dir.join(VENV_DIR).join("Scripts").join("python.exe")
         } else {
@@ -40,8 +65,22 @@ impl EnvPaths {
         let config = temp_dir.join(CONFIG_FILE);
         let main_py = temp_dir.join(MAIN_PY);
         let env = temp_dir.join(ENV);
-        // let soundfont = temp_dir.join(SOUNDFONT);
+        let soundfont = temp_dir.join(SOUNDFONT);
+        let bundled_fluidsynth = temp_dir.join(FLUIDSYNTH_EXE);
+        let fluidsynth = if cfg!(target_os = "windows") && bundled_fluidsynth.exists() {
+            bundled_fluidsynth
+        } else {
+            PathBuf::from("fluidsynth")
+        };
         let output_file = temp_dir.join(OUTPUT_FILE);
+        let output_midi = temp_dir.join(OUTPUT_MIDI);
+        let output_abc = temp_dir.join(OUTPUT_ABC);
+        let renders = temp_dir.join(RENDERS_DIR);
+        let library = temp_dir.join(LIBRARY_DIR);
+        let cache = temp_dir.join(CACHE_DIR);
+        let arrangements = temp_dir.join(ARRANGEMENTS_DIR);
+        let secrets = temp_dir.join(SECRETS_FILE);
+        let secrets_key = temp_dir.join(SECRETS_KEY_FILE);
 
         Self {
             python,
@@ -49,8 +88,17 @@ impl EnvPaths {
             temp_dir,
             main_py,
             env,
-            // soundfont,
-            output_file
+            soundfont,
+            fluidsynth,
+            output_file,
+            output_midi,
+            output_abc,
+            renders,
+            library,
+            cache,
+            arrangements,
+            secrets,
+            secrets_key,
         }
     }
 }
@@ -62,7 +110,7 @@ fn get_resource_path(app: &AppHandle, resource_type: &str) -> PathBuf {
         "config" => format!("{base}{CONFIG_FILE}"),
         "main_py" => format!("{base}{MAIN_PY}"),
         "env" => format!("{base}{ENV}"),
-        "fluidsynth" => format!("{base}fluidsynth.exe"),
+        "fluidsynth" => format!("{base}{FLUIDSYNTH_EXE}"),
         _ => {
             send_to_frontend(app, format!("Unsupported resource type: {}", resource_type), "error");
             return PathBuf::new();
@@ -220,10 +268,24 @@ async fn setup_config(app: &AppHandle, paths: &EnvPaths) -> Result<String, Strin
     copy_resource(app, &get_resource_path(app, "env"), &paths.env).await
 }
 
+// Windows builds bundle FluidSynth instead of installing it. It is copied on start when missing,
+// so installs set up before it was bundled pick it up too
+async fn setup_fluidsynth(app: &AppHandle, paths: &EnvPaths) -> Result<(), String> {
+    let destination = paths.temp_dir.join(FLUIDSYNTH_EXE);
+    if cfg!(target_os = "windows") && !destination.exists() {
+        copy_resource(app, &get_resource_path(app, "fluidsynth"), &destination).await?;
+    }
+    Ok(())
+}
+
 #[tauri::command]
 pub async fn initialize_setup(app: AppHandle) {
     let paths = EnvPaths::new();
 
+    if let Err(e) = setup_fluidsynth(&app, &paths).await {
+        send_to_frontend(&app, format!("Failed to set up FluidSynth: {}", e), "initialize_setup_error");
+    }
+
     if let Ok(message) = setup_python(&app, &paths).await {
         if message == "already installed.".to_string() {
             send_to_frontend(&app, "Python already installed".to_string(), "initialize_setup_completed");
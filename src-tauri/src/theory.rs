@@ -0,0 +1,642 @@
+use serde::{Deserialize, Serialize};
+use crate::library::Library;
+use crate::midi::{Composition, DRUM_CHANNEL};
+
+const SHARP_NAMES: [&str; 12] = ["C", "C#", "D", "D#", "E", "F", "F#", "G", "G#", "A", "A#", "B"];
+const FLAT_NAMES: [&str; 12] = ["C", "Db", "D", "Eb", "E", "F", "Gb", "G", "Ab", "A", "Bb", "B"];
+const LETTERS: [(char, u8); 7] = [('C', 0), ('D', 2), ('E', 4), ('F', 5), ('G', 7), ('A', 9), ('B', 11)];
+
+// Krumhansl-Kessler key profiles, indexed from the tonic
+const MAJOR_PROFILE: [f64; 12] = [6.35, 2.23, 3.48, 2.33, 4.38, 4.09, 2.52, 5.19, 2.39, 3.66, 2.29, 2.88];
+const MINOR_PROFILE: [f64; 12] = [6.33, 2.68, 3.52, 5.38, 2.60, 3.53, 2.54, 4.75, 3.98, 2.69, 3.34, 3.17];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub struct PitchClass(pub u8);
+
+impl PitchClass {
+    pub fn new(value: i32) -> Self {
+        PitchClass(value.rem_euclid(12) as u8)
+    }
+
+    pub fn of_midi(pitch: u8) -> Self {
+        PitchClass(pitch % 12)
+    }
+
+    // Parses a note name such as "C", "F#" or "Bb"
+    pub fn parse(name: &str) -> Option<(Self, usize)> {
+        let mut chars = name.chars();
+        let letter = chars.next()?.to_ascii_uppercase();
+        let mut value = LETTERS.iter().find(|(l, _)| *l == letter)?.1 as i32;
+        let mut length = 1;
+        for accidental in chars {
+            match accidental {
+                '#' => value += 1,
+                'b' => value -= 1,
+                _ => break,
+            }
+            length += 1;
+        }
+        Some((PitchClass::new(value), length))
+    }
+
+    pub fn name(self, flats: bool) -> &'static str {
+        if flats { FLAT_NAMES[self.0 as usize] } else { SHARP_NAMES[self.0 as usize] }
+    }
+
+    pub fn transpose(self, semitones: i32) -> Self {
+        PitchClass::new(self.0 as i32 + semitones)
+    }
+}
+
+// Alteration of each letter from C to B under a key signature of sharps (positive) or flats
+pub fn signature_alterations(sharps: i8) -> [i32; 7] {
+    // Letter indices in the order sharps are added, flats use the reverse
+    const SHARP_ORDER: [usize; 7] = [3, 0, 4, 1, 5, 2, 6];
+    let mut alterations = [0; 7];
+    for index in 0..(sharps.unsigned_abs() as usize).min(7) {
+        if sharps > 0 {
+            alterations[SHARP_ORDER[index]] = 1;
+        } else {
+            alterations[SHARP_ORDER[6 - index]] = -1;
+        }
+    }
+    alterations
+}
+
+// Semitone distance from `from` up to `to`
+pub fn interval(from: PitchClass, to: PitchClass) -> u8 {
+    (to.0 as i32 - from.0 as i32).rem_euclid(12) as u8
+}
+
+pub fn interval_name(semitones: u8) -> &'static str {
+    const NAMES: [&str; 12] = ["P1", "m2", "M2", "m3", "M3", "P4", "TT", "P5", "m6", "M6", "m7", "M7"];
+    NAMES[(semitones % 12) as usize]
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ScaleKind {
+    Major,
+    Dorian,
+    Phrygian,
+    Lydian,
+    Mixolydian,
+    NaturalMinor,
+    Locrian,
+    HarmonicMinor,
+    MelodicMinor,
+    MajorPentatonic,
+    MinorPentatonic,
+    Blues,
+}
+
+impl ScaleKind {
+    pub fn intervals(self) -> &'static [u8] {
+        match self {
+            ScaleKind::Major => &[0, 2, 4, 5, 7, 9, 11],
+            ScaleKind::Dorian => &[0, 2, 3, 5, 7, 9, 10],
+            ScaleKind::Phrygian => &[0, 1, 3, 5, 7, 8, 10],
+            ScaleKind::Lydian => &[0, 2, 4, 6, 7, 9, 11],
+            ScaleKind::Mixolydian => &[0, 2, 4, 5, 7, 9, 10],
+            ScaleKind::NaturalMinor => &[0, 2, 3, 5, 7, 8, 10],
+            ScaleKind::Locrian => &[0, 1, 3, 5, 6, 8, 10],
+            ScaleKind::HarmonicMinor => &[0, 2, 3, 5, 7, 8, 11],
+            ScaleKind::MelodicMinor => &[0, 2, 3, 5, 7, 9, 11],
+            ScaleKind::MajorPentatonic => &[0, 2, 4, 7, 9],
+            ScaleKind::MinorPentatonic => &[0, 3, 5, 7, 10],
+            ScaleKind::Blues => &[0, 3, 5, 6, 7, 10],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Scale {
+    pub tonic: PitchClass,
+    pub kind: ScaleKind,
+}
+
+impl Scale {
+    pub fn pitch_classes(&self) -> Vec<PitchClass> {
+        self.kind.intervals().iter().map(|&step| self.tonic.transpose(step as i32)).collect()
+    }
+
+    pub fn contains(&self, pitch: PitchClass) -> bool {
+        self.kind.intervals().contains(&interval(self.tonic, pitch))
+    }
+
+    // MIDI pitch of a zero-based scale degree counted from the tonic in the octave starting at `base`
+    pub fn degree_pitch(&self, base: u8, degree: i32) -> u8 {
+        let steps = self.kind.intervals();
+        let octave = degree.div_euclid(steps.len() as i32);
+        let step = steps[degree.rem_euclid(steps.len() as i32) as usize] as i32;
+        (base as i32 + self.tonic.0 as i32 + octave * 12 + step).clamp(0, 127) as u8
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChordQuality {
+    Major,
+    Minor,
+    Diminished,
+    Augmented,
+    Sus2,
+    Sus4,
+    Dominant7,
+    Major7,
+    Minor7,
+    HalfDiminished7,
+    Diminished7,
+}
+
+const CHORD_QUALITIES: [ChordQuality; 11] = [
+    ChordQuality::Major,
+    ChordQuality::Minor,
+    ChordQuality::Dominant7,
+    ChordQuality::Major7,
+    ChordQuality::Minor7,
+    ChordQuality::Diminished,
+    ChordQuality::HalfDiminished7,
+    ChordQuality::Diminished7,
+    ChordQuality::Augmented,
+    ChordQuality::Sus4,
+    ChordQuality::Sus2,
+];
+
+impl ChordQuality {
+    pub fn intervals(self) -> &'static [u8] {
+        match self {
+            ChordQuality::Major => &[0, 4, 7],
+            ChordQuality::Minor => &[0, 3, 7],
+            ChordQuality::Diminished => &[0, 3, 6],
+            ChordQuality::Augmented => &[0, 4, 8],
+            ChordQuality::Sus2 => &[0, 2, 7],
+            ChordQuality::Sus4 => &[0, 5, 7],
+            ChordQuality::Dominant7 => &[0, 4, 7, 10],
+            ChordQuality::Major7 => &[0, 4, 7, 11],
+            ChordQuality::Minor7 => &[0, 3, 7, 10],
+            ChordQuality::HalfDiminished7 => &[0, 3, 6, 10],
+            ChordQuality::Diminished7 => &[0, 3, 6, 9],
+        }
+    }
+
+    pub fn suffix(self) -> &'static str {
+        match self {
+            ChordQuality::Major => "",
+            ChordQuality::Minor => "m",
+            ChordQuality::Diminished => "dim",
+            ChordQuality::Augmented => "aug",
+            ChordQuality::Sus2 => "sus2",
+            ChordQuality::Sus4 => "sus4",
+            ChordQuality::Dominant7 => "7",
+            ChordQuality::Major7 => "maj7",
+            ChordQuality::Minor7 => "m7",
+            ChordQuality::HalfDiminished7 => "m7b5",
+            ChordQuality::Diminished7 => "dim7",
+        }
+    }
+
+    fn is_minor(self) -> bool {
+        matches!(self, ChordQuality::Minor | ChordQuality::Minor7 | ChordQuality::Diminished | ChordQuality::HalfDiminished7 | ChordQuality::Diminished7)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Chord {
+    pub root: PitchClass,
+    pub quality: ChordQuality,
+}
+
+impl Chord {
+    pub fn pitch_classes(&self) -> Vec<PitchClass> {
+        self.quality.intervals().iter().map(|&step| self.root.transpose(step as i32)).collect()
+    }
+
+    pub fn name(&self, flats: bool) -> String {
+        format!("{}{}", self.root.name(flats), self.quality.suffix())
+    }
+
+    // Parses chord symbols such as "Am", "G7" or "Bbmaj7/D". Unknown extensions fall back to the
+    // longest known suffix, so "C9" reads as C and "Dm11" as Dm.
+    pub fn parse(symbol: &str) -> Option<Chord> {
+        const ALIASES: [(&str, ChordQuality); 9] = [
+            ("min", ChordQuality::Minor),
+            ("-", ChordQuality::Minor),
+            ("min7", ChordQuality::Minor7),
+            ("-7", ChordQuality::Minor7),
+            ("M7", ChordQuality::Major7),
+            ("+", ChordQuality::Augmented),
+            ("°", ChordQuality::Diminished),
+            ("o", ChordQuality::Diminished),
+            ("ø", ChordQuality::HalfDiminished7),
+        ];
+        let symbol = symbol.split('/').next()?.trim();
+        let (root, length) = PitchClass::parse(symbol)?;
+        let rest = &symbol[length..];
+        CHORD_QUALITIES
+            .iter()
+            .map(|&quality| (quality.suffix(), quality))
+            .chain(ALIASES)
+            .filter(|(suffix, _)| rest.starts_with(suffix))
+            .max_by_key(|(suffix, _)| suffix.len())
+            .map(|(_, quality)| Chord { root, quality })
+    }
+
+    // Roman numeral of the chord within a key, lower case for minor and diminished chords
+    pub fn roman_numeral(&self, key: &Key) -> String {
+        const DEGREES: [&str; 12] = ["I", "bII", "II", "bIII", "III", "IV", "#IV", "V", "bVI", "VI", "bVII", "VII"];
+        let numeral = DEGREES[interval(key.tonic, self.root) as usize];
+        let numeral = if self.quality.is_minor() { numeral.to_lowercase() } else { numeral.to_string() };
+        match self.quality {
+            ChordQuality::Diminished | ChordQuality::Diminished7 => format!("{}°", numeral),
+            ChordQuality::HalfDiminished7 => format!("{}ø", numeral),
+            ChordQuality::Augmented => format!("{}+", numeral),
+            ChordQuality::Dominant7 | ChordQuality::Minor7 => format!("{}7", numeral),
+            ChordQuality::Major7 => format!("{}maj7", numeral),
+            _ => numeral,
+        }
+    }
+
+    // Best matching chord for weighted pitch classes; weights are indexed by pitch class
+    pub fn recognize(weights: &[f64; 12]) -> Option<Chord> {
+        let total: f64 = weights.iter().sum();
+        if total <= 0.0 {
+            return None;
+        }
+        let mut best: Option<(f64, Chord)> = None;
+        for root in 0..12u8 {
+            for quality in CHORD_QUALITIES {
+                let tones = quality.intervals();
+                let covered: f64 = tones.iter().map(|&step| weights[((root + step) % 12) as usize]).sum();
+                let missing = tones.iter().filter(|&&step| weights[((root + step) % 12) as usize] <= 0.0).count();
+                // Reward explained weight, penalise missing tones and larger chords slightly
+                let score = covered / total - 0.2 * missing as f64 - 0.02 * tones.len() as f64
+                    + 0.05 * weights[root as usize] / total;
+                if best.as_ref().is_none_or(|(best_score, _)| score > *best_score) {
+                    best = Some((score, Chord { root: PitchClass(root), quality }));
+                }
+            }
+        }
+        best.filter(|(score, _)| *score > 0.3).map(|(_, chord)| chord)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Key {
+    pub tonic: PitchClass,
+    pub minor: bool,
+}
+
+impl Key {
+    // Parses key names such as "D", "F#m", "Bb minor" or "A major"
+    pub fn parse(name: &str) -> Option<Self> {
+        let name = name.trim();
+        let (tonic, length) = PitchClass::parse(name)?;
+        let minor = match name[length..].trim().to_lowercase().as_str() {
+            "" | "maj" | "major" => false,
+            "m" | "min" | "minor" => true,
+            _ => return None,
+        };
+        Some(Key { tonic, minor })
+    }
+
+    pub fn from_signature(sharps: i8, minor: bool) -> Self {
+        let major = PitchClass::new(sharps as i32 * 7);
+        Key { tonic: if minor { major.transpose(9) } else { major }, minor }
+    }
+
+    // Number of sharps (positive) or flats (negative), F# and D#m are spelled with sharps
+    pub fn signature(&self) -> i8 {
+        let major = if self.minor { self.tonic.transpose(3) } else { self.tonic };
+        let fifths = (major.0 as i32 * 7).rem_euclid(12);
+        (if fifths > 6 { fifths - 12 } else { fifths }) as i8
+    }
+
+    pub fn uses_flats(&self) -> bool {
+        self.signature() < 0
+    }
+
+    pub fn scale(&self) -> Scale {
+        Scale { tonic: self.tonic, kind: if self.minor { ScaleKind::NaturalMinor } else { ScaleKind::Major } }
+    }
+
+    // Minor keys also accept the raised sixth and seventh of the melodic and harmonic forms
+    pub fn contains(&self, pitch: PitchClass) -> bool {
+        if self.scale().contains(pitch) {
+            return true;
+        }
+        self.minor && matches!(interval(self.tonic, pitch), 9 | 11)
+    }
+
+    pub fn name(&self) -> String {
+        format!("{} {}", self.tonic.name(self.uses_flats()), if self.minor { "minor" } else { "major" })
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ChordSpan {
+    pub bar: u32,
+    pub start_seconds: f64,
+    pub chord: String,
+    pub numeral: String,
+    pub notes: Vec<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct OutOfKeyNote {
+    pub track: usize,
+    pub bar: u32,
+    pub start_seconds: f64,
+    pub pitch: u8,
+    pub name: String,
+    // Interval above the tonic, e.g. "TT" for a raised fourth
+    pub interval: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Analysis {
+    pub key: String,
+    pub key_confidence: f64,
+    pub declared_key: Option<String>,
+    pub scale: Vec<String>,
+    pub chords: Vec<ChordSpan>,
+    pub out_of_key: Vec<OutOfKeyNote>,
+    pub out_of_key_ratio: f64,
+}
+
+fn pitched_notes(composition: &Composition) -> impl Iterator<Item = (usize, &crate::midi::Note)> {
+    composition
+        .tracks
+        .iter()
+        .enumerate()
+        .filter(|(_, track)| track.channel != DRUM_CHANNEL)
+        .flat_map(|(index, track)| track.notes.iter().map(move |note| (index, note)))
+}
+
+fn correlation(a: &[f64; 12], b: &[f64]) -> f64 {
+    let mean_a = a.iter().sum::<f64>() / 12.0;
+    let mean_b = b.iter().sum::<f64>() / 12.0;
+    let mut covariance = 0.0;
+    let mut variance_a = 0.0;
+    let mut variance_b = 0.0;
+    for i in 0..12 {
+        covariance += (a[i] - mean_a) * (b[i] - mean_b);
+        variance_a += (a[i] - mean_a).powi(2);
+        variance_b += (b[i] - mean_b).powi(2);
+    }
+    if variance_a == 0.0 || variance_b == 0.0 {
+        0.0
+    } else {
+        covariance / (variance_a * variance_b).sqrt()
+    }
+}
+
+// Key with the best Krumhansl-Schmuckler correlation against the duration-weighted pitch classes
+pub fn detect_key(composition: &Composition) -> Option<(Key, f64)> {
+    let mut weights = [0.0f64; 12];
+    for (_, note) in pitched_notes(composition) {
+        weights[(note.pitch % 12) as usize] += note.duration.max(1) as f64;
+    }
+    if weights.iter().all(|&weight| weight == 0.0) {
+        return None;
+    }
+
+    let mut best: Option<(Key, f64)> = None;
+    for tonic in 0..12 {
+        for (profile, minor) in [(&MAJOR_PROFILE, false), (&MINOR_PROFILE, true)] {
+            let rotated: Vec<f64> = (0..12).map(|pc| profile[(pc + 12 - tonic) % 12]).collect();
+            let score = correlation(&weights, &rotated);
+            if best.as_ref().is_none_or(|(_, best_score)| score > *best_score) {
+                best = Some((Key { tonic: PitchClass(tonic as u8), minor }, score));
+            }
+        }
+    }
+    best
+}
+
+// Key declared in the file, else the detected one
+pub fn composition_key(composition: &Composition) -> Option<Key> {
+    match composition.key_signatures.first() {
+        Some(signature) => Some(Key::from_signature(signature.sharps, signature.minor)),
+        None => detect_key(composition).map(|(key, _)| key),
+    }
+}
+
+pub fn analyze(composition: &Composition) -> Result<Analysis, String> {
+    let (key, confidence) = detect_key(composition).ok_or_else(|| "The composition has no pitched notes to analyse".to_string())?;
+    let flats = key.uses_flats();
+
+    // One chord per bar from the duration of each pitch class sounding in it
+    let mut chords: Vec<ChordSpan> = Vec::new();
+    let end = composition.end_tick();
+    let mut bar = 0u32;
+    let mut bar_start = 0u64;
+    while bar_start < end {
+        let bar_end = bar_start + composition.ticks_per_bar(bar_start).max(1);
+        let mut weights = [0.0f64; 12];
+        for (_, note) in pitched_notes(composition) {
+            let overlap = note.end().min(bar_end).saturating_sub(note.start.max(bar_start));
+            weights[(note.pitch % 12) as usize] += overlap as f64;
+        }
+        if let Some(chord) = Chord::recognize(&weights) {
+            let name = chord.name(flats);
+            // Repeated chords are reported once, where they start
+            if chords.last().is_none_or(|last| last.chord != name) {
+                chords.push(ChordSpan {
+                    bar: bar + 1,
+                    start_seconds: composition.tick_to_seconds(bar_start),
+                    chord: name,
+                    numeral: chord.roman_numeral(&key),
+                    notes: chord.pitch_classes().iter().map(|pitch| pitch.name(flats).to_string()).collect(),
+                });
+            }
+        }
+        bar += 1;
+        bar_start = bar_end;
+    }
+
+    let mut total = 0usize;
+    let mut out_of_key = Vec::new();
+    for (track, note) in pitched_notes(composition) {
+        total += 1;
+        let pitch_class = PitchClass::of_midi(note.pitch);
+        if !key.contains(pitch_class) {
+            let bar = composition.tick_to_bar(note.start) + 1;
+            out_of_key.push(OutOfKeyNote {
+                track,
+                bar,
+                start_seconds: composition.tick_to_seconds(note.start),
+                pitch: note.pitch,
+                name: format!("{}{}", pitch_class.name(flats), note.pitch as i32 / 12 - 1),
+                interval: interval_name(interval(key.tonic, pitch_class)).to_string(),
+            });
+        }
+    }
+
+    Ok(Analysis {
+        key: key.name(),
+        key_confidence: confidence,
+        declared_key: composition
+            .key_signatures
+            .first()
+            .map(|signature| Key::from_signature(signature.sharps, signature.minor).name()),
+        scale: key.scale().pitch_classes().iter().map(|pitch| pitch.name(flats).to_string()).collect(),
+        out_of_key_ratio: if total > 0 { out_of_key.len() as f64 / total as f64 } else { 0.0 },
+        chords,
+        out_of_key,
+    })
+}
+
+#[tauri::command]
+pub async fn analyze_composition(track: String) -> Result<Analysis, String> {
+    Library::update(move |library| {
+        let entry = library.get_mut(&track)?;
+        let midi = entry.midi.clone().ok_or_else(|| format!("{} has no MIDI to analyse", entry.title))?;
+        let analysis = analyze(&Composition::from_file(&midi)?)?;
+        entry.analysis = Some(analysis.clone());
+        Ok(analysis)
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{KeySignature, Note, Track};
+
+    fn composition(channel: u8, notes: &[(u8, u64)]) -> Composition {
+        let mut composition = Composition::new(480);
+        let mut start = 0;
+        let notes = notes
+            .iter()
+            .map(|&(pitch, duration)| {
+                let note = Note { start, duration, pitch, velocity: 80 };
+                start += duration;
+                note
+            })
+            .collect();
+        composition.tracks.push(Track { name: None, channel, program: 0, notes });
+        composition
+    }
+
+    fn weights(pitches: &[u8]) -> [f64; 12] {
+        let mut weights = [0.0; 12];
+        for &pitch in pitches {
+            weights[pitch as usize % 12] += 1.0;
+        }
+        weights
+    }
+
+    #[test]
+    fn key_names_parse() {
+        let cases = [
+            ("D", Some((2, false))),
+            ("F#m", Some((6, true))),
+            ("Bb minor", Some((10, true))),
+            ("A major", Some((9, false))),
+            (" eb ", Some((3, false))),
+            ("Cmaj", Some((0, false))),
+            ("G min", Some((7, true))),
+            ("C dorian", None),
+            ("H", None),
+            ("", None),
+        ];
+        for (name, expected) in cases {
+            let parsed = Key::parse(name).map(|key| (key.tonic.0, key.minor));
+            assert_eq!(parsed, expected, "{:?}", name);
+        }
+    }
+
+    #[test]
+    fn key_signatures_count_sharps_and_flats() {
+        let cases = [("C", 0), ("G", 1), ("F", -1), ("F#", 6), ("Gb", 6), ("Db", -5), ("Am", 0), ("Dm", -1), ("D#m", 6), ("Bbm", -5)];
+        for (name, sharps) in cases {
+            assert_eq!(Key::parse(name).unwrap().signature(), sharps, "{}", name);
+        }
+        for sharps in -5..=6 {
+            for minor in [false, true] {
+                assert_eq!(Key::from_signature(sharps, minor).signature(), sharps);
+            }
+        }
+        assert_eq!(Key::from_signature(-3, true).name(), "C minor");
+        assert_eq!(Key::from_signature(2, false).name(), "D major");
+    }
+
+    #[test]
+    fn chord_symbols_parse() {
+        let cases = [
+            ("Am", Some((9, ChordQuality::Minor))),
+            ("G7", Some((7, ChordQuality::Dominant7))),
+            ("Bbmaj7/D", Some((10, ChordQuality::Major7))),
+            ("C9", Some((0, ChordQuality::Major))),
+            ("Dm11", Some((2, ChordQuality::Minor))),
+            ("F#m7b5", Some((6, ChordQuality::HalfDiminished7))),
+            ("Eø", Some((4, ChordQuality::HalfDiminished7))),
+            ("Cdim7", Some((0, ChordQuality::Diminished7))),
+            ("Asus4", Some((9, ChordQuality::Sus4))),
+            ("E-7", Some((4, ChordQuality::Minor7))),
+            ("X", None),
+        ];
+        for (symbol, expected) in cases {
+            let parsed = Chord::parse(symbol).map(|chord| (chord.root.0, chord.quality));
+            assert_eq!(parsed, expected, "{}", symbol);
+        }
+    }
+
+    #[test]
+    fn chords_are_recognized_from_pitch_classes() {
+        let cases: [(&[u8], Option<&str>); 6] = [
+            (&[60, 64, 67], Some("C")),
+            (&[57, 60, 64], Some("Am")),
+            (&[55, 59, 62, 65], Some("G7")),
+            (&[60, 63, 66], Some("Cdim")),
+            (&[59, 62, 65, 69], Some("Bm7b5")),
+            (&[], None),
+        ];
+        for (pitches, expected) in cases {
+            let chord = Chord::recognize(&weights(pitches)).map(|chord| chord.name(false));
+            assert_eq!(chord.as_deref(), expected, "{:?}", pitches);
+        }
+    }
+
+    #[test]
+    fn roman_numerals_follow_the_key() {
+        let key = Key::parse("C").unwrap();
+        let cases = [("G7", "V7"), ("Am", "vi"), ("Bdim", "vii°"), ("Bb", "bVII"), ("Fmaj7", "IVmaj7")];
+        for (symbol, numeral) in cases {
+            assert_eq!(Chord::parse(symbol).unwrap().roman_numeral(&key), numeral, "{}", symbol);
+        }
+    }
+
+    #[test]
+    fn keys_are_detected_from_note_durations() {
+        // C major scale leaning on the tonic triad
+        let major = composition(0, &[(60, 960), (62, 240), (64, 480), (65, 240), (67, 960), (69, 240), (71, 240), (72, 960)]);
+        let (key, confidence) = detect_key(&major).unwrap();
+        assert_eq!(key, Key { tonic: PitchClass(0), minor: false });
+        assert!(confidence > 0.5);
+
+        // A minor with its raised seventh
+        let minor = composition(0, &[(57, 960), (59, 240), (60, 480), (62, 240), (64, 960), (65, 240), (68, 240), (69, 960)]);
+        assert_eq!(detect_key(&minor).unwrap().0, Key { tonic: PitchClass(9), minor: true });
+
+        assert_eq!(detect_key(&composition(DRUM_CHANNEL, &[(36, 480), (38, 480)])), None);
+    }
+
+    #[test]
+    fn a_declared_key_wins_over_detection() {
+        let mut piece = composition(0, &[(60, 960), (64, 480), (67, 960)]);
+        assert_eq!(composition_key(&piece), Some(Key { tonic: PitchClass(0), minor: false }));
+        piece.key_signatures.push(KeySignature { tick: 0, sharps: 1, minor: true });
+        assert_eq!(composition_key(&piece), Some(Key { tonic: PitchClass(4), minor: true }));
+    }
+
+    #[test]
+    fn scale_degrees_wrap_around_octaves() {
+        let scale = Key::parse("C").unwrap().scale();
+        assert_eq!(scale.degree_pitch(60, 0), 60);
+        assert_eq!(scale.degree_pitch(60, 7), 72);
+        assert_eq!(scale.degree_pitch(60, -1), 59);
+        assert!(Key::parse("Am").unwrap().contains(PitchClass(8)));
+        assert!(!Key::parse("C").unwrap().contains(PitchClass(8)));
+    }
+}
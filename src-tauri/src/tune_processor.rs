@@ -1,11 +1,408 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
 use tauri::{AppHandle, Manager};
-use crate::python::send_command;
-use serde_json::json;
+use crate::abc;
+use crate::cache;
+use crate::composer::{compose_with_style, style_for};
+use crate::config::{active_soundfont, provider_settings};
+use crate::library::{add_generated, Generated, Variant};
+use crate::midi::Composition;
+use crate::mood::analyze;
+use crate::python::{complete_generation, send_command};
+use crate::render::{render_midi, RenderSettings};
+use crate::segment::{excerpt, split_sections, stitch, TimelineEntry};
+use serde_json::{json, Value};
 use crate::setup::EnvPaths;
+use crate::utils::send_to_frontend;
+use crate::validation::ValidationIssue;
+
+// Generations failing validation or raising in the sidecar are repeated this many times
+const MAX_RETRIES: u32 = 2;
+const BATCH_DIR: &str = "batch";
+const MAX_VARIANTS: usize = 8;
+
+enum BatchKind {
+	// Sections of a long text, stitched into one piece once the last one is done
+	Sections,
+	// Candidates for the same prompt, kept side by side
+	Variants(Vec<u64>),
+}
+
+// A generation made of several sidecar requests sent one after the other
+struct Batch {
+	kind: BatchKind,
+	texts: Vec<String>,
+	commands: Vec<String>,
+	// MIDI and audio of every finished part
+	finished: Vec<(PathBuf, PathBuf)>,
+	issues: Vec<ValidationIssue>,
+}
+
+#[derive(Default)]
+struct GenerationRequest {
+	prompt: String,
+	// The text of the request in flight, a single section for segmented generations
+	text: String,
+	command: String,
+	retries: u32,
+	// Cache key of the request in flight, cleared once its result is cached or came from the cache
+	cache_key: Option<String>,
+	batch: Option<Batch>,
+}
+
+pub enum Progress {
+	NextPart,
+	Finished(Generated),
+}
+
+lazy_static::lazy_static! {
+	static ref CURRENT_REQUEST: Mutex<GenerationRequest> = Mutex::new(GenerationRequest::default());
+}
+
+// The text of the generation in progress, used to title its library entry
+pub fn current_prompt() -> String {
+	CURRENT_REQUEST.lock().map(|request| request.prompt.clone()).unwrap_or_default()
+}
+
+pub fn current_text() -> String {
+	CURRENT_REQUEST.lock().map(|request| request.text.clone()).unwrap_or_default()
+}
+
+// Sends the last request again, returns false once its retries are used up
+pub async fn retry_generation(app: &AppHandle) -> bool {
+	let command = match CURRENT_REQUEST.lock() {
+		Ok(mut request) if !request.command.is_empty() && request.retries < MAX_RETRIES => {
+			request.retries += 1;
+			request.command.clone()
+		}
+		_ => return false,
+	};
+	clear_outputs(&EnvPaths::new());
+	send_command(app, &command).await;
+	true
+}
+
+// Leftovers of an earlier request would pass for this one's output, so a missing output.mid
+// reliably means the sidecar wrote no MIDI
+fn clear_outputs(paths: &EnvPaths) {
+	for output in [&paths.output_midi, &paths.output_file, &paths.output_abc] {
+		let _ = fs::remove_file(output);
+	}
+}
+
+// Restores a cached result when there is one, otherwise sends the command to the sidecar
+async fn dispatch(app: &AppHandle, command: &str) {
+	let paths = EnvPaths::new();
+	clear_outputs(&paths);
+	let key = cache::cache_key(command);
+	let hit = cache::restore(&key, &paths.output_midi, &paths.output_file).unwrap_or_else(|e| {
+		send_to_frontend(app, format!("Failed to read the generation cache: {}", e), "error");
+		false
+	});
+	send_to_frontend(app, json!({ "key": key, "hit": hit }).to_string(), "cache");
+	if let Ok(mut request) = CURRENT_REQUEST.lock() {
+		request.cache_key = if hit { None } else { Some(key) };
+	}
+	if hit {
+		send_to_frontend(app, "Using a cached result for this request".to_string(), "initialize_setup_processing");
+		Box::pin(complete_generation(app, "Loaded from cache", "initialize_setup_processing")).await;
+	} else {
+		send_command(app, command).await;
+	}
+}
+
+// Keeps the sidecar's output for the request in flight so an identical request skips generation
+pub fn cache_result() {
+	let paths = EnvPaths::new();
+	let key = CURRENT_REQUEST.lock().ok().and_then(|mut request| request.cache_key.take());
+	if let Some(key) = key {
+		let _ = cache::store(&key, &paths.output_midi, &paths.output_file);
+	}
+}
+
+fn timeline(texts: &[String], ranges: Vec<(f64, f64)>) -> Vec<TimelineEntry> {
+	texts
+		.iter()
+		.zip(ranges)
+		.enumerate()
+		.map(|(section, (text, (start_seconds, end_seconds)))| TimelineEntry { section, excerpt: excerpt(text), start_seconds, end_seconds })
+		.collect()
+}
+
+fn part_label(kind: &BatchKind) -> &'static str {
+	match kind {
+		BatchKind::Sections => "section",
+		BatchKind::Variants(_) => "variant",
+	}
+}
+
+// Called once the sidecar finished a generation. Batches keep the part's files and ask for the
+// next part; after the last one sections are stitched into the output files.
+pub async fn finish_generation(app: &AppHandle, issues: Vec<ValidationIssue>) -> Result<Progress, String> {
+	let paths = EnvPaths::new();
+	let (command, label, index, count) = {
+		let mut request = CURRENT_REQUEST.lock().map_err(|e| e.to_string())?;
+		let Some(batch) = request.batch.as_mut() else {
+			return Ok(Progress::Finished(Generated { validation: issues, ..Generated::default() }));
+		};
+		let dir = paths.temp_dir.join(BATCH_DIR);
+		fs::create_dir_all(&dir).map_err(|e| format!("Failed to create batch directory: {}", e))?;
+		let part = batch.finished.len();
+		let (midi, audio) = (dir.join(format!("{}.mid", part)), dir.join(format!("{}.wav", part)));
+		fs::copy(&paths.output_midi, &midi).map_err(|_| format!("The sidecar wrote no MIDI for {} {}", part_label(&batch.kind), part + 1))?;
+		fs::copy(&paths.output_file, &audio).map_err(|e| format!("Failed to keep audio of {} {}: {}", part_label(&batch.kind), part + 1, e))?;
+		batch.finished.push((midi, audio));
+		batch.issues.extend(issues);
+
+		if let Some(batch) = request.batch.take_if(|batch| batch.finished.len() == batch.commands.len()) {
+			// Stitching and rendering take a while, other commands read the request meanwhile
+			drop(request);
+			return finish_batch(&paths, batch).map(Progress::Finished);
+		}
+
+		let Some(batch) = request.batch.as_ref() else {
+			return Ok(Progress::Finished(Generated::default()));
+		};
+		let index = batch.finished.len();
+		let (text, command, label, count) = (batch.texts[index].clone(), batch.commands[index].clone(), part_label(&batch.kind), batch.commands.len());
+		request.text = text;
+		request.command = command.clone();
+		request.retries = 0;
+		(command, label, index, count)
+	};
+	send_to_frontend(app, format!("Composing {} {} of {}", label, index + 1, count), "initialize_setup_processing");
+	dispatch(app, &command).await;
+	Ok(Progress::NextPart)
+}
+
+// Puts the parts of a completed batch together
+fn finish_batch(paths: &EnvPaths, batch: Batch) -> Result<Generated, String> {
+	let mut generated = Generated { validation: batch.issues, ..Generated::default() };
+	match batch.kind {
+		BatchKind::Sections => {
+			let segments = batch.finished.iter().map(|(midi, _)| Composition::from_file(midi)).collect::<Result<Vec<_>, _>>()?;
+			let (piece, ranges) = stitch(&segments)?;
+			piece.write_file(&paths.output_midi)?;
+			render_midi(&paths.output_midi, &active_soundfont()?, &paths.output_file, &RenderSettings::default())?;
+			generated.timeline = timeline(&batch.texts, ranges);
+		}
+		BatchKind::Variants(seeds) => {
+			generated.variants = seeds
+				.into_iter()
+				.zip(batch.finished)
+				.map(|(seed, (midi, audio))| Variant { seed, midi: Some(midi), audio, summary: None })
+				.collect();
+			use_first_variant(paths, &generated.variants)?;
+		}
+	}
+	Ok(generated)
+}
+
+// The first variant is the one a new entry starts with, so it also becomes the output files
+fn use_first_variant(paths: &EnvPaths, variants: &[Variant]) -> Result<(), String> {
+	let Some(first) = variants.first() else {
+		return Ok(());
+	};
+	fs::copy(&first.audio, &paths.output_file).map_err(|e| format!("Failed to copy audio file: {}", e))?;
+	let _ = fs::remove_file(&paths.output_midi);
+	if let Some(midi) = &first.midi {
+		fs::copy(midi, &paths.output_midi).map_err(|e| format!("Failed to copy MIDI file: {}", e))?;
+	}
+	Ok(())
+}
+
+// Composes without the sidecar and puts the result where a sidecar generation would
+fn generate_offline(paths: &EnvPaths, text: &str, sectioned: bool, seeds: &[u64]) -> Result<(), String> {
+	let style = style_for(text);
+	let texts = split_sections(text);
+	let mut generated = Generated::default();
+
+	if sectioned && texts.len() > 1 {
+		// Every section shares the key and instruments derived from the whole text
+		let segments = texts.iter().map(|section| compose_with_style(section, style, 0)).collect::<Result<Vec<_>, _>>()?;
+		let (piece, ranges) = stitch(&segments)?;
+		piece.write_file(&paths.output_midi)?;
+		generated.timeline = timeline(&texts, ranges);
+	} else if seeds.len() > 1 {
+		let dir = paths.temp_dir.join(BATCH_DIR);
+		fs::create_dir_all(&dir).map_err(|e| format!("Failed to create batch directory: {}", e))?;
+		for (index, &seed) in seeds.iter().enumerate() {
+			let (midi, audio) = (dir.join(format!("{}.mid", index)), dir.join(format!("{}.wav", index)));
+			compose_with_style(text, style, seed)?.write_file(&midi)?;
+			render_midi(&midi, &active_soundfont()?, &audio, &RenderSettings::default())?;
+			generated.variants.push(Variant { seed, midi: Some(midi), audio, summary: None });
+		}
+		use_first_variant(paths, &generated.variants)?;
+		add_generated(text, generated)?;
+		return Ok(());
+	} else {
+		compose_with_style(text, style, seeds.first().copied().unwrap_or(0))?.write_file(&paths.output_midi)?;
+	}
+	render_midi(&paths.output_midi, &active_soundfont()?, &paths.output_file, &RenderSettings::default())?;
+	add_generated(text, generated)?;
+	Ok(())
+}
+
+// Plays the text as ABC notation, parse errors also go to the frontend with their position
+fn generate_abc(app: &AppHandle, paths: &EnvPaths, text: &str) -> Result<(), String> {
+	let tune = abc::parse(text).map_err(|e| {
+		send_to_frontend(app, serde_json::to_string(&e).unwrap_or_default(), "abc_error");
+		format!("Invalid ABC notation at {}", e)
+	})?;
+	tune.composition.write_file(&paths.output_midi)?;
+	render_midi(&paths.output_midi, &active_soundfont()?, &paths.output_file, &RenderSettings::default())?;
+	add_generated(tune.title.as_deref().unwrap_or(text), Generated::default())?;
+	Ok(())
+}
+
+// Turns a provider's ABC reply into the MIDI and audio a generation ends with. Returns false when
+// the sidecar wrote no ABC reply, i.e. the provider produced MIDI itself
+pub fn render_abc_reply(paths: &EnvPaths) -> Result<bool, String> {
+	if !write_abc_reply(&paths.output_abc, &paths.output_midi)? {
+		return Ok(false);
+	}
+	render_midi(&paths.output_midi, &active_soundfont()?, &paths.output_file, &RenderSettings::default())?;
+	Ok(true)
+}
+
+// Converts the ABC reply to MIDI and removes it, so a later request never picks it up again
+fn write_abc_reply(abc_path: &Path, midi_path: &Path) -> Result<bool, String> {
+	if !abc_path.exists() {
+		return Ok(false);
+	}
+	let text = fs::read_to_string(abc_path).map_err(|e| format!("Failed to read the model's reply: {}", e));
+	let _ = fs::remove_file(abc_path);
+	let tune = abc::parse(&text?).map_err(|e| format!("The model's reply is not valid ABC notation at {}", e))?;
+	tune.composition.write_file(midi_path)?;
+	Ok(true)
+}
 
 #[tauri::command]
-pub async fn generate_tunes(app: AppHandle, text: String) {
+pub async fn generate_tunes(app: AppHandle, text: String, mode: Option<String>, sections: Option<bool>, variants: Option<usize>, seeds: Option<Vec<u64>>) {
 	let paths = EnvPaths::new();
-	let json_data = json!({ "text":text, "soundfont": "" });
-	send_command(&app, &json_data.to_string()).await;
-}
\ No newline at end of file
+	let sectioned = sections.unwrap_or(false);
+	// Explicit seeds decide the number of variants, otherwise they count up from zero
+	let seeds = seeds.filter(|seeds| !seeds.is_empty());
+	let explicit_seed = seeds.as_ref().map(|seeds| seeds[0]);
+	let seeds = seeds.unwrap_or_else(|| (0..variants.unwrap_or(1).max(1) as u64).collect());
+	let error = if seeds.len() > MAX_VARIANTS {
+		Some(format!("At most {} variants can be generated at once", MAX_VARIANTS))
+	} else if sectioned && seeds.len() > 1 {
+		Some("Variants cannot be combined with sections".to_string())
+	} else {
+		match mode.as_deref() {
+			None | Some("llm") => None,
+			Some("offline") => {
+				let result = generate_offline(&paths, &text, sectioned, &seeds);
+				send_to_frontend(&app, "Offline generation finished".to_string(), "initialize_setup_completed");
+				match result {
+					Ok(()) => send_to_frontend(&app, paths.output_file.display().to_string(), "tune_file_created"),
+					Err(e) => send_to_frontend(&app, format!("Offline generation failed: {}", e), "error"),
+				}
+				return;
+			}
+			Some("abc") if sectioned || seeds.len() > 1 => Some("Sections and variants are not available for ABC notation".to_string()),
+			Some("abc") => {
+				let result = generate_abc(&app, &paths, &text);
+				send_to_frontend(&app, "Notation rendered".to_string(), "initialize_setup_completed");
+				match result {
+					Ok(()) => send_to_frontend(&app, paths.output_file.display().to_string(), "tune_file_created"),
+					Err(e) => send_to_frontend(&app, e, "error"),
+				}
+				return;
+			}
+			Some(other) => Some(format!("Unknown generation mode: {}", other)),
+		}
+	};
+	if let Some(error) = error {
+		send_to_frontend(&app, error.clone(), "initialize_setup_completed");
+		send_to_frontend(&app, error, "error");
+		return;
+	}
+
+	// Locally derived musical choices guide the model
+	let suggestion = analyze(&text).suggestion;
+	let base = json!({ "text": text, "soundfont": "", "suggestion": suggestion, "provider": provider_settings().command() });
+	let with = |text: &str, extra: Value| {
+		let mut command = base.clone();
+		command["text"] = json!(text);
+		if let (Some(command), Some(extra)) = (command.as_object_mut(), extra.as_object()) {
+			command.extend(extra.clone());
+		}
+		command.to_string()
+	};
+
+	let texts = if sectioned { split_sections(&text) } else { Vec::new() };
+	let batch = if texts.len() > 1 {
+		let count = texts.len();
+		let commands = texts.iter().enumerate().map(|(index, section)| with(section, json!({ "section": { "index": index, "count": count } }))).collect();
+		Some(Batch { kind: BatchKind::Sections, texts, commands, finished: Vec::new(), issues: Vec::new() })
+	} else if seeds.len() > 1 {
+		let count = seeds.len();
+		let commands = seeds.iter().enumerate().map(|(index, seed)| with(&text, json!({ "seed": seed, "variant": { "index": index, "count": count } }))).collect();
+		Some(Batch { kind: BatchKind::Variants(seeds.clone()), texts: vec![text.clone(); count], commands, finished: Vec::new(), issues: Vec::new() })
+	} else {
+		None
+	};
+
+	let request = match batch {
+		Some(batch) => {
+			send_to_frontend(&app, format!("Composing {} 1 of {}", part_label(&batch.kind), batch.commands.len()), "initialize_setup_processing");
+			GenerationRequest { prompt: text.clone(), text: batch.texts[0].clone(), command: batch.commands[0].clone(), retries: 0, cache_key: None, batch: Some(batch) }
+		}
+		None => {
+			let extra = explicit_seed.map_or_else(|| json!({}), |seed| json!({ "seed": seed }));
+			GenerationRequest { prompt: text.clone(), text: text.clone(), command: with(&text, extra), retries: 0, cache_key: None, batch: None }
+		}
+	};
+	let command = request.command.clone();
+	if let Ok(mut current) = CURRENT_REQUEST.lock() {
+		*current = request;
+	}
+	dispatch(&app, &command).await;
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	// The tune the sidecar's mock provider answers with when no responses are configured
+	const MOCK_REPLY: &str = "X:1\nT:Mock Tune\nM:4/4\nL:1/8\nQ:1/4=120\nK:C\nCDEF GABc|c2G2 E2C2|DEFG ABcd|c4 z4|]\n";
+
+	fn scratch(name: &str) -> PathBuf {
+		let dir = std::env::temp_dir().join(format!("tune-processor-test-{}-{}", name, std::process::id()));
+		let _ = fs::remove_dir_all(&dir);
+		fs::create_dir_all(&dir).unwrap();
+		dir
+	}
+
+	#[test]
+	fn abc_reply_becomes_midi() {
+		let dir = scratch("reply");
+		let (abc_path, midi_path) = (dir.join("output.abc"), dir.join("output.mid"));
+		assert_eq!(write_abc_reply(&abc_path, &midi_path), Ok(false));
+		assert!(!midi_path.exists());
+
+		fs::write(&abc_path, MOCK_REPLY).unwrap();
+		assert_eq!(write_abc_reply(&abc_path, &midi_path), Ok(true));
+		assert!(!abc_path.exists());
+		let composition = Composition::from_bytes(&fs::read(&midi_path).unwrap()).unwrap();
+		let pitches: Vec<u8> = composition.tracks.iter().flat_map(|track| &track.notes).map(|note| note.pitch).collect();
+		assert_eq!(pitches.len(), 21);
+		assert_eq!(pitches[..8], [60, 62, 64, 65, 67, 69, 71, 72]);
+		fs::remove_dir_all(&dir).unwrap();
+	}
+
+	#[test]
+	fn invalid_reply_is_removed_and_reported() {
+		let dir = scratch("invalid");
+		let (abc_path, midi_path) = (dir.join("output.abc"), dir.join("output.mid"));
+		fs::write(&abc_path, "Sorry, I cannot compose that.").unwrap();
+		let error = write_abc_reply(&abc_path, &midi_path).unwrap_err();
+		assert!(error.starts_with("The model's reply is not valid ABC notation"), "{}", error);
+		assert!(!abc_path.exists());
+		assert!(!midi_path.exists());
+		fs::remove_dir_all(&dir).unwrap();
+	}
+}
@@ -0,0 +1,298 @@
+use std::path::Path;
+use serde::{Deserialize, Serialize};
+use crate::config::active_soundfont;
+use crate::midi::{Composition, Note, DRUM_CHANNEL};
+use crate::render::{render_midi, RenderSettings};
+
+const MIN_BPM: f64 = 20.0;
+const MAX_BPM: f64 = 300.0;
+const MIN_SECONDS: f64 = 2.0;
+// Generated pieces may miss the requested length by this factor before a retry is worthwhile
+const LENGTH_TOLERANCE: f64 = 2.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IssueKind {
+    NoNotes,
+    OutOfRange,
+    OverlappingNotes,
+    ZeroLengthNote,
+    AbsurdTempo,
+    TooShort,
+    LengthMismatch,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ValidationIssue {
+    pub kind: IssueKind,
+    pub track: Option<usize>,
+    pub message: String,
+    // Whether the composition was repaired
+    pub fixed: bool,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ValidationReport {
+    pub issues: Vec<ValidationIssue>,
+    // Problems no repair can address, generating again is the only remedy
+    pub retry: bool,
+}
+
+impl ValidationReport {
+    fn fixed(&mut self, kind: IssueKind, track: Option<usize>, message: String) {
+        self.issues.push(ValidationIssue { kind, track, message, fixed: true });
+    }
+
+    fn unfixed(&mut self, kind: IssueKind, track: Option<usize>, message: String) {
+        self.issues.push(ValidationIssue { kind, track, message, fixed: false });
+        self.retry = true;
+    }
+
+    pub fn changed(&self) -> bool {
+        self.issues.iter().any(|issue| issue.fixed)
+    }
+}
+
+// Playable MIDI range of each General MIDI instrument family (eight programs each)
+fn instrument_range(program: u8) -> (u8, u8) {
+    match program / 8 {
+        0 => (21, 108),  // Piano
+        1 => (48, 108),  // Chromatic percussion
+        2 => (36, 96),   // Organ
+        3 => (40, 88),   // Guitar
+        4 => (28, 67),   // Bass
+        5 => (28, 103),  // Strings
+        6 => (36, 96),   // Ensemble
+        7 => (34, 84),   // Brass
+        8 => (34, 93),   // Reed
+        9 => (48, 108),  // Pipe
+        10 | 11 => (36, 96), // Synth lead and pad
+        _ => (24, 108),
+    }
+}
+
+// Requested length in seconds when the prompt names one, e.g. "a 2 minute piece" or "90 seconds"
+pub fn requested_seconds(prompt: &str) -> Option<f64> {
+    let words: Vec<String> = prompt
+        .to_lowercase()
+        .split(|c: char| c.is_whitespace() || c == '-')
+        .map(|word| word.trim_matches(|c: char| !c.is_alphanumeric() && c != '.').trim_end_matches('.').to_string())
+        .collect();
+    words.windows(2).find_map(|pair| {
+        let amount: f64 = pair[0].parse().ok()?;
+        let unit = match pair[1].as_str() {
+            "s" | "sec" | "secs" | "second" | "seconds" => 1.0,
+            "m" | "min" | "mins" | "minute" | "minutes" => 60.0,
+            _ => return None,
+        };
+        Some(amount * unit).filter(|seconds| *seconds > 0.0)
+    })
+}
+
+// Checks the composition, repairing it in place where possible
+pub fn validate(composition: &mut Composition, requested: Option<f64>) -> ValidationReport {
+    let mut report = ValidationReport::default();
+
+    if composition.note_count() == 0 {
+        report.unfixed(IssueKind::NoNotes, None, "The composition contains no notes".to_string());
+        return report;
+    }
+
+    let sixteenth = (composition.ticks_per_beat as u64 / 4).max(1);
+    for (index, track) in composition.tracks.iter_mut().enumerate() {
+        let zero_length = track.notes.iter().filter(|note| note.duration == 0).count();
+        if zero_length > 0 {
+            track.notes.iter_mut().filter(|note| note.duration == 0).for_each(|note| note.duration = sixteenth);
+            report.fixed(IssueKind::ZeroLengthNote, Some(index), format!("Lengthened {} zero-length note(s) to a sixteenth", zero_length));
+        }
+
+        // Pitched notes are moved by octaves into the instrument's range, drum notes pick sounds
+        let mut moved = 0;
+        if track.channel != DRUM_CHANNEL {
+            let (low, high) = instrument_range(track.program);
+            for note in track.notes.iter_mut().filter(|note| note.pitch < low || note.pitch > high) {
+                while note.pitch < low && note.pitch + 12 <= high {
+                    note.pitch += 12;
+                }
+                while note.pitch > high && note.pitch >= low + 12 {
+                    note.pitch -= 12;
+                }
+                if (low..=high).contains(&note.pitch) {
+                    moved += 1;
+                }
+            }
+        }
+        if moved > 0 {
+            report.fixed(IssueKind::OutOfRange, Some(index), format!("Moved {} note(s) by octaves into the range of the instrument", moved));
+        }
+
+        // A note cut short by a repeat of the same pitch; identical notes are merged
+        track.notes.sort_by_key(|note| (note.pitch, note.start));
+        let mut overlaps = 0;
+        let mut kept: Vec<Note> = Vec::with_capacity(track.notes.len());
+        for note in track.notes.drain(..) {
+            match kept.last_mut() {
+                Some(previous) if previous.pitch == note.pitch && previous.start == note.start => {
+                    previous.duration = previous.duration.max(note.duration);
+                    previous.velocity = previous.velocity.max(note.velocity);
+                    overlaps += 1;
+                }
+                Some(previous) if previous.pitch == note.pitch && previous.end() > note.start => {
+                    previous.duration = note.start - previous.start;
+                    overlaps += 1;
+                    kept.push(note);
+                }
+                _ => kept.push(note),
+            }
+        }
+        kept.sort_by_key(|note| (note.start, note.pitch));
+        track.notes = kept;
+        if overlaps > 0 {
+            report.fixed(IssueKind::OverlappingNotes, Some(index), format!("Resolved {} overlapping note(s) of the same pitch", overlaps));
+        }
+    }
+
+    let mut clamped = 0;
+    for tempo in composition.tempos.iter_mut() {
+        let bpm = 60_000_000.0 / tempo.micros_per_beat.max(1) as f64;
+        if !(MIN_BPM..=MAX_BPM).contains(&bpm) {
+            tempo.micros_per_beat = (60_000_000.0 / bpm.clamp(MIN_BPM, MAX_BPM)).round() as u32;
+            clamped += 1;
+        }
+    }
+    if clamped > 0 {
+        report.fixed(IssueKind::AbsurdTempo, None, format!("Clamped {} tempo change(s) to {}-{} BPM", clamped, MIN_BPM, MAX_BPM));
+    }
+
+    let seconds = composition.duration_seconds();
+    if seconds < MIN_SECONDS {
+        report.unfixed(IssueKind::TooShort, None, format!("The composition is only {:.1} seconds long", seconds));
+    }
+    if let Some(requested) = requested {
+        if seconds * LENGTH_TOLERANCE < requested || seconds > requested * LENGTH_TOLERANCE {
+            report.unfixed(
+                IssueKind::LengthMismatch,
+                None,
+                format!("The composition lasts {:.0} seconds but {:.0} were requested", seconds, requested),
+            );
+        }
+    }
+    report
+}
+
+// Validates the sidecar's MIDI output, rewriting and re-rendering it when anything was repaired
+pub fn validate_output(midi: &Path, audio: &Path, prompt: &str) -> Result<ValidationReport, String> {
+    let mut composition = Composition::from_file(midi)?;
+    let report = validate(&mut composition, requested_seconds(prompt));
+    if report.changed() {
+        composition.write_file(midi)?;
+        render_midi(midi, &active_soundfont()?, audio, &RenderSettings::default())?;
+    }
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::midi::{TempoChange, Track};
+
+    fn note(start: u64, duration: u64, pitch: u8) -> Note {
+        Note { start, duration, pitch, velocity: 80 }
+    }
+
+    // Four bars at 120 BPM, long enough to pass the length checks
+    fn piece(channel: u8, program: u8, notes: Vec<Note>) -> Composition {
+        let mut composition = Composition::new(480);
+        let mut notes = notes;
+        notes.push(note(7200, 480, 60));
+        composition.tracks.push(Track { name: None, channel, program, notes });
+        composition
+    }
+
+    fn kinds(report: &ValidationReport) -> Vec<(IssueKind, bool)> {
+        report.issues.iter().map(|issue| (issue.kind, issue.fixed)).collect()
+    }
+
+    #[test]
+    fn a_clean_piece_has_no_issues() {
+        let mut composition = piece(0, 0, vec![note(0, 480, 60), note(480, 480, 64)]);
+        let report = validate(&mut composition, None);
+        assert!(report.issues.is_empty());
+        assert!(!report.retry);
+    }
+
+    #[test]
+    fn zero_length_notes_become_sixteenths() {
+        let mut composition = piece(0, 0, vec![note(0, 0, 60)]);
+        let report = validate(&mut composition, None);
+        assert_eq!(kinds(&report), vec![(IssueKind::ZeroLengthNote, true)]);
+        assert_eq!(composition.tracks[0].notes[0], note(0, 120, 60));
+        assert!(report.changed() && !report.retry);
+    }
+
+    #[test]
+    fn out_of_range_notes_move_by_octaves() {
+        // Bass range is 28-67
+        let mut composition = piece(0, 33, vec![note(0, 480, 90), note(480, 480, 10), note(960, 480, 40)]);
+        let report = validate(&mut composition, None);
+        assert_eq!(kinds(&report), vec![(IssueKind::OutOfRange, true)]);
+        assert!(report.issues[0].message.starts_with("Moved 2 note(s)"));
+        let pitches: Vec<u8> = composition.tracks[0].notes.iter().map(|note| note.pitch).collect();
+        assert_eq!(pitches, vec![66, 34, 40, 60]);
+    }
+
+    #[test]
+    fn drum_notes_are_left_alone() {
+        let mut composition = piece(DRUM_CHANNEL, 0, vec![note(0, 120, 20), note(480, 120, 100)]);
+        let report = validate(&mut composition, None);
+        assert!(report.issues.is_empty());
+        assert_eq!(composition.tracks[0].notes[0].pitch, 20);
+    }
+
+    #[test]
+    fn overlapping_repeats_are_cut_and_duplicates_merged() {
+        let mut composition = piece(0, 0, vec![note(0, 960, 62), note(480, 480, 62), note(1920, 240, 64), Note { velocity: 100, ..note(1920, 480, 64) }]);
+        let report = validate(&mut composition, None);
+        assert_eq!(kinds(&report), vec![(IssueKind::OverlappingNotes, true)]);
+        let notes = &composition.tracks[0].notes;
+        assert_eq!(notes[..3], [note(0, 480, 62), note(480, 480, 62), Note { velocity: 100, ..note(1920, 480, 64) }]);
+    }
+
+    #[test]
+    fn absurd_tempos_are_clamped() {
+        let mut composition = piece(0, 0, vec![note(0, 480, 60)]);
+        composition.tempos.push(TempoChange { tick: 0, micros_per_beat: 100_000 });
+        composition.tempos.push(TempoChange { tick: 3840, micros_per_beat: 10_000_000 });
+        let report = validate(&mut composition, None);
+        assert_eq!(kinds(&report), vec![(IssueKind::AbsurdTempo, true)]);
+        assert_eq!(composition.tempos[0].micros_per_beat, 200_000);
+        assert_eq!(composition.tempos[1].micros_per_beat, 3_000_000);
+    }
+
+    #[test]
+    fn unrepairable_pieces_ask_for_a_retry() {
+        let mut empty = Composition::new(480);
+        let report = validate(&mut empty, None);
+        assert_eq!(kinds(&report), vec![(IssueKind::NoNotes, false)]);
+        assert!(report.retry);
+
+        let mut short = Composition::new(480);
+        short.tracks.push(Track { name: None, channel: 0, program: 0, notes: vec![note(0, 480, 60)] });
+        assert_eq!(kinds(&validate(&mut short, None)), vec![(IssueKind::TooShort, false)]);
+
+        // Four seconds long, a minute was asked for
+        let mut composition = piece(0, 0, vec![]);
+        let report = validate(&mut composition, Some(60.0));
+        assert_eq!(kinds(&report), vec![(IssueKind::LengthMismatch, false)]);
+        assert!(validate(&mut composition, Some(6.0)).issues.is_empty());
+    }
+
+    #[test]
+    fn requested_lengths_are_read_from_the_prompt() {
+        assert_eq!(requested_seconds("a 2 minute piece for piano"), Some(120.0));
+        assert_eq!(requested_seconds("about 90 seconds, calm."), Some(90.0));
+        assert_eq!(requested_seconds("a 1.5-min jingle"), Some(90.0));
+        assert_eq!(requested_seconds("two minutes of rain"), None);
+        assert_eq!(requested_seconds("a calm piece"), None);
+    }
+}
@@ -5,13 +5,27 @@ const info_body = document.getElementById("info_body");
 const infoAlertModal = document.getElementById("infoAlertModal");
 const convert_button = document.getElementById("convert-button");
 const textInput = document.getElementById("textInput");
+const mode = document.getElementById("mode");
+const mood = document.getElementById("mood");
+const sections = document.getElementById("sections");
+const variants = document.getElementById("variants");
+const compare = document.getElementById("compare");
 const save_config = document.getElementById("save_config");
 const cancel_config = document.getElementById("cancel_config");
+const clear_cache = document.getElementById("clear_cache");
 const config = document.getElementById("config");
 const download = document.getElementById("download");
+const export_score = document.getElementById("export_score");
+const score_format = document.getElementById("score_format");
+const score = document.getElementById("score");
 const configModal = document.getElementById("configModal");
 const body = document.querySelector("body");
 const api_key = document.getElementById("api_key");
+const api_keys = document.getElementById("api_keys");
+const provider_kind = document.getElementById("provider_kind");
+const provider_base_url = document.getElementById("provider_base_url");
+const provider_model = document.getElementById("provider_model");
+let provider = null;
 const playButton = document.querySelector('.play-button');
 const waveBars = document.querySelectorAll('.wave-bar');
 let isPlaying = false;
@@ -47,6 +61,115 @@ listen('success', (event) => {
 listen('tune_file_created', (event) => {
 	appendConsoleMessage(`<span style="color:green">${event.payload}</span>`);
 	const assetUrl = convertFileSrc(event.payload);
+	show_compare();
+	show_score();
+});
+
+// Engraves the newest library entry into the score view
+let scoreTrack = null;
+let scoreTimer = null;
+const show_score = async () => {
+	const entries = await invokeAPI("list_library");
+	const entry = entries[entries.length - 1];
+	if (!entry || !entry.midi) {
+		return;
+	}
+	score.innerHTML = await invokeAPI("render_score", { track: entry.id });
+	scoreTrack = entry.id;
+}
+// Lights up the measure under the playback position
+const follow_score = async () => {
+	if (!scoreTrack) {
+		return;
+	}
+	const measure = await invokeAPI("current_measure", { track: scoreTrack });
+	score.querySelectorAll('.measure-highlight').forEach((rect) => {
+		rect.classList.toggle("active", Number(rect.dataset.measure) === measure);
+	});
+}
+
+// A/B buttons for the newest library entry when it has several variants
+const show_compare = async () => {
+	compare.innerHTML = "";
+	const entries = await invokeAPI("list_library");
+	const entry = entries[entries.length - 1];
+	if (!entry || entry.variants.length < 2) {
+		return;
+	}
+	const start = document.createElement("button");
+	start.className = "button cp";
+	start.innerText = "Compare";
+	start.addEventListener('click', () => invokeAPI("compare_variants", { track: entry.id }));
+	compare.appendChild(start);
+	let current = 0;
+	entry.variants.forEach((variant, index) => {
+		const button = document.createElement("button");
+		button.className = "button cp";
+		button.innerText = String.fromCharCode(65 + index);
+		button.addEventListener('click', () => {
+			current = index;
+			invokeAPI("switch_variant", { index });
+		});
+		compare.appendChild(button);
+	});
+	const keep = document.createElement("button");
+	keep.className = "button cp";
+	keep.innerText = "Keep current";
+	keep.addEventListener('click', () => invokeAPI("select_variant", { track: entry.id, variant: current }));
+	compare.appendChild(keep);
+}
+// One row per saved API key with its usage and health, and buttons to disable or remove it
+const show_api_keys = async (keys) => {
+	keys = keys || await invokeAPI("list_api_keys");
+	api_keys.innerHTML = "";
+	keys.forEach((key) => {
+		const row = document.createElement("div");
+		row.className = "api-key";
+		const text = document.createElement("span");
+		const error = key.last_error && key.health !== "healthy" ? ` · ${key.last_error}` : "";
+		const server = key.provider === "openai_compatible" ? " · server" : "";
+		text.innerText = `${key.active ? "● " : ""}${key.label} ${key.hint}${server} · ${key.health} · ${key.requests} requests${error}`;
+		row.appendChild(text);
+		const toggle = document.createElement("button");
+		toggle.className = "button cp";
+		toggle.innerText = key.enabled ? "Disable" : "Enable";
+		toggle.addEventListener('click', async () => show_api_keys(await invokeAPI("set_api_key_enabled", { id: key.id, enabled: !key.enabled })));
+		row.appendChild(toggle);
+		const remove = document.createElement("button");
+		remove.className = "button cp";
+		remove.innerText = "Remove";
+		remove.addEventListener('click', async () => show_api_keys(await invokeAPI("remove_api_key", { id: key.id })));
+		row.appendChild(remove);
+		api_keys.appendChild(row);
+	});
+}
+// The server fields only apply to OpenAI-compatible providers, the mock needs no key
+const show_provider = () => {
+	const server = provider_kind.value === "openai_compatible";
+	provider_base_url.style.display = server ? "" : "none";
+	provider_model.style.display = server ? "" : "none";
+	api_key.style.display = provider_kind.value === "mock" ? "none" : "";
+	api_key.placeholder = server ? "Server API key (optional)..." : "Gemini Key...";
+}
+provider_kind.addEventListener('change', show_provider);
+listen('cache', (event) => {
+	const result = JSON.parse(event.payload);
+	appendConsoleMessage(result.hit ? "Cache hit, reusing an earlier result" : "Cache miss, generating");
+});
+// Puts the cursor on the position of an ABC parse error
+listen('abc_error', (event) => {
+	const error = JSON.parse(event.payload);
+	const lines = textInput.value.split("\n");
+	const offset = lines.slice(0, error.line - 1).reduce((sum, line) => sum + line.length + 1, 0) + error.column - 1;
+	textInput.focus();
+	textInput.setSelectionRange(offset, offset + 1);
+});
+listen('validation_report', (event) => {
+	const report = JSON.parse(event.payload);
+	report.issues.forEach((issue) => {
+		const color = issue.fixed ? "orange" : "red";
+		appendConsoleMessage(`<span style="color:${color}">${issue.message}</span>`);
+	});
 });
 listen('initialize_setup_processing', (event) => {
 	info_body.innerHTML = event.payload;
@@ -120,11 +243,15 @@ const play_audio = () => {
 		document.head.appendChild(styleElement);
 	}
 	invokeAPI("play_audio");
+	clearInterval(scoreTimer);
+	scoreTimer = setInterval(follow_score, 200);
 }
 
 const stop_audio = () => {
 	isPlaying = false;
 	invokeAPI("stop_audio");
+	clearInterval(scoreTimer);
+	score.querySelectorAll('.measure-highlight.active').forEach((rect) => rect.classList.remove("active"));
 	// Change button back to play icon
 	playButton.innerHTML = play_svg;
 	playButton.classList.toggle("removeleft");
@@ -143,15 +270,28 @@ window.addEventListener('DOMContentLoaded', async () => {
 		convert_button.style.pointerEvents = "none";
 		convert_button.style.opacity = 0.5;
 
-		const api_key_value = await invokeAPI("load_config", { key: "api_key" });
-		if (api_key_value) {
-			api_key.value = api_key_value;
+		// Only a hint of the saved key comes back, the field stays empty unless it is replaced
+		const api_key_hint = await invokeAPI("load_config", { key: "api_key" });
+		if (api_key_hint) {
+			api_key.placeholder = `Gemini Key saved (${api_key_hint})...`;
 		}
 	} catch (error) {
 		console.error("Error loading saved selections:", error);
 	}
 });
 
+let moodTimer = null;
+const show_mood = async (text) => {
+	if (!text.trim()) {
+		mood.innerHTML = "";
+		return;
+	}
+	const analysis = await invokeAPI("analyze_text", { text });
+	const suggestion = analysis.suggestion;
+	const instruments = suggestion.instruments.map((instrument) => instrument.name).join(", ");
+	mood.innerHTML = `${suggestion.key} · ${suggestion.tempo_bpm} BPM · ${suggestion.dynamics} · ${instruments}`;
+}
+
 textInput.addEventListener('input', async (event) => {
 	if (event.target.value && event.target.value.length > 0) {
 		convert_button.style.pointerEvents = "";
@@ -160,6 +300,8 @@ textInput.addEventListener('input', async (event) => {
 		convert_button.style.pointerEvents = "none";
 		convert_button.style.opacity = 0.5;
 	}
+	clearTimeout(moodTimer);
+	moodTimer = setTimeout(() => show_mood(event.target.value), 400);
 });
 
 
@@ -167,20 +309,57 @@ convert_button.addEventListener('click', async () => {
 	info_header.innerText = "Generating Tunes...";
 	info_body.innerHTML = "";
 	infoAlertModal.style.display = "flex";
-	invokeAPI("generate_tunes", { text: textInput.value });
+	invokeAPI("generate_tunes", { text: textInput.value, mode: mode.value, sections: sections.checked, variants: Number(variants.value) || 1 });
+});
+
+// Writes the newest library entry as notation next to its audio
+export_score.addEventListener('click', async () => {
+	const entries = await invokeAPI("list_library");
+	const entry = entries[entries.length - 1];
+	if (!entry) {
+		return;
+	}
+	const path = await invokeAPI("export_notation", { track: entry.id, format: score_format.value });
+	appendConsoleMessage(`<span style="color:green">Score written to ${path}</span>`);
 });
 
 config.addEventListener('click', async () => {
 	body.style.overflow = 'hidden';
 	configModal.style.display = "flex";
+	show_api_keys();
+	provider = await invokeAPI("get_provider");
+	provider_kind.value = provider.kind;
+	provider_base_url.value = provider.base_url || "";
+	provider_model.value = provider.model || "";
+	show_provider();
 });
 
 save_config.addEventListener('click', async () => {
+	const settings = { ...provider, kind: provider_kind.value, base_url: provider_base_url.value || null, model: provider_model.value || null };
+	try {
+		provider = await invokeAPI("set_provider", { settings });
+	} catch (error) {
+		appendConsoleMessage(`<span style="color:red">${error}</span>`);
+		return;
+	}
 	body.style.overflow = '';
-	invokeAPI("save_config", { apiKey: api_key.value, systemPrompt: "" });
+	if (settings.kind === "gemini") {
+		invokeAPI("save_config", { apiKey: api_key.value || null, systemPrompt: "" });
+	} else if (settings.kind === "openai_compatible" && api_key.value) {
+		invokeAPI("add_api_key", { label: "", key: api_key.value, provider: settings.kind });
+	}
+	if (api_key.value) {
+		api_key.placeholder = "Add another key...";
+		api_key.value = "";
+	}
 	configModal.style.display = "none";
 });
 
+clear_cache.addEventListener('click', async () => {
+	const freed = await invokeAPI("clear_cache");
+	appendConsoleMessage(`Cleared ${(freed / 1048576).toFixed(1)} MB of cached results`);
+});
+
 cancel_config.addEventListener('click', async () => {
 	body.style.overflow = '';
 	configModal.style.display = "none";